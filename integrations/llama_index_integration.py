@@ -31,7 +31,7 @@ DEFAULT_TOPK = 32
 DEFAULT_TOPP = 0.1
 DEFAULT_REPEAT_LAST_N = 64
 DEFAULT_MAX_SEQS = 16
-DEFAULT_PREFIX_CACHE_N = 16
+DEFAULT_PREFIX_CACHE_DEVICE_MB = 1024
 
 
 def llama_index_to_mistralrs_messages(
@@ -168,7 +168,7 @@ class MistralRS(CustomLLM):
         in_situ_quant: Optional[str] = None,
         max_seqs: int = DEFAULT_MAX_SEQS,
         token_source: str = "cache",
-        prefix_cache_n: str = DEFAULT_PREFIX_CACHE_N,
+        prefix_cache_device_mb: int = DEFAULT_PREFIX_CACHE_DEVICE_MB,
         no_kv_cache: bool = False,
         chat_template: Optional[str] = None,
         top_logprobs: Optional[int] = None,
@@ -215,7 +215,7 @@ class MistralRS(CustomLLM):
             which=which,
             token_source=token_source,
             max_seqs=max_seqs,
-            prefix_cache_n=prefix_cache_n,
+            prefix_cache_device_mb=prefix_cache_device_mb,
             no_kv_cache=no_kv_cache,
             chat_template=chat_template,
             in_situ_quant=in_situ_quant,
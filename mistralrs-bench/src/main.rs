@@ -60,10 +60,25 @@ fn run_bench(
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        dry_params: None,
+        repeat_last_n: None,
         max_len: Some(n_gen),
         stop_toks: None,
+        stop_regexes: None,
+        stop_token_seqs: None,
+        include_stop_str_in_output: false,
         logits_bias: None,
         n_choices: 1,
+        best_of: None,
+        best_of_length_normalize: None,
+        beam_search: None,
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        top_a: None,
+        mirostat: None,
+        sampler_order: None,
     };
     let sender = mistralrs.get_sender();
     let (tx, mut rx) = channel(10_000);
@@ -77,6 +92,7 @@ fn run_bench(
         is_streaming: false,
         constraint: Constraint::None,
         suffix: None,
+        logits_processors: None,
     };
 
     let mut usages = Vec::new();
@@ -217,10 +233,25 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        dry_params: None,
+        repeat_last_n: None,
         max_len: Some(5),
         stop_toks: None,
+        stop_regexes: None,
+        stop_token_seqs: None,
+        include_stop_str_in_output: false,
         logits_bias: None,
         n_choices: 1,
+        best_of: None,
+        best_of_length_normalize: None,
+        beam_search: None,
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        top_a: None,
+        mirostat: None,
+        sampler_order: None,
     };
     let sender = mistralrs.get_sender();
     let (tx, mut rx) = channel(10_000);
@@ -230,7 +261,6 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         messages: RequestMessage::Completion {
             text: "Hello!".to_string(),
             echo_prompt: false,
-            best_of: 1,
         },
         sampling_params: sampling_params.clone(),
         response: tx,
@@ -238,6 +268,7 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         is_streaming: false,
         constraint: Constraint::None,
         suffix: None,
+        logits_processors: None,
     };
 
     sender
@@ -362,7 +393,6 @@ fn main() -> anyhow::Result<()> {
                 RequestMessage::Completion {
                     text: "Rust".to_string(),
                     echo_prompt: false,
-                    best_of: 1,
                 },
                 args.n_gen - 1,
                 *concurrency,
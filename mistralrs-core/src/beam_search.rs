@@ -0,0 +1,453 @@
+#![allow(clippy::cast_precision_loss)]
+
+//! Beam search over the sequences of a [`crate::sequence::SequenceGroup`]. Each beam is a
+//! [`Sequence`]: at every step the best continuations of all beams of the group are selected, and
+//! each beam continues from the state and KV cache of the beam it extends.
+
+use std::iter::zip;
+
+use candle_core::{DType, Result, Tensor};
+
+use crate::{
+    pipeline::Pipeline,
+    response::{
+        ChatCompletionResponse, Choice, CompletionChoice, CompletionResponse, Response,
+        ResponseLogprob, ResponseMessage, SYSTEM_FINGERPRINT,
+    },
+    sampler::{BeamSearchParams, Logprobs},
+    sequence::{Sequence, SequenceState, StopReason},
+};
+
+/// A finished beam.
+struct Hypothesis {
+    score: f32,
+    logprobs: Vec<Logprobs>,
+    completion_bytes: Vec<u8>,
+    reason: StopReason,
+}
+
+/// The beam search state of a sequence group.
+pub struct BeamSearch {
+    params: BeamSearchParams,
+    finished: Vec<Hypothesis>,
+}
+
+impl BeamSearch {
+    pub fn new(params: BeamSearchParams) -> Self {
+        Self {
+            params,
+            finished: Vec::new(),
+        }
+    }
+
+    fn score(&self, cumulative_logprob: f32, len: usize) -> f32 {
+        cumulative_logprob / (len.max(1) as f32).powf(self.params.length_penalty)
+    }
+
+    /// Add a finished beam, keeping only the best `num_beams`.
+    fn add(
+        &mut self,
+        cumulative_logprob: f32,
+        logprobs: Vec<Logprobs>,
+        completion_bytes: Vec<u8>,
+        reason: StopReason,
+    ) {
+        self.finished.push(Hypothesis {
+            score: self.score(cumulative_logprob, logprobs.len()),
+            logprobs,
+            completion_bytes,
+            reason,
+        });
+        self.finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.finished.truncate(self.params.num_beams);
+    }
+
+    /// Whether the search is over, given the best score of the running beams.
+    fn is_done(&self, best_running: Option<f32>) -> bool {
+        let Some(best_running) = best_running else {
+            return true;
+        };
+        if self.finished.len() < self.params.num_beams {
+            return false;
+        }
+        self.params.early_stopping
+            || self
+                .finished
+                .last()
+                .is_some_and(|worst| best_running <= worst.score)
+    }
+}
+
+/// The tokens which would repeat an n-gram of `size` tokens of `toks`.
+fn banned_ngram_tokens(toks: &[u32], size: usize) -> Vec<u32> {
+    if size == 0 || toks.len() < size {
+        return vec![];
+    }
+    let prefix = &toks[toks.len() - (size - 1)..];
+    toks.windows(size)
+        .filter(|ngram| ngram[..size - 1] == *prefix)
+        .map(|ngram| ngram[size - 1])
+        .collect()
+}
+
+/// Run a beam search step for the beam search sequences of a batch, given their logits.
+pub(crate) async fn step<P: Pipeline + ?Sized>(
+    pipeline: &P,
+    seqs: Vec<&mut Sequence>,
+    logits: Vec<Tensor>,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    let mut beams = zip(seqs, logits).collect::<Vec<_>>();
+    while !beams.is_empty() {
+        let mut rest = beams.into_iter();
+        let head = rest.next().expect("There is at least one beam.");
+        let (mut group, rest): (Vec<_>, Vec<_>) =
+            rest.partition(|(seq, _)| seq.same_group(&*head.0));
+        group.insert(0, head);
+        step_group(pipeline, group, eos_tok).await?;
+        beams = rest;
+    }
+    Ok(())
+}
+
+async fn step_group<P: Pipeline + ?Sized>(
+    pipeline: &P,
+    beams: Vec<(&mut Sequence, Tensor)>,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    let (mut seqs, logits): (Vec<&mut Sequence>, Vec<Tensor>) = beams.into_iter().unzip();
+    let params = seqs[0]
+        .get_mut_group()
+        .beam_search
+        .as_ref()
+        .expect("Not a beam search group.")
+        .params
+        .clone();
+    let metadata = pipeline.get_metadata();
+
+    // On the first step all beams are the same, so only the first one is expanded.
+    let n_sources = if seqs.iter().all(|seq| seq.logprobs().is_empty()) {
+        1
+    } else {
+        seqs.len()
+    };
+    let states = seqs[..n_sources]
+        .iter()
+        .map(|seq| seq.beam_state())
+        .collect::<Vec<_>>();
+
+    let mut candidates = Vec::new();
+    for (src, (seq, logits)) in zip(&mut seqs, logits).take(n_sources).enumerate() {
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        let toks = states[src].tokens();
+        let logits = seq.sampler().apply_logits_processors(logits, toks)?;
+        let repeat_last_n = seq
+            .sampler()
+            .repeat_last_n()
+            .unwrap_or(metadata.repeat_last_n);
+        let start_at = toks.len().saturating_sub(repeat_last_n);
+        let banned = banned_ngram_tokens(toks, params.no_repeat_ngram_size);
+        let return_logprobs = seq.return_logprobs();
+        for tok in seq.sampler().top_candidates(
+            logits,
+            Some(&toks[start_at..]),
+            2 * params.num_beams,
+            return_logprobs,
+            &banned,
+        )? {
+            candidates.push((states[src].cumulative_logprob() + tok.logprob, src, tok));
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    // Finished candidates become hypotheses, the best others continue in the beam slots.
+    let n_slots = seqs.len()
+        + seqs
+            .iter_mut()
+            .map(|seq| seq.forks_mut().len())
+            .sum::<usize>();
+    let mut finished = Vec::new();
+    let mut next = Vec::new();
+    for (rank, (cumulative_logprob, src, tok)) in candidates.into_iter().enumerate() {
+        if next.len() == n_slots {
+            break;
+        }
+        match seqs[src].is_done(tok.token, eos_tok, metadata.max_seq_len) {
+            Some(reason @ (StopReason::Eos | StopReason::StopTok(_))) => {
+                // A finished beam only counts if it is among the best `num_beams` candidates.
+                if rank < params.num_beams {
+                    let mut logprobs = states[src].logprobs().to_vec();
+                    logprobs.push(tok);
+                    let completion_bytes = states[src].completion_bytes().to_vec();
+                    finished.push((cumulative_logprob, logprobs, completion_bytes, reason));
+                }
+            }
+            _ => next.push((src, tok)),
+        }
+    }
+
+    let mut next = next.into_iter();
+    let mut assign = |seq: &mut Sequence| match next.next() {
+        Some((src, tok)) => {
+            let completion_bytes = metadata.tok_trie.decode(&[tok.token]);
+            seq.set_beam_state(states[src].clone(), tok, completion_bytes);
+        }
+        None => seq.set_state(SequenceState::Done(StopReason::Canceled)),
+    };
+    for seq in seqs.iter_mut() {
+        assign(&mut **seq);
+        seq.forks_mut().iter_mut().for_each(&mut assign);
+    }
+
+    // Beams can also finish by length or by a stop string.
+    let mut running = Vec::new();
+    let mut check = |seq: &mut Sequence| {
+        if !seq.is_running() {
+            return;
+        }
+        let tok = seq
+            .logprobs()
+            .last()
+            .expect("A token was just added.")
+            .token;
+        match seq.is_done(tok, None, metadata.max_seq_len) {
+            Some(reason) => {
+                seq.set_state(SequenceState::Done(reason));
+                finished.push((
+                    seq.cumulative_logprob(),
+                    seq.logprobs().to_vec(),
+                    seq.completion_bytes().to_vec(),
+                    reason,
+                ));
+            }
+            None => running.push((seq.cumulative_logprob(), seq.logprobs().len())),
+        }
+    };
+    for seq in seqs.iter_mut() {
+        check(&mut **seq);
+        seq.forks_mut().iter_mut().for_each(&mut check);
+    }
+
+    let hypotheses = {
+        let mut group = seqs[0].get_mut_group();
+        let search = group
+            .beam_search
+            .as_mut()
+            .expect("Not a beam search group.");
+        for (cumulative_logprob, logprobs, completion_bytes, reason) in finished {
+            search.add(cumulative_logprob, logprobs, completion_bytes, reason);
+        }
+        let best_running = running
+            .into_iter()
+            .map(|(cumulative_logprob, len)| search.score(cumulative_logprob, len))
+            .reduce(f32::max);
+        if !search.is_done(best_running) {
+            return Ok(());
+        }
+        std::mem::take(&mut search.finished)
+    };
+
+    let cancel = |seq: &mut Sequence| {
+        if seq.is_running() {
+            seq.set_state(SequenceState::Done(StopReason::Canceled));
+        }
+    };
+    for seq in seqs.iter_mut() {
+        cancel(&mut **seq);
+        seq.forks_mut().iter_mut().for_each(cancel);
+    }
+    finish(pipeline, &*seqs[0], hypotheses).await?;
+    pipeline.reset_non_granular_state();
+    Ok(())
+}
+
+/// Respond with the best hypotheses of a finished beam search.
+async fn finish<P: Pipeline + ?Sized>(
+    pipeline: &P,
+    seq: &Sequence,
+    hypotheses: Vec<Hypothesis>,
+) -> Result<()> {
+    let tokenizer = pipeline.tokenizer();
+    let (is_chat, n_choices) = {
+        let group = seq.get_mut_group();
+        (group.is_chat, group.n_choices())
+    };
+    for (index, hypothesis) in hypotheses.into_iter().take(n_choices).enumerate() {
+        let text = seq.output_text(&hypothesis.reason, &hypothesis.completion_bytes);
+        let completion_toks = hypothesis.logprobs.len();
+        if is_chat {
+            let logprobs = if seq.return_logprobs() {
+                let mut logprobs = Vec::new();
+                for logprob in &hypothesis.logprobs {
+                    logprobs.push(ResponseLogprob {
+                        token: tokenizer
+                            .decode(&[logprob.token], false)
+                            .map_err(candle_core::Error::msg)?,
+                        bytes: logprob.bytes.clone().into_bytes(),
+                        logprob: logprob.logprob,
+                        top_logprobs: logprob.top_logprobs.clone().unwrap_or_default(),
+                    });
+                }
+                Some(crate::response::Logprobs {
+                    content: Some(logprobs),
+                })
+            } else {
+                None
+            };
+            let choice = Choice {
+                finish_reason: hypothesis.reason.to_string(),
+                index,
+                message: ResponseMessage {
+                    content: text,
+                    role: "assistant".to_string(),
+                },
+                logprobs,
+            };
+            seq.add_beam_choice_to_group(choice, hypothesis.score, completion_toks);
+        } else {
+            let choice = CompletionChoice {
+                finish_reason: hypothesis.reason.to_string(),
+                index,
+                text,
+                logprobs: None,
+            };
+            seq.add_beam_completion_choice_to_group(choice, hypothesis.score, completion_toks);
+        }
+    }
+
+    let response = {
+        let group = seq.get_mut_group();
+        if is_chat {
+            Response::Done(ChatCompletionResponse {
+                id: seq.id().to_string(),
+                choices: group.get_choices(),
+                created: seq.creation_time(),
+                model: pipeline.name(),
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "chat.completion".to_string(),
+                usage: group.get_usage(),
+            })
+        } else {
+            Response::CompletionDone(CompletionResponse {
+                id: seq.id().to_string(),
+                choices: group.get_completion_choices(),
+                created: seq.creation_time(),
+                model: pipeline.name(),
+                system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                object: "text_completion".to_string(),
+                usage: group.get_usage(),
+            })
+        }
+    };
+    seq.responder()
+        .send(response)
+        .await
+        .map_err(candle_core::Error::msg)
+}
+
+mod tests {
+    /// A beam search with `num_beams` beams and a length penalty of 1.
+    #[allow(dead_code)]
+    fn beam_search(num_beams: usize, early_stopping: bool) -> super::BeamSearch {
+        super::BeamSearch::new(crate::sampler::BeamSearchParams {
+            num_beams,
+            early_stopping,
+            ..Default::default()
+        })
+    }
+
+    /// Add a finished beam of `len` tokens.
+    #[allow(dead_code)]
+    fn add(search: &mut super::BeamSearch, cumulative_logprob: f32, len: usize) {
+        let logprobs = (0..len)
+            .map(|token| crate::sampler::Logprobs {
+                token: token as u32,
+                logprob: cumulative_logprob / len as f32,
+                bytes: String::new(),
+                top_logprobs: None,
+                mirostat_surprise: None,
+            })
+            .collect();
+        search.add(
+            cumulative_logprob,
+            logprobs,
+            Vec::new(),
+            crate::sequence::StopReason::Eos,
+        );
+    }
+
+    #[allow(dead_code)]
+    fn scores(search: &super::BeamSearch) -> Vec<f32> {
+        search.finished.iter().map(|h| h.score).collect()
+    }
+
+    #[test]
+    fn test_banned_ngram_tokens() {
+        use super::banned_ngram_tokens;
+
+        assert!(banned_ngram_tokens(&[1, 2, 1], 0).is_empty());
+        // Every token which was generated is a repeated 1-gram.
+        assert_eq!(banned_ngram_tokens(&[3, 1, 3], 1), vec![3, 1, 3]);
+        assert!(banned_ngram_tokens(&[], 1).is_empty());
+        assert_eq!(banned_ngram_tokens(&[1, 2, 3, 1], 2), vec![2]);
+        assert_eq!(
+            banned_ngram_tokens(&[1, 2, 3, 1, 2, 4, 1, 2], 3),
+            vec![3, 4]
+        );
+        assert!(banned_ngram_tokens(&[1, 2, 3], 3).is_empty());
+        assert!(banned_ngram_tokens(&[1, 2], 3).is_empty());
+    }
+
+    #[test]
+    fn test_beam_search_score() {
+        use crate::sampler::BeamSearchParams;
+
+        let search = |length_penalty| {
+            super::BeamSearch::new(BeamSearchParams {
+                length_penalty,
+                ..Default::default()
+            })
+        };
+        assert_eq!(search(1.).score(-4., 2), -2.);
+        assert_eq!(search(2.).score(-4., 2), -1.);
+        // No length penalty ranks by the cumulative logprob alone, favouring short beams.
+        assert_eq!(search(0.).score(-4., 2), -4.);
+        assert_eq!(search(1.).score(-4., 0), -4.);
+    }
+
+    #[test]
+    fn test_beam_search_add() {
+        let mut search = beam_search(2, false);
+        add(&mut search, -3., 1);
+        add(&mut search, -1., 1);
+        assert_eq!(scores(&search), vec![-1., -3.]);
+        // Only the best `num_beams` hypotheses are kept.
+        add(&mut search, -2., 1);
+        assert_eq!(scores(&search), vec![-1., -2.]);
+        add(&mut search, -5., 1);
+        assert_eq!(scores(&search), vec![-1., -2.]);
+        // Hypotheses are ranked by their length normalized score.
+        add(&mut search, -3., 2);
+        assert_eq!(scores(&search), vec![-1., -1.5]);
+    }
+
+    #[test]
+    fn test_beam_search_is_done() {
+        let mut search = beam_search(2, false);
+        assert!(search.is_done(None));
+        add(&mut search, -1., 1);
+        assert!(!search.is_done(Some(-10.)));
+        add(&mut search, -2., 1);
+        // Without early stopping, the search goes on while a running beam can beat the worst
+        // hypothesis.
+        assert!(!search.is_done(Some(-1.5)));
+        assert!(search.is_done(Some(-2.)));
+        assert!(search.is_done(Some(-2.5)));
+
+        let mut search = beam_search(2, true);
+        add(&mut search, -1., 1);
+        assert!(!search.is_done(Some(0.)));
+        add(&mut search, -2., 1);
+        assert!(search.is_done(Some(0.)));
+    }
+}
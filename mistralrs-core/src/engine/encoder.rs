@@ -0,0 +1,101 @@
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    pipeline::EncoderPipeline,
+    request::EncoderRequest,
+    response::{EmbeddingOutput, RerankOutput},
+};
+
+/// Runs an [`EncoderPipeline`]. Requests which are waiting when a batch starts are run together:
+/// their inputs are concatenated, and the pipeline splits them into batches of similar lengths.
+pub(crate) struct EncoderEngine {
+    rx: Receiver<EncoderRequest>,
+    pipeline: EncoderPipeline,
+}
+
+/// Run the inputs of several requests together with `run`, and split the outputs by request. If
+/// that fails, each request is run on its own, so that a bad input only fails its own request.
+fn run_merged<I: Clone, O>(
+    requests: &[Vec<I>],
+    run: impl Fn(Vec<I>) -> anyhow::Result<Vec<O>>,
+) -> Vec<Result<Vec<O>, String>> {
+    match run(requests.iter().flatten().cloned().collect()) {
+        Ok(outputs) => {
+            let mut outputs = outputs.into_iter();
+            requests
+                .iter()
+                .map(|inputs| Ok(outputs.by_ref().take(inputs.len()).collect()))
+                .collect()
+        }
+        Err(e) if requests.len() == 1 => vec![Err(e.to_string())],
+        Err(_) => requests
+            .iter()
+            .map(|inputs| run(inputs.clone()).map_err(|e| e.to_string()))
+            .collect(),
+    }
+}
+
+impl EncoderEngine {
+    pub fn new(rx: Receiver<EncoderRequest>, pipeline: EncoderPipeline) -> Self {
+        Self { rx, pipeline }
+    }
+
+    pub async fn run(&mut self) {
+        while let Some(request) = self.rx.recv().await {
+            let (mut embeds, mut embed_responses) = (Vec::new(), Vec::new());
+            let (mut reranks, mut rerank_responses) = (Vec::new(), Vec::new());
+            let mut next = Some(request);
+            while let Some(request) = next {
+                match request {
+                    EncoderRequest::Embed { inputs, response } => {
+                        embeds.push(inputs);
+                        embed_responses.push(response);
+                    }
+                    EncoderRequest::Rerank {
+                        query,
+                        documents,
+                        response,
+                    } => {
+                        reranks.push(
+                            documents
+                                .into_iter()
+                                .map(|doc| (query.clone(), doc))
+                                .collect::<Vec<_>>(),
+                        );
+                        rerank_responses.push(response);
+                    }
+                }
+                next = self.rx.try_recv().ok();
+            }
+
+            if !embeds.is_empty() {
+                let outputs = run_merged(&embeds, |inputs| self.pipeline.embed(inputs));
+                for (output, response) in outputs.into_iter().zip(embed_responses) {
+                    let output = output.map(|outputs| {
+                        let (embeddings, toks): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+                        EmbeddingOutput {
+                            embeddings,
+                            prompt_tokens: toks.iter().sum(),
+                        }
+                    });
+                    // The requester may have gone away.
+                    let _ = response.send(output).await;
+                }
+            }
+
+            if !reranks.is_empty() {
+                let outputs = run_merged(&reranks, |pairs| self.pipeline.rerank(pairs));
+                for (output, response) in outputs.into_iter().zip(rerank_responses) {
+                    let output = output.map(|outputs| {
+                        let (scores, toks): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+                        RerankOutput {
+                            scores,
+                            prompt_tokens: toks.iter().sum(),
+                        }
+                    });
+                    let _ = response.send(output).await;
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,15 @@
+mod encoder;
+pub(crate) use encoder::EncoderEngine;
+
 use std::{
     collections::{HashMap, VecDeque},
     sync::Arc,
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::{
+    mpsc::{channel, error::TryRecvError, Receiver, Sender},
+    Mutex,
+};
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
@@ -18,29 +24,42 @@ use tracing::info;
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    logits_processor::LogitsProcessor,
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
-    request::Request,
+    prefix_cacher::{PinnedPrefix, PrefixCacheBudgets, PrefixCacheManager},
+    request::{text_messages, PrefixCacheRequest, PrefixMessage, Request},
     response::{ChatCompletionResponse, Choice, ResponseMessage},
     sampler::Sampler,
     scheduler::{Scheduler, SchedulerMethod},
-    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
-    Constraint, StopTokens,
+    sequence::{AttentionSinks, Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    stop_regex::StopRegexes,
+    Constraint, SamplingParams, StopTokens,
 };
 
 const SEED: u64 = 0;
 
+/// A pinned prefix whose cache is being computed.
+struct PendingPin {
+    id: usize,
+    rx: Receiver<Response>,
+    response: Sender<std::result::Result<PinnedPrefix, String>>,
+}
+
 pub struct Engine {
     rx: Receiver<Request>,
     isq_rx: Receiver<GgmlDType>,
+    prefix_rx: Receiver<PrefixCacheRequest>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
     scheduler: Scheduler<VecDeque<Sequence>>,
     id: usize,
     truncate_sequence: bool,
     no_kv_cache: bool,
     prefix_cacher: PrefixCacheManager,
+    pending_pins: Vec<PendingPin>,
     is_debug: bool,
     disable_eos_stop: bool,
+    attention_sinks: Option<AttentionSinks>,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
 }
 
 impl Engine {
@@ -48,19 +67,39 @@ impl Engine {
     pub fn new(
         rx: Receiver<Request>,
         isq_rx: Receiver<GgmlDType>,
+        prefix_rx: Receiver<PrefixCacheRequest>,
         pipeline: Arc<Mutex<dyn Pipeline>>,
         method: SchedulerMethod,
         truncate_sequence: bool,
         no_kv_cache: bool,
         no_prefix_cache: bool,
-        prefix_cache_n: usize,
+        prefix_cache_budgets: PrefixCacheBudgets,
         disable_eos_stop: bool,
+        attention_sinks: Option<AttentionSinks>,
+        logits_processors: Vec<Arc<dyn LogitsProcessor>>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
-        let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
+        let cache_kind = get_mut_arcmutex!(pipeline).get_metadata().cache_kind;
+        let attention_sinks = attention_sinks.filter(|sinks| {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let metadata = pipeline.get_metadata();
+            if no_kv_cache || metadata.rope_shift.is_none() {
+                info!("⚠️ WARNING: Attention sinks are not supported here, disabling them.");
+                false
+            } else if sinks.window == 0 || sinks.sink_tokens + sinks.window > metadata.max_seq_len {
+                info!(
+                    "⚠️ WARNING: Attention sinks must fit in the model length of {}, disabling them.",
+                    metadata.max_seq_len
+                );
+                false
+            } else {
+                true
+            }
+        });
         Self {
             rx,
             isq_rx,
+            prefix_rx,
             pipeline,
             scheduler: Scheduler::new(method),
             id: 0,
@@ -68,14 +107,17 @@ impl Engine {
             no_kv_cache,
             prefix_cacher: PrefixCacheManager::new(
                 device,
-                prefix_cache_n,
-                is_xlora,
+                prefix_cache_budgets,
                 no_prefix_cache,
+                cache_kind,
             ),
+            pending_pins: Vec::new(),
             is_debug: std::env::var("RUST_LOG")
                 .unwrap_or_default()
                 .contains("debug"),
             disable_eos_stop,
+            attention_sinks,
+            logits_processors,
         }
     }
 
@@ -86,6 +128,10 @@ impl Engine {
             while let Ok(request) = self.rx.try_recv() {
                 self.add_request(request).await;
             }
+            while let Ok(request) = self.prefix_rx.try_recv() {
+                self.handle_prefix_cache_request(request).await;
+            }
+            self.poll_pending_pins().await;
             let run_start = Instant::now();
             let mut scheduled = self.scheduler.schedule();
             if let Ok(dtype) = self.isq_rx.try_recv() {
@@ -97,14 +143,41 @@ impl Engine {
             if scheduled.completion.len() > 0 {
                 let current_completion_ids: Vec<usize> =
                     scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                let rope_shift = get_mut_arcmutex!(self.pipeline).get_metadata().rope_shift;
+                let evicted = match rope_shift {
+                    Some(rope_shift) if self.attention_sinks.is_some() => scheduled
+                        .completion
+                        .iter_mut()
+                        .try_fold(false, |evicted, seq| {
+                            Ok::<_, candle_core::Error>(
+                                seq.evict_to_attention_sinks(&rope_shift)? || evicted,
+                            )
+                        }),
+                    _ => Ok(false),
+                };
+                let evicted = handle_pipeline_forward_error!(
+                    "attention sink eviction",
+                    evicted,
+                    &mut scheduled.completion,
+                    self.pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
                 let res = {
                     let mut pipeline = get_mut_arcmutex!(self.pipeline);
-                    let pre_op =
-                        if !self.no_kv_cache && last_completion_ids != current_completion_ids {
-                            CacheInstruction::In
-                        } else {
-                            CacheInstruction::Nonthing
-                        };
+                    // If any sequence cache was modified, the model cache must be refreshed.
+                    // Beam search reorders the caches of the beams.
+                    let is_beam_search =
+                        scheduled.completion.iter().any(|seq| seq.is_beam_search());
+                    let pre_op = if !self.no_kv_cache
+                        && (evicted
+                            || is_beam_search
+                            || last_completion_ids != current_completion_ids)
+                    {
+                        CacheInstruction::In
+                    } else {
+                        CacheInstruction::Nonthing
+                    };
                     let post_op = if !self.no_kv_cache {
                         CacheInstruction::Out
                     } else {
@@ -138,47 +211,82 @@ impl Engine {
                 last_completion_ids = current_completion_ids;
             }
 
+            let mut forked = Vec::new();
             if scheduled.prompt.len() > 0 {
-                let logits = {
-                    let mut pipeline = get_mut_arcmutex!(self.pipeline);
-
-                    // Run the prompt seqs
-                    let post_op = if !self.no_kv_cache {
-                        CacheInstruction::Out
-                    } else {
-                        CacheInstruction::Reset {
-                            reset_non_granular: false,
-                        }
-                    };
+                // Sequences resuming from the prefix cache need their cache loaded, so they can only be
+                // batched with sequences with the same number of cached tokens.
+                let mut prompt_groups: HashMap<usize, Vec<&mut Sequence>> = HashMap::new();
+                for seq in scheduled.prompt.iter_mut() {
+                    prompt_groups
+                        .entry(seq.prefix_cache_len())
+                        .or_default()
+                        .push(&mut **seq);
+                }
+                // A group which fails only fails its own sequences, the others still start.
+                'group: for (prefix_cache_len, mut seqs) in prompt_groups {
+                    let logits = {
+                        let mut pipeline = get_mut_arcmutex!(self.pipeline);
+
+                        // Run the prompt seqs
+                        let post_op = if !self.no_kv_cache {
+                            CacheInstruction::Out
+                        } else {
+                            CacheInstruction::Reset {
+                                reset_non_granular: false,
+                            }
+                        };
 
-                    // Reset non granular state because the old sequence must be dead.
-                    // Technically we don't need to do this but it is better to be safe.
-                    pipeline
-                        .step(
-                            &mut scheduled.prompt,
-                            true,
-                            &mut self.prefix_cacher,
-                            self.disable_eos_stop,
-                            rng.clone(),
+                        // Reset non granular state because the old sequence must be dead.
+                        // Technically we don't need to do this but it is better to be safe.
+                        let pre_op = if prefix_cache_len > 0 {
+                            CacheInstruction::In
+                        } else {
                             CacheInstruction::Reset {
                                 reset_non_granular: false,
-                            },
-                            post_op,
-                        )
-                        .await
-                };
+                            }
+                        };
 
-                handle_pipeline_forward_error!(
-                    "prompt step",
-                    logits,
-                    &mut scheduled.prompt,
-                    self.pipeline,
-                    'lp,
-                    self.prefix_cacher
-                );
+                        pipeline
+                            .step(
+                                &mut seqs,
+                                true,
+                                &mut self.prefix_cacher,
+                                self.disable_eos_stop,
+                                rng.clone(),
+                                pre_op,
+                                post_op,
+                            )
+                            .await
+                    };
+
+                    if let Err(e) = logits {
+                        // The choices waiting to be forked from a failed prompt fail with it, so
+                        // the request still receives all of its choices.
+                        let mut forks = seqs
+                            .iter_mut()
+                            .flat_map(|seq| seq.take_forks())
+                            .collect::<Vec<_>>();
+                        let mut failed =
+                            seqs.into_iter().chain(forks.iter_mut()).collect::<Vec<_>>();
+                        handle_pipeline_forward_error!(
+                            "prompt step",
+                            Err::<(), _>(e),
+                            &mut failed,
+                            self.pipeline,
+                            'group,
+                            self.prefix_cacher
+                        );
+                    }
+                }
 
                 for seq in scheduled.prompt.iter_mut() {
-                    seq.set_state(SequenceState::RunningCompletion);
+                    if seq.is_error() {
+                        continue;
+                    }
+                    // The sequence may have finished on its first token.
+                    if seq.is_prompt() {
+                        seq.set_state(SequenceState::RunningCompletion);
+                    }
                     let now = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
                         .expect("Time travel has occurred!")
@@ -187,6 +295,25 @@ impl Engine {
                     let prompt_tok_per_sec = seq.len() as f32 / (now - seq.timestamp()) as f32;
                     seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                     seq.prompt_timestamp = Some(now);
+
+                    // Beam search already started the forks which it kept.
+                    let (started, forks): (Vec<_>, Vec<_>) = seq
+                        .take_forks()
+                        .into_iter()
+                        .filter(|fork| fork.is_running() || fork.is_waiting())
+                        .partition(|fork| fork.is_completion());
+                    forked.extend(started);
+                    if !forks.is_empty() {
+                        match seq.fork_cache() {
+                            Some((cache, xlora_cache)) => {
+                                forked.extend(forks.into_iter().map(|fork| {
+                                    fork.fork_from(cache.clone(), xlora_cache.clone())
+                                }));
+                            }
+                            // Fall back to processing the prompt for each choice.
+                            None => forked.extend(forks),
+                        }
+                    }
                 }
                 last_completion_ids = vec![];
             }
@@ -217,13 +344,19 @@ impl Engine {
                     );
                 }
             }
-            if scheduled.prompt.len() == 0
-                && scheduled.completion.len() == 0
-                && self.scheduler.waiting_len() == 0
-            {
-                // If there is nothing to do, sleep until a request comes in
-                if let Some(request) = self.rx.recv().await {
-                    self.add_request(request).await;
+            let is_idle = scheduled.prompt.len() == 0 && scheduled.completion.len() == 0;
+            for seq in forked {
+                self.scheduler.add_seq(seq);
+            }
+            if is_idle && self.scheduler.waiting_len() == 0 {
+                // If there is nothing to do, sleep until a request comes in. Prefix cache
+                // requests are handled right away, so they do not wait for a completion request.
+                tokio::select! {
+                    Some(request) = self.rx.recv() => self.add_request(request).await,
+                    Some(request) = self.prefix_rx.recv() => {
+                        self.handle_prefix_cache_request(request).await;
+                    }
+                    else => {}
                 }
             }
         }
@@ -258,7 +391,11 @@ impl Engine {
     }
 
     async fn add_request(&mut self, request: Request) {
-        let is_chat = matches!(request.messages, RequestMessage::Chat(_));
+        let is_chat = matches!(
+            request.messages,
+            RequestMessage::Chat(_) | RequestMessage::ChatContinuation { .. }
+        );
+        let continues_message = matches!(request.messages, RequestMessage::ChatContinuation { .. });
         let echo_prompt = matches!(
             request.messages,
             RequestMessage::Completion {
@@ -267,10 +404,6 @@ impl Engine {
             }
         );
 
-        let best_of = match request.messages {
-            RequestMessage::Completion { best_of, .. } => best_of,
-            RequestMessage::Chat(_) | RequestMessage::CompletionTokens(_) => 1,
-        };
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
                 .get_chat_template()
@@ -285,11 +418,62 @@ impl Engine {
         }
 
         let mut force_tokens = None;
+        let mut echoed_prefill = None;
         let formatted_prompt = match request.messages {
             RequestMessage::Chat(messages) => {
+                let messages = match text_messages(messages) {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(err.into()))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                };
                 let template = get_mut_arcmutex!(self.pipeline).apply_chat_template(messages, true);
                 handle_seq_error!(template, request.response)
             }
+            RequestMessage::ChatContinuation {
+                messages,
+                echo_prefill,
+            } => {
+                let messages = match text_messages(messages) {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(err.into()))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                };
+                let prefill = match messages.last() {
+                    Some(message)
+                        if message.get("role").is_some_and(|role| role == "assistant") =>
+                    {
+                        message.get("content").cloned().unwrap_or_default()
+                    }
+                    _ => {
+                        request
+                            .response
+                            .send(Response::ValidationError(
+                                "Only a final assistant message can be continued.".into(),
+                            ))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                };
+                if echo_prefill {
+                    echoed_prefill = Some(prefill);
+                }
+                let template =
+                    get_mut_arcmutex!(self.pipeline).apply_chat_template_continuation(messages);
+                handle_seq_error!(template, request.response)
+            }
             RequestMessage::Completion { text, .. } => text,
             RequestMessage::CompletionTokens(it) => {
                 let res = get_mut_arcmutex!(self.pipeline)
@@ -358,28 +542,10 @@ impl Engine {
             .get_metadata()
             .num_hidden_layers;
 
-        let (stop_toks, stop_strings) = match request.sampling_params.stop_toks {
+        let (mut stop_toks, stop_strings) = match request.sampling_params.stop_toks {
             None => (vec![], vec![]),
-            Some(StopTokens::Ids(ref i)) => {
-                let tok_trie = {
-                    let pipeline = get_mut_arcmutex!(self.pipeline);
-                    pipeline.get_metadata().tok_trie.clone()
-                };
-                for id in i {
-                    // We can't use ` ` (space) as a stop token because other tokens like ` moon` start with a space.
-                    if tok_trie.has_extensions(tok_trie.token(*id)) {
-                        request
-                            .response
-                            .send(Response::ValidationError(
-                                format!("Stop token {:?} is also a prefix of other tokens and cannot be used as a stop token.", tok_trie.token_str(*id)).into(),
-                            ))
-                            .await .expect("Expected receiver.");
-                        return;
-                    }
-                }
-
-                (i.clone(), vec![])
-            }
+            // Stop token ids are matched by id, so they may be prefixes of other tokens.
+            Some(StopTokens::Ids(ref i)) => (i.clone(), vec![]),
             Some(StopTokens::Seqs(ref s)) => {
                 let mut stop_toks = Vec::new();
                 let mut stop_strings: Vec<String> = Vec::new();
@@ -412,12 +578,92 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
-            request.is_streaming,
-            is_chat,
-            best_of,
-        )));
+        // Single token sequences are stop tokens.
+        let mut stop_token_seqs = Vec::new();
+        for seq in request.sampling_params.stop_token_seqs.iter().flatten() {
+            match seq[..] {
+                [] => {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Stop token sequences must not be empty.".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                [tok] => stop_toks.push(tok),
+                _ => stop_token_seqs.push(seq.clone()),
+            }
+        }
+        let stop_regexes = match request.sampling_params.stop_regexes {
+            Some(ref patterns) if !patterns.is_empty() => match StopRegexes::new(patterns) {
+                Ok(stop_regexes) => Some(stop_regexes),
+                Err(err) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("Invalid stop regexes. {}", err).into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        let n_choices = request.sampling_params.n_choices;
+        let best_of = request.sampling_params.best_of.unwrap_or(n_choices);
+        let err = if best_of < n_choices {
+            Some("`best_of` must be at least the number of choices.")
+        } else if best_of > n_choices && request.is_streaming {
+            Some("`best_of` does not support streaming.")
+        } else if let Some(beam_search) = &request.sampling_params.beam_search {
+            if beam_search.num_beams < n_choices {
+                Some("The number of beams must be at least the number of choices.")
+            } else if best_of > n_choices {
+                Some("Beam search does not support `best_of`.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if !matches!(request.constraint, Constraint::None) {
+                Some("Beam search does not support grammar constraints.")
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        if let Some(err) = err {
+            request
+                .response
+                .send(Response::ValidationError(err.into()))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        // With beam search, each beam is a sequence.
+        let num_seqs = request
+            .sampling_params
+            .beam_search
+            .as_ref()
+            .map_or(best_of, |beam_search| beam_search.num_beams);
+
+        let group = Arc::new(tokio::sync::Mutex::new(
+            SequenceGroup::new(
+                request.sampling_params.n_choices,
+                request.is_streaming,
+                is_chat,
+                best_of,
+            )
+            .with_beam_search(request.sampling_params.beam_search.clone())
+            .with_length_normalize(
+                request
+                    .sampling_params
+                    .best_of_length_normalize
+                    .unwrap_or(true),
+            ),
+        ));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
@@ -446,7 +692,39 @@ impl Engine {
             logits_bias,
             topk,
             topp,
+        )
+        .with_truncation(
+            request.sampling_params.min_p,
+            request.sampling_params.typical_p,
+            request.sampling_params.tfs_z,
+            request.sampling_params.top_a,
+        )
+        .with_mirostat(request.sampling_params.mirostat)
+        .with_order(request.sampling_params.sampler_order.clone())
+        .with_repetition_penalty(
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.repeat_last_n,
+        )
+        .with_logits_processors(
+            self.logits_processors
+                .iter()
+                .cloned()
+                .chain(request.logits_processors.clone().unwrap_or_default())
+                .collect(),
         );
+        let sampler = match sampler.with_dry(request.sampling_params.dry_params.clone()) {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!("Failed creation of the DRY sampler. {}", err).into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        };
 
         if request.sampling_params.n_choices == 0 {
             request
@@ -460,7 +738,8 @@ impl Engine {
         }
 
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        let mut seqs = Vec::with_capacity(num_seqs);
+        for response_index in 0..num_seqs {
             let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
@@ -495,8 +774,16 @@ impl Engine {
                 if echo_prompt {
                     Some(formatted_prompt.clone())
                 } else {
-                    None
+                    echoed_prefill.clone()
                 },
+            )
+            .with_attention_sinks(self.attention_sinks)
+            .with_cache_kind(get_mut_arcmutex!(self.pipeline).get_metadata().cache_kind)
+            .with_continued_message(continues_message)
+            .with_stop_conditions(
+                stop_regexes.clone(),
+                stop_token_seqs.clone(),
+                request.sampling_params.include_stop_str_in_output,
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
@@ -508,7 +795,117 @@ impl Engine {
                 seq
             };
             self.id += 1;
-            self.scheduler.add_seq(seq);
+            seqs.push(seq);
+        }
+
+        // The choices (or beams) share a prompt, so only the first one processes it. The others are
+        // forked from its KV cache.
+        let mut seqs = seqs.into_iter();
+        let first = seqs.next().expect("There is at least one choice.");
+        if self.no_kv_cache {
+            self.scheduler.add_seq(first);
+            seqs.for_each(|seq| self.scheduler.add_seq(seq));
+        } else {
+            self.scheduler.add_seq(first.with_forks(seqs.collect()));
+        }
+    }
+
+    async fn handle_prefix_cache_request(&mut self, request: PrefixCacheRequest) {
+        match request {
+            PrefixCacheRequest::Pin { message, response } => {
+                let toks = match self.tokenize_prefix(message) {
+                    Ok(toks) if !toks.is_empty() => toks,
+                    Ok(_) => {
+                        let _ = response.send(Err("Received an empty prefix.".into())).await;
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = response.send(Err(e.to_string())).await;
+                        return;
+                    }
+                };
+                let id = match self.prefix_cacher.pin(toks.clone()) {
+                    Ok(Some(id)) => id,
+                    Ok(None) => {
+                        let _ = response
+                            .send(Err("The prefix cache is disabled.".into()))
+                            .await;
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = response.send(Err(e.to_string())).await;
+                        return;
+                    }
+                };
+                let pinned = self
+                    .prefix_cacher
+                    .pinned_prefix(id)
+                    .expect("Prefix was just pinned.");
+                if pinned.cached_tokens == pinned.tokens {
+                    let _ = response.send(Ok(pinned)).await;
+                    return;
+                }
+                // Compute the cache by generating a single token. The sequence is added to the prefix
+                // cache once it is done.
+                let (tx, rx) = channel(1);
+                self.add_request(Request {
+                    messages: RequestMessage::CompletionTokens(toks),
+                    sampling_params: SamplingParams {
+                        max_len: Some(1),
+                        ..Default::default()
+                    },
+                    response: tx,
+                    return_logprobs: false,
+                    is_streaming: false,
+                    id: 0,
+                    constraint: Constraint::None,
+                    suffix: None,
+                    logits_processors: None,
+                })
+                .await;
+                self.pending_pins.push(PendingPin { id, rx, response });
+            }
+            PrefixCacheRequest::List { response } => {
+                let _ = response.send(self.prefix_cacher.pinned_prefixes()).await;
+            }
+            PrefixCacheRequest::Unpin { id, response } => {
+                let _ = response.send(self.prefix_cacher.unpin(id)).await;
+            }
+        }
+    }
+
+    fn tokenize_prefix(&self, message: PrefixMessage) -> anyhow::Result<Vec<u32>> {
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let text = match message {
+            PrefixMessage::Chat(messages) => pipeline
+                .apply_chat_template(text_messages(messages).map_err(anyhow::Error::msg)?, false)?,
+            PrefixMessage::Text(text) => text,
+        };
+        pipeline.tokenize_prompt(&text)
+    }
+
+    /// Respond to pins whose cache has finished computing.
+    async fn poll_pending_pins(&mut self) {
+        for mut pending in std::mem::take(&mut self.pending_pins) {
+            let res = match pending.rx.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    self.pending_pins.push(pending);
+                    continue;
+                }
+                Ok(Response::CompletionDone(_)) => self
+                    .prefix_cacher
+                    .pinned_prefix(pending.id)
+                    .ok_or_else(|| "The prefix was unpinned.".to_string()),
+                Ok(Response::InternalError(e) | Response::ValidationError(e)) => Err(e.to_string()),
+                Ok(Response::CompletionModelError(e, _) | Response::ModelError(e, _)) => Err(e),
+                Ok(Response::Done(_) | Response::Chunk(_)) | Err(TryRecvError::Disconnected) => {
+                    Err("Failed to compute the prefix cache.".to_string())
+                }
+            };
+            if res.is_err() {
+                self.prefix_cacher.unpin(pending.id);
+            }
+            let _ = pending.response.send(res).await;
         }
     }
 }
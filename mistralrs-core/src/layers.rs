@@ -2,15 +2,22 @@
 
 use std::{collections::HashMap, ops::Mul, str::FromStr, sync::Mutex};
 
-use candle_core::{quantized::QTensor, DType, Device, IndexOp, Result, Tensor, WithDType};
+use candle_core::{
+    quantized::{QMatMul, QTensor},
+    DType, Device, IndexOp, Result, Tensor, WithDType,
+};
 use candle_nn::{
     layer_norm::{RmsNormNonQuantized, RmsNormQuantized},
-    Module, VarBuilder,
+    linear_no_bias, Activation, Module, VarBuilder,
 };
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 static MASKS: Lazy<Mutex<HashMap<(usize, usize), Tensor>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
+static SLIDING_WINDOW_MASKS: Lazy<Mutex<HashMap<(usize, usize, usize), Tensor>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 use crate::models::{phi3, Cache};
 
@@ -236,6 +243,407 @@ impl PhiRotaryEmbedding {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", try_from = "String")]
+pub enum RopeScalingType {
+    /// No scaling.
+    Default,
+    Linear,
+    Dynamic,
+    Yarn,
+    Llama3,
+}
+
+impl FromStr for RopeScalingType {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "linear" => Ok(Self::Linear),
+            "dynamic" => Ok(Self::Dynamic),
+            "yarn" => Ok(Self::Yarn),
+            "llama3" => Ok(Self::Llama3),
+            a => Err(format!(
+                "Unknown RoPE scaling type `{a}`. Expected one of `default`, `linear`, `dynamic`, `yarn` or `llama3`."
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for RopeScalingType {
+    type Error = String;
+    /// Parse the scaling type of a model config. LongRoPE is only supported by the Phi 3 models,
+    /// which read their own `rope_scaling`, so elsewhere it is ignored rather than failing to load.
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        match s.as_str() {
+            "longrope" | "su" => {
+                warn!("RoPE scaling type `{s}` is not supported for this model, ignoring it.");
+                Ok(Self::Default)
+            }
+            _ => s.parse(),
+        }
+    }
+}
+
+fn default_rope_scaling_factor() -> f64 {
+    1.
+}
+
+/// The `rope_scaling` section of a model config.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RopeScaling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rope_type: Option<RopeScalingType>,
+    /// Older configs use `type` instead of `rope_type`.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub scaling_type: Option<RopeScalingType>,
+    /// Absent for `default` and LongRoPE scalings.
+    #[serde(default = "default_rope_scaling_factor")]
+    pub factor: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_max_position_embeddings: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_freq_factor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high_freq_factor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beta_fast: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beta_slow: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attention_factor: Option<f64>,
+}
+
+impl FromStr for RopeScaling {
+    type Err = String;
+    /// Parse a forced scaling such as `linear:4` or `yarn:2.5`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (ty, factor) = s.split_once(':').ok_or_else(|| {
+            format!("Expected RoPE scaling formatted as `<type>:<factor>`, got `{s}`.")
+        })?;
+        Ok(Self {
+            rope_type: Some(ty.parse()?),
+            scaling_type: None,
+            factor: factor.parse().map_err(|e| format!("{e:?}"))?,
+            original_max_position_embeddings: None,
+            low_freq_factor: None,
+            high_freq_factor: None,
+            beta_fast: None,
+            beta_slow: None,
+            attention_factor: None,
+        })
+    }
+}
+
+impl RopeScaling {
+    pub fn kind(&self) -> Result<RopeScalingType> {
+        self.rope_type.or(self.scaling_type).ok_or_else(|| {
+            candle_core::Error::Msg("`rope_scaling` needs a `type` or `rope_type`.".to_string())
+        })
+    }
+
+    /// Whether the RoPE is actually scaled, that is the type is not `default`.
+    pub fn is_scaled(&self) -> bool {
+        !matches!(self.kind(), Ok(RopeScalingType::Default))
+    }
+
+    /// The maximum sequence length supported by a model with this scaling applied.
+    pub fn max_position_embeddings(&self, max_position_embeddings: usize) -> usize {
+        match (self.kind(), self.original_max_position_embeddings) {
+            (Ok(RopeScalingType::Llama3 | RopeScalingType::Default), _) => max_position_embeddings,
+            // Dynamic NTK configs without an original length extend the model's own length.
+            (Ok(RopeScalingType::Dynamic), None) => {
+                max_position_embeddings.max((max_position_embeddings as f64 * self.factor) as usize)
+            }
+            (_, None) => max_position_embeddings,
+            (_, Some(original)) => {
+                max_position_embeddings.max((original as f64 * self.factor) as usize)
+            }
+        }
+    }
+
+    /// Compute the inverse frequencies and the attention factor which scales the cos and sin.
+    fn inv_freq(&self, base: f64, head_dim: usize, original: usize) -> Result<(Vec<f32>, f64)> {
+        let inv_freq: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1. / base.powf(i as f64 / head_dim as f64))
+            .collect();
+        let factor = self.factor;
+        let (inv_freq, attention_factor) = match self.kind()? {
+            // Dynamic NTK is applied when computing the cos and sin past the original length.
+            RopeScalingType::Default | RopeScalingType::Dynamic => (inv_freq, 1.),
+            RopeScalingType::Linear => (inv_freq.iter().map(|f| f / factor).collect(), 1.),
+            RopeScalingType::Llama3 => {
+                let low_freq_factor = self.low_freq_factor.unwrap_or(1.);
+                let high_freq_factor = self.high_freq_factor.unwrap_or(4.);
+                let low_freq_wavelen = original as f64 / low_freq_factor;
+                let high_freq_wavelen = original as f64 / high_freq_factor;
+                let inv_freq = inv_freq
+                    .iter()
+                    .map(|f| {
+                        let wavelen = 2. * std::f64::consts::PI / f;
+                        if wavelen < high_freq_wavelen {
+                            *f
+                        } else if wavelen > low_freq_wavelen {
+                            f / factor
+                        } else {
+                            let smooth = (original as f64 / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * f / factor + smooth * f
+                        }
+                    })
+                    .collect();
+                (inv_freq, 1.)
+            }
+            RopeScalingType::Yarn => {
+                let beta_fast = self.beta_fast.unwrap_or(32.);
+                let beta_slow = self.beta_slow.unwrap_or(1.);
+                let correction_dim = |n_rot: f64| {
+                    head_dim as f64 * (original as f64 / (n_rot * 2. * std::f64::consts::PI)).ln()
+                        / (2. * base.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let high = correction_dim(beta_slow).ceil().min(head_dim as f64 - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+                let inv_freq = inv_freq
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        let extrapolation_factor = 1. - ramp;
+                        (f / factor) * (1. - extrapolation_factor) + f * extrapolation_factor
+                    })
+                    .collect();
+                let attention_factor = self.attention_factor.unwrap_or(if factor <= 1. {
+                    1.
+                } else {
+                    0.1 * factor.ln() + 1.
+                });
+                (inv_freq, attention_factor)
+            }
+        };
+        Ok((
+            inv_freq.into_iter().map(|f| f as f32).collect(),
+            attention_factor,
+        ))
+    }
+}
+
+fn rope_cos_sin(
+    inv_freq: &[f32],
+    offset: usize,
+    seq_len: usize,
+    attention_factor: f64,
+    dtype: DType,
+    dev: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let inv_freq = Tensor::from_slice(inv_freq, (1, inv_freq.len()), dev)?;
+    let t = Tensor::arange(offset as u32, (offset + seq_len) as u32, dev)?
+        .to_dtype(DType::F32)?
+        .reshape((seq_len, 1))?;
+    let freqs = t.matmul(&inv_freq)?;
+    let cos = freqs.cos()?.affine(attention_factor, 0.)?.to_dtype(dtype)?;
+    let sin = freqs.sin()?.affine(attention_factor, 0.)?.to_dtype(dtype)?;
+    Ok((cos, sin))
+}
+
+#[derive(Debug)]
+struct DynamicNtk {
+    base: f64,
+    factor: f64,
+    original_max_position_embeddings: usize,
+}
+
+#[derive(Debug)]
+struct ScaledRotaryEmbedding {
+    cos: Tensor,
+    sin: Tensor,
+    head_dim: usize,
+    is_gpt_neox: bool,
+    dynamic: Option<DynamicNtk>,
+}
+
+impl ScaledRotaryEmbedding {
+    fn cos_sin(&self, offset: usize, seq_len: usize) -> Result<(Tensor, Tensor)> {
+        match &self.dynamic {
+            Some(dynamic) if offset + seq_len > dynamic.original_max_position_embeddings => {
+                let total = (offset + seq_len) as f64;
+                let original = dynamic.original_max_position_embeddings as f64;
+                let base = dynamic.base
+                    * ((dynamic.factor * total / original) - (dynamic.factor - 1.))
+                        .powf(self.head_dim as f64 / (self.head_dim as f64 - 2.));
+                let inv_freq: Vec<_> = (0..self.head_dim)
+                    .step_by(2)
+                    .map(|i| (1. / base.powf(i as f64 / self.head_dim as f64)) as f32)
+                    .collect();
+                rope_cos_sin(
+                    &inv_freq,
+                    offset,
+                    seq_len,
+                    1.,
+                    self.cos.dtype(),
+                    self.cos.device(),
+                )
+            }
+            _ => Ok((
+                self.cos.narrow(0, offset, seq_len)?,
+                self.sin.narrow(0, offset, seq_len)?,
+            )),
+        }
+    }
+
+    fn forward(
+        &self,
+        positions: &[usize],
+        q: &mut Tensor,
+        k: &mut Tensor,
+        b_sz: usize,
+    ) -> Result<()> {
+        // Bring q and k to (b_sz, n_heads, seq_len, head_dim)
+        let to_bhsd = |x: &Tensor| -> Result<Tensor> {
+            if x.rank() == 3 {
+                let (bs, n_heads, head_dim) = x.dims3()?;
+                x.reshape((b_sz, bs / b_sz, n_heads, head_dim))?
+                    .transpose(1, 2)?
+                    .contiguous()
+            } else {
+                x.contiguous()
+            }
+        };
+        let q_bhsd = to_bhsd(q)?;
+        let k_bhsd = to_bhsd(k)?;
+        let seq_len = q_bhsd.dim(2)?;
+        let mut q_embeds = Vec::new();
+        let mut k_embeds = Vec::new();
+        for (i, offset) in positions.iter().enumerate() {
+            let (cos, sin) = self.cos_sin(*offset, seq_len)?;
+            let q = q_bhsd.i(i)?.unsqueeze(0)?.contiguous()?;
+            let k = k_bhsd.i(i)?.unsqueeze(0)?.contiguous()?;
+            if self.is_gpt_neox {
+                q_embeds.push(candle_nn::rotary_emb::rope(&q, &cos, &sin)?);
+                k_embeds.push(candle_nn::rotary_emb::rope(&k, &cos, &sin)?);
+            } else {
+                q_embeds.push(candle_nn::rotary_emb::rope_i(&q, &cos, &sin)?);
+                k_embeds.push(candle_nn::rotary_emb::rope_i(&k, &cos, &sin)?);
+            }
+        }
+        *q = Tensor::cat(&q_embeds, 0)?;
+        *k = Tensor::cat(&k_embeds, 0)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum RotaryEmbeddingKind {
+    Unscaled(candle_nn::RotaryEmbedding),
+    Scaled(ScaledRotaryEmbedding),
+}
+
+/// RoPE supporting linear, dynamic NTK, YaRN and Llama 3.1 scaling. Without scaling, this
+/// uses the fused rotary embedding from `candle_nn`.
+#[derive(Debug)]
+pub struct RotaryEmbedding(RotaryEmbeddingKind);
+
+impl RotaryEmbedding {
+    pub fn new(
+        base: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        dev: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+        rope_scaling: Option<&RopeScaling>,
+    ) -> Result<Self> {
+        let Some(rope_scaling) = rope_scaling.filter(|s| s.is_scaled()) else {
+            return Ok(Self(RotaryEmbeddingKind::Unscaled(
+                candle_nn::RotaryEmbedding::new(
+                    base,
+                    head_dim,
+                    max_position_embeddings,
+                    dev,
+                    is_gpt_neox,
+                    dtype,
+                )?,
+            )));
+        };
+        let original = rope_scaling
+            .original_max_position_embeddings
+            .unwrap_or(max_position_embeddings);
+        let (inv_freq, attention_factor) =
+            rope_scaling.inv_freq(base as f64, head_dim, original)?;
+        let (cos, sin) = rope_cos_sin(
+            &inv_freq,
+            0,
+            rope_scaling.max_position_embeddings(max_position_embeddings),
+            attention_factor,
+            dtype,
+            dev,
+        )?;
+        let dynamic = (rope_scaling.kind()? == RopeScalingType::Dynamic).then_some(DynamicNtk {
+            base: base as f64,
+            factor: rope_scaling.factor,
+            original_max_position_embeddings: original,
+        });
+        Ok(Self(RotaryEmbeddingKind::Scaled(ScaledRotaryEmbedding {
+            cos,
+            sin,
+            head_dim,
+            is_gpt_neox,
+            dynamic,
+        })))
+    }
+
+    /// Apply RoPE to `q` and `k`. If the result is of rank 4, it is (b_sz, n_heads, seq_len, head_dim).
+    pub fn forward(
+        &self,
+        positions: &[usize],
+        positions_kernel: &Tensor,
+        q: &mut Tensor,
+        k: &mut Tensor,
+        b_sz: usize,
+    ) -> Result<()> {
+        match &self.0 {
+            RotaryEmbeddingKind::Unscaled(rope) => {
+                rope.forward(positions, positions_kernel, q, k, b_sz)
+            }
+            RotaryEmbeddingKind::Scaled(rope) => rope.forward(positions, q, k, b_sz),
+        }
+    }
+}
+
+/// The RoPE parameters needed to move keys which are already in the KV cache to a different position.
+#[derive(Debug, Clone, Copy)]
+pub struct RopeShift {
+    pub base: f32,
+    pub is_gpt_neox: bool,
+}
+
+impl RopeShift {
+    /// Rotate the cached keys `k` of shape (b_sz, n_kv_heads, seq_len, head_dim) back by `shift` positions.
+    /// Because RoPE rotations compose additively, this is exactly equivalent to having rotated
+    /// the keys at their original position minus `shift`.
+    pub fn shift_keys(&self, k: &Tensor, shift: usize) -> Result<Tensor> {
+        let (_b_sz, _n_kv_heads, seq_len, head_dim) = k.dims4()?;
+        let angles: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| -(shift as f32) / self.base.powf(i as f32 / head_dim as f32))
+            .collect();
+        let angles = Tensor::new(angles.as_slice(), k.device())?
+            .unsqueeze(0)?
+            .broadcast_as((seq_len, head_dim / 2))?
+            .contiguous()?;
+        let (cos, sin) = (angles.cos()?, angles.sin()?);
+        let dtype = k.dtype();
+        let k = k.to_dtype(DType::F32)?.contiguous()?;
+        let k = if self.is_gpt_neox {
+            candle_nn::rotary_emb::rope(&k, &cos, &sin)?
+        } else {
+            candle_nn::rotary_emb::rope_i(&k, &cos, &sin)?
+        };
+        k.to_dtype(dtype)
+    }
+}
+
 // https://github.com/huggingface/transformers/blob/main/src/transformers/modeling_attn_mask_utils.py
 pub struct CausalMasker;
 
@@ -313,26 +721,25 @@ impl CausalMasker {
         }
         let sliding_window = sliding_window.unwrap();
         let past_kv_len = self.calculate_past_kv_len(cache)?;
-        let (b_sz, tgt_len) = input_ids.dims2()?;
+        let (_b_sz, tgt_len) = input_ids.dims2()?;
         if tgt_len == 1 {
             return Ok(None);
         }
-        let res = MASKS.lock().unwrap().get(&(tgt_len, past_kv_len)).cloned();
+        let key = (tgt_len, past_kv_len, sliding_window);
+        let res = SLIDING_WINDOW_MASKS.lock().unwrap().get(&key).cloned();
         if let Some(mask) = res {
             Ok(Some(mask))
         } else {
             let mask = self.make_mask(tgt_len, past_kv_len, input_ids.device())?;
-            let diagonal = past_kv_len as isize - sliding_window as isize - 1;
+            // Also mask out every key which is `sliding_window` or more positions behind the query.
+            let diagonal = past_kv_len as isize - sliding_window as isize;
             let context_mask = apply_tril(&mask.ones_like()?, diagonal)?;
-            let mask = masked_fill(&mask.to_dtype(DType::F32)?, &context_mask, f32::MIN)?;
-            let mask = mask
-                .expand((b_sz, 1, tgt_len, tgt_len + past_kv_len))?
-                .to_dtype(DType::U8)?;
+            let mask = masked_fill(&mask, &context_mask, 1u8)?;
 
-            MASKS
+            SLIDING_WINDOW_MASKS
                 .lock()
                 .unwrap()
-                .insert((tgt_len, past_kv_len), mask.clone());
+                .insert(key, mask.clone());
             Ok(Some(mask))
         }
     }
@@ -355,3 +762,348 @@ impl CausalMasker {
         }
     }
 }
+
+/// A gated feed forward block, `down_proj(act(gate_proj(x)) * up_proj(x))`. Used for the dense
+/// MLPs of MoE models as well as for their experts.
+#[derive(Debug, Clone)]
+pub struct GatedMlp {
+    gate_proj: QMatMul,
+    up_proj: QMatMul,
+    down_proj: QMatMul,
+    act_fn: Activation,
+}
+
+impl GatedMlp {
+    pub fn new(
+        hidden_size: usize,
+        intermediate_size: usize,
+        act_fn: Activation,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let gate_proj = linear_no_bias(hidden_size, intermediate_size, vb.pp("gate_proj"))?;
+        let up_proj = linear_no_bias(hidden_size, intermediate_size, vb.pp("up_proj"))?;
+        let down_proj = linear_no_bias(intermediate_size, hidden_size, vb.pp("down_proj"))?;
+        Ok(Self {
+            gate_proj: QMatMul::Tensor(gate_proj.weight().clone()),
+            up_proj: QMatMul::Tensor(up_proj.weight().clone()),
+            down_proj: QMatMul::Tensor(down_proj.weight().clone()),
+            act_fn,
+        })
+    }
+
+    /// The weights which may be quantized by ISQ.
+    pub fn get_tensors(&mut self) -> Vec<&mut QMatMul> {
+        vec![&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj]
+    }
+}
+
+impl Module for GatedMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if matches!(self.gate_proj, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        let mut res = (lhs * rhs)?.apply(&self.down_proj)?;
+        if matches!(self.gate_proj, QMatMul::QTensor(_)) {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+/// An expert which processes every token, next to the routed experts of a [`MoeBlock`]. Its
+/// output may be scaled by a learned sigmoid gate, as in Qwen2-MoE.
+#[derive(Debug, Clone)]
+pub struct SharedExpert {
+    mlp: GatedMlp,
+    gate: Option<Tensor>,
+}
+
+impl SharedExpert {
+    pub fn new(mlp: GatedMlp, gate: Option<Tensor>) -> Self {
+        Self { mlp, gate }
+    }
+}
+
+impl Module for SharedExpert {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.mlp.forward(xs)?;
+        match &self.gate {
+            Some(gate) => {
+                let gate = candle_nn::ops::sigmoid(&xs.matmul(&gate.t()?)?)?;
+                ys.broadcast_mul(&gate)
+            }
+            None => Ok(ys),
+        }
+    }
+}
+
+/// Routing parameters of a [`MoeBlock`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoeConfig {
+    pub hidden_size: usize,
+    /// Intermediate size of each routed expert.
+    pub moe_intermediate_size: usize,
+    pub hidden_act: Activation,
+    pub num_experts: usize,
+    pub num_experts_per_tok: usize,
+    /// Renormalize the routing weights of the selected experts so that they sum to one.
+    pub norm_topk_prob: bool,
+    /// Applied to the routing weights after the optional renormalization.
+    pub routed_scaling_factor: f64,
+}
+
+/// A sparse mixture of experts with any number of routed experts and optional shared experts.
+/// Each token is sent to the `num_experts_per_tok` experts with the highest softmax router scores,
+/// and the shared experts are added on top for every token.
+///
+/// The router is loaded from `gate` and the routed experts from `experts.{i}`.
+#[derive(Debug, Clone)]
+pub struct MoeBlock {
+    gate: QMatMul,
+    experts: Vec<GatedMlp>,
+    shared_experts: Option<SharedExpert>,
+    cfg: MoeConfig,
+}
+
+impl MoeBlock {
+    pub fn new(
+        cfg: &MoeConfig,
+        shared_experts: Option<SharedExpert>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let gate = linear_no_bias(cfg.hidden_size, cfg.num_experts, vb.pp("gate"))?;
+        let vb_e = vb.pp("experts");
+        let experts = (0..cfg.num_experts)
+            .map(|idx| {
+                GatedMlp::new(
+                    cfg.hidden_size,
+                    cfg.moe_intermediate_size,
+                    cfg.hidden_act,
+                    vb_e.pp(idx),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            gate: QMatMul::Tensor(gate.weight().clone()),
+            experts,
+            shared_experts,
+            cfg: cfg.clone(),
+        })
+    }
+
+    /// The weights which may be quantized by ISQ: the router and all of the experts.
+    pub fn get_tensors(&mut self) -> Vec<&mut QMatMul> {
+        let mut tensors = vec![&mut self.gate];
+        for expert in &mut self.experts {
+            tensors.extend(expert.get_tensors());
+        }
+        if let Some(shared) = &mut self.shared_experts {
+            tensors.extend(shared.mlp.get_tensors());
+        }
+        tensors
+    }
+}
+
+impl Module for MoeBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs = xs.reshape(((), hidden_dim))?;
+
+        let router_logits = if matches!(self.gate, QMatMul::QTensor(_)) {
+            xs.to_dtype(DType::F32)?.apply(&self.gate)?
+        } else {
+            xs.apply(&self.gate)?.to_dtype(DType::F32)?
+        };
+        let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?;
+
+        // In order to extract topk, we extract the data from the tensor and manipulate it
+        // directly, as in the Mixtral model.
+        let routing_weights = routing_weights.to_vec2::<f32>()?;
+        let mut top_x = vec![vec![]; self.experts.len()];
+        let mut selected_rws = vec![vec![]; self.experts.len()];
+        for (row_idx, rw) in routing_weights.iter().enumerate() {
+            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
+            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+            let selected = &dst[..self.cfg.num_experts_per_tok.min(dst.len())];
+            let norm = if self.cfg.norm_topk_prob {
+                selected.iter().map(|&i| rw[i as usize]).sum::<f32>() + 1e-20
+            } else {
+                1.
+            };
+            let scale = self.cfg.routed_scaling_factor as f32 / norm;
+            for &expert_idx in selected {
+                let expert_idx = expert_idx as usize;
+                top_x[expert_idx].push(row_idx as u32);
+                selected_rws[expert_idx].push(rw[expert_idx] * scale);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert_idx, expert_layer) in self.experts.iter().enumerate() {
+            let top_x = &top_x[expert_idx];
+            if top_x.is_empty() {
+                continue;
+            }
+            let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+            let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let current_state = xs.index_select(&top_x, 0)?;
+            let current_hidden_states = expert_layer
+                .forward(&current_state)?
+                .broadcast_mul(&selected_rws)?;
+            ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+        }
+        if let Some(shared) = &self.shared_experts {
+            ys = (ys + shared.forward(&xs)?)?;
+        }
+
+        ys.reshape((b_size, seq_len, hidden_dim))
+    }
+}
+
+mod tests {
+    #[allow(dead_code)]
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() <= 1e-5 * b.abs().max(1.), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn test_rope_scaling_types() {
+        use super::{RopeScaling, RopeScalingType};
+
+        let default: RopeScaling = serde_json::from_str(r#"{"rope_type": "default"}"#).unwrap();
+        assert!(!default.is_scaled());
+        assert_eq!(default.max_position_embeddings(4096), 4096);
+
+        // LongRoPE is ignored outside of Phi 3, rather than failing to load the config.
+        let su: RopeScaling =
+            serde_json::from_str(r#"{"type": "su", "short_factor": [1.0], "long_factor": [2.0]}"#)
+                .unwrap();
+        assert!(!su.is_scaled());
+        let longrope: RopeScaling = serde_json::from_str(r#"{"rope_type": "longrope"}"#).unwrap();
+        assert!(!longrope.is_scaled());
+
+        assert!(serde_json::from_str::<RopeScaling>(r#"{"type": "ntk", "factor": 2.0}"#).is_err());
+        let linear: RopeScaling = "linear:4".parse().unwrap();
+        assert_eq!(linear.kind().unwrap(), RopeScalingType::Linear);
+        assert_eq!(linear.factor, 4.);
+    }
+
+    #[test]
+    fn test_rope_inv_freq() {
+        use super::RopeScaling;
+
+        let scaling = |s: &str| serde_json::from_str::<RopeScaling>(s).unwrap();
+        let base = [1., 0.1, 0.01, 0.001];
+
+        let (inv_freq, attention_factor) = scaling(r#"{"type": "linear", "factor": 4.0}"#)
+            .inv_freq(10000., 8, 4096)
+            .unwrap();
+        assert_close(&inv_freq, &base.map(|f| f / 4.));
+        assert_eq!(attention_factor, 1.);
+
+        // Dynamic NTK only changes the base past the original length.
+        let (inv_freq, attention_factor) = scaling(r#"{"type": "dynamic", "factor": 4.0}"#)
+            .inv_freq(10000., 8, 4096)
+            .unwrap();
+        assert_close(&inv_freq, &base);
+        assert_eq!(attention_factor, 1.);
+
+        // The correction range is dims 1 to 3: the first two frequencies are kept, the last one is
+        // interpolated and the one in between is ramped halfway.
+        let (inv_freq, attention_factor) =
+            scaling(r#"{"type": "yarn", "factor": 4.0, "beta_fast": 32.0, "beta_slow": 1.0}"#)
+                .inv_freq(10000., 8, 4096)
+                .unwrap();
+        assert_close(&inv_freq, &[1., 0.1, 0.01 * 0.625, 0.001 / 4.]);
+        assert!((attention_factor - (0.1 * 4f64.ln() + 1.)).abs() < 1e-9);
+
+        // Wavelengths are 6.3, 199, 6283 and 198692: the two first are below the high frequency
+        // wavelength of 2048 and kept, the last is above the low frequency wavelength of 8192 and
+        // interpolated, and the third is smoothed between both.
+        let (inv_freq, attention_factor) = scaling(
+            r#"{"rope_type": "llama3", "factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0}"#,
+        )
+        .inv_freq(1e6, 8, 8192)
+        .unwrap();
+        let smooth = (8192. / (2. * std::f32::consts::PI / 1e-3) - 1.) / 3.;
+        assert_close(
+            &inv_freq,
+            &[
+                1.,
+                1e6f32.powf(-0.25),
+                (1. - smooth) * 1e-3 / 8. + smooth * 1e-3,
+                1e6f32.powf(-0.75) / 8.,
+            ],
+        );
+        assert_eq!(attention_factor, 1.);
+    }
+
+    #[test]
+    fn test_dynamic_ntk_cos_sin() {
+        use super::{RopeScaling, RotaryEmbedding, RotaryEmbeddingKind};
+        use candle_core::{DType, Device};
+
+        let scaling: RopeScaling = serde_json::from_str(
+            r#"{"type": "dynamic", "factor": 2.0, "original_max_position_embeddings": 8}"#,
+        )
+        .unwrap();
+        let rope = RotaryEmbedding::new(
+            10000.,
+            8,
+            16,
+            &Device::Cpu,
+            true,
+            DType::F32,
+            Some(&scaling),
+        )
+        .unwrap();
+        let RotaryEmbeddingKind::Scaled(rope) = rope.0 else {
+            panic!("Expected a scaled RoPE");
+        };
+
+        // Within the original length, the base is unchanged.
+        let (cos, _) = rope.cos_sin(0, 4).unwrap();
+        let cos = cos.to_vec2::<f32>().unwrap();
+        assert_close(&cos[3], &[1f32, 0.1, 0.01, 0.001].map(|f| (3. * f).cos()));
+
+        // Past it, the base grows with the total length of 10.
+        let (cos, _) = rope.cos_sin(6, 4).unwrap();
+        let cos = cos.to_vec2::<f32>().unwrap();
+        let base = 10000f32 * (2f32 * 10. / 8. - 1.).powf(8. / 6.);
+        let inv_freq = [0., 2., 4., 6.].map(|i: f32| 1. / base.powf(i / 8.));
+        assert_close(&cos[0], &inv_freq.map(|f| (6. * f).cos()));
+    }
+
+    #[test]
+    fn test_dynamic_ntk_without_original() {
+        use super::{RopeScaling, RotaryEmbedding, RotaryEmbeddingKind};
+        use candle_core::{DType, Device};
+
+        // Without `original_max_position_embeddings`, the model's own length is the original one.
+        let scaling: RopeScaling =
+            serde_json::from_str(r#"{"type": "dynamic", "factor": 2}"#).unwrap();
+        assert_eq!(scaling.max_position_embeddings(8), 16);
+        let rope =
+            RotaryEmbedding::new(10000., 8, 8, &Device::Cpu, true, DType::F32, Some(&scaling))
+                .unwrap();
+        let RotaryEmbeddingKind::Scaled(rope) = rope.0 else {
+            panic!("Expected a scaled RoPE");
+        };
+        assert_eq!(rope.cos.dim(0).unwrap(), 16);
+
+        let (cos, _) = rope.cos_sin(6, 4).unwrap();
+        let cos = cos.to_vec2::<f32>().unwrap();
+        let base = 10000f32 * (2f32 * 10. / 8. - 1.).powf(8. / 6.);
+        let inv_freq = [0., 2., 4., 6.].map(|i: f32| 1. / base.powf(i / 8.));
+        assert_close(&cos[0], &inv_freq.map(|f| (6. * f).cos()));
+    }
+}
@@ -12,13 +12,16 @@ use std::{
 use tokio::sync::mpsc::{channel, Sender};
 
 use candle_core::quantized::GgmlDType;
-use engine::Engine;
+use engine::{EncoderEngine, Engine};
 pub use mistralrs_lora::Ordering;
 pub use pipeline::Pipeline;
 
 mod aici;
+mod beam_search;
 mod device_map;
 mod engine;
+mod logits_processor;
+pub use logits_processor::LogitsProcessor;
 mod model_loader;
 pub use model_loader::{get_tgt_non_granular_index, LoaderBuilder};
 mod model_selected;
@@ -28,27 +31,38 @@ pub mod layers;
 mod models;
 mod pipeline;
 mod prefix_cacher;
+pub use prefix_cacher::{PinnedPrefix, PrefixCacheBudgets};
 mod request;
 mod response;
 mod sampler;
 mod scheduler;
 mod sequence;
+mod stop_regex;
 mod toml_selector;
 mod utils;
 mod xlora_models;
 
 pub use device_map::{DeviceMapMetadata, LayerDeviceMapper};
 pub use pipeline::{
-    GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder,
-    GGUFSpecificConfig, GemmaLoader, LlamaLoader, Loader, MistralLoader, MixtralLoader, ModelKind,
-    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader,
-    Phi3Loader, Qwen2Loader, TokenSource,
+    is_offline, set_offline, set_verify_checksums, DeepSeekLoader, DummyWeights, EncoderLoader,
+    EncoderLoaderBuilder, EncoderPipeline, FalconLoader, GGMLLoader, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
+    LlamaLoader, Loader, MambaLoader, MistralLoader, MixtralLoader, ModelKind, NormalLoader,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader, Pooling,
+    Qwen2Loader, Qwen2MoeLoader, Starcoder2Loader, TokenSource,
+};
+pub use request::{
+    Constraint, ContentPart, EncoderRequest, MessageContent, PrefixCacheRequest, PrefixMessage,
+    Request, RequestMessage,
 };
-pub use request::{Constraint, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
-pub use sampler::{SamplingParams, StopTokens, TopLogprob};
+pub use sampler::{
+    BeamSearchParams, DrySamplingParams, Mirostat, SamplerStage, SamplingParams, StopTokens,
+    TopLogprob,
+};
 pub use scheduler::SchedulerMethod;
+pub use sequence::AttentionSinks;
 use serde::Serialize;
 use tokio::runtime::Runtime;
 pub use toml_selector::{TomlLoaderArgs, TomlSelector};
@@ -60,8 +74,13 @@ pub use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub struct MistralRs {
     sender: Sender<Request>,
     sender_isq: Sender<GgmlDType>,
+    sender_prefix: Sender<PrefixCacheRequest>,
+    sender_embedding: Option<Sender<EncoderRequest>>,
+    sender_reranker: Option<Sender<EncoderRequest>>,
     log: Option<String>,
     id: String,
+    embedding_id: Option<String>,
+    reranker_id: Option<String>,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
 }
@@ -76,8 +95,12 @@ pub struct MistralRsBuilder {
     truncate_sequence: Option<bool>,
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
-    prefix_cache_n: Option<usize>,
+    prefix_cache_budgets: Option<PrefixCacheBudgets>,
     disable_eos_stop: Option<bool>,
+    attention_sinks: Option<AttentionSinks>,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    embedding_model: Option<EncoderPipeline>,
+    reranker: Option<EncoderPipeline>,
 }
 
 impl MistralRsBuilder {
@@ -89,8 +112,12 @@ impl MistralRsBuilder {
             truncate_sequence: None,
             no_kv_cache: None,
             no_prefix_cache: None,
-            prefix_cache_n: None,
+            prefix_cache_budgets: None,
             disable_eos_stop: None,
+            attention_sinks: None,
+            logits_processors: Vec::new(),
+            embedding_model: None,
+            reranker: None,
         }
     }
 
@@ -114,14 +141,42 @@ impl MistralRsBuilder {
         self.no_prefix_cache = Some(no_prefix_cache);
         self
     }
-    pub fn with_prefix_cache_n(mut self, prefix_cache_n: usize) -> Self {
-        self.prefix_cache_n = Some(prefix_cache_n);
+    /// Set the byte budgets of the device, host and (optional) disk tiers of the prefix cache.
+    pub fn with_prefix_cache_budgets(mut self, prefix_cache_budgets: PrefixCacheBudgets) -> Self {
+        self.prefix_cache_budgets = Some(prefix_cache_budgets);
         self
     }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Keep `sink_tokens` attention sinks plus the last `window` tokens in the KV cache instead of
+    /// stopping sequences at the model length.
+    pub fn with_attention_sinks(mut self, sink_tokens: usize, window: usize) -> Self {
+        self.attention_sinks = Some(AttentionSinks {
+            sink_tokens,
+            window,
+        });
+        self
+    }
+
+    /// Run this logits processor for all requests, before the processors of the request.
+    pub fn with_logits_processor(mut self, logits_processor: Arc<dyn LogitsProcessor>) -> Self {
+        self.logits_processors.push(logits_processor);
+        self
+    }
+
+    /// Serve an embedding model alongside the model. It runs on its own thread.
+    pub fn with_embedding_model(mut self, embedding_model: EncoderPipeline) -> Self {
+        self.embedding_model = Some(embedding_model);
+        self
+    }
+
+    /// Serve a cross-encoder for reranking alongside the model. It runs on its own thread.
+    pub fn with_reranker(mut self, reranker: EncoderPipeline) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -137,24 +192,50 @@ impl MistralRs {
             truncate_sequence,
             no_kv_cache,
             no_prefix_cache,
-            prefix_cache_n,
+            prefix_cache_budgets,
             disable_eos_stop,
+            attention_sinks,
+            logits_processors,
+            embedding_model,
+            reranker,
         } = config;
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
-        let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        let prefix_cache_budgets = prefix_cache_budgets.unwrap_or_default();
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
 
         let (tx, rx) = channel(10_000);
         let (isq_tx, isq_rx) = channel(10_000);
+        let (prefix_tx, prefix_rx) = channel(10_000);
+
+        // Each encoder has its own engine, so embeddings and reranking do not wait on each other.
+        let spawn_encoder = |encoder: EncoderPipeline| {
+            let (encoder_tx, encoder_rx) = channel(10_000);
+            thread::spawn(move || {
+                let rt = Runtime::new().unwrap();
+                rt.block_on(async move {
+                    EncoderEngine::new(encoder_rx, encoder).run().await;
+                });
+            });
+            encoder_tx
+        };
+        let embedding_id = embedding_model.as_ref().map(EncoderPipeline::name);
+        let embedding_tx = embedding_model.map(spawn_encoder);
+        let reranker_id = reranker.as_ref().map(EncoderPipeline::name);
+        let reranker_tx = reranker.map(spawn_encoder);
 
         let this = Arc::new(Self {
             sender: tx,
             sender_isq: isq_tx,
+            sender_prefix: prefix_tx,
+            sender_embedding: embedding_tx,
+            sender_reranker: reranker_tx,
             log,
             id: pipeline.try_lock().unwrap().name(),
+            embedding_id,
+            reranker_id,
             creation_time: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!")
@@ -167,13 +248,16 @@ impl MistralRs {
                 let mut engine = Engine::new(
                     rx,
                     isq_rx,
+                    prefix_rx,
                     pipeline,
                     method,
                     truncate_sequence,
                     no_kv_cache,
                     no_prefix_cache,
-                    prefix_cache_n,
+                    prefix_cache_budgets,
                     disable_eos_stop,
+                    attention_sinks,
+                    logits_processors,
                 );
                 engine.run().await;
             });
@@ -194,6 +278,31 @@ impl MistralRs {
             .expect("Engine is not present.")
     }
 
+    /// Get a sender to pin, list and unpin prefix cache entries.
+    pub fn get_prefix_cache_sender(&self) -> Sender<PrefixCacheRequest> {
+        self.sender_prefix.clone()
+    }
+
+    /// Get a sender to the engine of the embedding model, if one is served.
+    pub fn get_embedding_sender(&self) -> Option<Sender<EncoderRequest>> {
+        self.sender_embedding.clone()
+    }
+
+    /// Get a sender to the engine of the reranker, if one is served.
+    pub fn get_reranker_sender(&self) -> Option<Sender<EncoderRequest>> {
+        self.sender_reranker.clone()
+    }
+
+    /// The id of the embedding model, if one is served.
+    pub fn get_embedding_id(&self) -> Option<String> {
+        self.embedding_id.clone()
+    }
+
+    /// The id of the reranker, if one is served.
+    pub fn get_reranker_id(&self) -> Option<String> {
+        self.reranker_id.clone()
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
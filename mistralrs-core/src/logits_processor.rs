@@ -0,0 +1,22 @@
+use candle_core::{Result, Tensor};
+
+/// A custom processor of the logits of a sequence, run before sampling and before the logits are
+/// masked by a [`crate::Constraint`].
+///
+/// Processors can be attached to a [`crate::Request`], or to all requests with
+/// [`crate::MistralRsBuilder::with_logits_processor`]. Any `Fn(&Tensor, &[u32]) -> Result<Tensor>`
+/// is a processor.
+pub trait LogitsProcessor: Send + Sync {
+    /// Process the logits, a 1D `f32` tensor over the vocabulary, given all tokens of the sequence
+    /// so far (prompt included).
+    fn apply(&self, logits: &Tensor, toks: &[u32]) -> Result<Tensor>;
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: Fn(&Tensor, &[u32]) -> Result<Tensor> + Send + Sync,
+{
+    fn apply(&self, logits: &Tensor, toks: &[u32]) -> Result<Tensor> {
+        self(logits, toks)
+    }
+}
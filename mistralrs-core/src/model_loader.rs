@@ -46,6 +46,7 @@ impl LoaderBuilder {
 pub fn get_tgt_non_granular_index(model: &ModelSelected) -> Option<usize> {
     match model {
         ModelSelected::Plain { .. }
+        | ModelSelected::Dummy { .. }
         | ModelSelected::Lora { .. }
         | ModelSelected::GGUF { .. }
         | ModelSelected::LoraGGUF { .. }
@@ -85,22 +86,43 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
         ModelSelected::Plain {
             model_id,
             repeat_last_n,
+            rope_scaling,
             tokenizer_json,
             arch,
         } => NormalLoaderBuilder::new(
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n,
+                rope_scaling,
             },
             args.chat_template,
             tokenizer_json,
             Some(model_id),
         )
         .build(arch),
+        ModelSelected::Dummy {
+            config,
+            tokenizer_json,
+            weights,
+            repeat_last_n,
+            arch,
+        } => NormalLoaderBuilder::new(
+            NormalSpecificConfig {
+                use_flash_attn,
+                repeat_last_n,
+                rope_scaling: None,
+            },
+            args.chat_template,
+            tokenizer_json,
+            Some(config),
+        )
+        .with_dummy_weights(weights)
+        .build(arch),
         ModelSelected::XLora {
             model_id,
             xlora_model_id,
             repeat_last_n,
+            rope_scaling,
             order,
             tokenizer_json,
             tgt_non_granular_index,
@@ -109,6 +131,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n,
+                rope_scaling,
             },
             args.chat_template,
             tokenizer_json,
@@ -129,12 +152,14 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             tokenizer_json,
             adapters_model_id,
             repeat_last_n,
+            rope_scaling,
             order,
             arch,
         } => NormalLoaderBuilder::new(
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n,
+                rope_scaling,
             },
             args.chat_template,
             tokenizer_json,
@@ -158,7 +183,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGUFSpecificConfig { repeat_last_n },
             args.chat_template,
             tokenizer_json,
-            Some(tok_model_id),
+            tok_model_id,
             quantized_model_id,
             quantized_filename,
         )
@@ -1,11 +1,22 @@
 use clap::Subcommand;
 
-use crate::pipeline::NormalLoaderType;
+use crate::{
+    layers::RopeScaling,
+    pipeline::{DummyWeights, NormalLoaderType},
+};
 
 fn parse_arch(x: &str) -> Result<NormalLoaderType, String> {
     x.parse()
 }
 
+fn parse_rope_scaling(x: &str) -> Result<RopeScaling, String> {
+    x.parse()
+}
+
+fn parse_dummy_weights(x: &str) -> Result<DummyWeights, String> {
+    x.parse()
+}
+
 #[derive(Debug, Subcommand)]
 pub enum ModelSelected {
     /// Select the model from a toml file
@@ -29,6 +40,34 @@ pub enum ModelSelected {
         #[arg(long, default_value_t = 64)]
         repeat_last_n: usize,
 
+        /// Override the model's RoPE scaling, as `<type>:<factor>`, where type is one of `linear`, `dynamic`, `yarn` or `llama3`.
+        #[arg(long, value_parser = parse_rope_scaling)]
+        rope_scaling: Option<RopeScaling>,
+
+        /// The architecture of the model.
+        #[arg(short, long, value_parser = parse_arch)]
+        arch: NormalLoaderType,
+    },
+
+    /// Select a plain model with random or zero weights, for testing and benchmarking
+    Dummy {
+        /// The model config: a built-in preset (`tiny` or `small`), a `config.json` file, or a
+        /// directory which contains one.
+        #[arg(short, long)]
+        config: String,
+
+        /// Path to local tokenizer.json file. If it is not specified, a synthetic byte level tokenizer is used.
+        #[arg(short, long)]
+        tokenizer_json: Option<String>,
+
+        /// The weights to initialize the model with, `random` or `zeros`.
+        #[arg(short, long, default_value = "random", value_parser = parse_dummy_weights)]
+        weights: DummyWeights,
+
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long, default_value_t = 64)]
+        repeat_last_n: usize,
+
         /// The architecture of the model.
         #[arg(short, long, value_parser = parse_arch)]
         arch: NormalLoaderType,
@@ -52,6 +91,10 @@ pub enum ModelSelected {
         #[arg(long, default_value_t = 64)]
         repeat_last_n: usize,
 
+        /// Override the model's RoPE scaling, as `<type>:<factor>`, where type is one of `linear`, `dynamic`, `yarn` or `llama3`.
+        #[arg(long, value_parser = parse_rope_scaling)]
+        rope_scaling: Option<RopeScaling>,
+
         /// Ordering JSON file
         #[arg(short, long)]
         order: String,
@@ -84,6 +127,10 @@ pub enum ModelSelected {
         #[arg(long, default_value_t = 64)]
         repeat_last_n: usize,
 
+        /// Override the model's RoPE scaling, as `<type>:<factor>`, where type is one of `linear`, `dynamic`, `yarn` or `llama3`.
+        #[arg(long, value_parser = parse_rope_scaling)]
+        rope_scaling: Option<RopeScaling>,
+
         /// Ordering JSON file
         #[arg(short, long)]
         order: String,
@@ -95,9 +142,10 @@ pub enum ModelSelected {
 
     /// Select a GGUF model.
     GGUF {
-        /// Model ID to load the tokenizer from. This may be a HF hub repo or a local path.
+        /// Model ID to load the tokenizer from. This may be a HF hub repo or a local path. If it is
+        /// not specified, the tokenizer and chat template are built from the GGUF metadata.
         #[arg(short, long)]
-        tok_model_id: String,
+        tok_model_id: Option<String>,
 
         /// Path to local tokenizer.json file. If this is specified it is used over any remote file.
         #[arg(long)]
@@ -108,9 +156,11 @@ pub enum ModelSelected {
         #[arg(short = 'm', long)]
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        #[arg(short = 'f', long)]
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as all of its shards, by repeating this flag or
+        /// passing several values to it.
+        #[arg(short = 'f', long, num_args = 1.., required = true)]
+        quantized_filename: Vec<String>,
 
         /// Control the application of repeat penalty for the last n tokens
         #[arg(long, default_value_t = 64)]
@@ -132,9 +182,11 @@ pub enum ModelSelected {
         #[arg(short = 'm', long)]
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        #[arg(short = 'f', long)]
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as all of its shards, by repeating this flag or
+        /// passing several values to it.
+        #[arg(short = 'f', long, num_args = 1.., required = true)]
+        quantized_filename: Vec<String>,
 
         /// Control the application of repeat penalty for the last n tokens
         #[arg(long, default_value_t = 64)]
@@ -169,9 +221,11 @@ pub enum ModelSelected {
         #[arg(short = 'm', long)]
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        #[arg(short = 'f', long)]
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as all of its shards, by repeating this flag or
+        /// passing several values to it.
+        #[arg(short = 'f', long, num_args = 1.., required = true)]
+        quantized_filename: Vec<String>,
 
         /// Control the application of repeat penalty for the last n tokens
         #[arg(long, default_value_t = 64)]
@@ -0,0 +1,290 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// BERT and XLM-RoBERTa encoders, used for sentence embeddings and cross-encoder reranking.
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/bert/modeling_bert.py
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/xlm_roberta/modeling_xlm_roberta.py
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{
+    embedding, layer_norm, linear, Activation, Embedding, LayerNorm, Linear, VarBuilder,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderFamily {
+    Bert,
+    /// RoBERTa and XLM-RoBERTa, which number positions after the padding index.
+    XlmRoberta,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(crate) family: EncoderFamily,
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) hidden_act: Activation,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) type_vocab_size: usize,
+    pub(crate) layer_norm_eps: f64,
+    pub(crate) pad_token_id: usize,
+    /// The number of labels of the classification head of a cross-encoder, or `None` for a plain
+    /// encoder.
+    pub(crate) num_labels: Option<usize>,
+}
+
+impl Config {
+    /// The longest input, in tokens. XLM-RoBERTa reserves the positions up to the padding index.
+    pub(crate) fn max_input_len(&self) -> usize {
+        match self.family {
+            EncoderFamily::Bert => self.max_position_embeddings,
+            EncoderFamily::XlmRoberta => self.max_position_embeddings - self.pad_token_id - 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Embeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl Embeddings {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("word_embeddings"))?,
+            position_embeddings: embedding(
+                cfg.max_position_embeddings,
+                cfg.hidden_size,
+                vb.pp("position_embeddings"),
+            )?,
+            token_type_embeddings: embedding(
+                cfg.type_vocab_size,
+                cfg.hidden_size,
+                vb.pp("token_type_embeddings"),
+            )?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("LayerNorm"))?,
+        })
+    }
+
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        position_ids: &Tensor,
+    ) -> Result<Tensor> {
+        let xs = self.word_embeddings.forward(input_ids)?;
+        let xs = (xs + self.token_type_embeddings.forward(token_type_ids)?)?;
+        let xs = (xs + self.position_embeddings.forward(position_ids)?)?;
+        self.layer_norm.forward(&xs)
+    }
+}
+
+#[derive(Debug)]
+struct Attention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    output: Linear,
+    layer_norm: LayerNorm,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden = cfg.hidden_size;
+        let vb_s = vb.pp("self");
+        let vb_o = vb.pp("output");
+        Ok(Self {
+            query: linear(hidden, hidden, vb_s.pp("query"))?,
+            key: linear(hidden, hidden, vb_s.pp("key"))?,
+            value: linear(hidden, hidden, vb_s.pp("value"))?,
+            output: linear(hidden, hidden, vb_o.pp("dense"))?,
+            layer_norm: layer_norm(hidden, cfg.layer_norm_eps, vb_o.pp("LayerNorm"))?,
+            num_heads: cfg.num_attention_heads,
+            head_dim: hidden / cfg.num_attention_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let heads = |proj: &Linear| {
+            proj.forward(xs)?
+                .reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let (q, k, v) = (heads(&self.query)?, heads(&self.key)?, heads(&self.value)?);
+
+        let scale = 1. / (self.head_dim as f64).sqrt();
+        let att = (q.matmul(&k.t()?)? * scale)?.broadcast_add(mask)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let ys = att
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, ()))?;
+        self.layer_norm.forward(&(self.output.forward(&ys)? + xs)?)
+    }
+}
+
+#[derive(Debug)]
+struct Layer {
+    attention: Attention,
+    intermediate: Linear,
+    output: Linear,
+    layer_norm: LayerNorm,
+    act: Activation,
+}
+
+impl Layer {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_o = vb.pp("output");
+        Ok(Self {
+            attention: Attention::new(cfg, vb.pp("attention"))?,
+            intermediate: linear(
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                vb.pp("intermediate").pp("dense"),
+            )?,
+            output: linear(cfg.intermediate_size, cfg.hidden_size, vb_o.pp("dense"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb_o.pp("LayerNorm"))?,
+            act: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let xs = self.attention.forward(xs, mask)?;
+        let ys = self.intermediate.forward(&xs)?.apply(&self.act)?;
+        self.layer_norm.forward(&(self.output.forward(&ys)? + xs)?)
+    }
+}
+
+/// The classification head of a cross-encoder, applied to the hidden state of the first token.
+#[derive(Debug)]
+enum ClassifierHead {
+    Bert { pooler: Linear, classifier: Linear },
+    XlmRoberta { dense: Linear, out_proj: Linear },
+}
+
+impl ClassifierHead {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Bert { pooler, classifier } => classifier.forward(&pooler.forward(xs)?.tanh()?),
+            Self::XlmRoberta { dense, out_proj } => out_proj.forward(&dense.forward(xs)?.tanh()?),
+        }
+    }
+}
+
+/// The position of each token, `(batch, seq_len)`, given the attention mask of the batch.
+pub(crate) fn position_ids(
+    family: EncoderFamily,
+    pad_token_id: usize,
+    attention_mask: &Tensor,
+) -> Result<Tensor> {
+    let (b_sz, seq_len) = attention_mask.dims2()?;
+    match family {
+        EncoderFamily::Bert => Tensor::arange(0u32, seq_len as u32, attention_mask.device())?
+            .unsqueeze(0)?
+            .broadcast_as((b_sz, seq_len))?
+            .contiguous(),
+        // Tokens are numbered from `pad_token_id + 1`, padding is at `pad_token_id`.
+        EncoderFamily::XlmRoberta => {
+            let mask = attention_mask.to_dtype(DType::F32)?;
+            ((mask.cumsum(1)? * &mask)? + pad_token_id as f64)?.to_dtype(DType::U32)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    embeddings: Embeddings,
+    layers: Vec<Layer>,
+    classifier: Option<ClassifierHead>,
+    family: EncoderFamily,
+    pad_token_id: usize,
+    pub device: Device,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        // Task models nest the encoder under `bert` or `roberta`, plain encoders may not.
+        let prefix = match cfg.family {
+            EncoderFamily::Bert => "bert",
+            EncoderFamily::XlmRoberta => "roberta",
+        };
+        let vb_m = if vb.contains_tensor(&format!("{prefix}.embeddings.word_embeddings.weight")) {
+            vb.pp(prefix)
+        } else {
+            vb.clone()
+        };
+
+        let embeddings = Embeddings::new(cfg, vb_m.pp("embeddings"))?;
+        let vb_l = vb_m.pp("encoder").pp("layer");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|layer_idx| Layer::new(cfg, vb_l.pp(layer_idx)))
+            .collect::<Result<Vec<_>>>()?;
+        let classifier = match cfg.num_labels {
+            Some(num_labels) => {
+                let hidden = cfg.hidden_size;
+                let vb_c = vb.pp("classifier");
+                Some(match cfg.family {
+                    EncoderFamily::Bert => ClassifierHead::Bert {
+                        pooler: linear(hidden, hidden, vb_m.pp("pooler").pp("dense"))?,
+                        classifier: linear(hidden, num_labels, vb_c)?,
+                    },
+                    EncoderFamily::XlmRoberta => ClassifierHead::XlmRoberta {
+                        dense: linear(hidden, hidden, vb_c.pp("dense"))?,
+                        out_proj: linear(hidden, num_labels, vb_c.pp("out_proj"))?,
+                    },
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            embeddings,
+            layers,
+            classifier,
+            family: cfg.family,
+            pad_token_id: cfg.pad_token_id,
+            device: vb.device().clone(),
+        })
+    }
+
+    pub fn is_cross_encoder(&self) -> bool {
+        self.classifier.is_some()
+    }
+
+    /// The last hidden states, `(batch, seq_len, hidden_size)`. The attention mask is 1 for the
+    /// tokens of the inputs and 0 for padding.
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let position_ids = position_ids(self.family, self.pad_token_id, attention_mask)?;
+        let mut xs = self
+            .embeddings
+            .forward(input_ids, token_type_ids, &position_ids)?;
+        // Padding is masked out of the attention of every token, `(batch, 1, 1, seq_len)`.
+        let mask = ((attention_mask.to_dtype(DType::F32)? - 1.)? * 1e4)?
+            .to_dtype(xs.dtype())?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs, &mask)?;
+        }
+        Ok(xs)
+    }
+
+    /// The logits of the classification head, `(batch, num_labels)`, from the last hidden states.
+    pub fn classify(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let Some(ref classifier) = self.classifier else {
+            candle_core::bail!("The model has no classification head.");
+        };
+        classifier.forward(&hidden_states.i((.., 0))?.contiguous()?)
+    }
+}
@@ -0,0 +1,502 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Falcon model.
+/// https://huggingface.co/tiiuae/falcon-7b
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
+use candle_nn::{embedding, layer_norm, Embedding, LayerNorm, VarBuilder};
+use mistralrs_lora::layer::QLinear;
+use std::sync::Arc;
+
+use crate::{
+    device_map::DeviceMapper,
+    layers::{CausalMasker, RopeShift, RotaryEmbedding},
+    pipeline::{extract_logits, NormalModel},
+    DeviceMapMetadata,
+};
+
+use super::{flash_attn, repeat_kv, starcoder2::qlinear_b, Cache};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) ffn_hidden_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_kv_heads: usize,
+    pub(crate) layer_norm_epsilon: f64,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) rope_theta: f64,
+    pub(crate) bias: bool,
+    pub(crate) multi_query: bool,
+    pub(crate) new_decoder_architecture: bool,
+    pub(crate) parallel_attn: bool,
+    pub(crate) num_ln_in_parallel_attn: usize,
+    pub(crate) tie_word_embeddings: bool,
+    pub(crate) use_flash_attn: bool,
+}
+
+impl Config {
+    pub(crate) fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    /// The width of the fused query, key and value projection.
+    pub(crate) fn qkv_size(&self) -> usize {
+        (self.num_attention_heads + 2 * self.num_kv_heads) * self.head_dim()
+    }
+
+    /// Whether the attention and the MLP both read the input of the layer. The new decoder
+    /// architecture (Falcon 40B and later) always works so.
+    pub(crate) fn is_parallel(&self) -> bool {
+        self.new_decoder_architecture || self.parallel_attn
+    }
+
+    /// Whether the attention and the MLP each have their own input layer norm.
+    pub(crate) fn has_two_layer_norms(&self) -> bool {
+        self.new_decoder_architecture && self.num_ln_in_parallel_attn == 2
+    }
+}
+
+/// Split the output of the fused query, key and value projection into q, k and v of shape
+/// (b_sz, seq_len, n_heads, head_dim).
+pub(crate) fn split_qkv(cfg: &Config, fused_qkv: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+    let (b_sz, seq_len, _) = fused_qkv.dims3()?;
+    let head_dim = cfg.head_dim();
+    if cfg.new_decoder_architecture {
+        // The heads are grouped: each group of queries is followed by its key and value.
+        let group = cfg.num_attention_heads / cfg.num_kv_heads;
+        let qkv = fused_qkv.reshape((b_sz, seq_len, cfg.num_kv_heads, group + 2, head_dim))?;
+        let q =
+            qkv.narrow(3, 0, group)?
+                .reshape((b_sz, seq_len, cfg.num_attention_heads, head_dim))?;
+        let k = qkv.narrow(3, group, 1)?.squeeze(3)?;
+        let v = qkv.narrow(3, group + 1, 1)?.squeeze(3)?;
+        Ok((q, k, v))
+    } else if cfg.multi_query {
+        let q = fused_qkv.narrow(D::Minus1, 0, cfg.hidden_size)?;
+        let k = fused_qkv.narrow(D::Minus1, cfg.hidden_size, head_dim)?;
+        let v = fused_qkv.narrow(D::Minus1, cfg.hidden_size + head_dim, head_dim)?;
+        Ok((
+            q.reshape((b_sz, seq_len, cfg.num_attention_heads, head_dim))?,
+            k.reshape((b_sz, seq_len, 1, head_dim))?,
+            v.reshape((b_sz, seq_len, 1, head_dim))?,
+        ))
+    } else {
+        let qkv = fused_qkv.reshape((b_sz, seq_len, cfg.num_attention_heads, 3, head_dim))?;
+        Ok((
+            qkv.narrow(3, 0, 1)?.squeeze(3)?,
+            qkv.narrow(3, 1, 1)?.squeeze(3)?,
+            qkv.narrow(3, 2, 1)?.squeeze(3)?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    dense_h_to_4h: QLinear,
+    dense_4h_to_h: QLinear,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dense_h_to_4h = qlinear_b(
+            cfg.hidden_size,
+            cfg.ffn_hidden_size,
+            cfg.bias,
+            vb.pp("dense_h_to_4h"),
+        )?;
+        let dense_4h_to_h = qlinear_b(
+            cfg.ffn_hidden_size,
+            cfg.hidden_size,
+            cfg.bias,
+            vb.pp("dense_4h_to_h"),
+        )?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if self.dense_h_to_4h.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        let mut res = xs
+            .apply(&self.dense_h_to_4h)?
+            .gelu_erf()?
+            .apply(&self.dense_4h_to_h)?;
+        if self.dense_h_to_4h.is_quant() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    query_key_value: QLinear,
+    dense: QLinear,
+    cfg: Config,
+    rotary_emb: Arc<RotaryEmbedding>,
+    neg_inf: Tensor,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let query_key_value = qlinear_b(
+            cfg.hidden_size,
+            cfg.qkv_size(),
+            cfg.bias,
+            vb.pp("query_key_value"),
+        )?;
+        let dense = qlinear_b(cfg.hidden_size, cfg.hidden_size, cfg.bias, vb.pp("dense"))?;
+        Ok(Self {
+            query_key_value,
+            dense,
+            cfg: cfg.clone(),
+            rotary_emb,
+            neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let num_heads = self.cfg.num_attention_heads;
+        let num_kv_heads = self.cfg.num_kv_heads;
+        let head_dim = self.cfg.head_dim();
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if self.query_key_value.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        let mut fused_qkv = self.query_key_value.forward(&xs)?;
+        if self.query_key_value.is_quant() {
+            fused_qkv = fused_qkv.to_dtype(original_dtype)?;
+        }
+        let (q, k, v) = split_qkv(&self.cfg, &fused_qkv)?;
+
+        let mut q = q.reshape((b_sz * q_len, num_heads, head_dim))?;
+        let mut k = k.reshape((b_sz * q_len, num_kv_heads, head_dim))?;
+        let v = v.transpose(1, 2)?.contiguous()?;
+
+        self.rotary_emb
+            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 {
+            q = q
+                .reshape((b_sz, q_len, num_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, q_len, num_kv_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        }
+
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, None)?;
+
+        let k = repeat_kv(k, num_heads / num_kv_heads)?.contiguous()?;
+        let v = repeat_kv(v, num_heads / num_kv_heads)?.contiguous()?;
+
+        let mut attn_output = if self.cfg.use_flash_attn {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (head_dim as f32).sqrt();
+            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else {
+            let scale = 1f64 / f64::sqrt(head_dim as f64);
+            let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+
+            let attn_weights =
+                CausalMasker.apply_mask(&attention_mask.cloned(), attn_weights, &self.neg_inf)?;
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&v)?
+        };
+        if self.query_key_value.is_quant() {
+            attn_output = attn_output.to_dtype(DType::F32)?;
+        }
+        let mut res = attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, ()))?
+            .apply(&self.dense)?;
+        if self.query_key_value.is_quant() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attention: Attention,
+    mlp: MLP,
+    /// The norm of the attention input, and of the MLP input unless there is `mlp_layernorm`.
+    input_layernorm: LayerNorm,
+    /// The separate norm of the MLP input: `ln_mlp` for the new decoder architecture, or
+    /// `post_attention_layernorm` for sequential attention and MLP.
+    mlp_layernorm: Option<LayerNorm>,
+    is_parallel: bool,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let self_attention = Attention::new(
+            rotary_emb,
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("self_attention"), loading_isq),
+        )?;
+        let mlp = MLP::new(cfg, mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq))?;
+        let norm = |name: &str| {
+            layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                mapper.set_device(layer_idx, vb.pp(name), false),
+            )
+        };
+        let (input_layernorm, mlp_layernorm) = if cfg.has_two_layer_norms() {
+            (norm("ln_attn")?, Some(norm("ln_mlp")?))
+        } else if !cfg.is_parallel() {
+            (
+                norm("input_layernorm")?,
+                Some(norm("post_attention_layernorm")?),
+            )
+        } else {
+            (norm("input_layernorm")?, None)
+        };
+        Ok(Self {
+            self_attention,
+            mlp,
+            input_layernorm,
+            mlp_layernorm,
+            is_parallel: cfg.is_parallel(),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let attn_input = xs.apply(&self.input_layernorm)?;
+        let attn_output = self.self_attention.forward(
+            &attn_input,
+            attention_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+        )?;
+        if self.is_parallel {
+            let mlp_input = match &self.mlp_layernorm {
+                Some(ln) => xs.apply(ln)?,
+                None => attn_input,
+            };
+            (mlp_input.apply(&self.mlp)? + attn_output)? + residual
+        } else {
+            let xs = (attn_output + residual)?;
+            let mlp_output = xs
+                .apply(self.mlp_layernorm.as_ref().unwrap())?
+                .apply(&self.mlp)?;
+            mlp_output + xs
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    word_embeddings: Embedding,
+    h: Vec<DecoderLayer>,
+    ln_f: LayerNorm,
+    lm_head: QLinear,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_shift: Option<RopeShift>,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        is_gptx: bool,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        real_device: Device,
+    ) -> Result<Self> {
+        let vb_t = vb.pp("transformer");
+        let mapper = mapper.into_mapper(cfg.num_hidden_layers, &real_device)?;
+        let word_embeddings = embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_t.pp("word_embeddings"), false),
+        )?;
+        let mut h = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_h = vb_t.pp("h");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let rotary_emb = Arc::new(RotaryEmbedding::new(
+                cfg.rope_theta as f32,
+                cfg.head_dim(),
+                cfg.max_position_embeddings,
+                mapper.device_for(layer_idx, false).unwrap_or(&real_device),
+                is_gptx,
+                vb.dtype(),
+                None,
+            )?);
+            let layer = DecoderLayer::new(
+                rotary_emb,
+                cfg,
+                vb_h.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                loading_isq,
+            )?;
+            h.push(layer)
+        }
+        let ln_f = layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_t.pp("ln_f"), false),
+        )?;
+        let lm_head = if cfg.tie_word_embeddings {
+            QLinear::from_parts(word_embeddings.embeddings().clone(), None)
+        } else {
+            qlinear_b(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                false,
+                mapper.set_nm_device(vb.pp("lm_head"), loading_isq),
+            )?
+        };
+        Ok(Self {
+            word_embeddings,
+            h,
+            ln_f,
+            lm_head,
+            device: real_device,
+            cache: Cache::new(cfg.num_hidden_layers, false),
+            max_seq_len: cfg.max_position_embeddings,
+            mapper,
+            rope_shift: Some(RopeShift {
+                base: cfg.rope_theta as f32,
+                is_gpt_neox: is_gptx,
+            }),
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let attention_mask = CausalMasker.make_causal_mask(input_ids, &self.cache)?;
+        let mut xs = self.word_embeddings.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.h.iter_mut().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+            )?
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.ln_f)?;
+        if self.lm_head.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            context_lens,
+        )
+    }
+    fn xlora_forward(
+        &mut self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn rope_shift(&self) -> Option<RopeShift> {
+        self.rope_shift
+    }
+    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((self.lm_head.inner(), None));
+        for (i, layer) in self.h.iter_mut().enumerate() {
+            tensors.push((layer.self_attention.query_key_value.inner(), Some(i)));
+            tensors.push((layer.self_attention.dense.inner(), Some(i)));
+            tensors.push((layer.mlp.dense_h_to_4h.inner(), Some(i)));
+            tensors.push((layer.mlp.dense_4h_to_h.inner(), Some(i)));
+        }
+        (tensors, &*self.mapper)
+    }
+}
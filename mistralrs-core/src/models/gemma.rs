@@ -8,7 +8,7 @@ use mistralrs_lora::layer::QLinear;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::CausalMasker,
+    layers::{CausalMasker, RopeShift},
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -329,6 +329,7 @@ pub struct Model {
     pub cache: Cache,
     pub max_seq_len: usize,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_shift: RopeShift,
 }
 
 impl Model {
@@ -384,6 +385,10 @@ impl Model {
             cache: Cache::new(cfg.num_hidden_layers, false),
             max_seq_len: default_max_position_embeddings(),
             mapper,
+            rope_shift: RopeShift {
+                base: cfg.rope_theta as f32,
+                is_gpt_neox: is_gptx,
+            },
         })
     }
 
@@ -463,6 +468,9 @@ impl NormalModel for Model {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
+    fn rope_shift(&self) -> Option<RopeShift> {
+        Some(self.rope_shift)
+    }
     fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
         tensors.push((&mut self.lm_head, None));
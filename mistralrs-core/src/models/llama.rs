@@ -1,15 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor, D};
-use candle_nn::{
-    embedding, linear_no_bias as linear, Embedding, Module, RotaryEmbedding, VarBuilder,
-};
+use candle_nn::{embedding, linear_no_bias as linear, Embedding, Module, VarBuilder};
 use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{CausalMasker, RmsNorm},
+    layers::{CausalMasker, RmsNorm, RopeScaling, RopeShift, RotaryEmbedding},
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -28,6 +26,7 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
     pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 #[derive(Debug, Clone)]
@@ -159,7 +158,12 @@ impl CausalSelfAttention {
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             use_flash_attn: cfg.use_flash_attn,
             rotary_emb: rope,
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |s| {
+                    s.max_position_embeddings(cfg.max_position_embeddings)
+                }),
             neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
         })
     }
@@ -276,6 +280,7 @@ pub struct Llama {
     pub kv_cache: super::Cache,
     pub device: Device,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_shift: Option<RopeShift>,
 }
 
 impl Llama {
@@ -344,6 +349,7 @@ impl Llama {
                         mapper.device_for(i, false).unwrap_or(&real_device),
                         is_gptx,
                         vb.dtype(),
+                        cfg.rope_scaling.as_ref(),
                     )
                     .expect("Failed to create RoPE"),
                 );
@@ -367,6 +373,15 @@ impl Llama {
             kv_cache: super::Cache::new(cfg.num_hidden_layers, false),
             device: real_device,
             mapper,
+            // Cached keys cannot be moved with a plain rotation if the RoPE is scaled.
+            rope_shift: (!cfg
+                .rope_scaling
+                .as_ref()
+                .is_some_and(RopeScaling::is_scaled))
+            .then_some(RopeShift {
+                base: cfg.rope_theta,
+                is_gpt_neox: is_gptx,
+            }),
         })
     }
 }
@@ -414,6 +429,9 @@ impl NormalModel for Llama {
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
+    fn rope_shift(&self) -> Option<RopeShift> {
+        self.rope_shift
+    }
     fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
         tensors.push((&mut self.lm_head, None));
@@ -0,0 +1,368 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Mamba model
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/mamba/modeling_mamba.py
+/// https://arxiv.org/abs/2312.00752
+use candle_core::{quantized::QMatMul, DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{linear_no_bias, VarBuilder};
+
+use crate::{
+    device_map::DeviceMapper,
+    layers::RmsNorm,
+    pipeline::{extract_logits, NormalModel},
+    DeviceMapMetadata,
+};
+
+use super::Cache;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) state_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) conv_kernel: usize,
+    pub(crate) time_step_rank: usize,
+    pub(crate) layer_norm_epsilon: f64,
+    pub(crate) use_bias: bool,
+    pub(crate) use_conv_bias: bool,
+    pub(crate) tie_word_embeddings: bool,
+    pub(crate) max_seq_len: usize,
+}
+
+/// Apply a projection which may have been quantized by ISQ, keeping the dtype of `xs`.
+fn project(proj: &QMatMul, xs: &Tensor) -> Result<Tensor> {
+    match proj {
+        QMatMul::QTensor(_) => xs.to_dtype(DType::F32)?.apply(proj)?.to_dtype(xs.dtype()),
+        _ => xs.apply(proj),
+    }
+}
+
+fn project_b(proj: &QMatMul, bias: Option<&Tensor>, xs: &Tensor) -> Result<Tensor> {
+    let ys = project(proj, xs)?;
+    match bias {
+        Some(bias) => ys.broadcast_add(bias),
+        None => Ok(ys),
+    }
+}
+
+/// `log(1 + exp(x))`, computed without overflowing for large `x`.
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    xs.relu()? + (xs.abs()?.neg()?.exp()? + 1.)?.log()?
+}
+
+/// The selective state space block. Its per layer cache holds the last `conv_kernel - 1` inputs
+/// of the causal convolution and the SSM state, shaped `(batch, intermediate_size, state_size)`.
+#[derive(Debug, Clone)]
+pub(crate) struct Mixer {
+    pub(crate) in_proj: QMatMul,
+    pub(crate) in_proj_bias: Option<Tensor>,
+    /// The depthwise convolution kernel, `(intermediate_size, conv_kernel)`.
+    pub(crate) conv1d_weight: Tensor,
+    pub(crate) conv1d_bias: Option<Tensor>,
+    pub(crate) x_proj: QMatMul,
+    pub(crate) dt_proj: QMatMul,
+    pub(crate) dt_proj_bias: Tensor,
+    /// `-exp(A_log)` in F32, `(intermediate_size, state_size)`.
+    pub(crate) a: Tensor,
+    /// The skip connection in F32, `(intermediate_size,)`.
+    pub(crate) d: Tensor,
+    pub(crate) out_proj: QMatMul,
+    pub(crate) out_proj_bias: Option<Tensor>,
+    pub(crate) intermediate_size: usize,
+    pub(crate) state_size: usize,
+    pub(crate) conv_kernel: usize,
+    pub(crate) time_step_rank: usize,
+}
+
+impl Mixer {
+    /// `vb` is used for the projections, which may be quantized by ISQ, and `vb_nq` for the
+    /// other weights.
+    fn new(cfg: &Config, vb: VarBuilder, vb_nq: VarBuilder) -> Result<Self> {
+        let (hidden, inter) = (cfg.hidden_size, cfg.intermediate_size);
+        let in_proj = linear_no_bias(hidden, inter * 2, vb.pp("in_proj"))?;
+        let x_proj = linear_no_bias(
+            inter,
+            cfg.time_step_rank + cfg.state_size * 2,
+            vb.pp("x_proj"),
+        )?;
+        let dt_proj = linear_no_bias(cfg.time_step_rank, inter, vb.pp("dt_proj"))?;
+        let out_proj = linear_no_bias(inter, hidden, vb.pp("out_proj"))?;
+        let proj_bias = |name: &str, size: usize| {
+            cfg.use_bias
+                .then(|| vb_nq.pp(name).get(size, "bias"))
+                .transpose()
+        };
+
+        let vb_conv = vb_nq.pp("conv1d");
+        let conv1d_weight = vb_conv
+            .get((inter, 1, cfg.conv_kernel), "weight")?
+            .squeeze(1)?;
+        let conv1d_bias = if cfg.use_conv_bias {
+            Some(vb_conv.get(inter, "bias")?)
+        } else {
+            None
+        };
+        let a = vb_nq
+            .get((inter, cfg.state_size), "A_log")?
+            .to_dtype(DType::F32)?
+            .exp()?
+            .neg()?;
+        Ok(Self {
+            in_proj: QMatMul::Tensor(in_proj.weight().clone()),
+            in_proj_bias: proj_bias("in_proj", inter * 2)?,
+            conv1d_weight,
+            conv1d_bias,
+            x_proj: QMatMul::Tensor(x_proj.weight().clone()),
+            dt_proj: QMatMul::Tensor(dt_proj.weight().clone()),
+            dt_proj_bias: vb_nq.pp("dt_proj").get(inter, "bias")?,
+            a,
+            d: vb_nq.get(inter, "D")?.to_dtype(DType::F32)?,
+            out_proj: QMatMul::Tensor(out_proj.weight().clone()),
+            out_proj_bias: proj_bias("out_proj", hidden)?,
+            intermediate_size: inter,
+            state_size: cfg.state_size,
+            conv_kernel: cfg.conv_kernel,
+            time_step_rank: cfg.time_step_rank,
+        })
+    }
+
+    pub(crate) fn forward(
+        &self,
+        xs: &Tensor,
+        cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let dtype = xs.dtype();
+        let inter = self.intermediate_size;
+
+        let xz = project_b(&self.in_proj, self.in_proj_bias.as_ref(), xs)?;
+        let x = xz.narrow(2, 0, inter)?.transpose(1, 2)?;
+        let z = xz.narrow(2, inter, inter)?;
+
+        let (conv_state, mut ssm_state) = match cache.take() {
+            Some(state) => state,
+            None => (
+                Tensor::zeros((b_sz, inter, self.conv_kernel - 1), dtype, xs.device())?,
+                Tensor::zeros((b_sz, inter, self.state_size), DType::F32, xs.device())?,
+            ),
+        };
+
+        // Causal depthwise convolution over the cached inputs followed by the new ones.
+        let padded = Tensor::cat(&[&conv_state.to_dtype(dtype)?, &x], 2)?;
+        let mut x = padded
+            .narrow(2, 0, seq_len)?
+            .broadcast_mul(&self.conv1d_weight.narrow(1, 0, 1)?)?;
+        for k in 1..self.conv_kernel {
+            let tap = padded
+                .narrow(2, k, seq_len)?
+                .broadcast_mul(&self.conv1d_weight.narrow(1, k, 1)?)?;
+            x = (x + tap)?;
+        }
+        if let Some(bias) = &self.conv1d_bias {
+            x = x.broadcast_add(&bias.unsqueeze(1)?)?;
+        }
+        let conv_state = padded
+            .narrow(2, seq_len, self.conv_kernel - 1)?
+            .contiguous()?;
+        let x = candle_nn::ops::silu(&x)?.transpose(1, 2)?.contiguous()?;
+
+        let x_dbl = project(&self.x_proj, &x)?;
+        let dt = x_dbl.narrow(2, 0, self.time_step_rank)?;
+        let b = x_dbl
+            .narrow(2, self.time_step_rank, self.state_size)?
+            .to_dtype(DType::F32)?
+            .contiguous()?;
+        let c = x_dbl
+            .narrow(2, self.time_step_rank + self.state_size, self.state_size)?
+            .to_dtype(DType::F32)?
+            .contiguous()?;
+        let dt = softplus(
+            &project_b(&self.dt_proj, Some(&self.dt_proj_bias), &dt.contiguous()?)?
+                .to_dtype(DType::F32)?,
+        )?;
+        let x = x.to_dtype(DType::F32)?;
+
+        // The selective scan, one token at a time.
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let dt_t = dt.i((.., t))?;
+            let x_t = x.i((.., t))?;
+            let da = dt_t.unsqueeze(2)?.broadcast_mul(&self.a)?.exp()?;
+            let dbx = (&dt_t * &x_t)?
+                .unsqueeze(2)?
+                .broadcast_mul(&b.i((.., t))?.unsqueeze(1)?)?;
+            ssm_state = ((da * ssm_state)? + dbx)?;
+            let c_t = c.i((.., t))?.unsqueeze(2)?.contiguous()?;
+            let y = ssm_state.matmul(&c_t)?.squeeze(2)?;
+            ys.push((y + x_t.broadcast_mul(&self.d)?)?);
+        }
+        let y = Tensor::stack(&ys, 1)?.to_dtype(dtype)?;
+        let y = (y * candle_nn::ops::silu(&z)?)?;
+
+        *cache = Some((conv_state, ssm_state));
+        project_b(&self.out_proj, self.out_proj_bias.as_ref(), &y)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ResidualBlock {
+    norm: RmsNorm,
+    mixer: Mixer,
+}
+
+impl ResidualBlock {
+    fn forward(&self, xs: &Tensor, cache: &mut Option<(Tensor, Tensor)>) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.mixer.forward(&self.norm.forward(xs)?, cache)?;
+        xs + residual
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    embeddings: candle_nn::Embedding,
+    layers: Vec<ResidualBlock>,
+    norm_f: RmsNorm,
+    lm_head: QMatMul,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        real_device: Device,
+    ) -> Result<Self> {
+        let vb_b = vb.pp("backbone");
+        let mapper = mapper.into_mapper(cfg.num_hidden_layers, &real_device)?;
+        // The original checkpoints name the embeddings `embedding`.
+        let embeddings_name = if vb_b.contains_tensor("embeddings.weight") {
+            "embeddings"
+        } else {
+            "embedding"
+        };
+        let embeddings = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_b.pp(embeddings_name), false),
+        )?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_b.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let vb = vb_l.pp(layer_idx);
+            let norm = RmsNorm::new(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                mapper.set_device(layer_idx, vb.pp("norm"), false),
+            )?;
+            let mixer = Mixer::new(
+                cfg,
+                mapper.set_device(layer_idx, vb.pp("mixer"), loading_isq),
+                mapper.set_device(layer_idx, vb.pp("mixer"), false),
+            )?;
+            layers.push(ResidualBlock { norm, mixer })
+        }
+        let norm_f = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_b.pp("norm_f"), false),
+        )?;
+        let lm_head = if cfg.tie_word_embeddings {
+            QMatMul::Tensor(embeddings.embeddings().clone())
+        } else {
+            let lm_head = linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                mapper.set_nm_device(vb.pp("lm_head"), loading_isq),
+            )?;
+            QMatMul::Tensor(lm_head.weight().clone())
+        };
+        Ok(Self {
+            embeddings,
+            layers,
+            norm_f,
+            lm_head,
+            device: real_device,
+            cache: Cache::new_recurrent(cfg.num_hidden_layers),
+            max_seq_len: cfg.max_seq_len,
+            mapper,
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(&xs, &mut cache[i])?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm_f)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, context_lens)
+    }
+    fn xlora_forward(
+        &mut self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.mixer.in_proj, Some(i)));
+            tensors.push((&mut layer.mixer.x_proj, Some(i)));
+            tensors.push((&mut layer.mixer.dt_proj, Some(i)));
+            tensors.push((&mut layer.mixer.out_proj, Some(i)));
+        }
+        (tensors, &*self.mapper)
+    }
+}
@@ -1,13 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
-use candle_nn::{linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
+use candle_nn::{linear_no_bias, Activation, VarBuilder};
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{CausalMasker, RmsNorm},
+    layers::{CausalMasker, RmsNorm, RopeScaling, RopeShift, RotaryEmbedding},
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -28,6 +28,7 @@ pub struct Config {
     pub(crate) rope_theta: f64,
     pub(crate) sliding_window: Option<usize>,
     pub(crate) use_flash_attn: bool,
+    pub(crate) rope_scaling: Option<RopeScaling>,
 }
 
 #[derive(Debug, Clone)]
@@ -161,43 +162,7 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v, attn_mask) = match kv_cache.clone() {
-            None => (k, v, attention_mask.cloned()),
-            Some((mut prev_k, mut prev_v)) => {
-                let mut mask = attention_mask.cloned();
-                if let Some(sliding_window) = self.sliding_window {
-                    let kv_seq_len = prev_k.dim(2)?;
-                    if kv_seq_len > sliding_window {
-                        prev_k = prev_k.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        prev_v = prev_v.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        if let Some(ref mut mask) = mask {
-                            let mask_len = mask.dim(1)?;
-                            *mask = mask.narrow(
-                                1,
-                                mask_len - (sliding_window - 1),
-                                sliding_window - 1,
-                            )?;
-                            *mask = Tensor::cat(
-                                &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                                D::Minus1,
-                            )?;
-                        }
-                    }
-                }
-                let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
-                let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
-                (k, v, mask)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, self.sliding_window)?;
 
         let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
         let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
@@ -213,7 +178,8 @@ impl Attention {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
             let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
 
-            let attn_weights = CausalMasker.apply_mask(&attn_mask, attn_weights, &self.neg_inf)?;
+            let attn_weights =
+                CausalMasker.apply_mask(&attention_mask.cloned(), attn_weights, &self.neg_inf)?;
             let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
             attn_weights.matmul(&v)?
         };
@@ -307,6 +273,7 @@ pub struct Model {
     pub cache: Cache,
     pub max_seq_len: usize,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_shift: Option<RopeShift>,
 }
 
 impl Model {
@@ -336,6 +303,7 @@ impl Model {
                 mapper.device_for(layer_idx, false).unwrap_or(&real_device),
                 is_gptx,
                 vb.dtype(),
+                cfg.rope_scaling.as_ref(),
             )?);
             let layer = DecoderLayer::new(
                 rotary_emb.clone(),
@@ -365,8 +333,23 @@ impl Model {
             sliding_window: cfg.sliding_window,
             device: real_device,
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |s| {
+                    s.max_position_embeddings(cfg.max_position_embeddings)
+                }),
             mapper,
+            // Attention sinks need an unscaled RoPE and do not apply to the rolling KV cache of sliding window models.
+            rope_shift: (cfg.sliding_window.is_none()
+                && !cfg
+                    .rope_scaling
+                    .as_ref()
+                    .is_some_and(RopeScaling::is_scaled))
+            .then_some(RopeShift {
+                base: cfg.rope_theta as f32,
+                is_gpt_neox: is_gptx,
+            }),
         })
     }
 
@@ -449,6 +432,9 @@ impl NormalModel for Model {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
+    fn rope_shift(&self) -> Option<RopeShift> {
+        self.rope_shift
+    }
     fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
         tensors.push((&mut self.lm_head, None));
@@ -3,7 +3,7 @@
 /// Mixtral Model
 /// https://github.com/huggingface/transformers/blob/main/src/transformers/models/mixtral/modeling_mixtral.py
 /// https://mistral.ai/news/mixtral-of-experts/
-use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
 use candle_nn::{linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -125,43 +125,7 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v, attn_mask) = match kv_cache.clone() {
-            None => (k, v, attention_mask.cloned()),
-            Some((mut prev_k, mut prev_v)) => {
-                let mut mask = attention_mask.cloned();
-                if let Some(sliding_window) = self.sliding_window {
-                    let kv_seq_len = prev_k.dim(2)?;
-                    if kv_seq_len > sliding_window {
-                        prev_k = prev_k.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        prev_v = prev_v.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        if let Some(ref mut mask) = mask {
-                            let mask_len = mask.dim(1)?;
-                            *mask = mask.narrow(
-                                1,
-                                mask_len - (sliding_window - 1),
-                                sliding_window - 1,
-                            )?;
-                            *mask = Tensor::cat(
-                                &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                                D::Minus1,
-                            )?;
-                        }
-                    }
-                }
-                let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
-                let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
-                (k, v, mask)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, self.sliding_window)?;
 
         let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
         let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
@@ -177,7 +141,8 @@ impl Attention {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
             let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
 
-            let attn_weights = CausalMasker.apply_mask(&attn_mask, attn_weights, &self.neg_inf)?;
+            let attn_weights =
+                CausalMasker.apply_mask(&attention_mask.cloned(), attn_weights, &self.neg_inf)?;
             let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
             attn_weights.matmul(&v)?
         };
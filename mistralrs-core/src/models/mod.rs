@@ -1,33 +1,62 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor};
+use candle_core::{
+    quantized::{ggml_file::qtensor_from_ggml, gguf_file, QTensor},
+    Device, Result, Tensor,
+};
 
 use crate::get_mut_arcmutex;
 
+pub(crate) mod bert;
+pub(crate) mod deepseek;
+pub(crate) mod falcon;
 pub(crate) mod gemma;
 pub(crate) mod llama;
+pub(crate) mod mamba;
 pub(crate) mod mistral;
 pub(crate) mod mixtral;
 pub(crate) mod phi2;
 pub(crate) mod phi3;
+pub(crate) mod quantized_falcon;
+pub(crate) mod quantized_gemma;
+pub(crate) mod quantized_gptneox;
 pub(crate) mod quantized_llama;
+pub(crate) mod quantized_mamba;
 pub(crate) mod quantized_phi2;
 pub(crate) mod quantized_phi3;
+pub(crate) mod quantized_qwen2;
+pub(crate) mod quantized_starcoder2;
 pub(crate) mod qwen2;
+pub(crate) mod qwen2_moe;
+pub(crate) mod starcoder2;
 
+/// The cache of each layer. What the two tensors hold depends on the [`CacheKind`], but both always
+/// have the batch as their first dimension.
 pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
 
+/// What the per layer entries of [`LayerCaches`] hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// Attention keys and values, shaped `(batch, kv_heads, seq_len, head_dim)`.
+    Kv,
+    /// The convolution and SSM states of a state space model. These have a fixed size no matter
+    /// how many tokens were processed, so they cannot be narrowed to a shorter prefix.
+    Recurrent,
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    kind: CacheKind,
 }
 
 impl Cache {
     pub(crate) fn new(len: usize, is_xlora: bool) -> Self {
         Self {
+            kind: CacheKind::Kv,
             cache: Arc::new(Mutex::new(vec![None; len])),
             xlora_cache: if is_xlora {
                 Some(Arc::new(Mutex::new(vec![None; len])))
@@ -43,6 +72,18 @@ impl Cache {
         }
     }
 
+    /// A cache for the recurrent state of a state space model.
+    pub(crate) fn new_recurrent(len: usize) -> Self {
+        Self {
+            kind: CacheKind::Recurrent,
+            ..Self::new(len, false)
+        }
+    }
+
+    pub(crate) fn kind(&self) -> CacheKind {
+        self.kind
+    }
+
     pub(crate) fn lock(&self) -> MutexGuard<'_, LayerCaches> {
         get_mut_arcmutex!(self.cache)
     }
@@ -69,6 +110,43 @@ impl Cache {
     pub(crate) fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
+
+    /// Update a layer's KV cache with the new `k` and `v` and return the full K and V to attend over.
+    ///
+    /// If `sliding_window` is set, only the last `sliding_window - 1` entries are kept so that the
+    /// next token sees exactly `sliding_window` keys. RoPE is applied before caching, so dropping
+    /// the oldest entries does not require recomputing positions.
+    ///
+    /// This bounds the memory of the cache, not the work per step: it is not a ring buffer, and once
+    /// the window is full each step copies the kept `sliding_window - 1` entries into a new
+    /// contiguous tensor, on top of the concatenation.
+    pub(crate) fn update_kv_cache(
+        cache: &mut Option<(Tensor, Tensor)>,
+        k: Tensor,
+        v: Tensor,
+        sliding_window: Option<usize>,
+    ) -> Result<(Tensor, Tensor)> {
+        let (k, v) = match &*cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => {
+                let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
+                let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
+                (k, v)
+            }
+        };
+        let kv_seq_len = k.dim(2)?;
+        *cache = match sliding_window {
+            Some(sliding_window) if kv_seq_len > sliding_window - 1 => {
+                let start = kv_seq_len - (sliding_window - 1);
+                Some((
+                    k.narrow(2, start, sliding_window - 1)?.contiguous()?,
+                    v.narrow(2, start, sliding_window - 1)?.contiguous()?,
+                ))
+            }
+            _ => Some((k.clone(), v.clone())),
+        };
+        Ok((k, v))
+    }
 }
 
 #[cfg(feature = "flash-attn")]
@@ -94,6 +172,45 @@ pub fn verify_sanity_gguf(arch: &str, expected_arch: &str) -> Result<()> {
     Ok(())
 }
 
+/// Read the weights of the experts of a GGUF MoE layer. Older files store one tensor per expert
+/// (`{prefix}.{name}.{i}.weight`), newer ones a single tensor of all experts
+/// (`{prefix}.{name}_exps.weight`). The experts of the latter are contiguous ranges of quantized
+/// blocks, so each is read as is, without dequantizing.
+pub fn gguf_expert_weights<R: std::io::Seek + std::io::Read>(
+    ct: &gguf_file::Content,
+    reader: &mut R,
+    prefix: &str,
+    name: &str,
+    n_expert: usize,
+    device: &Device,
+) -> Result<Vec<QTensor>> {
+    let merged = format!("{prefix}.{name}_exps.weight");
+    if !ct.tensor_infos.contains_key(&merged) {
+        return (0..n_expert)
+            .map(|i| ct.tensor(reader, &format!("{prefix}.{name}.{i}.weight"), device))
+            .collect();
+    }
+    let info = &ct.tensor_infos[&merged];
+    let (experts, rows, cols) = info.shape.dims3()?;
+    if experts != n_expert {
+        candle_core::bail!("`{merged}` has {experts} experts, expected {n_expert}.");
+    }
+    let dtype = info.ggml_dtype;
+    if (rows * cols) % dtype.block_size() != 0 {
+        candle_core::bail!("The experts of `{merged}` are not a whole number of {dtype:?} blocks.");
+    }
+    let expert_bytes = rows * cols / dtype.block_size() * dtype.type_size();
+    let mut raw = vec![0u8; expert_bytes];
+    (0..n_expert)
+        .map(|i| {
+            let start = ct.tensor_data_offset + info.offset + (i * expert_bytes) as u64;
+            reader.seek(std::io::SeekFrom::Start(start))?;
+            reader.read_exact(&mut raw)?;
+            qtensor_from_ggml(dtype, &raw, vec![rows, cols], device)
+        })
+        .collect()
+}
+
 pub fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     if n_rep == 1 {
         Ok(x)
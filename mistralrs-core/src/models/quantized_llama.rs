@@ -6,11 +6,11 @@ use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::{Embedding, Module, RotaryEmbedding};
 
 use crate::device_map::DeviceMapper;
-use crate::layers::{CausalMasker, QRmsNorm};
+use crate::layers::{CausalMasker, QRmsNorm, RopeShift};
 use crate::pipeline::extract_logits;
 use crate::DeviceMapMetadata;
 
-use super::{repeat_kv, verify_sanity_gguf, Cache};
+use super::{gguf_expert_weights, repeat_kv, verify_sanity_gguf, Cache};
 
 const MAX_SEQ_LEN: u32 = 4096;
 
@@ -192,6 +192,7 @@ pub struct ModelWeights {
     pub device: Device,
     pub cache: Cache,
     pub max_seq_len: usize,
+    pub rope_shift: Option<RopeShift>,
     mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
 }
 
@@ -254,6 +255,10 @@ impl ModelWeights {
             device: ct.device.clone(),
             cache: Cache::new(ct.hparams.n_layer as usize, false),
             max_seq_len: MAX_SEQ_LEN as usize, // Cannot determine from ggml.
+            rope_shift: (ct.hparams.n_rot as usize == head_dim).then_some(RopeShift {
+                base: 10000.,
+                is_gpt_neox: false,
+            }),
             mapper: None,
         })
     }
@@ -339,14 +344,13 @@ impl ModelWeights {
             } else {
                 let feed_forward_gate_inp =
                     ct.tensor(reader, &format!("{prefix}.ffn_gate_inp.weight"), device)?;
+                let gate = gguf_expert_weights(&ct, reader, &prefix, "ffn_gate", n_expert, device)?;
+                let down = gguf_expert_weights(&ct, reader, &prefix, "ffn_down", n_expert, device)?;
+                let up = gguf_expert_weights(&ct, reader, &prefix, "ffn_up", n_expert, device)?;
                 let mut experts = Vec::with_capacity(n_expert);
-                for i in 0..n_expert {
-                    let feed_forward_w1 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_gate.{i}.weight"), device)?;
-                    let feed_forward_w2 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_down.{i}.weight"), device)?;
-                    let feed_forward_w3 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_up.{i}.weight"), device)?;
+                for ((feed_forward_w1, feed_forward_w2), feed_forward_w3) in
+                    gate.into_iter().zip(down).zip(up)
+                {
                     experts.push(Mlp {
                         feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
                         feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
@@ -385,6 +389,10 @@ impl ModelWeights {
             device: device.clone(),
             cache: Cache::new(block_count, false),
             max_seq_len,
+            rope_shift: (rope_dim == head_dim).then_some(RopeShift {
+                base: rope_freq_base,
+                is_gpt_neox: false,
+            }),
             mapper: Some(mapper),
         })
     }
@@ -0,0 +1,130 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use candle_core::quantized::gguf_file;
+use candle_core::quantized::QMatMul;
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn::Embedding;
+
+use crate::device_map::DeviceMapper;
+use crate::layers::QRmsNorm;
+use crate::pipeline::extract_logits;
+use crate::DeviceMapMetadata;
+
+use super::mamba::Mixer;
+use super::{verify_sanity_gguf, Cache};
+
+const MAX_SEQ_LEN: u32 = 1 << 20;
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attn_norm: QRmsNorm,
+    mixer: Mixer,
+}
+
+#[derive(Debug)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: QRmsNorm,
+    output: QMatMul,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        verify_sanity_gguf(
+            md_get("general.architecture")?.to_string().unwrap(),
+            "mamba",
+        )?;
+
+        // Parameter extraction from metadata.
+        let block_count = md_get("mamba.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("mamba.embedding_length")?.to_u32()? as usize;
+        let conv_kernel = md_get("mamba.ssm.conv_kernel")?.to_u32()? as usize;
+        let inner_size = md_get("mamba.ssm.inner_size")?.to_u32()? as usize;
+        let state_size = md_get("mamba.ssm.state_size")?.to_u32()? as usize;
+        let time_step_rank = md_get("mamba.ssm.time_step_rank")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("mamba.attention.layer_norm_rms_epsilon")?.to_f32()?;
+        let max_seq_len = md_get("mamba.context_length")
+            .and_then(|m| m.to_u64())
+            .unwrap_or(MAX_SEQ_LEN as u64) as usize;
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_norm = QRmsNorm::new(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        // Mamba models usually tie the output projection to the token embeddings.
+        let output = if ct.tensor_infos.contains_key("output.weight") {
+            ct.tensor(reader, "output.weight", device)?
+        } else {
+            ct.tensor(reader, "token_embd.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        let mapper = mapper.into_mapper(block_count, device)?;
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let mut get = |name: &str| ct.tensor(reader, &format!("{prefix}.{name}"), device);
+            let attn_norm = QRmsNorm::new(get("attn_norm.weight")?, rms_norm_eps)?;
+            // The conversion to GGUF already computes `A = -exp(A_log)` and drops the channel
+            // dimension of the convolution kernel.
+            let mixer = Mixer {
+                in_proj: QMatMul::from_qtensor(get("ssm_in.weight")?)?,
+                in_proj_bias: None,
+                conv1d_weight: get("ssm_conv1d.weight")?.dequantize(device)?,
+                conv1d_bias: Some(get("ssm_conv1d.bias")?.dequantize(device)?),
+                x_proj: QMatMul::from_qtensor(get("ssm_x.weight")?)?,
+                dt_proj: QMatMul::from_qtensor(get("ssm_dt.weight")?)?,
+                dt_proj_bias: get("ssm_dt.bias")?.dequantize(device)?,
+                a: get("ssm_a")?.dequantize(device)?,
+                d: get("ssm_d")?.dequantize(device)?,
+                out_proj: QMatMul::from_qtensor(get("ssm_out.weight")?)?,
+                out_proj_bias: None,
+                intermediate_size: inner_size,
+                state_size,
+                conv_kernel,
+                time_step_rank,
+            };
+            layers.push(LayerWeights { attn_norm, mixer })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output: QMatMul::from_qtensor(output)?,
+            device: device.clone(),
+            cache: Cache::new_recurrent(block_count),
+            max_seq_len,
+            mapper,
+        })
+    }
+
+    pub fn forward(&mut self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer_in = self.mapper.map(layer_in, i)?;
+            let residual = &layer_in;
+            let x = layer.attn_norm.forward(&layer_in)?;
+            let x = layer.mixer.forward(&x, &mut cache[i])?;
+            layer_in = (x + residual)?;
+        }
+        let layer_in = layer_in.to_device(&self.device)?;
+        let x = self.output_norm.forward(&layer_in)?;
+        extract_logits(&self.output.forward(&x.contiguous()?)?, context_lens)
+    }
+}
@@ -101,39 +101,13 @@ impl LayerWeights {
         let q = self.apply_rotary_emb(&q, seqlen_offsets)?.contiguous()?;
         let k = self.apply_rotary_emb(&k, seqlen_offsets)?;
 
-        let (k, v, attn_mask) = match kv_cache.clone() {
-            None => (k, v, mask.cloned()),
-            Some((mut prev_k, mut prev_v)) => {
-                let mut mask = mask.cloned();
-                let kv_seq_len = prev_k.dim(2)?;
-                let sliding_window = self.sliding_window;
-                if kv_seq_len > sliding_window {
-                    prev_k =
-                        prev_k.narrow(2, kv_seq_len - (sliding_window - 1), sliding_window - 1)?;
-                    prev_v =
-                        prev_v.narrow(2, kv_seq_len - (sliding_window - 1), sliding_window - 1)?;
-                    if let Some(ref mut mask) = mask {
-                        let mask_len = mask.dim(1)?;
-                        *mask =
-                            mask.narrow(1, mask_len - (sliding_window - 1), sliding_window - 1)?;
-                        *mask = Tensor::cat(
-                            &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                            D::Minus1,
-                        )?;
-                    }
-                }
-                let k = Tensor::cat(&[prev_k, k], 2)?;
-                let v = Tensor::cat(&[prev_v, v], 2)?;
-                (k, v, mask)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, Some(self.sliding_window))?;
 
         let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
         let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
 
         let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-        let att = CausalMasker.apply_mask(&attn_mask, att, &self.neg_inf)?;
+        let att = CausalMasker.apply_mask(&mask.cloned(), att, &self.neg_inf)?;
         let att = candle_nn::ops::softmax_last_dim(&att)?;
         // Convert to contiguous as matmul doesn't support strided vs for now.
         let y = att.matmul(&v.contiguous()?)?;
@@ -0,0 +1,258 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use candle_core::quantized::gguf_file;
+use candle_core::quantized::QMatMul;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Module, RotaryEmbedding};
+use mistralrs_lora::layer::QLinear;
+
+use crate::device_map::DeviceMapper;
+use crate::layers::{CausalMasker, QRmsNorm, RopeShift};
+use crate::pipeline::extract_logits;
+use crate::DeviceMapMetadata;
+
+use super::{repeat_kv, verify_sanity_gguf, Cache};
+
+const MAX_SEQ_LEN: u32 = 32768;
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_w1: QMatMul,
+    feed_forward_w2: QMatMul,
+    feed_forward_w3: QMatMul,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.forward(xs)?;
+        let w3 = self.feed_forward_w3.forward(xs)?;
+        self.feed_forward_w2
+            .forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: QLinear,
+    attention_wk: QLinear,
+    attention_wv: QLinear,
+    attention_wo: QMatMul,
+    attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: RotaryEmbedding,
+    neg_inf: Tensor,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: &Option<Tensor>,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let mut q = q.reshape((b_sz * seq_len, self.n_head, self.head_dim))?;
+        let mut k = k.reshape((b_sz * seq_len, self.n_kv_head, self.head_dim))?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        self.rotary
+            .forward(start_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 {
+            q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+        }
+
+        let (k, v) = match &*kv_cache {
+            None => (k, v),
+            Some((k_cache, v_cache)) => {
+                let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
+                let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
+                (k, v)
+            }
+        };
+        *kv_cache = Some((k.clone(), v.clone()));
+
+        let k = repeat_kv(k, self.n_head / self.n_kv_head)?.contiguous()?;
+        let v = repeat_kv(v, self.n_head / self.n_kv_head)?.contiguous()?;
+
+        let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
+        let att = CausalMasker.apply_mask(mask, att, &self.neg_inf)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = self.attention_wo.forward(&y)?;
+        Ok(y)
+    }
+}
+
+#[derive(Debug)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: QMatMul,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    pub rope_shift: Option<RopeShift>,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        verify_sanity_gguf(
+            md_get("general.architecture")?.to_string().unwrap(),
+            "qwen2",
+        )?;
+
+        // Parameter extraction from metadata.
+        let head_count = md_get("qwen2.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("qwen2.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("qwen2.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("qwen2.embedding_length")?.to_u32()? as usize;
+        let head_dim = embedding_length / head_count;
+        let rms_norm_eps = md_get("qwen2.attention.layer_norm_rms_epsilon")?.to_f32()?;
+        let max_seq_len = md_get("qwen2.context_length")
+            .and_then(|m| m.to_u64())
+            .unwrap_or(MAX_SEQ_LEN as u64) as usize;
+        let rope_freq_base = md_get("qwen2.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(1_000_000f32);
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        // Smaller Qwen2 models tie the output projection to the token embeddings.
+        let output = if ct.tensor_infos.contains_key("output.weight") {
+            ct.tensor(reader, "output.weight", device)?
+        } else {
+            ct.tensor(reader, "token_embd.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        let mapper = mapper.into_mapper(block_count, device)?;
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = RotaryEmbedding::new_partial(
+                rope_freq_base,
+                head_dim,
+                head_dim,
+                max_seq_len,
+                device,
+                true,
+                DType::F32,
+            )?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+            let attention_wo =
+                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let feed_forward_w1 =
+                ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
+            let feed_forward_w2 =
+                ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
+            let feed_forward_w3 = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+            let attention_norm =
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
+            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+            layers.push(LayerWeights {
+                attention_wq: QLinear::new(&ct, reader, &format!("{prefix}.attn_q"), device)?,
+                attention_wk: QLinear::new(&ct, reader, &format!("{prefix}.attn_k"), device)?,
+                attention_wv: QLinear::new(&ct, reader, &format!("{prefix}.attn_v"), device)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm: QRmsNorm::new(attention_norm, rms_norm_eps)?,
+                mlp: Mlp {
+                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
+                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
+                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+                },
+                ffn_norm: QRmsNorm::new(ffn_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                neg_inf,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            device: device.clone(),
+            cache: Cache::new(block_count, false),
+            max_seq_len,
+            rope_shift: Some(RopeShift {
+                base: rope_freq_base,
+                is_gpt_neox: true,
+            }),
+            mapper,
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mask = CausalMasker.make_causal_mask(x, &self.cache)?;
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer_in = self.mapper.map(layer_in, i)?;
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(
+                &x,
+                &mask.as_ref().map(|m| m.to_device(x.device()).unwrap()),
+                start_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+            )?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let layer_in = layer_in.to_device(&self.device)?;
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(&self.output.forward(&x.contiguous()?)?, context_lens)
+    }
+}
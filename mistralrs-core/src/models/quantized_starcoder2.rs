@@ -0,0 +1,262 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use candle_core::quantized::gguf_file;
+use candle_core::quantized::{QMatMul, QTensor};
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm, RotaryEmbedding};
+use mistralrs_lora::layer::QLinear;
+
+use crate::device_map::DeviceMapper;
+use crate::layers::{CausalMasker, RopeShift};
+use crate::pipeline::extract_logits;
+use crate::DeviceMapMetadata;
+
+use super::{repeat_kv, verify_sanity_gguf, Cache};
+
+const MAX_SEQ_LEN: u32 = 16384;
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    ffn_up: QLinear,
+    ffn_down: QLinear,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.ffn_up)?.gelu()?.apply(&self.ffn_down)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attn_q: QLinear,
+    attn_k: QLinear,
+    attn_v: QLinear,
+    attn_output: QLinear,
+    attn_norm: LayerNorm,
+    mlp: Mlp,
+    ffn_norm: LayerNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: RotaryEmbedding,
+    neg_inf: Tensor,
+}
+
+impl LayerWeights {
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: &Option<Tensor>,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.attn_q.forward(x)?;
+        let k = self.attn_k.forward(x)?;
+        let v = self.attn_v.forward(x)?;
+
+        let mut q = q.reshape((b_sz * seq_len, self.n_head, self.head_dim))?;
+        let mut k = k.reshape((b_sz * seq_len, self.n_kv_head, self.head_dim))?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        self.rotary
+            .forward(start_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 {
+            q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+        }
+
+        let (k, v) = match &*kv_cache {
+            None => (k, v),
+            Some((k_cache, v_cache)) => {
+                let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
+                let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
+                (k, v)
+            }
+        };
+        *kv_cache = Some((k.clone(), v.clone()));
+
+        let k = repeat_kv(k, self.n_head / self.n_kv_head)?.contiguous()?;
+        let v = repeat_kv(v, self.n_head / self.n_kv_head)?.contiguous()?;
+
+        let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
+        let att = CausalMasker.apply_mask(mask, att, &self.neg_inf)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = self.attn_output.forward(&y)?;
+        Ok(y)
+    }
+}
+
+#[derive(Debug)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: LayerNorm,
+    output: QMatMul,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    pub rope_shift: Option<RopeShift>,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
+    let w = w.dequantize(&w.device())?;
+    let b = b.dequantize(&b.device())?;
+    let ln = LayerNorm::new(w, b, eps);
+    Ok(ln)
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        verify_sanity_gguf(
+            md_get("general.architecture")?.to_string().unwrap(),
+            "starcoder2",
+        )?;
+
+        // Parameter extraction from metadata.
+        let head_count = md_get("starcoder2.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("starcoder2.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("starcoder2.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("starcoder2.embedding_length")?.to_u32()? as usize;
+        let ln_eps = md_get("starcoder2.attention.layer_norm_epsilon")?.to_f32()? as f64;
+        let max_seq_len = md_get("starcoder2.context_length")
+            .and_then(|m| m.to_u64())
+            .unwrap_or(MAX_SEQ_LEN as u64) as usize;
+        let rope_freq_base = md_get("starcoder2.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let head_dim = embedding_length / head_count;
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_norm = layer_norm(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            ct.tensor(reader, "output_norm.bias", device)?,
+            ln_eps,
+        )?;
+        // Smaller StarCoder2 models tie the output projection to the token embeddings.
+        let output = if ct.tensor_infos.contains_key("output.weight") {
+            ct.tensor(reader, "output.weight", device)?
+        } else {
+            ct.tensor(reader, "token_embd.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        let mapper = mapper.into_mapper(block_count, device)?;
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = RotaryEmbedding::new_partial(
+                rope_freq_base,
+                head_dim,
+                head_dim,
+                max_seq_len,
+                device,
+                true,
+                DType::F32,
+            )?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+            let attn_norm = layer_norm(
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?,
+                ct.tensor(reader, &format!("{prefix}.attn_norm.bias"), device)?,
+                ln_eps,
+            )?;
+            let ffn_norm = layer_norm(
+                ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?,
+                ct.tensor(reader, &format!("{prefix}.ffn_norm.bias"), device)?,
+                ln_eps,
+            )?;
+            let mlp = Mlp {
+                ffn_up: QLinear::new(&ct, reader, &format!("{prefix}.ffn_up"), device)?,
+                ffn_down: QLinear::new(&ct, reader, &format!("{prefix}.ffn_down"), device)?,
+            };
+            layers.push(LayerWeights {
+                attn_q: QLinear::new(&ct, reader, &format!("{prefix}.attn_q"), device)?,
+                attn_k: QLinear::new(&ct, reader, &format!("{prefix}.attn_k"), device)?,
+                attn_v: QLinear::new(&ct, reader, &format!("{prefix}.attn_v"), device)?,
+                attn_output: QLinear::new(&ct, reader, &format!("{prefix}.attn_output"), device)?,
+                attn_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                neg_inf,
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output: QMatMul::from_qtensor(output)?,
+            device: device.clone(),
+            cache: Cache::new(block_count, false),
+            max_seq_len,
+            rope_shift: Some(RopeShift {
+                base: rope_freq_base,
+                is_gpt_neox: true,
+            }),
+            mapper,
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mask = CausalMasker.make_causal_mask(x, &self.cache)?;
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let mut cache = self.cache.lock();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer_in = self.mapper.map(layer_in, i)?;
+            let x = layer_in;
+            let residual = &x;
+            let x = x.apply(&layer.attn_norm)?;
+            let attn = layer.forward_attn(
+                &x,
+                &mask.as_ref().map(|m| m.to_device(x.device()).unwrap()),
+                start_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+            )?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = x.apply(&layer.ffn_norm)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let layer_in = layer_in.to_device(&self.device)?;
+        let x = layer_in.apply(&self.output_norm)?;
+        extract_logits(&self.output.forward(&x.contiguous()?)?, context_lens)
+    }
+}
@@ -1,13 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
-use candle_nn::{linear, linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
+use candle_nn::{linear, linear_no_bias, Activation, VarBuilder};
 use mistralrs_lora::layer::QLinear;
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{CausalMasker, RmsNorm},
+    layers::{CausalMasker, RmsNorm, RopeScaling, RopeShift, RotaryEmbedding},
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -31,6 +31,7 @@ pub struct Config {
     pub use_sliding_window: bool,
     pub hidden_act: Activation,
     pub use_flash_attn: bool,
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 #[derive(Debug, Clone)]
@@ -87,6 +88,7 @@ struct Attention {
     head_dim: usize,
     rotary_emb: Arc<RotaryEmbedding>,
     use_flash_attn: bool,
+    sliding_window: Option<usize>,
     neg_inf: Tensor,
 }
 
@@ -112,6 +114,7 @@ impl Attention {
             head_dim,
             rotary_emb,
             use_flash_attn: cfg.use_flash_attn,
+            sliding_window: cfg.use_sliding_window.then_some(cfg.sliding_window),
             neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
         })
     }
@@ -160,15 +163,7 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v) = match &*kv_cache {
-            None => (k, v),
-            Some((prev_k, prev_v)) => {
-                let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
-                let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
-                (k, v)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, self.sliding_window)?;
 
         let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
         let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
@@ -274,11 +269,12 @@ pub struct Model {
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: QMatMul,
-    sliding_window: usize,
+    sliding_window: Option<usize>,
     pub device: Device,
     pub cache: Cache,
     pub max_seq_len: usize,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_shift: Option<RopeShift>,
 }
 
 impl Model {
@@ -308,6 +304,7 @@ impl Model {
                 mapper.device_for(layer_idx, false).unwrap_or(&real_device),
                 is_gptx,
                 vb.dtype(),
+                cfg.rope_scaling.as_ref(),
             )?);
             let layer = DecoderLayer::new(
                 rotary_emb.clone(),
@@ -334,11 +331,26 @@ impl Model {
             layers,
             norm,
             lm_head: QMatMul::Tensor(lm_head.weight().clone()),
-            sliding_window: cfg.sliding_window,
+            sliding_window: cfg.use_sliding_window.then_some(cfg.sliding_window),
             device: real_device,
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |s| {
+                    s.max_position_embeddings(cfg.max_position_embeddings)
+                }),
             mapper,
+            // Attention sinks need an unscaled RoPE and do not apply to the rolling KV cache of sliding window models.
+            rope_shift: (!cfg.use_sliding_window
+                && !cfg
+                    .rope_scaling
+                    .as_ref()
+                    .is_some_and(RopeScaling::is_scaled))
+            .then_some(RopeShift {
+                base: cfg.rope_theta as f32,
+                is_gpt_neox: is_gptx,
+            }),
         })
     }
 
@@ -352,7 +364,7 @@ impl Model {
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window(
             input_ids,
             &self.cache,
-            Some(self.sliding_window),
+            self.sliding_window,
         )?;
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
@@ -421,6 +433,9 @@ impl NormalModel for Model {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
+    fn rope_shift(&self) -> Option<RopeShift> {
+        self.rope_shift
+    }
     fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
         tensors.push((&mut self.lm_head, None));
@@ -59,6 +59,36 @@ pub struct ChatTemplate {
 }
 
 impl ChatTemplate {
+    /// A chat template for a model without a `tokenizer_config.json`, such as a GGUF model whose
+    /// tokenizer is built from its metadata.
+    pub fn new(
+        chat_template: Option<String>,
+        bos_token: Option<String>,
+        eos_token: String,
+        unk_token: Option<String>,
+    ) -> Self {
+        Self {
+            add_bos_token: None,
+            add_eos_token: None,
+            added_tokens_decoder: None,
+            additional_special_tokens: None,
+            bos_token: bos_token.map(|tok| Bos(Either::Left(tok))),
+            chat_template,
+            clean_up_tokenization_spaces: None,
+            device_map: None,
+            eos_token: Either::Left(eos_token),
+            legacy: None,
+            model_max_length: f64::MAX,
+            pad_token: None,
+            sp_model_kwargs: None,
+            spaces_between_special_tokens: None,
+            tokenizer_class: "PreTrainedTokenizerFast".to_string(),
+            truncation_size: None,
+            unk_token: unk_token.map(|tok| Unk(Either::Left(tok))),
+            use_default_system_prompt: None,
+        }
+    }
+
     pub fn has_chat_template(&self) -> bool {
         self.chat_template.is_some()
     }
@@ -85,6 +115,31 @@ impl ChatTemplate {
     }
 }
 
+/// A chat template specified by the user, for a model without a `tokenizer_config.json`.
+#[derive(Debug, Deserialize)]
+pub struct SpecifiedTemplate {
+    pub chat_template: String,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+impl SpecifiedTemplate {
+    /// Load a `.json` file with a `chat_template` and optionally the BOS and EOS tokens, or else
+    /// take `template` as the JINJA template itself.
+    pub fn load(template: &str) -> Result<Self> {
+        if template.ends_with(".json") {
+            info!("Loading specified loading chat template file at `{template}`.");
+            Ok(serde_json::from_str(&std::fs::read_to_string(template)?)?)
+        } else {
+            Ok(Self {
+                chat_template: template.to_string(),
+                bos_token: None,
+                eos_token: None,
+            })
+        }
+    }
+}
+
 pub fn calculate_eos_tokens(
     chat_template: &ChatTemplate,
     gen_conf: Option<GenerationConfig>,
@@ -0,0 +1,210 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use candle_core::{DType, Device};
+use candle_nn::{VarBuilder, VarMap};
+use serde_json::{json, Value};
+use tokenizers::{models::bpe::BPE, pre_tokenizers::byte_level::ByteLevel, AddedToken, Tokenizer};
+use tracing::info;
+
+use super::chat_template::{ChatTemplate, SpecifiedTemplate};
+use super::NormalLoaderType;
+
+const UNK_TOKEN: &str = "<unk>";
+const BOS_TOKEN: &str = "<s>";
+const EOS_TOKEN: &str = "</s>";
+
+const DUMMY_CHAT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{{ '<|' + message['role'] + '|>\n' + message['content'] + eos_token + '\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>\n' }}{% endif %}";
+
+#[derive(Clone, Copy, Debug)]
+/// The weights a model is initialized with when it is loaded without a checkpoint.
+pub enum DummyWeights {
+    /// All weights are zero.
+    Zeros,
+    /// Weights are drawn from the initialization of each layer.
+    Random,
+}
+
+impl FromStr for DummyWeights {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zeros" => Ok(Self::Zeros),
+            "random" => Ok(Self::Random),
+            a => Err(format!(
+                "Unknown dummy weights `{a}`, expected `zeros` or `random`"
+            )),
+        }
+    }
+}
+
+impl DummyWeights {
+    /// A VarBuilder which creates the weights of the model as they are requested.
+    pub(crate) fn var_builder(&self, dtype: DType, device: &Device) -> VarBuilder<'static> {
+        match self {
+            Self::Zeros => VarBuilder::zeros(dtype, device),
+            Self::Random => VarBuilder::from_varmap(&VarMap::new(), dtype, device),
+        }
+    }
+}
+
+/// A built-in model config: `tiny` is meant for tests and `small` for benchmarks. The fields of
+/// every architecture are set, each architecture only reads its own.
+fn preset_config(preset: &str, arch: &NormalLoaderType) -> Option<Value> {
+    let (hidden_size, intermediate_size, num_hidden_layers, num_attention_heads, vocab_size) =
+        match preset {
+            "tiny" => (64, 128, 2, 4, 512),
+            "small" => (512, 1408, 8, 8, 32000),
+            _ => return None,
+        };
+    let mut config = json!({
+        "vocab_size": vocab_size,
+        "hidden_size": hidden_size,
+        "intermediate_size": intermediate_size,
+        "num_hidden_layers": num_hidden_layers,
+        "num_attention_heads": num_attention_heads,
+        "num_key_value_heads": num_attention_heads / 2,
+        "head_dim": hidden_size / num_attention_heads,
+        "hidden_act": "silu",
+        "max_position_embeddings": 4096,
+        "original_max_position_embeddings": 4096,
+        "rms_norm_eps": 1e-5,
+        "layer_norm_eps": 1e-5,
+        "layer_norm_epsilon": 1e-5,
+        "norm_epsilon": 1e-5,
+        "rope_theta": 10000.0,
+        "sliding_window": 4096,
+        "use_sliding_window": false,
+        "max_window_layers": num_hidden_layers,
+        "tie_word_embeddings": false,
+        "attention_bias": false,
+        "partial_rotary_factor": 0.5,
+        "qk_layernorm": false,
+        "num_experts_per_tok": 2,
+        "num_local_experts": 4,
+        "num_experts": 4,
+        "n_routed_experts": 4,
+        "n_shared_experts": 1,
+        "first_k_dense_replace": 1,
+        "moe_intermediate_size": intermediate_size / 2,
+        "shared_expert_intermediate_size": intermediate_size,
+        "state_size": 16,
+        "conv_kernel": 4,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+    });
+    match arch {
+        NormalLoaderType::Gemma => {
+            config["hidden_act"] = Value::Null;
+            config["hidden_activation"] = json!("gelu_pytorch_tanh");
+        }
+        NormalLoaderType::Phi2 => config["hidden_act"] = json!("gelu_new"),
+        NormalLoaderType::Starcoder2 => config["hidden_act"] = json!("gelu_pytorch_tanh"),
+        _ => {}
+    }
+    Some(config)
+}
+
+/// The config of a model loaded with dummy weights: a built-in preset (`tiny` or `small`), a
+/// `config.json` file, or a directory which contains one.
+pub(crate) fn dummy_config(config: &str, arch: &NormalLoaderType) -> Result<String> {
+    if let Some(preset) = preset_config(config, arch) {
+        info!("Using the `{config}` preset config.");
+        return Ok(serde_json::to_string(&preset)?);
+    }
+    let path = Path::new(config);
+    let path = if path.is_dir() {
+        path.join("config.json")
+    } else {
+        path.to_path_buf()
+    };
+    if !path.is_file() {
+        anyhow::bail!(
+            "`{config}` is neither a config preset (`tiny` or `small`) nor a `config.json` file."
+        );
+    }
+    Ok(std::fs::read_to_string(&path)?)
+}
+
+/// A byte level tokenizer for a model without one: every byte is a token, and the vocabulary is
+/// padded to `vocab_size` so that it covers every logit of the model.
+pub(crate) fn dummy_tokenizer(vocab_size: usize) -> Result<Tokenizer> {
+    let mut alphabet = ByteLevel::alphabet().into_iter().collect::<Vec<_>>();
+    alphabet.sort_unstable();
+    let mut tokens = vec![
+        UNK_TOKEN.to_string(),
+        BOS_TOKEN.to_string(),
+        EOS_TOKEN.to_string(),
+    ];
+    tokens.extend(alphabet.into_iter().map(|c| c.to_string()));
+    if tokens.len() > vocab_size {
+        anyhow::bail!(
+            "The synthetic tokenizer needs a `vocab_size` of at least {}, but it is {vocab_size}.",
+            tokens.len()
+        );
+    }
+    let n_bytes = tokens.len();
+    tokens.extend((n_bytes..vocab_size).map(|id| format!("<extra_{id}>")));
+
+    let vocab = tokens.into_iter().zip(0u32..).collect();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, vec![])
+        .unk_token(UNK_TOKEN.to_string())
+        .build()
+        .map_err(anyhow::Error::msg)?;
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+    tokenizer.with_decoder(ByteLevel::default());
+    tokenizer.add_special_tokens(
+        &[UNK_TOKEN, BOS_TOKEN, EOS_TOKEN].map(|tok| AddedToken::from(tok.to_string(), true)),
+    );
+    info!("Using a synthetic byte level tokenizer with {vocab_size} tokens.");
+    Ok(tokenizer)
+}
+
+/// The chat template of a model loaded with dummy weights. A given chat template is loaded like
+/// any other, as a `.json` file with a `chat_template` key or as a literal template. The BOS and
+/// EOS tokens come from that file, or else from the `bos_token_id` and `eos_token_id` of the config
+/// as named by the tokenizer. The synthetic tokenizer always has its own special tokens.
+pub(crate) fn dummy_chat_template(
+    chat_template: Option<&str>,
+    config: &Value,
+    tokenizer: &Tokenizer,
+    synthetic: bool,
+) -> Result<ChatTemplate> {
+    let (template, bos, eos) = match chat_template.map(SpecifiedTemplate::load).transpose()? {
+        Some(specified) => (
+            specified.chat_template,
+            specified.bos_token,
+            specified.eos_token,
+        ),
+        None => (DUMMY_CHAT_TEMPLATE.to_string(), None, None),
+    };
+    let (bos, eos, unk) = if synthetic {
+        (
+            bos.or(Some(BOS_TOKEN.to_string())),
+            eos.unwrap_or(EOS_TOKEN.to_string()),
+            Some(UNK_TOKEN.to_string()),
+        )
+    } else {
+        let config_token = |key: &str| {
+            let id = match &config[key] {
+                Value::Array(ids) => ids.first()?,
+                id => id,
+            };
+            tokenizer.id_to_token(u32::try_from(id.as_u64()?).ok()?)
+        };
+        let bos = bos.or_else(|| config_token("bos_token_id"));
+        let Some(eos) = eos.or_else(|| config_token("eos_token_id")) else {
+            anyhow::bail!(
+                "The EOS token is unknown, give it as `eos_token` in a `.json` chat template or as `eos_token_id` in the config."
+            );
+        };
+        (bos, eos, None)
+    };
+    if tokenizer.token_to_id(&eos).is_none() {
+        anyhow::bail!("The tokenizer does not contain the EOS token `{eos}`.");
+    }
+    Ok(ChatTemplate::new(Some(template), bos, eos, unk))
+}
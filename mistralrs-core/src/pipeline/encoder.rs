@@ -0,0 +1,520 @@
+use std::{path::Path, path::PathBuf, str::FromStr};
+
+use anyhow::Result;
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::Activation;
+use serde::Deserialize;
+use serde_json::Value;
+use tokenizers::{EncodeInput, Encoding, Tokenizer, TruncationParams};
+use tracing::info;
+
+use super::offline::{is_offline, validate_offline_files, OfflineModelFiles};
+use super::{get_model_paths, ModelRepo, TokenSource};
+use crate::models::bert::{self, EncoderFamily};
+use crate::utils::varbuilder_utils::{check_mmaped_safetensors, from_mmaped_safetensors};
+use crate::{api_dir_list, api_get_file};
+
+/// The pooling config of a sentence-transformers model.
+const SENTENCE_TRANSFORMERS_POOLING: &str = "1_Pooling/config.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How the hidden states of the tokens of an input are pooled into its embedding.
+pub enum Pooling {
+    /// The hidden state of the first (`[CLS]` or `<s>`) token.
+    #[default]
+    Cls,
+    /// The mean of the hidden states of all tokens.
+    Mean,
+    /// The hidden state of the last token.
+    LastToken,
+}
+
+impl FromStr for Pooling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cls" => Ok(Self::Cls),
+            "mean" => Ok(Self::Mean),
+            "last" => Ok(Self::LastToken),
+            p => Err(format!(
+                "Unknown pooling `{p}`, expected `cls`, `mean` or `last`."
+            )),
+        }
+    }
+}
+
+impl Pooling {
+    /// The pooling of a sentence-transformers model, from its pooling config.
+    fn from_sentence_transformers(config: &str) -> Result<Option<Self>> {
+        let config: Value = serde_json::from_str(config)?;
+        let enabled = |mode: &str| config[mode].as_bool().unwrap_or(false);
+        Ok(if enabled("pooling_mode_cls_token") {
+            Some(Self::Cls)
+        } else if enabled("pooling_mode_mean_tokens") {
+            Some(Self::Mean)
+        } else if enabled("pooling_mode_lasttoken") {
+            Some(Self::LastToken)
+        } else {
+            None
+        })
+    }
+
+    /// Pool the hidden states `(batch, seq_len, hidden_size)` given the attention mask
+    /// `(batch, seq_len)` and the number of tokens of each input.
+    fn pool(&self, hidden_states: &Tensor, mask: &Tensor, lens: &[usize]) -> Result<Tensor> {
+        Ok(match self {
+            Self::Cls => hidden_states.i((.., 0))?,
+            Self::Mean => {
+                let mask = mask.to_dtype(hidden_states.dtype())?;
+                hidden_states
+                    .broadcast_mul(&mask.unsqueeze(2)?)?
+                    .sum(1)?
+                    .broadcast_div(&mask.sum_keepdim(1)?)?
+            }
+            Self::LastToken => {
+                let last = lens
+                    .iter()
+                    .enumerate()
+                    .map(|(i, len)| hidden_states.i((i, len - 1)))
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                Tensor::stack(&last, 0)?
+            }
+        })
+    }
+}
+
+fn default_type_vocab_size() -> usize {
+    2
+}
+
+fn default_encoder_act() -> Activation {
+    Activation::Gelu
+}
+
+#[derive(Deserialize)]
+struct EncoderBasicConfig {
+    model_type: String,
+    #[serde(default)]
+    architectures: Vec<String>,
+    vocab_size: usize,
+    hidden_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    intermediate_size: usize,
+    #[serde(default = "default_encoder_act")]
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    #[serde(default = "default_type_vocab_size")]
+    type_vocab_size: usize,
+    layer_norm_eps: f64,
+    #[serde(default)]
+    pad_token_id: usize,
+    id2label: Option<serde_json::Map<String, Value>>,
+    num_labels: Option<usize>,
+}
+
+impl EncoderBasicConfig {
+    fn deserialize(slice: &str) -> Result<bert::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        let family = match basic_config.model_type.as_str() {
+            "bert" => EncoderFamily::Bert,
+            "roberta" | "xlm-roberta" => EncoderFamily::XlmRoberta,
+            other => anyhow::bail!(
+                "Unsupported encoder model type `{other}`, expected `bert`, `roberta` or `xlm-roberta`."
+            ),
+        };
+        let is_cross_encoder = basic_config
+            .architectures
+            .iter()
+            .any(|arch| arch.ends_with("ForSequenceClassification"));
+        let num_labels = is_cross_encoder.then(|| {
+            basic_config
+                .num_labels
+                .or(basic_config.id2label.as_ref().map(|labels| labels.len()))
+                .unwrap_or(1)
+        });
+        Ok(bert::Config {
+            family,
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            intermediate_size: basic_config.intermediate_size,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            type_vocab_size: basic_config.type_vocab_size,
+            layer_norm_eps: basic_config.layer_norm_eps,
+            pad_token_id: basic_config.pad_token_id,
+            num_labels,
+        })
+    }
+}
+
+/// A builder for an [`EncoderLoader`].
+pub struct EncoderLoaderBuilder {
+    model_id: String,
+    tokenizer_json: Option<String>,
+    pooling: Option<Pooling>,
+    normalize: bool,
+    max_batch_size: usize,
+}
+
+impl EncoderLoaderBuilder {
+    pub fn new(model_id: String, tokenizer_json: Option<String>) -> Self {
+        Self {
+            model_id,
+            tokenizer_json,
+            pooling: None,
+            normalize: true,
+            max_batch_size: 32,
+        }
+    }
+
+    /// Pool embeddings this way instead of as the sentence-transformers config of the model says,
+    /// or with [`Pooling::Cls`] if it has none.
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    /// Whether embeddings are normalized to unit length. Defaults to `true`.
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// The most inputs to run in one forward pass. Defaults to 32.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+
+    pub fn build(self) -> EncoderLoader {
+        EncoderLoader {
+            model_id: self.model_id,
+            tokenizer_json: self.tokenizer_json,
+            pooling: self.pooling,
+            normalize: self.normalize,
+            max_batch_size: self.max_batch_size,
+        }
+    }
+}
+
+/// Loads an encoder-only model (BERT, RoBERTa or XLM-RoBERTa) from safetensors. A model whose
+/// architecture is a `...ForSequenceClassification` is loaded as a cross-encoder for reranking,
+/// any other as an embedding model.
+pub struct EncoderLoader {
+    model_id: String,
+    tokenizer_json: Option<String>,
+    pooling: Option<Pooling>,
+    normalize: bool,
+    max_batch_size: usize,
+}
+
+impl EncoderLoader {
+    pub fn load_model(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: Option<DType>,
+        device: &Device,
+        silent: bool,
+    ) -> Result<EncoderPipeline> {
+        let revision = revision.unwrap_or("main".to_string());
+        if is_offline() {
+            validate_offline_files(OfflineModelFiles {
+                model_id: Some(self.model_id.as_str()),
+                revision: &revision,
+                tokenizer_json: self.tokenizer_json.as_deref(),
+                quantized_model_id: None,
+                quantized_filenames: None,
+                xlora_model_id: None,
+                xlora_order: None,
+            })?;
+        }
+        let api = ModelRepo::new(&self.model_id, &revision, &token_source, silent)?;
+        let model_id = Path::new(&self.model_id);
+
+        let tokenizer_filename = match self.tokenizer_json {
+            Some(ref p) => {
+                info!("Using tokenizer.json at `{p}`");
+                PathBuf::from(p)
+            }
+            None => api_get_file!(api, "tokenizer.json", model_id),
+        };
+        let config = std::fs::read_to_string(api_get_file!(api, "config.json", model_id))?;
+        let filenames = get_model_paths(revision, &token_source, &None, &None, &api, model_id)?;
+        let pooling = match self.pooling {
+            Some(pooling) => pooling,
+            None if api_dir_list!(api, model_id).any(|f| f == SENTENCE_TRANSFORMERS_POOLING) => {
+                let pooling_config = std::fs::read_to_string(api_get_file!(
+                    api,
+                    SENTENCE_TRANSFORMERS_POOLING,
+                    model_id
+                ))?;
+                Pooling::from_sentence_transformers(&pooling_config)?.unwrap_or_default()
+            }
+            None => Pooling::default(),
+        };
+
+        let cfg = EncoderBasicConfig::deserialize(&config)?;
+        info!("Encoder config: {cfg:?}");
+        let dtype = dtype.unwrap_or(if device.is_cuda() {
+            DType::BF16
+        } else {
+            DType::F32
+        });
+        check_mmaped_safetensors(&filenames, &[], dtype, |vb| {
+            bert::Model::new(&cfg, vb)
+                .map(|_| ())
+                .map_err(anyhow::Error::from)
+        })?;
+        let vb = from_mmaped_safetensors(filenames, Vec::new(), dtype, device, silent)?;
+        let model = bert::Model::new(&cfg, vb)?;
+        if model.is_cross_encoder() {
+            info!("Loaded a cross-encoder for reranking.");
+        } else {
+            info!("Loaded an embedding model with {pooling:?} pooling.");
+        }
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+        // Batches are padded when they are built.
+        tokenizer.with_padding(None);
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: cfg.max_input_len(),
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+
+        Ok(EncoderPipeline {
+            model,
+            tokenizer,
+            model_id: self.model_id.clone(),
+            pooling,
+            normalize: self.normalize,
+            max_batch_size: self.max_batch_size,
+            pad_token_id: cfg.pad_token_id as u32,
+        })
+    }
+
+    pub fn get_id(&self) -> String {
+        self.model_id.clone()
+    }
+}
+
+/// An encoder-only model, which embeds inputs or scores query and document pairs. There is no KV
+/// cache: each batch of inputs is padded to its longest input and processed in one forward pass.
+pub struct EncoderPipeline {
+    model: bert::Model,
+    tokenizer: Tokenizer,
+    model_id: String,
+    pooling: Pooling,
+    normalize: bool,
+    max_batch_size: usize,
+    pad_token_id: u32,
+}
+
+impl EncoderPipeline {
+    pub fn name(&self) -> String {
+        self.model_id.clone()
+    }
+
+    /// Whether this is a cross-encoder, which reranks, instead of an embedding model.
+    pub fn is_cross_encoder(&self) -> bool {
+        self.model.is_cross_encoder()
+    }
+
+    /// Embed each input. Returns the embedding and the number of tokens of each input.
+    pub fn embed(&self, inputs: Vec<String>) -> Result<Vec<(Vec<f32>, usize)>> {
+        if self.is_cross_encoder() {
+            anyhow::bail!("The encoder is a cross-encoder, which cannot embed inputs.");
+        }
+        self.run_batches(inputs, |hidden_states, mask, lens| {
+            let embeddings = self
+                .pooling
+                .pool(hidden_states, mask, lens)?
+                .to_dtype(DType::F32)?;
+            let embeddings = if self.normalize {
+                embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?
+            } else {
+                embeddings
+            };
+            Ok(embeddings.to_vec2()?)
+        })
+    }
+
+    /// Score the relevance of each `(query, document)` pair. A model with one label is scored with
+    /// the sigmoid of its logit, and a model with several labels with the probability of the last
+    /// one. Returns the score and the number of tokens of each pair.
+    pub fn rerank(&self, pairs: Vec<(String, String)>) -> Result<Vec<(f32, usize)>> {
+        if !self.is_cross_encoder() {
+            anyhow::bail!("The encoder is not a cross-encoder, so it cannot rerank documents.");
+        }
+        let scores = self.run_batches(pairs, |hidden_states, _, _| {
+            let logits = self.model.classify(hidden_states)?.to_dtype(DType::F32)?;
+            let scores = match logits.dim(1)? {
+                1 => candle_nn::ops::sigmoid(&logits)?,
+                n => candle_nn::ops::softmax_last_dim(&logits)?.narrow(1, n - 1, 1)?,
+            };
+            Ok(scores.to_vec2()?)
+        })?;
+        Ok(scores
+            .into_iter()
+            .map(|(score, toks)| (score[0], toks))
+            .collect())
+    }
+
+    /// Tokenize the inputs and run them in batches of similar lengths, so that little padding is
+    /// needed. `f` maps the hidden states, attention mask and lengths of a batch to one output per
+    /// input, and the outputs are returned in the order of the inputs along with their lengths.
+    fn run_batches<'s, I, F>(&self, inputs: Vec<I>, f: F) -> Result<Vec<(Vec<f32>, usize)>>
+    where
+        I: Into<EncodeInput<'s>> + Send,
+        F: Fn(&Tensor, &Tensor, &[usize]) -> Result<Vec<Vec<f32>>>,
+    {
+        let encodings = self
+            .tokenizer
+            .encode_batch(inputs, true)
+            .map_err(anyhow::Error::msg)?;
+        let mut order = (0..encodings.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| encodings[*i].len());
+
+        let mut outputs = vec![None; encodings.len()];
+        for batch in order.chunks(self.max_batch_size) {
+            let batch_encodings = batch.iter().map(|i| &encodings[*i]).collect::<Vec<_>>();
+            let lens = batch_encodings
+                .iter()
+                .map(|encoding| encoding.len())
+                .collect::<Vec<_>>();
+            let (input_ids, token_type_ids, mask) =
+                pad_batch(&batch_encodings, self.pad_token_id, &self.model.device)?;
+            let hidden_states = self.model.forward(&input_ids, &token_type_ids, &mask)?;
+            let batch_outputs = f(&hidden_states, &mask, &lens)?;
+            for ((i, output), len) in batch.iter().zip(batch_outputs).zip(lens) {
+                outputs[*i] = Some((output, len));
+            }
+        }
+        Ok(outputs
+            .into_iter()
+            .map(|output| output.expect("Every input is in a batch."))
+            .collect())
+    }
+}
+
+/// The input ids, token type ids and attention mask of a batch, padded on the right to its longest
+/// input.
+fn pad_batch(
+    encodings: &[&Encoding],
+    pad_token_id: u32,
+    device: &Device,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let max_len = encodings
+        .iter()
+        .map(|encoding| encoding.len())
+        .max()
+        .unwrap_or(0);
+    let (mut input_ids, mut token_type_ids, mut mask) = (Vec::new(), Vec::new(), Vec::new());
+    for encoding in encodings {
+        let pad = max_len - encoding.len();
+        input_ids.extend(encoding.get_ids().iter().copied());
+        input_ids.extend(std::iter::repeat(pad_token_id).take(pad));
+        token_type_ids.extend(encoding.get_type_ids().iter().copied());
+        token_type_ids.extend(std::iter::repeat(0).take(pad));
+        mask.extend(std::iter::repeat(1u32).take(encoding.len()));
+        mask.extend(std::iter::repeat(0u32).take(pad));
+    }
+    let shape = (encodings.len(), max_len);
+    Ok((
+        Tensor::from_vec(input_ids, shape, device)?,
+        Tensor::from_vec(token_type_ids, shape, device)?,
+        Tensor::from_vec(mask, shape, device)?,
+    ))
+}
+
+mod tests {
+    #[allow(dead_code)]
+    fn encoding(ids: &[u32], type_ids: &[u32]) -> tokenizers::Encoding {
+        let n = ids.len();
+        tokenizers::Encoding::new(
+            ids.to_vec(),
+            type_ids.to_vec(),
+            vec![String::new(); n],
+            vec![None; n],
+            vec![(0, 0); n],
+            vec![0; n],
+            vec![1; n],
+            Vec::new(),
+            std::collections::HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn test_pooling() {
+        use super::Pooling;
+        use candle_core::{Device, Tensor};
+
+        let dev = Device::Cpu;
+        // Two inputs of 2 and 3 tokens, the first padded by one token.
+        let hidden_states = Tensor::new(
+            &[
+                [[1f32, 2.], [3., 4.], [100., 100.]],
+                [[5., 6.], [7., 8.], [9., 10.]],
+            ],
+            &dev,
+        )
+        .unwrap();
+        let mask = Tensor::new(&[[1u32, 1, 0], [1, 1, 1]], &dev).unwrap();
+        let lens = [2, 3];
+        let pool = |pooling: Pooling| {
+            pooling
+                .pool(&hidden_states, &mask, &lens)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        };
+        assert_eq!(pool(Pooling::Cls), [[1., 2.], [5., 6.]]);
+        // Padding is left out of the mean.
+        assert_eq!(pool(Pooling::Mean), [[2., 3.], [7., 8.]]);
+        // The last token of each input, not of the padded batch.
+        assert_eq!(pool(Pooling::LastToken), [[3., 4.], [9., 10.]]);
+    }
+
+    #[test]
+    fn test_pad_batch() {
+        use super::pad_batch;
+        use candle_core::Device;
+
+        let (short, long) = (
+            encoding(&[5, 6], &[0, 0]),
+            encoding(&[7, 8, 9, 10], &[0, 0, 1, 1]),
+        );
+        let (input_ids, token_type_ids, mask) =
+            pad_batch(&[&short, &long], 1, &Device::Cpu).unwrap();
+        assert_eq!(
+            input_ids.to_vec2::<u32>().unwrap(),
+            [[5, 6, 1, 1], [7, 8, 9, 10]]
+        );
+        assert_eq!(
+            token_type_ids.to_vec2::<u32>().unwrap(),
+            [[0, 0, 0, 0], [0, 0, 1, 1]]
+        );
+        assert_eq!(mask.to_vec2::<u32>().unwrap(), [[1, 1, 0, 0], [1, 1, 1, 1]]);
+    }
+
+    #[test]
+    fn test_position_ids() {
+        use crate::models::bert::{position_ids, EncoderFamily};
+        use candle_core::{Device, Tensor};
+
+        let mask = Tensor::new(&[[1u32, 1, 0, 0], [1, 1, 1, 1]], &Device::Cpu).unwrap();
+        let bert = position_ids(EncoderFamily::Bert, 0, &mask).unwrap();
+        assert_eq!(bert.to_vec2::<u32>().unwrap(), [[0, 1, 2, 3], [0, 1, 2, 3]]);
+        // XLM-RoBERTa numbers tokens after the padding index, and padding is at the padding index.
+        let xlm_roberta = position_ids(EncoderFamily::XlmRoberta, 1, &mask).unwrap();
+        assert_eq!(
+            xlm_roberta.to_vec2::<u32>().unwrap(),
+            [[2, 3, 1, 1], [2, 3, 4, 5]]
+        );
+    }
+}
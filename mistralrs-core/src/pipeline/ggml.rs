@@ -5,7 +5,7 @@ use super::{
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
-use crate::models::Cache;
+use crate::models::{Cache, CacheKind};
 use crate::pipeline::chat_template::calculate_eos_tokens;
 use crate::pipeline::{ChatTemplate, SimpleModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
@@ -14,13 +14,11 @@ use crate::utils::varbuilder_utils::from_mmaped_safetensors;
 use crate::xlora_models::NonGranularState;
 use crate::{deserialize_chat_template, do_sample, get_mut_arcmutex, get_paths, DeviceMapMetadata};
 use crate::{
-    models::quantized_llama::ModelWeights as QLlama, utils::tokens::get_token,
-    xlora_models::XLoraModelWeights as XLoraQLlama,
+    models::quantized_llama::ModelWeights as QLlama, xlora_models::XLoraModelWeights as XLoraQLlama,
 };
 use anyhow::Result;
 use candle_core::quantized::{ggml_file, GgmlDType};
 use candle_core::{DType, Device, Tensor};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_lora::Ordering;
 use rand_isaac::Isaac64Rng;
 use serde_json::Value;
@@ -225,8 +223,9 @@ impl Loader for GGMLLoader {
             &token_source,
             revision,
             self,
+            self.model_id,
             self.quantized_model_id,
-            self.quantized_filename,
+            self.quantized_filename.clone().map(|f| vec![f]),
             silent
         );
         let paths = paths?;
@@ -320,6 +319,10 @@ impl Loader for GGMLLoader {
             Model::XLoraLlama(ref model) => model.cache.lock().len(),
         };
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let rope_shift = match model {
+            Model::Llama(ref model) => model.rope_shift,
+            Model::XLoraLlama(_) => None,
+        };
         Ok(Arc::new(Mutex::new(GGMLPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -341,6 +344,8 @@ impl Loader for GGMLLoader {
                 is_xlora,
                 num_hidden_layers,
                 eos_tok: eos,
+                rope_shift,
+                cache_kind: CacheKind::Kv,
             },
         })))
     }
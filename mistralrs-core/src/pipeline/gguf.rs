@@ -1,12 +1,15 @@
 use super::cache_manager::DefaultCacheManager;
+use super::gguf_shards::open_gguf_shards;
+use super::gguf_tokenizer::convert_gguf_to_hf_tokenizer;
 use super::{
-    get_model_paths, get_xlora_paths, CacheManager, GeneralMetadata, Loader, ModelInputs,
-    ModelKind, ModelPaths, Pipeline, TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths, is_offline, validate_offline_files, CacheManager,
+    GeneralMetadata, Loader, ModelInputs, ModelKind, ModelPaths, ModelRepo, OfflineModelFiles,
+    Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
-use crate::models::Cache;
-use crate::pipeline::chat_template::calculate_eos_tokens;
+use crate::models::{Cache, CacheKind};
+use crate::pipeline::chat_template::{calculate_eos_tokens, SpecifiedTemplate};
 use crate::pipeline::{ChatTemplate, SimpleModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
@@ -14,23 +17,24 @@ use crate::utils::varbuilder_utils::from_mmaped_safetensors;
 use crate::xlora_models::NonGranularState;
 use crate::{deserialize_chat_template, do_sample, get_mut_arcmutex, get_paths, DeviceMapMetadata};
 use crate::{
-    models::quantized_llama::ModelWeights as QLlama, models::quantized_phi2::ModelWeights as QPhi,
-    models::quantized_phi3::ModelWeights as QPhi3, utils::tokens::get_token,
-    xlora_models::XLoraModelWeights as XLoraQLlama,
+    models::quantized_falcon::ModelWeights as QFalcon,
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gptneox::ModelWeights as QGptNeox,
+    models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba, models::quantized_phi2::ModelWeights as QPhi,
+    models::quantized_phi3::ModelWeights as QPhi3, models::quantized_qwen2::ModelWeights as QQwen2,
+    models::quantized_starcoder2::ModelWeights as QStarcoder2,
+    xlora_models::XLoraModelWeights as XLoraQLlama, xlora_models::XLoraQGemma,
 };
 use anyhow::{bail, Result};
-use candle_core::quantized::{
-    gguf_file::{self, Value as GgufValue},
-    GgmlDType,
-};
+use candle_core::quantized::{gguf_file::Value as GgufValue, GgmlDType};
 use candle_core::{DType, Device, Tensor};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_lora::Ordering;
 use rand_isaac::Isaac64Rng;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
@@ -42,6 +46,13 @@ enum Model {
     Phi2(QPhi),
     XLoraLlama(XLoraQLlama),
     Phi3(QPhi3),
+    Gemma(QGemma),
+    XLoraGemma(XLoraQGemma),
+    Qwen2(QQwen2),
+    Falcon(QFalcon),
+    GptNeox(QGptNeox),
+    Starcoder2(QStarcoder2),
+    Mamba(QMamba),
 }
 
 pub struct GGUFPipeline {
@@ -56,10 +67,10 @@ pub struct GGUFPipeline {
 }
 
 pub struct GGUFLoader {
-    model_id: String,
+    model_id: Option<String>,
     config: GGUFSpecificConfig,
     quantized_model_id: Option<String>,
-    quantized_filename: Option<String>,
+    quantized_filenames: Option<Vec<String>>,
     xlora_model_id: Option<String>,
     xlora_order: Option<Ordering>,
     no_kv_cache: bool,
@@ -82,6 +93,9 @@ enum GGUFArchitecture {
     Rwkv,
     Phi2,
     Phi3,
+    Gemma,
+    Qwen2,
+    Starcoder2,
 }
 
 impl FromStr for GGUFArchitecture {
@@ -100,6 +114,9 @@ impl FromStr for GGUFArchitecture {
             "rwkv" => Ok(GGUFArchitecture::Rwkv),
             "phi2" => Ok(GGUFArchitecture::Phi2),
             "phi3" => Ok(GGUFArchitecture::Phi3),
+            "gemma" => Ok(GGUFArchitecture::Gemma),
+            "qwen2" => Ok(GGUFArchitecture::Qwen2),
+            "starcoder2" => Ok(GGUFArchitecture::Starcoder2),
             a => Err(format!("Unknown GGUF architecture `{a}`")),
         }
     }
@@ -117,7 +134,7 @@ pub struct GGUFLoaderBuilder {
     model_id: Option<String>,
     config: GGUFSpecificConfig,
     quantized_model_id: String,
-    quantized_filename: String,
+    quantized_filenames: Vec<String>,
     xlora_model_id: Option<String>,
     kind: ModelKind,
     xlora_order: Option<Ordering>,
@@ -134,7 +151,7 @@ impl GGUFLoaderBuilder {
         tokenizer_json: Option<String>,
         model_id: Option<String>,
         quantized_model_id: String,
-        quantized_filename: String,
+        quantized_filenames: Vec<String>,
     ) -> Self {
         Self {
             config,
@@ -142,7 +159,7 @@ impl GGUFLoaderBuilder {
             tokenizer_json,
             model_id,
             kind: ModelKind::QuantizedGGUF,
-            quantized_filename,
+            quantized_filenames,
             quantized_model_id,
             ..Default::default()
         }
@@ -194,7 +211,7 @@ impl GGUFLoaderBuilder {
 
     pub fn build(self) -> Box<dyn Loader> {
         Box::new(GGUFLoader {
-            model_id: self.model_id.unwrap(),
+            model_id: self.model_id,
             config: self.config,
             xlora_model_id: self.xlora_model_id,
             kind: self.kind,
@@ -203,7 +220,7 @@ impl GGUFLoaderBuilder {
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
             tgt_non_granular_index: self.tgt_non_granular_index,
-            quantized_filename: Some(self.quantized_filename),
+            quantized_filenames: Some(self.quantized_filenames),
             quantized_model_id: Some(self.quantized_model_id),
         })
     }
@@ -215,7 +232,7 @@ impl GGUFLoader {
         model_id: Option<String>,
         config: GGUFSpecificConfig,
         quantized_model_id: Option<String>,
-        quantized_filename: Option<String>,
+        quantized_filenames: Option<Vec<String>>,
         xlora_model_id: Option<String>,
         kind: ModelKind,
         xlora_order: Option<Ordering>,
@@ -224,20 +241,16 @@ impl GGUFLoader {
         tokenizer_json: Option<String>,
         tgt_non_granular_index: Option<usize>,
     ) -> Self {
-        let model_id = if let Some(id) = model_id {
-            id
-        } else {
-            info!(
-                "Using adapter base model ID: `{}`",
-                xlora_order.as_ref().unwrap().base_model_id
-            );
-            xlora_order.as_ref().unwrap().base_model_id.clone()
-        };
+        let model_id = model_id.or_else(|| {
+            let base_model_id = &xlora_order.as_ref()?.base_model_id;
+            info!("Using adapter base model ID: `{base_model_id}`");
+            Some(base_model_id.clone())
+        });
         Self {
             model_id,
             config,
             quantized_model_id,
-            quantized_filename,
+            quantized_filenames,
             xlora_model_id,
             xlora_order,
             no_kv_cache,
@@ -283,25 +296,57 @@ impl Loader for GGUFLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
-            SimpleModelPaths,
-            &token_source,
-            revision,
-            self,
-            self.quantized_model_id,
-            self.quantized_filename,
-            silent
-        );
-        let paths = paths?;
+        // Without a model ID, the tokenizer and chat template are built from the GGUF metadata.
+        let (paths, weight_filenames) = match self.model_id {
+            Some(ref model_id) => {
+                let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
+                    SimpleModelPaths,
+                    &token_source,
+                    revision,
+                    self,
+                    model_id,
+                    self.quantized_model_id,
+                    self.quantized_filenames,
+                    silent
+                );
+                let paths = paths?;
+                let weight_filenames = paths.get_weight_filenames().to_vec();
+                (Some(paths), weight_filenames)
+            }
+            None => {
+                let revision = revision.unwrap_or("main".to_string());
+                let quantized_model_id = self.quantized_model_id.clone().unwrap();
+                if is_offline() {
+                    validate_offline_files(OfflineModelFiles {
+                        model_id: None,
+                        revision: &revision,
+                        tokenizer_json: None,
+                        quantized_model_id: Some(&quantized_model_id),
+                        quantized_filenames: self.quantized_filenames.as_deref(),
+                        xlora_model_id: self.xlora_model_id.as_deref(),
+                        xlora_order: self.xlora_order.as_ref(),
+                    })?;
+                }
+                let api = ModelRepo::new(&quantized_model_id, &revision, &token_source, silent)?;
+                let filenames = get_model_paths(
+                    revision,
+                    &token_source,
+                    &self.quantized_model_id,
+                    &self.quantized_filenames,
+                    &api,
+                    Path::new(&quantized_model_id),
+                )?;
+                (None, filenames)
+            }
+        };
 
         if in_situ_quant.is_some() {
             anyhow::bail!(
                 "You are trying to in-situ quantize a GGUF model. This will not do anything."
             );
         }
-        let mut file = std::fs::File::open(paths.get_weight_filenames().first().unwrap())?;
-        let model = gguf_file::Content::read(&mut file)
-            .map_err(|e| e.with_path(paths.get_weight_filenames().first().unwrap()))?;
+        // A split model is read as if its shards were a single file.
+        let (model, mut file) = open_gguf_shards(&weight_filenames)?;
         let arch: GGUFArchitecture = model.metadata["general.architecture"]
             .to_string()
             .unwrap()
@@ -315,6 +360,13 @@ impl Loader for GGUFLoader {
                 println!("{name}: {}", value);
             }
         }
+        let gguf_tokenizer = match paths {
+            Some(_) => None,
+            None => Some(convert_gguf_to_hf_tokenizer(
+                &model,
+                self.tokenizer_json.as_deref(),
+            )?),
+        };
 
         let mut is_lora = false;
         let model = match self.kind {
@@ -328,9 +380,28 @@ impl Loader for GGUFLoader {
                 GGUFArchitecture::Phi3 => {
                     Model::Phi3(QPhi3::from_gguf(model, &mut file, device, mapper)?)
                 }
+                GGUFArchitecture::Gemma => {
+                    Model::Gemma(QGemma::from_gguf(model, &mut file, device, mapper)?)
+                }
+                GGUFArchitecture::Qwen2 => {
+                    Model::Qwen2(QQwen2::from_gguf(model, &mut file, device, mapper)?)
+                }
+                GGUFArchitecture::Falcon => {
+                    Model::Falcon(QFalcon::from_gguf(model, &mut file, device, mapper)?)
+                }
+                GGUFArchitecture::Gptneox => {
+                    Model::GptNeox(QGptNeox::from_gguf(model, &mut file, device, mapper)?)
+                }
+                GGUFArchitecture::Starcoder2 => {
+                    Model::Starcoder2(QStarcoder2::from_gguf(model, &mut file, device, mapper)?)
+                }
+                GGUFArchitecture::Mamba => {
+                    Model::Mamba(QMamba::from_gguf(model, &mut file, device, mapper)?)
+                }
                 a => bail!("Unsupported architecture `{a:?}`"),
             },
             ModelKind::XLoraGGUF => {
+                let paths = paths.as_ref().expect("Adapters have a base model ID.");
                 let vb = from_mmaped_safetensors(
                     vec![paths.get_classifier_path().as_ref().unwrap().to_path_buf()],
                     paths
@@ -356,11 +427,22 @@ impl Loader for GGUFLoader {
                         Some(paths.get_classifier_config().as_ref().unwrap().clone()),
                         mapper,
                     )?),
+                    GGUFArchitecture::Gemma => Model::XLoraGemma(XLoraQGemma::from_gguf(
+                        model,
+                        &mut file,
+                        device,
+                        paths.get_adapter_configs().as_ref().unwrap(),
+                        &vb,
+                        paths.get_ordering().as_ref().unwrap(),
+                        Some(paths.get_classifier_config().as_ref().unwrap().clone()),
+                        mapper,
+                    )?),
                     a => bail!("Unsupported architecture for GGUF X-LoRA `{a:?}`"),
                 }
             }
             ModelKind::LoraGGUF => {
                 is_lora = true;
+                let paths = paths.as_ref().expect("Adapters have a base model ID.");
                 let vb = from_mmaped_safetensors(
                     vec![],
                     paths
@@ -386,42 +468,120 @@ impl Loader for GGUFLoader {
                         None,
                         mapper,
                     )?),
+                    GGUFArchitecture::Gemma => Model::XLoraGemma(XLoraQGemma::from_gguf(
+                        model,
+                        &mut file,
+                        device,
+                        paths.get_adapter_configs().as_ref().unwrap(),
+                        &vb,
+                        paths.get_ordering().as_ref().unwrap(),
+                        None,
+                        mapper,
+                    )?),
                     a => bail!("Unsupported architecture for GGUF X-LoRA `{a:?}`"),
                 }
             }
             _ => unreachable!(),
         };
 
-        let tokenizer =
-            Tokenizer::from_file(paths.get_tokenizer_filename()).map_err(anyhow::Error::msg)?;
-
-        let (chat_template, gen_conf) = deserialize_chat_template!(paths, self);
+        let (tokenizer, chat_template, gen_conf) = match paths {
+            Some(paths) => {
+                let tokenizer = Tokenizer::from_file(paths.get_tokenizer_filename())
+                    .map_err(anyhow::Error::msg)?;
+                let (chat_template, gen_conf) = deserialize_chat_template!(paths, self);
+                (tokenizer, chat_template, gen_conf)
+            }
+            None => {
+                let conversion =
+                    gguf_tokenizer.expect("The tokenizer was built from the metadata.");
+                // A specified chat template overrides the one embedded in the GGUF file.
+                let (template, bos, eos) = match &self.chat_template {
+                    Some(t) => {
+                        let specified = SpecifiedTemplate::load(t)?;
+                        (
+                            Some(specified.chat_template),
+                            specified.bos_token.or(conversion.bos),
+                            specified.eos_token.unwrap_or(conversion.eos),
+                        )
+                    }
+                    None => (conversion.chat_template, conversion.bos, conversion.eos),
+                };
+                if template.is_none() {
+                    info!("The GGUF file does not contain a chat template, and no chat template was specified. Only prompts will be accepted, not messages.");
+                }
+                let chat_template = ChatTemplate::new(template, bos, eos, conversion.unk);
+                (conversion.tokenizer, chat_template, None)
+            }
+        };
 
         let max_seq_len = match model {
             Model::Llama(ref l) => l.max_seq_len,
             Model::Phi2(ref p) => p.max_seq_len,
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
             Model::Phi3(ref p) => p.max_seq_len,
+            Model::Gemma(ref p) => p.max_seq_len,
+            Model::XLoraGemma(ref xl) => xl.max_seq_len,
+            Model::Qwen2(ref p) => p.max_seq_len,
+            Model::Falcon(ref p) => p.max_seq_len,
+            Model::GptNeox(ref p) => p.max_seq_len,
+            Model::Starcoder2(ref p) => p.max_seq_len,
+            Model::Mamba(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let is_xlora = match &model {
-            Model::Llama(_) | Model::Phi2(_) | Model::Phi3(_) => false,
-            Model::XLoraLlama(_) => !is_lora,
+            Model::Llama(_)
+            | Model::Phi2(_)
+            | Model::Phi3(_)
+            | Model::Gemma(_)
+            | Model::Qwen2(_)
+            | Model::Falcon(_)
+            | Model::GptNeox(_)
+            | Model::Starcoder2(_)
+            | Model::Mamba(_) => false,
+            Model::XLoraLlama(_) | Model::XLoraGemma(_) => !is_lora,
         };
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.lock().len(),
             Model::Phi2(ref model) => model.cache.lock().len(),
             Model::XLoraLlama(ref model) => model.cache.lock().len(),
             Model::Phi3(ref model) => model.cache.lock().len(),
+            Model::Gemma(ref model) => model.cache.lock().len(),
+            Model::XLoraGemma(ref model) => model.cache.lock().len(),
+            Model::Qwen2(ref model) => model.cache.lock().len(),
+            Model::Falcon(ref model) => model.cache.lock().len(),
+            Model::GptNeox(ref model) => model.cache.lock().len(),
+            Model::Starcoder2(ref model) => model.cache.lock().len(),
+            Model::Mamba(ref model) => model.cache.lock().len(),
         };
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let rope_shift = match model {
+            Model::Llama(ref model) => model.rope_shift,
+            Model::Gemma(ref model) => model.rope_shift,
+            Model::Qwen2(ref model) => model.rope_shift,
+            Model::Falcon(ref model) => model.rope_shift,
+            Model::GptNeox(ref model) => model.rope_shift,
+            Model::Starcoder2(ref model) => model.rope_shift,
+            Model::Phi2(_)
+            | Model::XLoraLlama(_)
+            | Model::Phi3(_)
+            | Model::XLoraGemma(_)
+            | Model::Mamba(_) => None,
+        };
+        let cache_kind = match model {
+            Model::Mamba(_) => CacheKind::Recurrent,
+            _ => CacheKind::Kv,
+        };
         Ok(Arc::new(Mutex::new(GGUFPipeline {
             model,
             tok_trie: tok_trie.clone(),
             tokenizer: tokenizer.into(),
             no_kv_cache: self.no_kv_cache,
             chat_template: Arc::new(chat_template),
-            model_id: self.model_id.clone(),
+            model_id: self
+                .model_id
+                .clone()
+                .or_else(|| self.quantized_filenames.as_ref()?.first().cloned())
+                .unwrap_or_default(),
             non_granular_state: self.tgt_non_granular_index.map(|tgt_non_granular_index| {
                 NonGranularState {
                     non_granular_index: Arc::new(Mutex::new(0)),
@@ -436,6 +596,8 @@ impl Loader for GGUFLoader {
                 is_xlora,
                 num_hidden_layers,
                 eos_tok: eos,
+                rope_shift,
+                cache_kind,
             },
         })))
     }
@@ -443,7 +605,13 @@ impl Loader for GGUFLoader {
     fn get_id(&self) -> String {
         self.xlora_model_id
             .as_deref()
-            .unwrap_or(&self.model_id)
+            .or(self.model_id.as_deref())
+            .or(self
+                .quantized_filenames
+                .as_ref()
+                .and_then(|f| f.first())
+                .map(String::as_str))
+            .unwrap_or_default()
             .to_string()
     }
 
@@ -487,6 +655,48 @@ impl Pipeline for GGUFPipeline {
                 context_lens,
             ),
             Model::Phi3(ref mut model) => model.forward(&input_ids, &seqlen_offsets),
+            Model::Gemma(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::XLoraGemma(ref mut model) => model.forward(
+                &input_ids,
+                input_ids_full.as_ref().unwrap_or(&input_ids),
+                &seqlen_offsets,
+                seqlen_offsets_full.as_ref().unwrap_or(&seqlen_offsets),
+                seqlen_offsets_kernel.clone(),
+                seqlen_offsets_kernel_full.unwrap_or(seqlen_offsets_kernel),
+                self.no_kv_cache,
+                &self.non_granular_state,
+                context_lens,
+            ),
+            Model::Qwen2(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::Falcon(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::GptNeox(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::Starcoder2(ref mut model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
+            Model::Mamba(ref mut model) => model.forward(&input_ids, context_lens),
         }
     }
     async fn sample(
@@ -505,6 +715,13 @@ impl Pipeline for GGUFPipeline {
             Model::Phi2(ref model) => model.device.clone(),
             Model::XLoraLlama(ref model) => model.device.clone(),
             Model::Phi3(ref model) => model.device.clone(),
+            Model::Gemma(ref model) => model.device.clone(),
+            Model::XLoraGemma(ref model) => model.device.clone(),
+            Model::Qwen2(ref model) => model.device.clone(),
+            Model::Falcon(ref model) => model.device.clone(),
+            Model::GptNeox(ref model) => model.device.clone(),
+            Model::Starcoder2(ref model) => model.device.clone(),
+            Model::Mamba(ref model) => model.device.clone(),
         }
     }
     fn tokenizer(&self) -> Arc<Tokenizer> {
@@ -548,6 +765,13 @@ impl Pipeline for GGUFPipeline {
             Model::Phi2(ref model) => &model.cache,
             Model::XLoraLlama(ref model) => &model.cache,
             Model::Phi3(ref model) => &model.cache,
+            Model::Gemma(ref model) => &model.cache,
+            Model::XLoraGemma(ref model) => &model.cache,
+            Model::Qwen2(ref model) => &model.cache,
+            Model::Falcon(ref model) => &model.cache,
+            Model::GptNeox(ref model) => &model.cache,
+            Model::Starcoder2(ref model) => &model.cache,
+            Model::Mamba(ref model) => &model.cache,
         }
     }
 }
@@ -0,0 +1,256 @@
+#![allow(clippy::cast_possible_truncation)]
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file::{Content, TensorInfo};
+use memmap2::Mmap;
+use tracing::info;
+
+/// The filenames of a GGUF model given as `quantized_filename`. This is either the shards of a
+/// split model, or a single filename. A single shard of a split model (`model-00001-of-00004.gguf`)
+/// stands for all of its shards.
+pub(crate) fn gguf_shard_filenames(quantized_filenames: &[String]) -> Vec<String> {
+    let [name] = quantized_filenames else {
+        return quantized_filenames.to_vec();
+    };
+    let shards = name.strip_suffix(".gguf").and_then(|stem| {
+        let (rest, count) = stem.rsplit_once("-of-")?;
+        let (prefix, index) = rest.rsplit_once('-')?;
+        let is_shard_number = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_shard_number(index) || !is_shard_number(count) {
+            return None;
+        }
+        let n_shards = count.parse::<usize>().ok()?;
+        Some(
+            (1..=n_shards)
+                .map(|i| format!("{prefix}-{i:05}-of-{count}.gguf"))
+                .collect::<Vec<_>>(),
+        )
+    });
+    match shards {
+        Some(shards) => {
+            if shards.len() > 1 {
+                info!("`{name}` is one of {} GGUF shards.", shards.len());
+            }
+            shards
+        }
+        None => vec![name.clone()],
+    }
+}
+
+/// A reader over the memory mapped shards of a GGUF model, as if they were one file.
+pub struct GgufShardReader {
+    shards: Vec<Mmap>,
+    /// The offset of each shard in the concatenation of all shards.
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for GgufShardReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let shard = self.starts.partition_point(|start| *start <= self.pos) - 1;
+        let data = &self.shards[shard][(self.pos - self.starts[shard]) as usize..];
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for GgufShardReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek to a negative position.",
+            )),
+        }
+    }
+}
+
+/// Memory map the shards of a GGUF model and merge their metadata and tensor indices. The tensor
+/// offsets of the merged content are relative to the start of the returned reader, so every
+/// tensor can be read (and placed on any device) as if the model were a single file.
+pub fn open_gguf_shards(paths: &[PathBuf]) -> Result<(Content, GgufShardReader)> {
+    let mut shards = Vec::with_capacity(paths.len());
+    let mut starts = Vec::with_capacity(paths.len());
+    let mut merged: Option<Content> = None;
+    let mut len = 0u64;
+    for path in paths {
+        let file = File::open(path)
+            .map_err(|e| anyhow::Error::msg(format!("Could not open `{}`: {e}", path.display())))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let content = Content::read(&mut Cursor::new(&mmap[..])).map_err(|e| e.with_path(path))?;
+        let base = len + content.tensor_data_offset;
+        let tensor_infos = content
+            .tensor_infos
+            .into_iter()
+            .map(|(name, info)| {
+                (
+                    name,
+                    TensorInfo {
+                        ggml_dtype: info.ggml_dtype,
+                        shape: info.shape,
+                        offset: base + info.offset,
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        match merged {
+            None => {
+                merged = Some(Content {
+                    magic: content.magic,
+                    metadata: content.metadata,
+                    tensor_infos,
+                    tensor_data_offset: 0,
+                })
+            }
+            Some(ref mut merged) => {
+                for (name, info) in tensor_infos {
+                    if merged.tensor_infos.insert(name.clone(), info).is_some() {
+                        anyhow::bail!(
+                            "Tensor `{name}` is in more than one shard (`{}`).",
+                            path.display()
+                        );
+                    }
+                }
+                for (key, value) in content.metadata {
+                    merged.metadata.entry(key).or_insert(value);
+                }
+            }
+        }
+        starts.push(len);
+        len += mmap.len() as u64;
+        shards.push(mmap);
+    }
+    let Some(content) = merged else {
+        anyhow::bail!("No GGUF file was given.");
+    };
+    if let Some(count) = content.metadata.get("split.count") {
+        let count = count.to_u16()? as usize;
+        if count != paths.len() {
+            anyhow::bail!(
+                "The GGUF model is split into {count} shards, but {} were given.",
+                paths.len()
+            );
+        }
+    }
+    if paths.len() > 1 {
+        info!(
+            "Loaded {} tensors from {} GGUF shards.",
+            content.tensor_infos.len(),
+            paths.len()
+        );
+    }
+    Ok((
+        content,
+        GgufShardReader {
+            shards,
+            starts,
+            len,
+            pos: 0,
+        },
+    ))
+}
+
+mod tests {
+    #[test]
+    fn test_gguf_shard_filenames() {
+        use super::gguf_shard_filenames;
+
+        assert_eq!(
+            gguf_shard_filenames(&["my model-00001-of-00003.gguf".to_string()]),
+            [
+                "my model-00001-of-00003.gguf",
+                "my model-00002-of-00003.gguf",
+                "my model-00003-of-00003.gguf"
+            ]
+        );
+        assert_eq!(
+            gguf_shard_filenames(&["my model.Q4_K_M.gguf".to_string()]),
+            ["my model.Q4_K_M.gguf"]
+        );
+        let given = vec!["part a.gguf".to_string(), "part b.gguf".to_string()];
+        assert_eq!(gguf_shard_filenames(&given), given);
+    }
+
+    #[test]
+    fn test_open_gguf_shards() {
+        use super::open_gguf_shards;
+        use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+        use candle_core::{Device, Tensor};
+
+        let dev = Device::Cpu;
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-gguf-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let tensor = |start: f32, rows: usize| {
+            let values = (0..rows * 32).map(|i| start + i as f32).collect::<Vec<_>>();
+            Tensor::from_vec(values, (rows, 32), &dev).unwrap()
+        };
+        let expected = [
+            ("first", tensor(0., 2)),
+            ("second", tensor(100., 3)),
+            ("third", tensor(1000., 1)),
+        ];
+        let count = gguf_file::Value::U16(2);
+        let arch = gguf_file::Value::String("llama".to_string());
+        let shards = [&expected[..2], &expected[2..]];
+        let mut paths = Vec::new();
+        for (i, shard) in shards.iter().enumerate() {
+            let qtensors = shard
+                .iter()
+                .map(|(name, t)| (*name, QTensor::quantize(t, GgmlDType::F32).unwrap()))
+                .collect::<Vec<_>>();
+            let qtensors = qtensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>();
+            let mut metadata = vec![("split.count", &count)];
+            if i == 0 {
+                metadata.push(("general.architecture", &arch));
+            }
+            let path = dir.join(format!("model-{:05}-of-00002.gguf", i + 1));
+            let mut file = std::fs::File::create(&path).unwrap();
+            gguf_file::write(&mut file, &metadata, &qtensors).unwrap();
+            paths.push(path);
+        }
+
+        let (content, mut reader) = open_gguf_shards(&paths).unwrap();
+        assert_eq!(content.tensor_data_offset, 0);
+        assert!(content.metadata.contains_key("general.architecture"));
+        let first_len = std::fs::metadata(&paths[0]).unwrap().len();
+        assert!(content.tensor_infos["third"].offset >= first_len);
+        for (name, t) in &expected {
+            let read = content
+                .tensor(&mut reader, name, &dev)
+                .unwrap()
+                .dequantize(&dev)
+                .unwrap();
+            assert_eq!(
+                read.to_vec2::<f32>().unwrap(),
+                t.to_vec2::<f32>().unwrap(),
+                "{name}"
+            );
+        }
+
+        // A shard missing from a split model is an error.
+        assert!(open_gguf_shards(&paths[..1]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file::{Content, Value};
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence as DecoderSequence,
+        strip::Strip,
+    },
+    models::bpe::BPE,
+    normalizers::{Prepend, Replace, Sequence as NormalizerSequence},
+    pre_tokenizers::{
+        byte_level::ByteLevel,
+        sequence::Sequence as PreTokenizerSequence,
+        split::{Split, SplitPattern},
+        PreTokenizerWrapper,
+    },
+    AddedToken, DecoderWrapper, NormalizerWrapper, SplitDelimiterBehavior, Tokenizer,
+};
+use tracing::info;
+
+// https://github.com/ggerganov/llama.cpp/blob/master/llama.h `llama_token_type`
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// A tokenizer and the special tokens of a model, built from the `tokenizer.*` metadata of its
+/// GGUF file.
+pub struct GgufTokenizerConversion {
+    pub tokenizer: Tokenizer,
+    pub bos: Option<String>,
+    pub eos: String,
+    pub unk: Option<String>,
+    pub chat_template: Option<String>,
+}
+
+fn get_string_array(content: &Content, key: &str) -> Result<Option<Vec<String>>> {
+    let Some(value) = content.metadata.get(key) else {
+        return Ok(None);
+    };
+    value
+        .to_vec()?
+        .iter()
+        .map(|v| Ok(v.to_string()?.clone()))
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+fn get_token_id(content: &Content, key: &str) -> Result<Option<u32>> {
+    content
+        .metadata
+        .get(key)
+        .map(|v| v.to_u32().map_err(anyhow::Error::from))
+        .transpose()
+}
+
+/// The merges of a SentencePiece BPE model, which GGUF does not store: any two tokens which form
+/// a token are merged, and merges into likelier tokens come first.
+fn sentencepiece_merges(tokens: &[String], scores: &[f32]) -> Vec<(String, String)> {
+    let ids = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, tok)| (tok.as_str(), id))
+        .collect::<HashMap<_, _>>();
+    let mut merges = Vec::new();
+    for (id, tok) in tokens.iter().enumerate() {
+        for (split, _) in tok.char_indices().skip(1) {
+            let (left, right) = tok.split_at(split);
+            if let (Some(&left_id), Some(&right_id)) = (ids.get(left), ids.get(right)) {
+                merges.push((scores[id], id, left_id, right_id));
+            }
+        }
+    }
+    merges.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .expect("No ordering.")
+            .then((a.1, a.2).cmp(&(b.1, b.2)))
+    });
+    merges
+        .into_iter()
+        .map(|(_, _, left, right)| (tokens[left].clone(), tokens[right].clone()))
+        .collect()
+}
+
+/// The regex splitting text into words before the byte level BPE, selected by the
+/// `tokenizer.ggml.pre` metadata. `None` is the GPT-2 regex of the byte level pre-tokenizer.
+/// https://github.com/ggerganov/llama.cpp/blob/master/src/llama.cpp `llm_tokenizer_bpe`
+fn gpt2_pre_tokenizer_regex(pre: &str) -> Result<Option<&'static str>> {
+    match pre {
+        "default" | "gpt-2" => Ok(None),
+        "llama3" | "llama-bpe" | "smaug-bpe" => Ok(Some(
+            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+        )),
+        "qwen2" => Ok(Some(
+            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+        )),
+        "starcoder" | "refact" => Ok(Some(
+            r"\p{N}|'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+",
+        )),
+        other => anyhow::bail!("Unsupported GGUF pre-tokenizer `{other}`."),
+    }
+}
+
+/// Build a tokenizer from the `tokenizer.*` metadata of a GGUF model. SentencePiece BPE (`llama`)
+/// and GPT-2 BPE (`gpt2`) tokenizers are supported.
+fn build_tokenizer(content: &Content) -> Result<Tokenizer> {
+    let model = content
+        .metadata
+        .get("tokenizer.ggml.model")
+        .ok_or_else(|| anyhow::Error::msg("The GGUF file does not contain a tokenizer."))?
+        .to_string()?
+        .clone();
+    let tokens = get_string_array(content, "tokenizer.ggml.tokens")?
+        .ok_or_else(|| anyhow::Error::msg("The GGUF file does not contain tokenizer tokens."))?;
+    let token_types = match content.metadata.get("tokenizer.ggml.token_type") {
+        Some(value) => value
+            .to_vec()?
+            .iter()
+            .map(|v| v.to_i32().map_err(anyhow::Error::from))
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![],
+    };
+    let unk = get_token_id(content, "tokenizer.ggml.unknown_token_id")?;
+    let token = |id: u32| {
+        tokens
+            .get(id as usize)
+            .cloned()
+            .ok_or_else(|| anyhow::Error::msg(format!("Token id {id} is not in the vocabulary.")))
+    };
+
+    let vocab = tokens
+        .iter()
+        .cloned()
+        .zip(0u32..)
+        .collect::<HashMap<_, _>>();
+    let mut tokenizer = match model.as_str() {
+        "llama" => {
+            let scores = match content.metadata.get("tokenizer.ggml.scores") {
+                Some(value) => value
+                    .to_vec()?
+                    .iter()
+                    .map(|v| v.to_f32().map_err(anyhow::Error::from))
+                    .collect::<Result<Vec<_>>>()?,
+                None => anyhow::bail!("The GGUF file does not contain tokenizer scores."),
+            };
+            if scores.len() != tokens.len() {
+                anyhow::bail!(
+                    "The GGUF file has a score for {} of {} tokens.",
+                    scores.len(),
+                    tokens.len()
+                );
+            }
+            let merges = sentencepiece_merges(&tokens, &scores);
+            let mut bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .byte_fallback(true)
+                .fuse_unk(true);
+            if let Some(unk) = unk {
+                bpe = bpe.unk_token(token(unk)?);
+            }
+            let mut tokenizer = Tokenizer::new(bpe.build().map_err(anyhow::Error::msg)?);
+            tokenizer.with_normalizer(NormalizerSequence::new(vec![
+                NormalizerWrapper::Prepend(Prepend::new("▁".to_string())),
+                NormalizerWrapper::Replace(Replace::new(" ", "▁").map_err(anyhow::Error::msg)?),
+            ]));
+            tokenizer.with_decoder(DecoderSequence::new(vec![
+                DecoderWrapper::Replace(Replace::new("▁", " ").map_err(anyhow::Error::msg)?),
+                DecoderWrapper::ByteFallback(ByteFallback::new()),
+                DecoderWrapper::Fuse(Fuse::new()),
+                DecoderWrapper::Strip(Strip::new(' ', 1, 0)),
+            ]));
+            tokenizer
+        }
+        "gpt2" => {
+            let merges = get_string_array(content, "tokenizer.ggml.merges")?
+                .ok_or_else(|| {
+                    anyhow::Error::msg("The GGUF file does not contain tokenizer merges.")
+                })?
+                .into_iter()
+                .map(|merge| {
+                    merge
+                        .split_once(' ')
+                        .map(|(left, right)| (left.to_string(), right.to_string()))
+                        .ok_or_else(|| anyhow::Error::msg(format!("Invalid merge `{merge}`.")))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let pre = match content.metadata.get("tokenizer.ggml.pre") {
+                Some(pre) => pre.to_string()?.clone(),
+                None => "default".to_string(),
+            };
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .build()
+                .map_err(anyhow::Error::msg)?;
+            let mut tokenizer = Tokenizer::new(bpe);
+            match gpt2_pre_tokenizer_regex(&pre)? {
+                Some(regex) => {
+                    let split = Split::new(
+                        SplitPattern::Regex(regex.to_string()),
+                        SplitDelimiterBehavior::Isolated,
+                        false,
+                    )
+                    .map_err(anyhow::Error::msg)?;
+                    tokenizer.with_pre_tokenizer(PreTokenizerSequence::new(vec![
+                        PreTokenizerWrapper::Split(split),
+                        PreTokenizerWrapper::ByteLevel(ByteLevel::new(false, true, false)),
+                    ]));
+                }
+                None => {
+                    tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+                }
+            }
+            tokenizer.with_decoder(ByteLevel::default());
+            tokenizer
+        }
+        other => anyhow::bail!("Unsupported GGUF tokenizer model `{other}`."),
+    };
+
+    // Control tokens are special, and user defined tokens are never split.
+    let mut special = Vec::new();
+    let mut added = Vec::new();
+    for (tok, ty) in tokens.iter().zip(&token_types) {
+        match *ty {
+            TOKEN_TYPE_UNKNOWN | TOKEN_TYPE_CONTROL => {
+                special.push(AddedToken::from(tok.clone(), true))
+            }
+            TOKEN_TYPE_USER_DEFINED => added.push(AddedToken::from(tok.clone(), false)),
+            _ => {}
+        }
+    }
+    tokenizer.add_special_tokens(&special);
+    tokenizer.add_tokens(&added);
+
+    info!(
+        "Built a `{model}` tokenizer with {} tokens from the GGUF metadata.",
+        tokens.len()
+    );
+    Ok(tokenizer)
+}
+
+/// Get the tokenizer and the special tokens of a GGUF model. The tokenizer is built from the
+/// metadata, unless a `tokenizer.json` is given.
+pub fn convert_gguf_to_hf_tokenizer(
+    content: &Content,
+    tokenizer_json: Option<&str>,
+) -> Result<GgufTokenizerConversion> {
+    let tokenizer = match tokenizer_json {
+        Some(p) => {
+            info!("Using tokenizer.json at `{p}`");
+            Tokenizer::from_file(p).map_err(anyhow::Error::msg)?
+        }
+        None => build_tokenizer(content)?,
+    };
+    let bos = get_token_id(content, "tokenizer.ggml.bos_token_id")?;
+    let eos = get_token_id(content, "tokenizer.ggml.eos_token_id")?
+        .ok_or_else(|| anyhow::Error::msg("The GGUF file does not specify an EOS token."))?;
+    let unk = get_token_id(content, "tokenizer.ggml.unknown_token_id")?;
+    let token = |id: u32| {
+        tokenizer
+            .id_to_token(id)
+            .ok_or_else(|| anyhow::Error::msg(format!("Token id {id} is not in the vocabulary.")))
+    };
+    let chat_template = content
+        .metadata
+        .get("tokenizer.chat_template")
+        .map(Value::to_string)
+        .transpose()?
+        .cloned();
+    Ok(GgufTokenizerConversion {
+        bos: bos.map(&token).transpose()?,
+        eos: token(eos)?,
+        unk: unk.map(&token).transpose()?,
+        chat_template,
+        tokenizer,
+    })
+}
+
+mod tests {
+    #[allow(dead_code)]
+    fn content(
+        model: &str,
+        tokens: &[&str],
+        extra: Vec<(&str, candle_core::quantized::gguf_file::Value)>,
+    ) -> candle_core::quantized::gguf_file::Content {
+        use candle_core::quantized::gguf_file::{Content, Value, VersionedMagic};
+        use std::collections::HashMap;
+
+        let mut metadata = HashMap::from([
+            (
+                "tokenizer.ggml.model".to_string(),
+                Value::String(model.to_string()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                Value::Array(
+                    tokens
+                        .iter()
+                        .map(|tok| Value::String(tok.to_string()))
+                        .collect(),
+                ),
+            ),
+        ]);
+        metadata.extend(extra.into_iter().map(|(k, v)| (k.to_string(), v)));
+        Content {
+            magic: VersionedMagic::GgufV3,
+            metadata,
+            tensor_infos: HashMap::new(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_sentencepiece_tokenizer() {
+        use super::{convert_gguf_to_hf_tokenizer, sentencepiece_merges};
+        use candle_core::quantized::gguf_file::Value;
+
+        let tokens = [
+            "<unk>", "<s>", "</s>", "<0x21>", "▁", "a", "b", "▁a", "ab", "▁ab",
+        ];
+        let scores = [0., 0., 0., 0., -3., -4., -5., -1., -2., -0.5];
+        let types = [2, 3, 3, 6, 1, 1, 1, 1, 1, 1];
+
+        // Merges into likelier tokens come first, and ties are ordered by the left token.
+        let owned = tokens.map(str::to_string);
+        let merges = sentencepiece_merges(&owned, &scores);
+        let merges = merges
+            .iter()
+            .map(|(l, r)| (l.as_str(), r.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(merges, [("▁", "ab"), ("▁a", "b"), ("▁", "a"), ("a", "b")]);
+
+        let content = content(
+            "llama",
+            &tokens,
+            vec![
+                (
+                    "tokenizer.ggml.scores",
+                    Value::Array(scores.iter().map(|s| Value::F32(*s)).collect()),
+                ),
+                (
+                    "tokenizer.ggml.token_type",
+                    Value::Array(types.iter().map(|t| Value::I32(*t)).collect()),
+                ),
+                ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+                ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+                ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+            ],
+        );
+        let conversion = convert_gguf_to_hf_tokenizer(&content, None).unwrap();
+        assert_eq!(conversion.bos.as_deref(), Some("<s>"));
+        assert_eq!(conversion.eos, "</s>");
+        assert_eq!(conversion.unk.as_deref(), Some("<unk>"));
+
+        let tokenizer = conversion.tokenizer;
+        let ids = tokenizer.encode("ab ab", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, [9, 9]);
+        assert_eq!(tokenizer.decode(&ids, false).unwrap(), "ab ab");
+
+        // `!` is not in the vocabulary, so it falls back to its byte.
+        let ids = tokenizer.encode("a!", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, [7, 3]);
+        assert_eq!(tokenizer.decode(&ids, false).unwrap(), "a!");
+    }
+
+    #[test]
+    fn test_gpt2_tokenizer() {
+        use super::convert_gguf_to_hf_tokenizer;
+        use candle_core::quantized::gguf_file::Value;
+
+        let tokens = [
+            "<|endoftext|>",
+            "a",
+            "b",
+            "Ġ",
+            "Ġa",
+            "ab",
+            "Ġab",
+            "1",
+            "2",
+            "12",
+        ];
+        let gpt2 = |pre: Option<&str>| {
+            let mut extra = vec![
+                (
+                    "tokenizer.ggml.merges",
+                    Value::Array(
+                        ["Ġ a", "a b", "Ġa b", "1 2"]
+                            .iter()
+                            .map(|m| Value::String(m.to_string()))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "tokenizer.ggml.token_type",
+                    Value::Array(
+                        std::iter::once(Value::I32(3))
+                            .chain(std::iter::repeat(Value::I32(1)).take(9))
+                            .collect(),
+                    ),
+                ),
+                ("tokenizer.ggml.eos_token_id", Value::U32(0)),
+            ];
+            if let Some(pre) = pre {
+                extra.push(("tokenizer.ggml.pre", Value::String(pre.to_string())));
+            }
+            convert_gguf_to_hf_tokenizer(&content("gpt2", &tokens, extra), None)
+        };
+
+        let conversion = gpt2(None).unwrap();
+        assert_eq!(conversion.eos, "<|endoftext|>");
+        assert_eq!(conversion.bos, None);
+        let tokenizer = conversion.tokenizer;
+        let ids = tokenizer.encode("ab ab", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, [5, 6]);
+        assert_eq!(tokenizer.decode(&ids, false).unwrap(), "ab ab");
+        assert_eq!(tokenizer.encode("12", false).unwrap().get_ids(), [9]);
+
+        // The pre-tokenizer decides how digits are split.
+        let encode = |pre: &str, text: &str| {
+            gpt2(Some(pre))
+                .unwrap()
+                .tokenizer
+                .encode(text, false)
+                .unwrap()
+                .get_ids()
+                .to_vec()
+        };
+        assert_eq!(encode("llama-bpe", "12"), [9]);
+        assert_eq!(encode("llama-bpe", "ab ab"), [5, 6]);
+        assert_eq!(encode("qwen2", "12"), [7, 8]);
+        assert_eq!(encode("starcoder", "12"), [7, 8]);
+        assert!(gpt2(Some("unknown")).is_err());
+    }
+}
@@ -33,6 +33,16 @@ pub enum NormalLoaderType {
     Phi3,
     #[serde(rename = "qwen2")]
     Qwen2,
+    #[serde(rename = "falcon")]
+    Falcon,
+    #[serde(rename = "starcoder2")]
+    Starcoder2,
+    #[serde(rename = "qwen2moe")]
+    Qwen2Moe,
+    #[serde(rename = "deepseek")]
+    DeepSeek,
+    #[serde(rename = "mamba")]
+    Mamba,
 }
 
 impl FromStr for NormalLoaderType {
@@ -46,6 +56,11 @@ impl FromStr for NormalLoaderType {
             "phi2" => Ok(Self::Phi2),
             "phi3" => Ok(Self::Phi3),
             "qwen2" => Ok(Self::Qwen2),
+            "falcon" => Ok(Self::Falcon),
+            "starcoder2" => Ok(Self::Starcoder2),
+            "qwen2moe" => Ok(Self::Qwen2Moe),
+            "deepseek" => Ok(Self::DeepSeek),
+            "mamba" => Ok(Self::Mamba),
             a => Err(format!("Unknown architecture `{a}`")),
         }
     }
@@ -66,6 +81,7 @@ struct MistralBasicConfig {
     rms_norm_eps: f64,
     rope_theta: f64,
     sliding_window: Option<usize>,
+    rope_scaling: Option<crate::layers::RopeScaling>,
 }
 
 impl MistralBasicConfig {
@@ -84,6 +100,7 @@ impl MistralBasicConfig {
             rope_theta: basic_config.rope_theta,
             sliding_window: basic_config.sliding_window,
             use_flash_attn,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -262,6 +279,7 @@ struct LlamaBasicConfig {
     #[serde(default = "default_rope")]
     rope_theta: f32,
     max_position_embeddings: usize,
+    rope_scaling: Option<crate::layers::RopeScaling>,
 }
 
 fn default_rope() -> f32 {
@@ -284,6 +302,7 @@ impl LlamaBasicConfig {
             rope_theta: basic_config.rope_theta,
             use_flash_attn,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -664,6 +683,7 @@ struct Qwen2BasicConfig {
     rms_norm_eps: f64,
     use_sliding_window: bool,
     hidden_act: Activation,
+    rope_scaling: Option<crate::layers::RopeScaling>,
 }
 
 impl Qwen2BasicConfig {
@@ -685,6 +705,7 @@ impl Qwen2BasicConfig {
             tie_word_embeddings: basic_config.tie_word_embeddings,
             use_sliding_window: basic_config.use_sliding_window,
             use_flash_attn,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -734,3 +755,637 @@ impl NormalModelLoader for Qwen2Loader {
         )?))
     }
 }
+
+// ======================== Falcon loader
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_falcon_rope_theta() -> f64 {
+    10000.
+}
+
+fn default_falcon_max_position_embeddings() -> usize {
+    2048
+}
+
+#[derive(Deserialize)]
+struct FalconBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    #[serde(alias = "n_layer")]
+    num_hidden_layers: usize,
+    #[serde(alias = "n_head")]
+    num_attention_heads: usize,
+    #[serde(alias = "n_head_kv")]
+    num_kv_heads: Option<usize>,
+    ffn_hidden_size: Option<usize>,
+    layer_norm_epsilon: f64,
+    #[serde(default = "default_falcon_rope_theta")]
+    rope_theta: f64,
+    #[serde(default = "default_falcon_max_position_embeddings")]
+    max_position_embeddings: usize,
+    #[serde(default)]
+    bias: bool,
+    #[serde(default)]
+    alibi: bool,
+    #[serde(default = "default_true")]
+    multi_query: bool,
+    #[serde(default)]
+    new_decoder_architecture: bool,
+    #[serde(default = "default_true")]
+    parallel_attn: bool,
+    num_ln_in_parallel_attn: Option<usize>,
+    #[serde(default = "default_true")]
+    tie_word_embeddings: bool,
+}
+
+impl FalconBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::falcon::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        if basic_config.alibi {
+            anyhow::bail!("Falcon models with ALiBi position embeddings are not supported.");
+        }
+        // The new decoder architecture has grouped KV heads, otherwise there is either a single KV
+        // head (multi-query attention) or one per attention head.
+        let num_kv_heads = if basic_config.new_decoder_architecture {
+            basic_config
+                .num_kv_heads
+                .unwrap_or(basic_config.num_attention_heads)
+        } else if basic_config.multi_query {
+            1
+        } else {
+            basic_config.num_attention_heads
+        };
+        Ok(models::falcon::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            ffn_hidden_size: basic_config
+                .ffn_hidden_size
+                .unwrap_or(4 * basic_config.hidden_size),
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_kv_heads,
+            layer_norm_epsilon: basic_config.layer_norm_epsilon,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rope_theta: basic_config.rope_theta,
+            bias: basic_config.bias,
+            multi_query: basic_config.multi_query,
+            new_decoder_architecture: basic_config.new_decoder_architecture,
+            parallel_attn: basic_config.parallel_attn,
+            num_ln_in_parallel_attn: basic_config.num_ln_in_parallel_attn.unwrap_or(2),
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+            use_flash_attn,
+        })
+    }
+}
+
+pub struct FalconLoader;
+
+impl NormalModelLoader for FalconLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::falcon::Model::new(
+            &FalconBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(xlora_models::XLoraFalcon::new(
+            &FalconBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            lora_config,
+            xlora_config,
+            xlora_ordering,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(FalconBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+}
+
+// ======================== Starcoder2 loader
+
+#[derive(Deserialize)]
+struct Starcoder2BasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    norm_epsilon: f64,
+    rope_theta: f64,
+    #[serde(default = "default_true")]
+    use_bias: bool,
+    sliding_window: Option<usize>,
+    #[serde(default = "default_true")]
+    tie_word_embeddings: bool,
+}
+
+impl Starcoder2BasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::starcoder2::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::starcoder2::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            norm_epsilon: basic_config.norm_epsilon,
+            rope_theta: basic_config.rope_theta,
+            use_bias: basic_config.use_bias,
+            sliding_window: basic_config.sliding_window,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+            use_flash_attn,
+        })
+    }
+}
+
+pub struct Starcoder2Loader;
+
+impl NormalModelLoader for Starcoder2Loader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::starcoder2::Model::new(
+            &Starcoder2BasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(xlora_models::XLoraStarcoder2::new(
+            &Starcoder2BasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            lora_config,
+            xlora_config,
+            xlora_ordering,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Starcoder2BasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+}
+
+// ======================== Qwen2-MoE loader
+
+fn default_one() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+struct Qwen2MoeBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    max_position_embeddings: usize,
+    sliding_window: Option<usize>,
+    #[serde(default)]
+    use_sliding_window: bool,
+    rope_theta: f64,
+    rms_norm_eps: f64,
+    hidden_act: Activation,
+    rope_scaling: Option<crate::layers::RopeScaling>,
+    #[serde(default = "default_one")]
+    decoder_sparse_step: usize,
+    moe_intermediate_size: usize,
+    shared_expert_intermediate_size: usize,
+    num_experts_per_tok: usize,
+    num_experts: usize,
+    #[serde(default)]
+    norm_topk_prob: bool,
+    #[serde(default)]
+    mlp_only_layers: Vec<usize>,
+}
+
+impl Qwen2MoeBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::qwen2_moe::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::qwen2_moe::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            sliding_window: basic_config
+                .sliding_window
+                .unwrap_or(basic_config.max_position_embeddings),
+            use_sliding_window: basic_config.use_sliding_window,
+            rope_theta: basic_config.rope_theta,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            hidden_act: basic_config.hidden_act,
+            use_flash_attn,
+            rope_scaling: basic_config.rope_scaling,
+            decoder_sparse_step: basic_config.decoder_sparse_step,
+            moe_intermediate_size: basic_config.moe_intermediate_size,
+            shared_expert_intermediate_size: basic_config.shared_expert_intermediate_size,
+            num_experts_per_tok: basic_config.num_experts_per_tok,
+            num_experts: basic_config.num_experts,
+            norm_topk_prob: basic_config.norm_topk_prob,
+            mlp_only_layers: basic_config.mlp_only_layers,
+        })
+    }
+}
+
+pub struct Qwen2MoeLoader;
+
+impl NormalModelLoader for Qwen2MoeLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::qwen2_moe::Model::new(
+            &Qwen2MoeBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[(String, LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _mapper: DeviceMapMetadata,
+        _loading_isq: bool,
+        _device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA and LoRA adapters are not supported for Qwen2-MoE models.")
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Qwen2MoeBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+}
+
+// ======================== DeepSeek loader
+
+fn default_silu() -> Activation {
+    Activation::Silu
+}
+
+fn default_routed_scaling_factor() -> f64 {
+    1.
+}
+
+#[derive(Deserialize)]
+struct DeepSeekBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    moe_intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    #[serde(default = "default_silu")]
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    rms_norm_eps: f64,
+    rope_theta: f64,
+    rope_scaling: Option<crate::layers::RopeScaling>,
+    #[serde(default)]
+    attention_bias: bool,
+    n_routed_experts: Option<usize>,
+    n_shared_experts: Option<usize>,
+    num_experts_per_tok: Option<usize>,
+    #[serde(default)]
+    first_k_dense_replace: usize,
+    #[serde(default = "default_one")]
+    moe_layer_freq: usize,
+    #[serde(default)]
+    norm_topk_prob: bool,
+    #[serde(default = "default_routed_scaling_factor")]
+    routed_scaling_factor: f64,
+    #[serde(default)]
+    kv_lora_rank: Option<usize>,
+    #[serde(default)]
+    topk_method: Option<String>,
+    #[serde(default)]
+    n_group: Option<usize>,
+    #[serde(default)]
+    scoring_func: Option<String>,
+}
+
+impl DeepSeekBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::deepseek::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        // Only DeepSeek-MoE (V1) is supported: reject the attention and routing of later versions
+        // rather than silently loading them as V1.
+        if basic_config.kv_lora_rank.is_some() {
+            anyhow::bail!("Only DeepSeek-MoE (V1) models are supported, multi-head latent attention (`kv_lora_rank`) is not.");
+        }
+        if let Some(method) = basic_config
+            .topk_method
+            .as_deref()
+            .filter(|method| *method != "greedy")
+        {
+            anyhow::bail!("Only DeepSeek-MoE (V1) models are supported, expert routing with `topk_method` `{method}` is not.");
+        }
+        if basic_config.n_group.is_some_and(|n_group| n_group > 1) {
+            anyhow::bail!("Only DeepSeek-MoE (V1) models are supported, grouped expert routing (`n_group`) is not.");
+        }
+        if let Some(func) = basic_config
+            .scoring_func
+            .as_deref()
+            .filter(|func| *func != "softmax")
+        {
+            anyhow::bail!("Only DeepSeek-MoE (V1) models are supported, expert scoring with `scoring_func` `{func}` is not.");
+        }
+        if basic_config.n_routed_experts.is_some() && basic_config.num_experts_per_tok.is_none() {
+            anyhow::bail!("DeepSeek config has `n_routed_experts` but no `num_experts_per_tok`.");
+        }
+        Ok(models::deepseek::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            moe_intermediate_size: basic_config.moe_intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config
+                .num_key_value_heads
+                .unwrap_or(basic_config.num_attention_heads),
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rope_theta: basic_config.rope_theta,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            hidden_act: basic_config.hidden_act,
+            use_flash_attn,
+            rope_scaling: basic_config.rope_scaling,
+            attention_bias: basic_config.attention_bias,
+            n_routed_experts: basic_config.n_routed_experts,
+            n_shared_experts: basic_config.n_shared_experts,
+            num_experts_per_tok: basic_config.num_experts_per_tok.unwrap_or(0),
+            first_k_dense_replace: basic_config.first_k_dense_replace,
+            moe_layer_freq: basic_config.moe_layer_freq,
+            norm_topk_prob: basic_config.norm_topk_prob,
+            routed_scaling_factor: basic_config.routed_scaling_factor,
+        })
+    }
+}
+
+/// Loads DeepSeek-MoE (V1) models. The multi-head latent attention and grouped expert routing of
+/// DeepSeek-V2 and later are not supported, and such configs are rejected.
+pub struct DeepSeekLoader;
+
+impl NormalModelLoader for DeepSeekLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::deepseek::Model::new(
+            &DeepSeekBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[(String, LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _mapper: DeviceMapMetadata,
+        _loading_isq: bool,
+        _device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA and LoRA adapters are not supported for DeepSeek-MoE models.")
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(DeepSeekBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+}
+
+// ======================== Mamba loader
+
+fn default_mamba_state_size() -> usize {
+    16
+}
+
+fn default_mamba_expand() -> usize {
+    2
+}
+
+fn default_mamba_conv_kernel() -> usize {
+    4
+}
+
+fn default_mamba_layer_norm_epsilon() -> f64 {
+    1e-5
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum MambaTimeStepRank {
+    Fixed(usize),
+    /// `"auto"`, which is `ceil(hidden_size / 16)`.
+    Auto(String),
+}
+
+/// Accepts both the `transformers` config and the original `state-spaces` config, which uses
+/// `d_model`, `n_layer` and `tie_embeddings` and pads the vocabulary at load time.
+#[derive(Deserialize)]
+struct MambaBasicConfig {
+    vocab_size: usize,
+    #[serde(alias = "d_model")]
+    hidden_size: usize,
+    intermediate_size: Option<usize>,
+    #[serde(default = "default_mamba_expand")]
+    expand: usize,
+    #[serde(alias = "n_layer")]
+    num_hidden_layers: usize,
+    #[serde(default = "default_mamba_state_size")]
+    state_size: usize,
+    #[serde(default = "default_mamba_conv_kernel")]
+    conv_kernel: usize,
+    time_step_rank: Option<MambaTimeStepRank>,
+    #[serde(default = "default_mamba_layer_norm_epsilon")]
+    layer_norm_epsilon: f64,
+    #[serde(default)]
+    use_bias: bool,
+    #[serde(default = "default_true")]
+    use_conv_bias: bool,
+    #[serde(default = "default_true", alias = "tie_embeddings")]
+    tie_word_embeddings: bool,
+    pad_vocab_size_multiple: Option<usize>,
+    max_position_embeddings: Option<usize>,
+}
+
+impl MambaBasicConfig {
+    fn deserialize(slice: &str) -> Result<models::mamba::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        let vocab_size = match basic_config.pad_vocab_size_multiple {
+            Some(multiple) if multiple > 0 => basic_config.vocab_size.div_ceil(multiple) * multiple,
+            _ => basic_config.vocab_size,
+        };
+        let time_step_rank = match basic_config.time_step_rank {
+            Some(MambaTimeStepRank::Fixed(rank)) => rank,
+            Some(MambaTimeStepRank::Auto(ref s)) if s != "auto" => {
+                anyhow::bail!("Unknown Mamba `time_step_rank` `{s}`.")
+            }
+            _ => basic_config.hidden_size.div_ceil(16),
+        };
+        Ok(models::mamba::Config {
+            vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config
+                .intermediate_size
+                .unwrap_or(basic_config.expand * basic_config.hidden_size),
+            state_size: basic_config.state_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            conv_kernel: basic_config.conv_kernel,
+            time_step_rank,
+            layer_norm_epsilon: basic_config.layer_norm_epsilon,
+            use_bias: basic_config.use_bias,
+            use_conv_bias: basic_config.use_conv_bias,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+            // There is no positional limit, this only bounds the scheduler.
+            max_seq_len: basic_config.max_position_embeddings.unwrap_or(1 << 20),
+        })
+    }
+}
+
+pub struct MambaLoader;
+
+impl NormalModelLoader for MambaLoader {
+    fn load(
+        &self,
+        config: &str,
+        _use_flash_attn: bool,
+        vb: VarBuilder,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::mamba::Model::new(
+            &MambaBasicConfig::deserialize(config)?,
+            vb,
+            mapper,
+            loading_isq,
+            device,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[(String, LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _mapper: DeviceMapMetadata,
+        _loading_isq: bool,
+        _device: Device,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA and LoRA adapters are not supported for Mamba models.")
+    }
+    fn is_gptx(&self) -> bool {
+        false
+    }
+    fn get_config_repr(&self, config: &str, _use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(MambaBasicConfig::deserialize(config)?))
+    }
+}
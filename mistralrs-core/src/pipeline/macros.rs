@@ -1,60 +1,69 @@
 #[macro_export]
 macro_rules! api_dir_list {
     ($api:expr, $model_id:expr) => {
-        $api.info()
-            .map(|repo| {
-                repo.siblings
-                    .iter()
-                    .map(|x| x.rfilename.clone())
-                    .collect::<Vec<String>>()
-            })
-            .unwrap_or_else(|e| {
-                // If we do not get a 404, it was something else.
-                let format = format!("{e:?}");
-                if let hf_hub::api::sync::ApiError::RequestError(resp) = e {
-                    if resp.into_response().is_some_and(|r| r.status() != 404) {
-                        panic!("{format}");
+        match &$api {
+            $crate::pipeline::ModelRepo::Local(repo) => repo.list(),
+            $crate::pipeline::ModelRepo::Hub(api) => api
+                .info()
+                .map(|repo| {
+                    repo.siblings
+                        .iter()
+                        .map(|x| x.rfilename.clone())
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_else(|e| {
+                    // If we do not get a 404, it was something else.
+                    let format = format!("{e:?}");
+                    if let hf_hub::api::sync::ApiError::RequestError(resp) = e {
+                        if resp.into_response().is_some_and(|r| r.status() != 404) {
+                            panic!("{format}");
+                        }
                     }
-                }
 
-                let listing = std::fs::read_dir($model_id);
-                if listing.is_err() {
-                    panic!("Cannot list directory {:?}", $model_id)
-                }
-                let listing = listing.unwrap();
-                listing
-                    .into_iter()
-                    .map(|s| {
-                        s.unwrap()
-                            .path()
-                            .to_str()
-                            .expect("Could not convert to str")
-                            .to_string()
-                    })
-                    .collect::<Vec<String>>()
-            })
-            .into_iter()
+                    let listing = std::fs::read_dir($model_id);
+                    if listing.is_err() {
+                        panic!("Cannot list directory {:?}", $model_id)
+                    }
+                    let listing = listing.unwrap();
+                    listing
+                        .into_iter()
+                        .map(|s| {
+                            s.unwrap()
+                                .path()
+                                .to_str()
+                                .expect("Could not convert to str")
+                                .to_string()
+                        })
+                        .collect::<Vec<String>>()
+                }),
+        }
+        .into_iter()
     };
 }
 
 #[macro_export]
 macro_rules! api_get_file {
     ($api:expr, $file:expr, $model_id:expr) => {
-        $api.get($file).unwrap_or_else(|e| {
-            // If we do not get a 404, it was something else.
-            let format = format!("{e:?}");
-            if let hf_hub::api::sync::ApiError::RequestError(resp) = e {
-                if resp.into_response().is_some_and(|r| r.status() != 404) {
-                    panic!("{format}");
+        match &$api {
+            $crate::pipeline::ModelRepo::Local(repo) => repo.get($file).unwrap_or_else(|| {
+                panic!("File \"{}\" not found at model id {:?}", $file, $model_id)
+            }),
+            $crate::pipeline::ModelRepo::Hub(api) => api.get($file).unwrap_or_else(|e| {
+                // If we do not get a 404, it was something else.
+                let format = format!("{e:?}");
+                if let hf_hub::api::sync::ApiError::RequestError(resp) = e {
+                    if resp.into_response().is_some_and(|r| r.status() != 404) {
+                        panic!("{format}");
+                    }
                 }
-            }
 
-            let path = $model_id.join($file);
-            if !path.exists() {
-                panic!("File \"{}\" not found at model id {:?}", $file, $model_id)
-            }
-            path
-        })
+                let path = $model_id.join($file);
+                if !path.exists() {
+                    panic!("File \"{}\" not found at model id {:?}", $file, $model_id)
+                }
+                path
+            }),
+        }
     };
 }
 
@@ -130,18 +139,23 @@ macro_rules! deserialize_chat_template {
 
 #[macro_export]
 macro_rules! get_paths {
-    ($path_name:ident, $token_source:expr, $revision:expr, $this:expr, $quantized_model_id:expr, $quantized_filename:expr, $silent:expr) => {{
-        let api = ApiBuilder::new()
-            .with_progress(!$silent)
-            .with_token(Some(get_token($token_source)?))
-            .build()?;
+    ($path_name:ident, $token_source:expr, $revision:expr, $this:expr, $model_id:expr, $quantized_model_id:expr, $quantized_filename:expr, $silent:expr) => {{
         let revision = $revision.unwrap_or("main".to_string());
-        let api = api.repo(Repo::with_revision(
-            $this.model_id.clone(),
-            RepoType::Model,
-            revision.clone(),
-        ));
-        let model_id = std::path::Path::new(&$this.model_id);
+        if $crate::pipeline::is_offline() {
+            let quantized_model_id: &Option<String> = &$quantized_model_id;
+            let quantized_filenames: &Option<Vec<String>> = &$quantized_filename;
+            $crate::pipeline::validate_offline_files($crate::pipeline::OfflineModelFiles {
+                model_id: Some($model_id.as_str()),
+                revision: &revision,
+                tokenizer_json: $this.tokenizer_json.as_deref(),
+                quantized_model_id: quantized_model_id.as_deref(),
+                quantized_filenames: quantized_filenames.as_deref(),
+                xlora_model_id: $this.xlora_model_id.as_deref(),
+                xlora_order: $this.xlora_order.as_ref(),
+            })?;
+        }
+        let api = $crate::pipeline::ModelRepo::new(&$model_id, &revision, $token_source, $silent)?;
+        let model_id = std::path::Path::new(&$model_id);
 
         let tokenizer_filename = if let Some(ref p) = $this.tokenizer_json {
             info!("Using tokenizer.json at `{p}`");
@@ -168,7 +182,7 @@ macro_rules! get_paths {
             xlora_order,
             xlora_config,
         } = get_xlora_paths(
-            $this.model_id.clone(),
+            $model_id.clone(),
             &$this.xlora_model_id,
             &$token_source,
             revision.clone(),
@@ -208,6 +222,23 @@ macro_rules! get_paths {
 #[macro_export]
 macro_rules! normal_model_loader {
     ($paths:expr, $dtype:expr, $default_dtype:expr, $device:expr, $config:expr, $loader:expr, $use_flash_attn:expr, $silent:expr, $mapper:expr, $loading_isq:expr, $real_device:expr) => {{
+        check_mmaped_safetensors(
+            $paths.get_weight_filenames(),
+            &[],
+            $dtype.unwrap_or($default_dtype),
+            |vb| {
+                $loader
+                    .load(
+                        &$config,
+                        $use_flash_attn,
+                        vb,
+                        DeviceMapMetadata::dummy(),
+                        false,
+                        Device::Cpu,
+                    )
+                    .map(|_| ())
+            },
+        )?;
         let vb = from_mmaped_safetensors(
             $paths.get_weight_filenames().to_vec(),
             Vec::new(),
@@ -232,18 +263,40 @@ macro_rules! xlora_model_loader {
     ($paths:expr, $dtype:expr, $default_dtype:expr, $device:expr, $config:expr, $loader:expr, $use_flash_attn:expr, $silent:expr, $mapper:expr, $loading_isq:expr, $real_device:expr) => {{
         let mut safetensors_paths = $paths.get_weight_filenames().iter().collect::<Vec<_>>();
         safetensors_paths.push($paths.get_classifier_path().as_ref().unwrap());
+        let safetensors_paths = safetensors_paths
+            .iter()
+            .map(|x| (*x).to_owned())
+            .collect::<Vec<_>>();
+        let adapter_paths = $paths
+            .get_adapter_filenames()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(_, x)| (*x).to_owned())
+            .collect::<Vec<_>>();
+        check_mmaped_safetensors(
+            &safetensors_paths,
+            &adapter_paths,
+            $dtype.unwrap_or($default_dtype),
+            |vb| {
+                $loader
+                    .load_xlora(
+                        &$config,
+                        $use_flash_attn,
+                        vb,
+                        $paths.get_adapter_configs().as_ref().unwrap(),
+                        Some($paths.get_classifier_config().as_ref().unwrap().clone()),
+                        $paths.get_ordering().as_ref().unwrap().clone(),
+                        DeviceMapMetadata::dummy(),
+                        false,
+                        Device::Cpu,
+                    )
+                    .map(|_| ())
+            },
+        )?;
         let vb = from_mmaped_safetensors(
-            safetensors_paths
-                .iter()
-                .map(|x| (*x).to_owned())
-                .collect::<Vec<_>>(),
-            $paths
-                .get_adapter_filenames()
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|(_, x)| (*x).to_owned())
-                .collect::<Vec<_>>(),
+            safetensors_paths,
+            adapter_paths,
             $dtype.unwrap_or($default_dtype),
             $device,
             $silent,
@@ -268,18 +321,40 @@ macro_rules! lora_model_loader {
     ($paths:expr, $dtype:expr, $default_dtype:expr, $device:expr, $config:expr, $loader:expr, $use_flash_attn:expr, $silent:expr, $mapper:expr, $loading_isq:expr, $real_device:expr) => {{
         let mut safetensors_paths = $paths.get_weight_filenames().iter().collect::<Vec<_>>();
         safetensors_paths.push($paths.get_classifier_path().as_ref().unwrap());
+        let safetensors_paths = safetensors_paths
+            .iter()
+            .map(|x| (*x).to_owned())
+            .collect::<Vec<_>>();
+        let adapter_paths = $paths
+            .get_adapter_filenames()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(_, x)| (*x).to_owned())
+            .collect::<Vec<_>>();
+        check_mmaped_safetensors(
+            &safetensors_paths,
+            &adapter_paths,
+            $dtype.unwrap_or($default_dtype),
+            |vb| {
+                $loader
+                    .load_xlora(
+                        &$config,
+                        $use_flash_attn,
+                        vb,
+                        $paths.get_adapter_configs().as_ref().unwrap(),
+                        None,
+                        $paths.get_ordering().as_ref().unwrap().clone(),
+                        DeviceMapMetadata::dummy(),
+                        false,
+                        Device::Cpu,
+                    )
+                    .map(|_| ())
+            },
+        )?;
         let vb = from_mmaped_safetensors(
-            safetensors_paths
-                .iter()
-                .map(|x| (*x).to_owned())
-                .collect::<Vec<_>>(),
-            $paths
-                .get_adapter_filenames()
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|(_, x)| (*x).to_owned())
-                .collect::<Vec<_>>(),
+            safetensors_paths,
+            adapter_paths,
             $dtype.unwrap_or($default_dtype),
             $device,
             $silent,
@@ -1,13 +1,20 @@
 mod cache_manager;
 mod chat_template;
+mod dummy;
+mod encoder;
 mod ggml;
 mod gguf;
+mod gguf_shards;
+mod gguf_tokenizer;
 mod loaders;
 mod macros;
 mod normal;
+mod offline;
+mod safetensors_index;
 mod sampling;
 use crate::aici::toktree::TokTrie;
 use crate::device_map::DeviceMapper;
+use crate::layers::RopeShift;
 use crate::prefix_cacher::PrefixCacheManager;
 mod sampling_pipeline;
 use crate::{api_dir_list, api_get_file, DeviceMapMetadata};
@@ -15,23 +22,27 @@ use candle_core::quantized::{GgmlDType, QMatMul, QTensor};
 use candle_nn::VarBuilder;
 use chat_template::{apply_chat_template_to, ChatTemplate};
 use core::fmt;
+pub use dummy::DummyWeights;
 use either::Either;
+pub use encoder::{EncoderLoader, EncoderLoaderBuilder, EncoderPipeline, Pooling};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
-use hf_hub::{
-    api::sync::{ApiBuilder, ApiRepo},
-    Repo, RepoType,
-};
+use gguf_shards::gguf_shard_filenames;
 use indexmap::IndexMap;
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 pub use loaders::{
-    GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader,
-    Phi3Loader, Qwen2Loader,
+    DeepSeekLoader, FalconLoader, GemmaLoader, LlamaLoader, MambaLoader, MistralLoader,
+    MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader, Qwen2Loader, Qwen2MoeLoader,
+    Starcoder2Loader,
 };
 use mistralrs_lora::{LoraConfig, Ordering};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
+pub use offline::{is_offline, set_offline};
+pub(crate) use offline::{validate_offline_files, ModelRepo, OfflineModelFiles};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+pub use safetensors_index::set_verify_checksums;
+use safetensors_index::{verify_checksums, SafetensorsShards, SAFETENSORS_INDEX};
 use std::fmt::{Debug, Display};
 use std::path::Path;
 use std::sync::atomic::AtomicUsize;
@@ -45,9 +56,8 @@ use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
 
 use crate::{
-    models::Cache,
+    models::{Cache, CacheKind},
     sequence::Sequence,
-    utils::tokens::get_token,
     xlora_models::{NonGranularState, XLoraConfig},
 };
 
@@ -244,6 +254,8 @@ pub struct GeneralMetadata {
     pub is_xlora: bool,
     pub num_hidden_layers: usize,
     pub eos_tok: Vec<u32>,
+    pub rope_shift: Option<RopeShift>,
+    pub cache_kind: CacheKind,
 }
 
 pub enum CacheInstruction {
@@ -356,6 +368,45 @@ pub trait Pipeline: Send + Sync {
             unk_tok,
         )
     }
+    /// Render the messages so that the final assistant message is left open, to be continued by
+    /// the model. The rendered prompt is cut where the content of that message starts, and the
+    /// content is appended as is, since templates may trim it or add a suffix after it.
+    fn apply_chat_template_continuation(
+        &self,
+        mut messages: Vec<IndexMap<String, String>>,
+    ) -> Result<String> {
+        let prefill = messages
+            .last()
+            .and_then(|message| message.get("content"))
+            .cloned()
+            .unwrap_or_default();
+        let trimmed = prefill.trim();
+        if trimmed.is_empty() {
+            // Nothing to continue, so this is a new assistant turn.
+            messages.pop();
+            return self.apply_chat_template(messages, true);
+        }
+        // The final message starts after the render of the messages before it, so an identical
+        // earlier message is not mistaken for it.
+        let previous = self.apply_chat_template(messages[..messages.len() - 1].to_vec(), false);
+        let rendered = self.apply_chat_template(messages, false)?;
+        let anchor = previous.map_or(0, |previous| {
+            previous
+                .char_indices()
+                .zip(rendered.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(previous.len().min(rendered.len()), |((i, _), _)| i)
+        });
+        let Some(pos) = rendered[anchor..].find(trimmed).map(|pos| anchor + pos) else {
+            anyhow::bail!(
+                "The chat template does not render the content of the final message, so it cannot be continued."
+            );
+        };
+        // Leading whitespace which the template kept is part of the prefill.
+        let leading = &prefill[..prefill.len() - prefill.trim_start().len()];
+        let start = rendered[..pos].strip_suffix(leading).map_or(pos, str::len);
+        Ok(format!("{}{prefill}", &rendered[..start]))
+    }
     fn get_chat_template(&self) -> Arc<ChatTemplate>;
     fn reset_non_granular_state(&self);
     fn get_metadata(&self) -> &GeneralMetadata;
@@ -444,6 +495,10 @@ pub trait NormalModel {
     fn cache(&self) -> &Cache;
     fn max_seq_len(&self) -> usize;
     fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper);
+    /// The RoPE parameters used to move cached keys, if this model supports attention sinks.
+    fn rope_shift(&self) -> Option<RopeShift> {
+        None
+    }
     /// Quantize the model in-situ.
     fn quantize(&mut self, dtype: GgmlDType, device: Device) -> candle_core::Result<()> {
         let (tensors, mapper) = self.get_tensors();
@@ -512,10 +567,11 @@ fn get_prompt_input(
     let mut position_ids = Vec::new();
     for seq in input_seqs.iter() {
         let mut ctxt = seq.get_toks().to_vec();
+        // A sequence resuming from the prefix cache starts after the cached tokens.
         let offset = if let Some((_, offset)) = last_n_context_len {
             offset
         } else {
-            0
+            seq.prefix_cache_len()
         };
         seqlen_offsets.push(offset);
 
@@ -524,30 +580,21 @@ fn get_prompt_input(
             seq.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
             last_n_context_len.map(|(a, _)| a).unwrap_or(1),
         ));
-        position_ids.push(seq.len());
+        position_ids.push(offset + seq.len());
 
         seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
     }
 
     let mut tmp = Vec::new();
-    if last_n_context_len.is_some() {
-        for pos in (0..seqs_tensors.len())
-            .map(|i| {
-                (*seqlen_offsets.get(i).unwrap() as i64
-                    ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>()
-        {
-            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-        }
-    } else {
-        for pos in (0..seqs_tensors.len())
-            .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
-            .collect::<Vec<_>>()
-        {
-            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-        }
+    for pos in (0..seqs_tensors.len())
+        .map(|i| {
+            (*seqlen_offsets.get(i).unwrap() as i64
+                ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+    {
+        tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
     }
     let positions_kernel = Tensor::cat(&tmp, 0)?;
     Ok(InputMetadata {
@@ -576,9 +623,10 @@ fn get_completion_input(
     for seq in input_seqs.iter() {
         let start_pos = seq.get_toks().len().saturating_sub(1);
         let ctxt = seq.get_toks()[start_pos..].to_vec();
-        seqlen_offsets.push(start_pos);
+        seqlen_offsets.push(start_pos - seq.evicted_toks());
         context_lens.push((0, 1));
-        position_ids.push(seq.len());
+        // The KV cache may only hold the sliding window, so use the token count for the position.
+        position_ids.push(seq.get_toks().len());
 
         seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
     }
@@ -728,15 +776,7 @@ fn get_xlora_paths(
     xlora_order: &Option<Ordering>,
 ) -> Result<XLoraPaths> {
     Ok(if let Some(ref xlora_id) = xlora_model_id {
-        let api = ApiBuilder::new()
-            .with_progress(true)
-            .with_token(Some(get_token(token_source)?))
-            .build()?;
-        let api = api.repo(Repo::with_revision(
-            xlora_id.clone(),
-            RepoType::Model,
-            revision,
-        ));
+        let api = ModelRepo::new(xlora_id, &revision, token_source, false)?;
         let model_id = Path::new(&xlora_id);
 
         let xlora_classifier = &api_dir_list!(api, model_id)
@@ -866,32 +906,57 @@ fn get_model_paths(
     revision: String,
     token_source: &TokenSource,
     quantized_model_id: &Option<String>,
-    quantized_filename: &Option<String>,
-    api: &ApiRepo,
+    quantized_filenames: &Option<Vec<String>>,
+    api: &ModelRepo,
     model_id: &Path,
 ) -> Result<Vec<PathBuf>> {
-    match &quantized_filename {
-        Some(name) => match quantized_model_id.as_ref().unwrap().as_str() {
-            "" => Ok(vec![PathBuf::from_str(name).unwrap()]),
+    match &quantized_filenames {
+        Some(names) => match quantized_model_id.as_ref().unwrap().as_str() {
+            "" => Ok(gguf_shard_filenames(names)
+                .into_iter()
+                .map(PathBuf::from)
+                .collect()),
             id => {
-                let qapi = ApiBuilder::new()
-                    .with_progress(true)
-                    .with_token(Some(get_token(token_source)?))
-                    .build()?;
-                let qapi = qapi.repo(Repo::with_revision(
-                    id.to_string(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
+                let qapi = ModelRepo::new(id, &revision, token_source, false)?;
                 let model_id = Path::new(&id);
-                Ok(vec![api_get_file!(qapi, name, model_id)])
+                Ok(gguf_shard_filenames(names)
+                    .iter()
+                    .map(|name| api_get_file!(qapi, name, model_id))
+                    .collect())
             }
         },
         None => {
-            let mut filenames = vec![];
-            for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
-                filenames.push(api_get_file!(api, &rfilename, Path::new("")));
-            }
+            let files = api_dir_list!(api, model_id).collect::<Vec<_>>();
+            // With an index, exactly the shards it references are loaded.
+            let index = files.iter().find(|x| {
+                *x == SAFETENSORS_INDEX || Path::new(x) == model_id.join(SAFETENSORS_INDEX)
+            });
+            let filenames = match index {
+                Some(index) => {
+                    let index_dir = Path::new(index).parent().unwrap_or(Path::new(""));
+                    let shards =
+                        SafetensorsShards::read(&api_get_file!(api, index, Path::new("")))?;
+                    let shard_paths = shards
+                        .shards()
+                        .into_iter()
+                        .map(|shard| {
+                            let rfilename = index_dir.join(&shard).to_string_lossy().to_string();
+                            let path = api_get_file!(api, &rfilename, Path::new(""));
+                            (shard, path)
+                        })
+                        .collect::<Vec<_>>();
+                    shards.check(&shard_paths)?;
+                    shard_paths.into_iter().map(|(_, path)| path).collect()
+                }
+                None => {
+                    let mut filenames = vec![];
+                    for rfilename in files.iter().filter(|x| x.ends_with(".safetensors")) {
+                        filenames.push(api_get_file!(api, rfilename, Path::new("")));
+                    }
+                    filenames
+                }
+            };
+            verify_checksums(&filenames)?;
             Ok(filenames)
         }
     }
@@ -969,4 +1034,75 @@ mod tests {
             assert_eq!(output, expected, "Template number {i}");
         }
     }
+
+    #[test]
+    fn test_dummy_weights() {
+        use candle_core::{Device, Tensor};
+
+        use super::{
+            DummyWeights, Loader, ModelInputs, NormalLoaderBuilder, NormalLoaderType,
+            NormalSpecificConfig, TokenSource,
+        };
+        use crate::DeviceMapMetadata;
+
+        let archs = [
+            NormalLoaderType::Mistral,
+            NormalLoaderType::Gemma,
+            NormalLoaderType::Mixtral,
+            NormalLoaderType::Llama,
+            NormalLoaderType::Phi2,
+            NormalLoaderType::Phi3,
+            NormalLoaderType::Qwen2,
+            NormalLoaderType::Falcon,
+            NormalLoaderType::Starcoder2,
+            NormalLoaderType::Qwen2Moe,
+            NormalLoaderType::DeepSeek,
+            NormalLoaderType::Mamba,
+        ];
+        for arch in archs {
+            let loader = NormalLoaderBuilder::new(
+                NormalSpecificConfig::default(),
+                None,
+                None,
+                Some("tiny".to_string()),
+            )
+            .with_dummy_weights(DummyWeights::Random)
+            .build(arch.clone());
+            let pipeline = loader
+                .load_model(
+                    None,
+                    TokenSource::None,
+                    None,
+                    &Device::Cpu,
+                    true,
+                    DeviceMapMetadata::dummy(),
+                    None,
+                )
+                .unwrap_or_else(|e| panic!("Loading {arch:?}: {e}"));
+            let mut pipeline = pipeline.try_lock().unwrap();
+
+            let toks = [1u32, 72, 101, 108, 108, 111];
+            let positions = (0i64..6).collect::<Vec<_>>();
+            let inputs = ModelInputs {
+                input_ids: Tensor::new(&toks, &Device::Cpu)
+                    .unwrap()
+                    .unsqueeze(0)
+                    .unwrap(),
+                input_ids_full: None,
+                seqlen_offsets: vec![0],
+                seqlen_offsets_full: None,
+                seqlen_offsets_kernel: Tensor::new(positions, &Device::Cpu)
+                    .unwrap()
+                    .unsqueeze(0)
+                    .unwrap(),
+                seqlen_offsets_kernel_full: None,
+                context_lens: vec![(toks.len() - 1, 1)],
+                position_ids: vec![toks.len()],
+            };
+            let logits = pipeline
+                .forward_inputs(inputs)
+                .unwrap_or_else(|e| panic!("Running {arch:?}: {e}"));
+            assert_eq!(logits.dims(), [1, 1, 512], "{arch:?}");
+        }
+    }
 }
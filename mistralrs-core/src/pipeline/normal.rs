@@ -1,20 +1,24 @@
 use super::cache_manager::DefaultCacheManager;
+use super::dummy::{dummy_chat_template, dummy_config, dummy_tokenizer};
 use super::loaders::{
-    GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader,
-    Phi3Loader, Qwen2Loader,
+    DeepSeekLoader, FalconLoader, GemmaLoader, LlamaLoader, MambaLoader, MistralLoader,
+    MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader, Qwen2Loader, Qwen2MoeLoader,
+    Starcoder2Loader,
 };
 use super::{
-    get_model_paths, get_xlora_paths, CacheManager, GeneralMetadata, Loader, ModelInputs,
-    ModelKind, ModelPaths, NormalModel, NormalModelLoader, Pipeline, TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths, CacheManager, DummyWeights, GeneralMetadata, Loader,
+    ModelInputs, ModelKind, ModelPaths, NormalModel, NormalModelLoader, Pipeline, TokenSource,
+    XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
+use crate::layers::RopeScaling;
 use crate::models::Cache;
 use crate::pipeline::chat_template::calculate_eos_tokens;
 use crate::pipeline::{ChatTemplate, SimpleModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::varbuilder_utils::{check_mmaped_safetensors, from_mmaped_safetensors};
 use crate::xlora_models::NonGranularState;
 use crate::{
     deserialize_chat_template, do_sample, get_mut_arcmutex, get_paths, lora_model_loader,
@@ -23,7 +27,6 @@ use crate::{
 use anyhow::Result;
 use candle_core::quantized::GgmlDType;
 use candle_core::{DType, Device, Tensor};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_lora::Ordering;
 use rand_isaac::Isaac64Rng;
 use serde_json::Value;
@@ -59,6 +62,8 @@ pub struct NormalLoader {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    arch: NormalLoaderType,
+    dummy_weights: Option<DummyWeights>,
 }
 
 #[derive(Default)]
@@ -73,6 +78,7 @@ pub struct NormalLoaderBuilder {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    dummy_weights: Option<DummyWeights>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -80,6 +86,23 @@ pub struct NormalLoaderBuilder {
 pub struct NormalSpecificConfig {
     pub use_flash_attn: bool,
     pub repeat_last_n: usize,
+    /// Force this RoPE scaling, overriding the `rope_scaling` in the model config.
+    pub rope_scaling: Option<RopeScaling>,
+}
+
+/// Replace the `rope_scaling` of a model config. If the original length is not specified, the
+/// `max_position_embeddings` of the config is used so that the context is extended by the factor.
+fn override_rope_scaling(config: &str, rope_scaling: &RopeScaling) -> Result<String> {
+    let mut config: Value = serde_json::from_str(config)?;
+    let mut rope_scaling = rope_scaling.clone();
+    if rope_scaling.original_max_position_embeddings.is_none() {
+        rope_scaling.original_max_position_embeddings = config
+            .get("max_position_embeddings")
+            .and_then(Value::as_u64)
+            .and_then(|x| usize::try_from(x).ok());
+    }
+    config["rope_scaling"] = serde_json::to_value(rope_scaling)?;
+    Ok(serde_json::to_string(&config)?)
 }
 
 impl NormalLoaderBuilder {
@@ -143,8 +166,16 @@ impl NormalLoaderBuilder {
         self.with_adapter(xlora_model_id, xlora_order, false, None)
     }
 
-    pub fn build(self, loader: NormalLoaderType) -> Box<dyn Loader> {
-        let loader: Box<dyn NormalModelLoader> = match loader {
+    /// Load the model without a checkpoint: the model ID is a config preset (`tiny` or `small`)
+    /// or a `config.json`, and the weights are initialized as given. Unless a tokenizer is
+    /// specified, a synthetic byte level tokenizer is used. Only plain models can be loaded so.
+    pub fn with_dummy_weights(mut self, dummy_weights: DummyWeights) -> Self {
+        self.dummy_weights = Some(dummy_weights);
+        self
+    }
+
+    pub fn build(self, arch: NormalLoaderType) -> Box<dyn Loader> {
+        let loader: Box<dyn NormalModelLoader> = match arch {
             NormalLoaderType::Mistral => Box::new(MistralLoader),
             NormalLoaderType::Gemma => Box::new(GemmaLoader),
             NormalLoaderType::Llama => Box::new(LlamaLoader),
@@ -152,6 +183,11 @@ impl NormalLoaderBuilder {
             NormalLoaderType::Phi2 => Box::new(Phi2Loader),
             NormalLoaderType::Phi3 => Box::new(Phi3Loader),
             NormalLoaderType::Qwen2 => Box::new(Qwen2Loader),
+            NormalLoaderType::Falcon => Box::new(FalconLoader),
+            NormalLoaderType::Starcoder2 => Box::new(Starcoder2Loader),
+            NormalLoaderType::Qwen2Moe => Box::new(Qwen2MoeLoader),
+            NormalLoaderType::DeepSeek => Box::new(DeepSeekLoader),
+            NormalLoaderType::Mamba => Box::new(MambaLoader),
         };
         Box::new(NormalLoader {
             inner: loader,
@@ -164,6 +200,8 @@ impl NormalLoaderBuilder {
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
             tgt_non_granular_index: self.tgt_non_granular_index,
+            arch,
+            dummy_weights: self.dummy_weights,
         })
     }
 }
@@ -180,18 +218,32 @@ impl Loader for NormalLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
-            SimpleModelPaths,
-            &token_source,
-            revision,
-            self,
-            None,
-            None,
-            silent
-        );
-        let paths = paths?;
+        // With dummy weights, nothing but the config is read.
+        let paths = match self.dummy_weights {
+            Some(_) => None,
+            None => {
+                let paths: anyhow::Result<Box<dyn ModelPaths>> = get_paths!(
+                    SimpleModelPaths,
+                    &token_source,
+                    revision,
+                    self,
+                    self.model_id,
+                    None,
+                    None,
+                    silent
+                );
+                Some(paths?)
+            }
+        };
 
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let config = match paths {
+            Some(ref paths) => std::fs::read_to_string(paths.get_config_filename())?,
+            None => dummy_config(&self.model_id, &self.arch)?,
+        };
+        let config = match &self.config.rope_scaling {
+            Some(rope_scaling) => override_rope_scaling(&config, rope_scaling)?,
+            None => config,
+        };
         let default_dtype = if device.is_cuda() && mapper.is_dummy() {
             DType::BF16
         } else if !mapper.is_dummy() {
@@ -213,38 +265,24 @@ impl Loader for NormalLoader {
         };
 
         let mut is_lora = false;
-        let mut model = match self.kind {
-            ModelKind::QuantizedGGUF => unreachable!(),
-            ModelKind::QuantizedGGML => unreachable!(),
-            ModelKind::Normal => normal_model_loader!(
-                paths,
-                dtype,
-                default_dtype,
-                &load_device,
-                config,
-                self.inner,
-                self.config.use_flash_attn,
-                silent,
-                mapper,
-                in_situ_quant.is_some(),
-                device.clone()
-            ),
-            ModelKind::XLoraNormal => xlora_model_loader!(
-                paths,
-                dtype,
-                default_dtype,
-                &load_device,
-                config,
-                self.inner,
-                self.config.use_flash_attn,
-                silent,
-                mapper,
-                in_situ_quant.is_some(),
-                device.clone()
-            ),
-            ModelKind::LoraNormal => {
-                is_lora = true;
-                lora_model_loader!(
+        let mut model = match paths {
+            Some(ref paths) => match self.kind {
+                ModelKind::QuantizedGGUF => unreachable!(),
+                ModelKind::QuantizedGGML => unreachable!(),
+                ModelKind::Normal => normal_model_loader!(
+                    paths,
+                    dtype,
+                    default_dtype,
+                    &load_device,
+                    config,
+                    self.inner,
+                    self.config.use_flash_attn,
+                    silent,
+                    mapper,
+                    in_situ_quant.is_some(),
+                    device.clone()
+                ),
+                ModelKind::XLoraNormal => xlora_model_loader!(
                     paths,
                     dtype,
                     default_dtype,
@@ -256,22 +294,79 @@ impl Loader for NormalLoader {
                     mapper,
                     in_situ_quant.is_some(),
                     device.clone()
-                )
+                ),
+                ModelKind::LoraNormal => {
+                    is_lora = true;
+                    lora_model_loader!(
+                        paths,
+                        dtype,
+                        default_dtype,
+                        &load_device,
+                        config,
+                        self.inner,
+                        self.config.use_flash_attn,
+                        silent,
+                        mapper,
+                        in_situ_quant.is_some(),
+                        device.clone()
+                    )
+                }
+                ModelKind::XLoraGGUF => unreachable!(),
+                ModelKind::XLoraGGML => unreachable!(),
+                ModelKind::LoraGGUF => unreachable!(),
+                ModelKind::LoraGGML => unreachable!(),
+                ModelKind::Speculative {
+                    target: _,
+                    draft: _,
+                } => unreachable!(),
+            },
+            None => {
+                if !matches!(self.kind, ModelKind::Normal) {
+                    anyhow::bail!("Dummy weights can only be used for plain models.");
+                }
+                let vb = self
+                    .dummy_weights
+                    .unwrap()
+                    .var_builder(dtype.unwrap_or(default_dtype), &load_device);
+                self.inner.load(
+                    &config,
+                    self.config.use_flash_attn,
+                    vb,
+                    mapper,
+                    in_situ_quant.is_some(),
+                    device.clone(),
+                )?
             }
-            ModelKind::XLoraGGUF => unreachable!(),
-            ModelKind::XLoraGGML => unreachable!(),
-            ModelKind::LoraGGUF => unreachable!(),
-            ModelKind::LoraGGML => unreachable!(),
-            ModelKind::Speculative {
-                target: _,
-                draft: _,
-            } => unreachable!(),
         };
 
-        let tokenizer =
-            Tokenizer::from_file(paths.get_tokenizer_filename()).map_err(anyhow::Error::msg)?;
-
-        let (chat_template, gen_conf) = deserialize_chat_template!(paths, self);
+        let (tokenizer, chat_template, gen_conf) = match paths {
+            Some(ref paths) => {
+                let tokenizer = Tokenizer::from_file(paths.get_tokenizer_filename())
+                    .map_err(anyhow::Error::msg)?;
+                let (chat_template, gen_conf) = deserialize_chat_template!(paths, self);
+                (tokenizer, chat_template, gen_conf)
+            }
+            None => {
+                let config: Value = serde_json::from_str(&config)?;
+                let tokenizer = match self.tokenizer_json {
+                    Some(ref path) => Tokenizer::from_file(path).map_err(anyhow::Error::msg)?,
+                    None => {
+                        let vocab_size = config["vocab_size"]
+                            .as_u64()
+                            .and_then(|v| usize::try_from(v).ok())
+                            .ok_or_else(|| anyhow::Error::msg("The config has no `vocab_size`."))?;
+                        dummy_tokenizer(vocab_size)?
+                    }
+                };
+                let chat_template = dummy_chat_template(
+                    self.chat_template.as_deref(),
+                    &config,
+                    &tokenizer,
+                    self.tokenizer_json.is_none(),
+                )?;
+                (tokenizer, chat_template, None)
+            }
+        };
 
         if let Some(in_situ_quant) = in_situ_quant {
             model.quantize(in_situ_quant, device.clone())?;
@@ -282,6 +377,8 @@ impl Loader for NormalLoader {
         let is_xlora = model.is_xlora() && !is_lora;
         let num_hidden_layers = model.cache().lock().len();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let rope_shift = model.rope_shift();
+        let cache_kind = model.cache().kind();
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -303,6 +400,8 @@ impl Loader for NormalLoader {
                 is_xlora,
                 num_hidden_layers,
                 eos_tok: eos,
+                rope_shift,
+                cache_kind,
             },
         })))
     }
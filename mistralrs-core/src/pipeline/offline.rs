@@ -0,0 +1,344 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use anyhow::Result;
+use hf_hub::{
+    api::sync::{ApiBuilder, ApiRepo},
+    Cache, Repo, RepoType,
+};
+use mistralrs_lora::Ordering;
+use tokenizers::Tokenizer;
+
+use super::gguf_shards::gguf_shard_filenames;
+use super::TokenSource;
+use crate::utils::tokens::get_token;
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Environment variables which enable the offline mode when set to `1`, `true`, `yes` or `on`.
+pub const OFFLINE_ENV_VARS: [&str; 2] = ["MISTRALRS_OFFLINE", "HF_HUB_OFFLINE"];
+
+/// Enable or disable the offline mode. In offline mode, models are only loaded from local
+/// directories or the Hugging Face cache and the network is never used. All files needed to load
+/// a model are checked before loading starts, and any missing files are reported at once.
+///
+/// The offline mode is also enabled by the `MISTRALRS_OFFLINE` or `HF_HUB_OFFLINE` environment
+/// variables.
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, AtomicOrdering::Relaxed);
+}
+
+/// Whether models are loaded in offline mode, see [`set_offline`].
+pub fn is_offline() -> bool {
+    OFFLINE.load(AtomicOrdering::Relaxed)
+        || OFFLINE_ENV_VARS.iter().any(|var| {
+            std::env::var(var)
+                .is_ok_and(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        })
+}
+
+/// A model repository resolved without the network: a local directory, or the snapshot of the
+/// revision of a HF hub repo in the Hugging Face cache.
+pub struct LocalRepo {
+    model_id: String,
+    root: Option<PathBuf>,
+}
+
+impl LocalRepo {
+    pub fn new(model_id: &str, revision: &str) -> Self {
+        let root = if Path::new(model_id).is_dir() {
+            Some(PathBuf::from(model_id))
+        } else {
+            let repo =
+                Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string());
+            let repo_dir = Cache::default().path().join(repo.folder_name());
+            // A revision is either a ref to a commit, or a commit itself.
+            let commit = fs::read_to_string(repo_dir.join("refs").join(revision))
+                .map(|commit| commit.trim().to_string())
+                .unwrap_or(revision.to_string());
+            Some(repo_dir.join("snapshots").join(commit)).filter(|dir| dir.is_dir())
+        };
+        Self {
+            model_id: model_id.to_string(),
+            root,
+        }
+    }
+
+    /// All files of the repo, relative to its root.
+    pub fn list(&self) -> Vec<String> {
+        fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+                let path = entry.path();
+                if path.is_dir() {
+                    walk(&path, &format!("{name}/"), files);
+                } else if path.is_file() {
+                    files.push(name);
+                }
+            }
+        }
+        let mut files = Vec::new();
+        if let Some(ref root) = self.root {
+            walk(root, "", &mut files);
+        }
+        files.sort();
+        files
+    }
+
+    pub fn get(&self, file: &str) -> Option<PathBuf> {
+        Some(self.root.as_ref()?.join(file)).filter(|path| path.is_file())
+    }
+
+    fn location(&self) -> String {
+        match self.root {
+            Some(ref root) => format!("`{}` (at `{}`)", self.model_id, root.display()),
+            None => format!(
+                "`{}` (neither a local directory nor in the Hugging Face cache at `{}`)",
+                self.model_id,
+                Cache::default().path().display()
+            ),
+        }
+    }
+}
+
+/// Where the files of a model repo come from.
+pub enum ModelRepo {
+    Hub(ApiRepo),
+    Local(LocalRepo),
+}
+
+impl ModelRepo {
+    /// The repo of `model_id`. A local directory is used as is, and a HF hub repo is only resolved
+    /// from the Hugging Face cache in offline mode.
+    pub fn new(
+        model_id: &str,
+        revision: &str,
+        token_source: &TokenSource,
+        silent: bool,
+    ) -> Result<Self> {
+        if is_offline() || Path::new(model_id).is_dir() {
+            return Ok(Self::Local(LocalRepo::new(model_id, revision)));
+        }
+        let api = ApiBuilder::new()
+            .with_progress(!silent)
+            .with_token(Some(get_token(token_source)?))
+            .build()?;
+        Ok(Self::Hub(api.repo(Repo::with_revision(
+            model_id.to_string(),
+            RepoType::Model,
+            revision.to_string(),
+        ))))
+    }
+}
+
+/// The files needed to load a model, as given to a loader.
+pub struct OfflineModelFiles<'a> {
+    pub model_id: Option<&'a str>,
+    pub revision: &'a str,
+    pub tokenizer_json: Option<&'a str>,
+    pub quantized_model_id: Option<&'a str>,
+    pub quantized_filenames: Option<&'a [String]>,
+    pub xlora_model_id: Option<&'a str>,
+    pub xlora_order: Option<&'a Ordering>,
+}
+
+/// The problems found when checking the files of a model before loading it offline.
+#[derive(Default)]
+struct OfflineReport {
+    problems: Vec<String>,
+}
+
+impl OfflineReport {
+    fn require(&mut self, repo: &LocalRepo, file: &str, what: &str) -> Option<PathBuf> {
+        let path = repo.get(file);
+        if path.is_none() {
+            self.problems
+                .push(format!("missing {what} `{file}` in {}", repo.location()));
+        }
+        path
+    }
+
+    fn require_matching(
+        &mut self,
+        repo: &LocalRepo,
+        files: &[String],
+        pattern: &str,
+        what: &str,
+    ) -> Vec<String> {
+        let matching = files
+            .iter()
+            .filter(|f| f.contains(pattern))
+            .cloned()
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            self.problems.push(format!(
+                "missing {what} (a file matching `{pattern}`) in {}",
+                repo.location()
+            ));
+        }
+        matching
+    }
+
+    fn check_json(&mut self, path: &Path, what: &str) -> Option<serde_json::Value> {
+        match fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+        {
+            Ok(json) => Some(json),
+            Err(e) => {
+                self.problems
+                    .push(format!("invalid {what} `{}`: {e}", path.display()));
+                None
+            }
+        }
+    }
+}
+
+/// Check, without the network, that all files needed to load a model are present and consistent.
+/// All problems are reported in one error.
+pub fn validate_offline_files(files: OfflineModelFiles<'_>) -> Result<()> {
+    let mut report = OfflineReport::default();
+
+    if let Some(model_id) = files.model_id {
+        let repo = LocalRepo::new(model_id, files.revision);
+        let config = report
+            .require(&repo, "config.json", "model config")
+            .and_then(|path| report.check_json(&path, "model config"));
+        let tokenizer = match files.tokenizer_json {
+            Some(path) if Path::new(path).is_file() => Some(PathBuf::from(path)),
+            Some(path) => {
+                report.problems.push(format!(
+                    "missing tokenizer `{path}` given as `tokenizer_json`"
+                ));
+                None
+            }
+            None => report.require(&repo, "tokenizer.json", "tokenizer"),
+        };
+        if let Some(path) = report.require(&repo, "tokenizer_config.json", "tokenizer config") {
+            report.check_json(&path, "tokenizer config");
+        }
+        if let Some(path) = tokenizer {
+            match Tokenizer::from_file(&path) {
+                Ok(tokenizer) => {
+                    let vocab_size = config
+                        .as_ref()
+                        .and_then(|config| config.get("vocab_size")?.as_u64());
+                    let tokenizer_size = tokenizer.get_vocab_size(true) as u64;
+                    if let Some(vocab_size) = vocab_size.filter(|v| tokenizer_size > *v) {
+                        report.problems.push(format!(
+                            "the tokenizer `{}` has {tokenizer_size} tokens, more than the `vocab_size` of {vocab_size} in the model config",
+                            path.display(),
+                        ));
+                    }
+                }
+                Err(e) => report
+                    .problems
+                    .push(format!("invalid tokenizer `{}`: {e}", path.display())),
+            }
+        }
+
+        if files.quantized_filenames.is_none() {
+            if let Some(index) = repo.get("model.safetensors.index.json") {
+                let weight_map = report
+                    .check_json(&index, "weights index")
+                    .and_then(|index| index.get("weight_map")?.as_object().cloned());
+                if let Some(weight_map) = weight_map {
+                    let mut shards = weight_map
+                        .values()
+                        .filter_map(|shard| shard.as_str())
+                        .collect::<Vec<_>>();
+                    shards.sort();
+                    shards.dedup();
+                    for shard in shards {
+                        report.require(&repo, shard, "weights shard listed in the index");
+                    }
+                }
+            } else if !repo.list().iter().any(|f| f.ends_with(".safetensors")) {
+                // Without an index, the weights are a single `.safetensors` file.
+                report.problems.push(format!(
+                    "missing model weights (`model.safetensors.index.json` or a `.safetensors` file) in {}",
+                    repo.location()
+                ));
+            }
+        }
+    }
+
+    if let Some(quantized_filenames) = files.quantized_filenames {
+        let shards = gguf_shard_filenames(quantized_filenames);
+        match files.quantized_model_id {
+            None | Some("") => {
+                for shard in shards {
+                    if !Path::new(&shard).is_file() {
+                        report
+                            .problems
+                            .push(format!("missing quantized weights `{shard}`"));
+                    }
+                }
+            }
+            Some(id) => {
+                let repo = LocalRepo::new(id, files.revision);
+                for shard in shards {
+                    report.require(&repo, &shard, "quantized weights");
+                }
+            }
+        }
+    }
+
+    if let Some(xlora_model_id) = files.xlora_model_id {
+        let repo = LocalRepo::new(xlora_model_id, files.revision);
+        let repo_files = repo.list();
+        report.require_matching(
+            &repo,
+            &repo_files,
+            "xlora_classifier.safetensors",
+            "X-LoRA classifier",
+        );
+        for config in
+            report.require_matching(&repo, &repo_files, "xlora_config.json", "X-LoRA config")
+        {
+            if let Some(path) = repo.get(&config) {
+                report.check_json(&path, "X-LoRA config");
+            }
+        }
+        let adapters = files
+            .xlora_order
+            .and_then(|order| order.adapters.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        for adapter in adapters {
+            let adapter_files = repo_files
+                .iter()
+                .filter(|f| f.contains(&adapter))
+                .collect::<Vec<_>>();
+            if !adapter_files.iter().any(|f| f.ends_with(".safetensors")) {
+                report.problems.push(format!(
+                    "missing weights of adapter `{adapter}` in {}",
+                    repo.location()
+                ));
+            }
+            if !adapter_files.iter().any(|f| f.ends_with(".json")) {
+                report.problems.push(format!(
+                    "missing config of adapter `{adapter}` in {}",
+                    repo.location()
+                ));
+            }
+        }
+    }
+
+    if report.problems.is_empty() {
+        return Ok(());
+    }
+    let mut msg = format!(
+        "Cannot load the model offline, {} problem(s) were found:",
+        report.problems.len()
+    );
+    for problem in &report.problems {
+        write!(msg, "\n  - {problem}")?;
+    }
+    anyhow::bail!(msg)
+}
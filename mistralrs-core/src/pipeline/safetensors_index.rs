@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use candle_core::safetensors::MmapedSafetensors;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+/// The index of a model whose safetensors weights are split into shards.
+pub(crate) const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+
+static VERIFY_CHECKSUMS: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the verification of the SHA256 of safetensors weights. Weights downloaded
+/// from the HF hub are checked against the hash under which the Hugging Face cache stores them,
+/// other weights are not checked.
+pub fn set_verify_checksums(verify: bool) {
+    VERIFY_CHECKSUMS.store(verify, Ordering::Relaxed);
+}
+
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// The tensors of a safetensors index, mapped to the shard which holds them.
+pub(crate) struct SafetensorsShards {
+    weight_map: HashMap<String, String>,
+}
+
+impl SafetensorsShards {
+    pub(crate) fn read(index: &Path) -> Result<Self> {
+        let SafetensorsIndex { weight_map } = serde_json::from_str(&fs::read_to_string(index)?)
+            .map_err(|e| {
+                anyhow::Error::msg(format!("Invalid weights index `{}`: {e}", index.display()))
+            })?;
+        Ok(Self { weight_map })
+    }
+
+    /// The shards referenced by the index, each once.
+    pub(crate) fn shards(&self) -> Vec<String> {
+        let mut shards = self.weight_map.values().cloned().collect::<Vec<_>>();
+        shards.sort();
+        shards.dedup();
+        shards
+    }
+
+    /// Check that each shard holds exactly the tensors the index maps to it. The shards are
+    /// given with the name under which the index references them.
+    pub(crate) fn check(&self, shards: &[(String, PathBuf)]) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for (shard, path) in shards {
+            let safetensors = unsafe { MmapedSafetensors::new(path)? };
+            for (name, _) in safetensors.tensors() {
+                match self.weight_map.get(&name) {
+                    Some(expected) if expected == shard => {
+                        seen.insert(name);
+                    }
+                    Some(expected) => problems.push(format!(
+                        "tensor `{name}` is in `{shard}`, but the index maps it to `{expected}`"
+                    )),
+                    None => {
+                        problems.push(format!("tensor `{name}` in `{shard}` is not in the index"))
+                    }
+                }
+            }
+        }
+        let mut missing = self
+            .weight_map
+            .iter()
+            .filter(|(name, _)| !seen.contains(*name))
+            .collect::<Vec<_>>();
+        missing.sort();
+        for (name, shard) in missing {
+            problems.push(format!("tensor `{name}` is not in `{shard}`"));
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "The weights do not match `{SAFETENSORS_INDEX}`, {} problem(s) were found:",
+            problems.len()
+        );
+        for problem in problems {
+            write!(msg, "\n  - {problem}")?;
+        }
+        anyhow::bail!(msg)
+    }
+}
+
+/// The SHA256 of a file from the Hugging Face cache: large files are stored as `blobs/<sha256>`,
+/// and the snapshot of a revision links to them.
+fn cached_sha256(path: &Path) -> Option<String> {
+    let blob = fs::canonicalize(path).ok()?;
+    if blob.parent()?.file_name()? != "blobs" {
+        return None;
+    }
+    let hash = blob.file_name()?.to_str()?;
+    (hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())).then(|| hash.to_string())
+}
+
+/// Verify the SHA256 of the weights, if enabled by [`set_verify_checksums`].
+pub(crate) fn verify_checksums(paths: &[PathBuf]) -> Result<()> {
+    if !VERIFY_CHECKSUMS.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    for path in paths {
+        let Some(expected) = cached_sha256(path) else {
+            info!(
+                "No checksum is known for `{}`, it is not verified.",
+                path.display()
+            );
+            continue;
+        };
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != expected {
+            anyhow::bail!(
+                "The SHA256 of `{}` is {actual}, but {expected} was expected. The file may be corrupted, delete it to download it again.",
+                path.display()
+            );
+        }
+        info!("Verified the SHA256 of `{}`.", path.display());
+    }
+    Ok(())
+}
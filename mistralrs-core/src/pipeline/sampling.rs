@@ -24,6 +24,10 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let logits = seq
+        .sampler()
+        .apply_logits_processors(logits, seq.get_toks())?;
+    let repeat_last_n = seq.sampler().repeat_last_n().unwrap_or(repeat_last_n);
     let start_at = seq.get_toks().len().saturating_sub(repeat_last_n);
 
     let sampler = seq.sampler();
@@ -71,6 +75,10 @@ pub async fn sample_sequence(
         None => first_lobprobs_response,
     };
 
+    if let Some(surprise) = second_logprobs_response.mirostat_surprise {
+        seq.sampler().update_mirostat(surprise);
+    }
+
     if add_to_trie {
         match seq.recognizer {
             SequenceRecognizer::Regex(ref mut rx) => {
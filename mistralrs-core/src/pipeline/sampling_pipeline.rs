@@ -2,7 +2,7 @@
 macro_rules! finish_and_add_tokens_to_seq {
     ($this:expr, $prefix_cacher:expr, $seq:expr, $logprobs:expr, $eos_tok:expr, $use_prefix_cacher:expr) => {{
         let is_done = $seq.is_done($logprobs.token, $eos_tok, $this.metadata.max_seq_len);
-        $seq.add_token(
+        let is_done = $seq.add_token(
             $logprobs.clone(),
             $this.get_metadata().tok_trie.decode(&[$logprobs.token]),
             &is_done,
@@ -38,7 +38,6 @@ macro_rules! finish_and_add_tokens_to_seq {
                     if let Some(reason) = is_done {
                         if $use_prefix_cacher {
                             $prefix_cacher.add_sequence($seq);
-                            $prefix_cacher.evict_to_cpu()?;
                         }
                         $seq.set_state($crate::sequence::SequenceState::Done(reason));
                         $this.reset_non_granular_state();
@@ -91,24 +90,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                     None
                 };
 
-                let text = match reason {
-                    $crate::sequence::StopReason::Length(_)
-                    | $crate::sequence::StopReason::ModelLength(_)
-                    | $crate::sequence::StopReason::Eos
-                    | $crate::sequence::StopReason::StopTok(_)
-                    | $crate::sequence::StopReason::Canceled => {
-                        String::from_utf8_lossy($seq.completion_bytes())
-                            .trim_start()
-                            .to_string()
-                    }
-                    $crate::sequence::StopReason::StopString {
-                        completion_bytes_pos,
-                        ..
-                    } => {
-                        let txt = String::from_utf8_lossy($seq.completion_bytes());
-                        txt[..completion_bytes_pos].trim_start().to_string()
-                    }
-                };
+                let text = $seq.output_text(&reason, $seq.completion_bytes());
 
                 if $seq.get_mut_group().is_chat {
                     let choice = $crate::Choice {
@@ -133,7 +115,6 @@ macro_rules! finish_and_add_tokens_to_seq {
 
                 if $use_prefix_cacher {
                     $prefix_cacher.add_sequence($seq);
-                    $prefix_cacher.evict_to_cpu()?;
                 }
 
                 let group = $seq.get_mut_group();
@@ -142,7 +123,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                         .maybe_send_done_response(
                             $crate::ChatCompletionResponse {
                                 id: $seq.id().to_string(),
-                                choices: group.get_choices().to_vec(),
+                                choices: group.get_choices(),
                                 created: $seq.creation_time(),
                                 model: pipeline_name,
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
@@ -158,7 +139,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                         .maybe_send_completion_done_response(
                             $crate::CompletionResponse {
                                 id: $seq.id().to_string(),
-                                choices: group.get_completion_choices().to_vec(),
+                                choices: group.get_completion_choices(),
                                 created: $seq.creation_time(),
                                 model: pipeline_name,
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
@@ -184,9 +165,33 @@ macro_rules! do_sample {
         let logits_seq = $logits.to_device(&Device::Cpu)?.chunk(seqs_len, 0)?;
         debug_assert_eq!(logits_seq.len(), seqs_len);
 
-        let use_async_pool = seqs_len > 1;
+        let eos_tok = if $disable_eos_stop {
+            None
+        } else {
+            Some(&$this.get_metadata().eos_tok[..])
+        };
 
-        let sampling_futures: Vec<_> = std::iter::zip(logits_seq, $seqs.iter_mut())
+        // Beam search sequences are advanced together with the other beams of their group.
+        let mut beam_seqs = Vec::new();
+        let mut beam_logits = Vec::new();
+        let mut sample_seqs = Vec::new();
+        let mut sample_logits = Vec::new();
+        for (logits_per_seq, seq) in std::iter::zip(logits_seq, $seqs.iter_mut()) {
+            if seq.is_beam_search() {
+                beam_seqs.push(&mut **seq);
+                beam_logits.push(logits_per_seq);
+            } else {
+                sample_seqs.push(&mut **seq);
+                sample_logits.push(logits_per_seq);
+            }
+        }
+        if !beam_seqs.is_empty() {
+            $crate::beam_search::step($this, beam_seqs, beam_logits, eos_tok).await?;
+        }
+
+        let use_async_pool = sample_seqs.len() > 1;
+
+        let sampling_futures: Vec<_> = std::iter::zip(sample_logits, sample_seqs.iter_mut())
             .map(|(logits_per_seq, seq)| {
                 let return_logprobs = seq.return_logprobs();
                 $crate::pipeline::sampling::sample_sequence(
@@ -204,15 +209,9 @@ macro_rules! do_sample {
             .collect();
         let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-        for (sampled, seq) in std::iter::zip(sampled_vec, $seqs.iter_mut()) {
+        for (sampled, seq) in std::iter::zip(sampled_vec, sample_seqs.iter_mut()) {
             let next_token = $crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
-            let eos_tok = if $disable_eos_stop {
-                None
-            } else {
-                Some(&$this.get_metadata().eos_tok[..])
-            };
-
             $crate::finish_and_add_tokens_to_seq!(
                 $this,
                 $prefix_cacher,
@@ -1,196 +1,1223 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
 use candle_core::{Device, Result, Tensor};
-use radix_trie::{Trie, TrieCommon, TrieKey};
+use serde::Serialize;
+use tracing::warn;
 
-use crate::{get_mut_arcmutex, models::LayerCaches, sequence::Sequence};
+use crate::{
+    models::{CacheKind, LayerCaches},
+    sequence::Sequence,
+};
 
-#[derive(PartialEq, Eq)]
-struct Tokens(Vec<u32>);
+/// Byte budgets for the tiers of the prefix cache. Caches are demoted from the device to the host and then,
+/// if a directory is given, to disk in least recently used order. Once the last tier is full, caches are dropped.
+#[derive(Debug, Clone)]
+pub struct PrefixCacheBudgets {
+    pub device_bytes: usize,
+    pub host_bytes: usize,
+    /// Directory to spill caches to once the host tier is full.
+    pub disk_dir: Option<PathBuf>,
+    pub disk_bytes: usize,
+}
 
-impl TrieKey for Tokens {
-    fn encode_bytes(&self) -> Vec<u8> {
-        self.0
-            .iter()
-            .flat_map(|x| bytemuck::bytes_of(x).to_vec())
-            .collect::<Vec<u8>>()
+impl Default for PrefixCacheBudgets {
+    fn default() -> Self {
+        Self {
+            device_bytes: 1 << 30,
+            host_bytes: 4 << 30,
+            disk_dir: None,
+            disk_bytes: 16 << 30,
+        }
     }
 }
 
-impl From<Vec<u32>> for Tokens {
-    fn from(value: Vec<u32>) -> Self {
-        Self(value)
+/// A prefix which is pinned in the prefix cache. Its cache is kept on the device and never evicted.
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedPrefix {
+    pub id: usize,
+    pub tokens: usize,
+    /// The number of tokens of the prefix which are in the cache. This is less than `tokens` while the
+    /// prefix is being computed.
+    pub cached_tokens: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Tier {
+    Device,
+    Host,
+    Disk,
+}
+
+#[derive(Clone)]
+struct KvCaches {
+    normal: LayerCaches,
+    xlora: Option<LayerCaches>,
+}
+
+fn map_layers(cache: &LayerCaches, f: impl Fn(&Tensor) -> Result<Tensor>) -> Result<LayerCaches> {
+    cache
+        .iter()
+        .map(|layer| layer.as_ref().map(|(k, v)| Ok((f(k)?, f(v)?))).transpose())
+        .collect()
+}
+
+fn layers_bytes(cache: &LayerCaches) -> usize {
+    cache
+        .iter()
+        .flatten()
+        .map(|(k, v)| {
+            k.elem_count() * k.dtype().size_in_bytes() + v.elem_count() * v.dtype().size_in_bytes()
+        })
+        .sum()
+}
+
+fn cat_layers(caches: &[&LayerCaches]) -> Result<LayerCaches> {
+    (0..caches[0].len())
+        .map(|layer| {
+            let (ks, vs): (Vec<_>, Vec<_>) = caches
+                .iter()
+                .map(|cache| {
+                    cache[layer]
+                        .clone()
+                        .expect("Prefix cache layers are always populated.")
+                })
+                .unzip();
+            Ok(Some((
+                Tensor::cat(&ks, 2)?.contiguous()?,
+                Tensor::cat(&vs, 2)?.contiguous()?,
+            )))
+        })
+        .collect()
+}
+
+impl KvCaches {
+    fn map(&self, f: impl Fn(&Tensor) -> Result<Tensor> + Copy) -> Result<Self> {
+        Ok(Self {
+            normal: map_layers(&self.normal, f)?,
+            xlora: self.xlora.as_ref().map(|c| map_layers(c, f)).transpose()?,
+        })
+    }
+
+    /// Narrow each layer of the cache along the sequence dimension.
+    fn narrow(&self, start: usize, len: usize) -> Result<Self> {
+        self.map(|x| x.narrow(2, start, len)?.contiguous())
+    }
+
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        self.map(|x| x.to_device(device))
+    }
+
+    fn bytes(&self) -> usize {
+        layers_bytes(&self.normal) + self.xlora.as_ref().map_or(0, layers_bytes)
+    }
+
+    fn cat(caches: &[&Self]) -> Result<Self> {
+        let normal = caches.iter().map(|c| &c.normal).collect::<Vec<_>>();
+        let xlora = caches
+            .iter()
+            .map(|c| c.xlora.as_ref())
+            .collect::<Option<Vec<_>>>();
+        Ok(Self {
+            normal: cat_layers(&normal)?,
+            xlora: xlora.map(|c| cat_layers(&c)).transpose()?,
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut tensors = HashMap::new();
+        let caches = [
+            ("normal", Some(&self.normal)),
+            ("xlora", self.xlora.as_ref()),
+        ];
+        for (name, cache) in caches {
+            for (i, layer) in cache.into_iter().flatten().enumerate() {
+                let (k, v) = layer
+                    .as_ref()
+                    .expect("Prefix cache layers are always populated.");
+                tensors.insert(format!("{name}.{i}.k"), k.clone());
+                tensors.insert(format!("{name}.{i}.v"), v.clone());
+            }
+        }
+        candle_core::safetensors::save(&tensors, path)
+    }
+
+    fn load(path: &Path, device: &Device) -> Result<Self> {
+        let mut tensors = candle_core::safetensors::load(path, device)?;
+        let mut take = |name: &str| {
+            let mut cache = Vec::new();
+            while let (Some(k), Some(v)) = (
+                tensors.remove(&format!("{name}.{}.k", cache.len())),
+                tensors.remove(&format!("{name}.{}.v", cache.len())),
+            ) {
+                cache.push(Some((k, v)));
+            }
+            cache
+        };
+        let normal = take("normal");
+        let xlora = take("xlora");
+        Ok(Self {
+            normal,
+            xlora: (!xlora.is_empty()).then_some(xlora),
+        })
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+enum Storage {
+    Memory(KvCaches),
+    Disk(PathBuf),
+}
+
+/// A node of the radix tree. Each node owns the KV cache for the tokens on the edge leading into it,
+/// so sequences which share a prefix also share the nodes (and KV cache) of that prefix.
+struct Node {
+    toks: Vec<u32>,
+    parent: usize,
+    /// Children keyed by the first token of their edge.
+    children: HashMap<u32, usize>,
+    tier: Tier,
+    storage: Storage,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// A snapshot of the recurrent state of a model such as Mamba. Unlike a KV cache, it cannot be narrowed to
+/// a shorter prefix, so each snapshot is kept whole along with exactly the tokens it has consumed.
+struct RecurrentEntry {
+    toks: Vec<u32>,
+    caches: KvCaches,
+    tier: Tier,
+    bytes: usize,
+    last_used: u64,
+}
+
+const ROOT: usize = 0;
 
 pub struct PrefixCacheManager {
-    caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
-    xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    /// Radix tree of token sequences. The root has no tokens.
+    nodes: HashMap<usize, Node>,
+    next_id: usize,
+    /// Logical clock used for LRU ordering.
+    clock: u64,
     device: Device,
-    pub n_on_device: usize,
+    budgets: PrefixCacheBudgets,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    pins: HashMap<usize, Vec<u32>>,
+    next_pin_id: usize,
+    cache_kind: CacheKind,
+    /// Snapshots of recurrent states, used instead of the radix tree for recurrent caches. They are not
+    /// spilled to disk.
+    recurrent: Vec<RecurrentEntry>,
 }
 
 #[derive(Clone)]
 pub struct MatchingCache {
     pub normal: LayerCaches,
     pub xlora: Option<LayerCaches>,
+    /// The tokens which are not covered by the cache and still need to be prefilled.
     pub toks: Vec<u32>,
 }
 
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
 impl PrefixCacheManager {
-    pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+    pub fn new(
+        device: Device,
+        mut budgets: PrefixCacheBudgets,
+        no_prefix_cache: bool,
+        cache_kind: CacheKind,
+    ) -> Self {
+        if let Some(dir) = &budgets.disk_dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                warn!(
+                    "Could not create prefix cache directory `{}`, disabling the disk tier: {e}",
+                    dir.display()
+                );
+                budgets.disk_dir = None;
+            }
+        }
+        let root = Node {
+            toks: Vec::new(),
+            parent: ROOT,
+            children: HashMap::new(),
+            tier: Tier::Device,
+            storage: Storage::Memory(KvCaches {
+                normal: Vec::new(),
+                xlora: None,
+            }),
+            bytes: 0,
+            last_used: 0,
+        };
         PrefixCacheManager {
-            caches: Trie::new(),
-            xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            nodes: HashMap::from([(ROOT, root)]),
+            next_id: ROOT + 1,
+            clock: 0,
             device,
-            n_on_device,
+            budgets,
             no_prefix_cache,
-            eviction_cache_ptrs: Vec::new(),
+            pins: HashMap::new(),
+            next_pin_id: 0,
+            cache_kind,
+            recurrent: Vec::new(),
         }
     }
 
-    /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
-    /// some caches will be evicted.
+    /// Add the cache of a finished sequence. This may demote or drop least recently used caches to stay
+    /// within the budgets.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
-        if self.no_prefix_cache {
+        // A cache with evicted tokens no longer corresponds to the token sequence.
+        if self.no_prefix_cache || seq.evicted_toks() > 0 {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
-                .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
-        } else {
-            self.eviction_cache_ptrs.push((cache, None));
-        }
-    }
-
-    fn cache_to<'a>(
-        cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
-        device: &Device,
-    ) -> Result<()> {
-        for layer in cache {
-            if let Some((ref q, ref k)) = layer {
-                *layer = Some((q.to_device(device)?, k.to_device(device)?));
+        if self.cache_kind == CacheKind::Recurrent {
+            if let Err(e) = self.add_recurrent(seq) {
+                warn!("Failed to add sequence to the prefix cache: {e}");
             }
+            return;
+        }
+        let Some(cache_len) = Self::cache_len(seq.cache()) else {
+            return;
+        };
+        // A rolling (sliding window) cache does not start at the first token.
+        if cache_len + 1 < seq.get_toks().len() || cache_len > seq.get_toks().len() {
+            return;
+        }
+        let toks = seq.get_toks()[..cache_len].to_vec();
+        let caches = KvCaches {
+            normal: seq.cache().clone(),
+            xlora: seq.is_xlora().then(|| seq.xlora_cache().clone()),
+        };
+        if caches
+            .xlora
+            .as_ref()
+            .is_some_and(|c| Self::cache_len(c) != Some(cache_len))
+        {
+            return;
+        }
+        if let Err(e) = self
+            .insert(&toks, &caches)
+            .and_then(|_| self.enforce_budgets())
+        {
+            warn!("Failed to add sequence to the prefix cache: {e}");
         }
-        Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
-    /// the maximum allowed. Returns the number of evicted sequences.
-    pub fn evict_to_cpu(&mut self) -> Result<usize> {
-        if self.no_prefix_cache {
-            return Ok(0);
+    /// The recurrent state has consumed every token but the last sampled one, which is what it is keyed by.
+    fn add_recurrent(&mut self, seq: &mut Sequence) -> Result<()> {
+        if seq.cache().is_empty() || seq.cache().iter().any(Option::is_none) {
+            return Ok(());
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
+        let toks = seq.get_toks();
+        let toks = toks[..toks.len().saturating_sub(1)].to_vec();
+        if toks.is_empty() {
+            return Ok(());
+        }
+        let caches = KvCaches {
+            normal: seq.cache().clone(),
+            xlora: seq.is_xlora().then(|| seq.xlora_cache().clone()),
+        };
+        self.clock += 1;
+        self.recurrent.retain(|entry| entry.toks != toks);
+        self.recurrent.push(RecurrentEntry {
+            toks,
+            bytes: caches.bytes(),
+            caches,
+            tier: Tier::Device,
+            last_used: self.clock,
+        });
+        self.enforce_budgets()
+    }
+
+    fn is_pinned_recurrent(&self, entry: &RecurrentEntry) -> bool {
+        self.pins.values().any(|toks| toks.starts_with(&entry.toks))
+    }
+
+    /// The least recently used recurrent snapshot in a tier which is not pinned.
+    fn lru_recurrent(&self, tier: Tier) -> Option<usize> {
+        self.recurrent
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.tier == tier && !self.is_pinned_recurrent(entry))
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(idx, _)| idx)
+    }
+
+    fn move_recurrent_to(&mut self, idx: usize, tier: Tier) -> Result<()> {
+        let device = match tier {
+            Tier::Device => self.device.clone(),
+            Tier::Host | Tier::Disk => Device::Cpu,
+        };
+        let entry = &mut self.recurrent[idx];
+        if entry.tier != tier {
+            entry.caches = entry.caches.to_device(&device)?;
+            entry.tier = tier;
+        }
+        Ok(())
+    }
+
+    fn enforce_recurrent_budgets(&mut self) -> Result<()> {
+        for (tier, budget) in [
+            (Tier::Device, self.budgets.device_bytes),
+            (Tier::Host, self.budgets.host_bytes),
+        ] {
+            while self
+                .recurrent
+                .iter()
+                .filter(|entry| entry.tier == tier)
+                .map(|entry| entry.bytes)
+                .sum::<usize>()
+                > budget
+            {
+                let Some(idx) = self.lru_recurrent(tier) else {
+                    break;
+                };
+                if tier == Tier::Device {
+                    self.move_recurrent_to(idx, Tier::Host)?;
+                } else {
+                    self.recurrent.swap_remove(idx);
+                }
             }
         }
-        let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
+        Ok(())
+    }
+
+    /// The longest recurrent snapshot whose tokens are a strict prefix of some toks.
+    fn longest_recurrent(&self, toks: &[u32]) -> Option<usize> {
+        self.recurrent
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.toks.len() < toks.len() && toks.starts_with(&entry.toks))
+            .max_by_key(|(_, entry)| entry.toks.len())
+            .map(|(idx, _)| idx)
+    }
+
+    fn search_recurrent(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+        let Some(idx) = self.longest_recurrent(toks) else {
+            return Ok(None);
+        };
+        self.clock += 1;
+        self.recurrent[idx].last_used = self.clock;
+        self.move_recurrent_to(idx, Tier::Device)?;
+        let entry = &self.recurrent[idx];
+        let matching = MatchingCache {
+            normal: entry.caches.normal.clone(),
+            xlora: entry.caches.xlora.clone(),
+            toks: toks[entry.toks.len()..].to_vec(),
+        };
+        self.enforce_budgets()?;
+        Ok(Some(matching))
+    }
+
+    /// The number of tokens in the cache, if all layers are populated.
+    fn cache_len(cache: &LayerCaches) -> Option<usize> {
+        if cache.is_empty() || cache.iter().any(Option::is_none) {
+            return None;
+        }
+        cache[0].as_ref().and_then(|(k, _)| k.dim(2).ok())
+    }
+
+    fn node(&self, id: usize) -> &Node {
+        self.nodes.get(&id).expect("Prefix cache node must exist.")
+    }
+
+    fn node_mut(&mut self, id: usize) -> &mut Node {
+        self.nodes
+            .get_mut(&id)
+            .expect("Prefix cache node must exist.")
+    }
+
+    /// Mark a node as used. Its ancestors are used along with it, so each of them is marked as more recently
+    /// used than the node and a node is never evicted before its descendants.
+    fn touch(&mut self, id: usize) {
+        let mut id = id;
+        loop {
+            self.clock += 1;
+            let clock = self.clock;
+            let node = self.node_mut(id);
+            node.last_used = clock;
+            if id == ROOT {
                 break;
             }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
+            id = node.parent;
+        }
+    }
+
+    fn push_node(&mut self, parent: usize, toks: Vec<u32>, caches: KvCaches, tier: Tier) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let first = toks[0];
+        self.nodes.insert(
+            id,
+            Node {
+                toks,
+                parent,
+                children: HashMap::new(),
+                tier,
+                bytes: caches.bytes(),
+                storage: Storage::Memory(caches),
+                last_used: 0,
+            },
+        );
+        self.node_mut(parent).children.insert(first, id);
+        self.touch(id);
+        id
+    }
+
+    fn insert(&mut self, toks: &[u32], caches: &KvCaches) -> Result<()> {
+        let mut node = ROOT;
+        let mut pos = 0;
+        while pos < toks.len() {
+            match self.node(node).children.get(&toks[pos]).copied() {
+                Some(child) => {
+                    let common = common_prefix_len(&self.node(child).toks, &toks[pos..]);
+                    if common < self.node(child).toks.len() {
+                        self.split(child, common)?;
+                    }
+                    self.touch(child);
+                    node = child;
+                    pos += common;
+                }
+                None => {
+                    let len = toks.len() - pos;
+                    self.push_node(
+                        node,
+                        toks[pos..].to_vec(),
+                        caches.narrow(pos, len)?,
+                        Tier::Device,
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the edge leading into `id` after `at` tokens. The node keeps the first `at` tokens and a new
+    /// child takes the rest of the tokens along with the original children.
+    fn split(&mut self, id: usize, at: usize) -> Result<()> {
+        if self.node(id).tier == Tier::Disk {
+            self.move_to(id, Tier::Host)?;
+        }
+        let node = self.node(id);
+        let tier = node.tier;
+        let Storage::Memory(caches) = &node.storage else {
+            unreachable!("Node was loaded from disk.");
+        };
+        let len = node.toks.len() - at;
+        let suffix_caches = caches.narrow(at, len)?;
+        let prefix_caches = caches.narrow(0, at)?;
+
+        let node = self.node_mut(id);
+        let suffix_toks = node.toks.split_off(at);
+        let children = std::mem::take(&mut node.children);
+        node.bytes = prefix_caches.bytes();
+        node.storage = Storage::Memory(prefix_caches);
+
+        let suffix = self.push_node(id, suffix_toks, suffix_caches, tier);
+        for child in children.values() {
+            self.node_mut(*child).parent = suffix;
+        }
+        self.node_mut(suffix).children = children;
+        Ok(())
+    }
+
+    fn disk_path(&self, id: usize) -> PathBuf {
+        self.budgets
+            .disk_dir
+            .as_ref()
+            .expect("No prefix cache directory.")
+            .join(format!(
+                "prefix-cache-{}-{id}.safetensors",
+                std::process::id()
+            ))
+    }
+
+    /// Move a node to another tier, loading or spilling it if needed.
+    fn move_to(&mut self, id: usize, tier: Tier) -> Result<()> {
+        if self.node(id).tier == tier {
+            return Ok(());
+        }
+        let disk_path = (tier == Tier::Disk).then(|| self.disk_path(id));
+        let device = match tier {
+            Tier::Device => self.device.clone(),
+            Tier::Host | Tier::Disk => Device::Cpu,
+        };
+        let node = self.node_mut(id);
+        let (caches, old_path) = match &node.storage {
+            Storage::Memory(caches) => (caches.to_device(&device)?, None),
+            Storage::Disk(path) => (KvCaches::load(path, &device)?, Some(path.clone())),
+        };
+        node.storage = match disk_path {
+            Some(path) => {
+                caches.save(&path)?;
+                Storage::Disk(path)
+            }
+            None => Storage::Memory(caches),
+        };
+        node.tier = tier;
+        if let Some(path) = old_path {
+            let _ = fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    /// Remove a node and all of its descendants, taking their bytes off the used bytes of their tiers.
+    fn remove(&mut self, id: usize, used: &mut HashMap<Tier, usize>) {
+        let Some(node) = self.nodes.remove(&id) else {
+            return;
+        };
+        if let Storage::Disk(path) = &node.storage {
+            let _ = fs::remove_file(path);
+        }
+        *used.entry(node.tier).or_default() -= node.bytes;
+        if let Some(parent) = self.nodes.get_mut(&node.parent) {
+            parent.children.retain(|_, child| *child != id);
+        }
+        for child in node.children.into_values() {
+            self.remove(child, used);
+        }
+    }
+
+    /// The nodes of a tier which are not pinned, from the least to the most recently used. As a node is
+    /// used along with its ancestors, descendants come before their ancestors.
+    fn lru_order(&self, tier: Tier, pinned: &HashSet<usize>) -> Vec<usize> {
+        let mut nodes = self
+            .nodes
+            .iter()
+            .filter(|(id, node)| **id != ROOT && node.tier == tier && !pinned.contains(*id))
+            .map(|(id, node)| (node.last_used, *id))
+            .collect::<Vec<_>>();
+        nodes.sort_unstable();
+        nodes.into_iter().map(|(_, id)| id).collect()
+    }
 
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
+    /// Demote or drop the least recently used caches until every tier is within its budget.
+    fn enforce_budgets(&mut self) -> Result<()> {
+        if self.cache_kind == CacheKind::Recurrent {
+            return self.enforce_recurrent_budgets();
+        }
+        let pinned = self.pinned_nodes();
+        let mut used = HashMap::<Tier, usize>::new();
+        for node in self.nodes.values() {
+            *used.entry(node.tier).or_default() += node.bytes;
+        }
+        // Without a directory, caches are dropped from the host tier.
+        let disk = self.budgets.disk_dir.as_ref().map(|_| Tier::Disk);
+        for (tier, budget, next) in [
+            (Tier::Device, self.budgets.device_bytes, Some(Tier::Host)),
+            (Tier::Host, self.budgets.host_bytes, disk),
+            (Tier::Disk, self.budgets.disk_bytes, None),
+        ] {
+            for id in self.lru_order(tier, &pinned) {
+                if used.get(&tier).copied().unwrap_or(0) <= budget {
+                    break;
+                }
+                let bytes = self.node(id).bytes;
+                match next {
+                    Some(next) => {
+                        self.move_to(id, next)?;
+                        *used.entry(tier).or_default() -= bytes;
+                        *used.entry(next).or_default() += bytes;
+                    }
+                    None => self.remove(id, &mut used),
                 }
-                n_evicted += 1;
             }
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        Ok(())
     }
 
-    /// Evict all the caches to CPU.
+    /// Evict all the caches which are not pinned from the device. Returns the number of evicted caches.
     pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
+        if self.cache_kind == CacheKind::Recurrent {
+            let on_device = (0..self.recurrent.len())
+                .filter(|idx| {
+                    let entry = &self.recurrent[*idx];
+                    entry.tier == Tier::Device && !self.is_pinned_recurrent(entry)
+                })
+                .collect::<Vec<_>>();
+            for idx in &on_device {
+                self.move_recurrent_to(*idx, Tier::Host)?;
+            }
+            self.enforce_budgets()?;
+            return Ok(on_device.len());
+        }
+        let pinned = self.pinned_nodes();
+        let on_device = self
+            .nodes
+            .iter()
+            .filter(|(id, node)| **id != ROOT && node.tier == Tier::Device && !pinned.contains(*id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in &on_device {
+            self.move_to(*id, Tier::Host)?;
+        }
+        self.enforce_budgets()?;
+        Ok(on_device.len())
+    }
 
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+    /// Walk the longest cached prefix of some toks, returning the nodes along with how many of their
+    /// tokens match.
+    fn walk(&self, toks: &[u32]) -> Vec<(usize, usize)> {
+        let mut path = Vec::new();
+        let mut node = ROOT;
+        let mut pos = 0;
+        while pos < toks.len() {
+            let Some(&child) = self.node(node).children.get(&toks[pos]) else {
+                break;
+            };
+            let common = common_prefix_len(&self.node(child).toks, &toks[pos..]);
+            path.push((child, common));
+            pos += common;
+            if common < self.node(child).toks.len() {
+                break;
             }
+            node = child;
         }
-        Ok(self.caches.len())
+        path
     }
 
-    /// Search for a matching cache given some toks
-    pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+    fn pinned_nodes(&self) -> HashSet<usize> {
+        self.pins
+            .values()
+            .flat_map(|toks| self.walk(toks))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Pin a prefix so that its cache is kept on the device and exempt from eviction. Any part of the prefix
+    /// which is not cached yet is pinned once it is added. Returns the pin id, or `None` if the prefix cache
+    /// is disabled.
+    pub fn pin(&mut self, toks: Vec<u32>) -> Result<Option<usize>> {
         if self.no_prefix_cache {
             return Ok(None);
         }
+        for (node, _) in self.walk(&toks) {
+            self.touch(node);
+            self.move_to(node, Tier::Device)?;
+        }
+        for idx in 0..self.recurrent.len() {
+            if toks.starts_with(&self.recurrent[idx].toks) {
+                self.move_recurrent_to(idx, Tier::Device)?;
+            }
+        }
+        let id = self.next_pin_id;
+        self.next_pin_id += 1;
+        self.pins.insert(id, toks);
+        Ok(Some(id))
+    }
 
-        let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
-                Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
-            } else {
-                None
+    /// Unpin a prefix, returning whether it was pinned. Its cache becomes subject to the budgets again.
+    pub fn unpin(&mut self, id: usize) -> bool {
+        if self.pins.remove(&id).is_none() {
+            return false;
+        }
+        if let Err(e) = self.enforce_budgets() {
+            warn!("Failed to evict from the prefix cache: {e}");
+        }
+        true
+    }
+
+    pub fn pinned_prefix(&self, id: usize) -> Option<PinnedPrefix> {
+        self.pins.get(&id).map(|toks| PinnedPrefix {
+            id,
+            tokens: toks.len(),
+            cached_tokens: match self.cache_kind {
+                CacheKind::Kv => self.walk(toks).iter().map(|(_, len)| len).sum(),
+                CacheKind::Recurrent => self
+                    .recurrent
+                    .iter()
+                    .filter(|entry| toks.starts_with(&entry.toks))
+                    .map(|entry| entry.toks.len())
+                    .max()
+                    .unwrap_or(0),
+            },
+        })
+    }
+
+    pub fn pinned_prefixes(&self) -> Vec<PinnedPrefix> {
+        let mut ids = self.pins.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        ids.into_iter()
+            .filter_map(|id| self.pinned_prefix(id))
+            .collect()
+    }
+
+    /// Search for the longest cached prefix of some toks. At least the last token is always left to be prefilled
+    /// so that there are logits to sample from.
+    pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+        if self.no_prefix_cache || toks.is_empty() {
+            return Ok(None);
+        }
+        if self.cache_kind == CacheKind::Recurrent {
+            return self.search_recurrent(toks);
+        }
+
+        let path = self.walk(&toks[..toks.len() - 1]);
+        let pos = path.iter().map(|(_, len)| len).sum::<usize>();
+        if pos == 0 {
+            return Ok(None);
+        }
+
+        // Bring the matched nodes onto the device, as they are now the most recently used.
+        let mut parts = Vec::new();
+        for (id, len) in &path {
+            self.touch(*id);
+            self.move_to(*id, Tier::Device)?;
+            let Storage::Memory(caches) = &self.node(*id).storage else {
+                unreachable!("Node was moved to the device.");
             };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
-            Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
-            }))
-        } else {
-            Ok(None)
+            parts.push(caches.narrow(0, *len)?);
         }
+        let caches = KvCaches::cat(&parts.iter().collect::<Vec<_>>())?;
+        self.enforce_budgets()?;
+
+        Ok(Some(MatchingCache {
+            normal: caches.normal,
+            xlora: caches.xlora,
+            toks: toks[pos..].to_vec(),
+        }))
+    }
+}
+
+impl Drop for PrefixCacheManager {
+    fn drop(&mut self) {
+        for node in self.nodes.values() {
+            if let Storage::Disk(path) = &node.storage {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+mod tests {
+    /// A single layer cache of `toks`, with each token as its key and ten times the token as its value,
+    /// so narrowed and concatenated caches can be checked against their tokens. Each token takes 8 bytes.
+    #[allow(dead_code)]
+    fn caches(toks: &[u32]) -> super::KvCaches {
+        use candle_core::{Device, Tensor};
+
+        let tensor = |scale: f32| {
+            let data = toks.iter().map(|t| *t as f32 * scale).collect::<Vec<_>>();
+            Tensor::from_vec(data, (1, 1, toks.len(), 1), &Device::Cpu).unwrap()
+        };
+        super::KvCaches {
+            normal: vec![Some((tensor(1.), tensor(10.)))],
+            xlora: None,
+        }
+    }
+
+    /// The keys and values of a single layer cache.
+    #[allow(dead_code)]
+    fn contents(cache: &crate::models::LayerCaches) -> (Vec<f32>, Vec<f32>) {
+        let (k, v) = cache[0].as_ref().unwrap();
+        (
+            k.flatten_all().unwrap().to_vec1().unwrap(),
+            v.flatten_all().unwrap().to_vec1().unwrap(),
+        )
+    }
+
+    #[allow(dead_code)]
+    fn node_contents(manager: &super::PrefixCacheManager, id: usize) -> (Vec<f32>, Vec<f32>) {
+        match &manager.node(id).storage {
+            super::Storage::Memory(caches) => contents(&caches.normal),
+            super::Storage::Disk(_) => panic!("Node {id} is on disk."),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn manager(budgets: super::PrefixCacheBudgets) -> super::PrefixCacheManager {
+        use candle_core::Device;
+
+        super::PrefixCacheManager::new(Device::Cpu, budgets, false, crate::models::CacheKind::Kv)
+    }
+
+    /// Insert `toks` and their cache, as adding a finished sequence does.
+    #[allow(dead_code)]
+    fn add(manager: &mut super::PrefixCacheManager, toks: &[u32]) {
+        manager.insert(toks, &caches(toks)).unwrap();
+        manager.enforce_budgets().unwrap();
+    }
+
+    /// The node whose edge ends exactly at the end of `toks`.
+    #[allow(dead_code)]
+    fn node_id(manager: &super::PrefixCacheManager, toks: &[u32]) -> Option<usize> {
+        let path = manager.walk(toks);
+        let (id, len) = *path.last()?;
+        let matched = path.iter().map(|(_, len)| len).sum::<usize>();
+        (matched == toks.len() && len == manager.node(id).toks.len()).then_some(id)
+    }
+
+    #[test]
+    fn test_prefix_cache_shared_prefixes() {
+        use super::{PrefixCacheBudgets, ROOT};
+
+        let mut manager = manager(PrefixCacheBudgets::default());
+        add(&mut manager, &[1, 2, 3, 4]);
+        add(&mut manager, &[1, 2, 5, 6]);
+
+        // Both sequences share the node of their common prefix.
+        assert_eq!(manager.nodes.len(), 4);
+        assert_eq!(manager.node(ROOT).children.len(), 1);
+        let shared = node_id(&manager, &[1, 2]).unwrap();
+        assert_eq!(manager.node(shared).toks, vec![1, 2]);
+        let mut children = manager
+            .node(shared)
+            .children
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        children.sort();
+        assert_eq!(children, vec![3, 5]);
+        for toks in [[1, 2, 3, 4], [1, 2, 5, 6]] {
+            let leaf = node_id(&manager, &toks).unwrap();
+            assert_eq!(manager.node(leaf).parent, shared);
+            assert_eq!(manager.node(leaf).toks, toks[2..].to_vec());
+            assert_eq!(
+                node_contents(&manager, leaf).0,
+                vec![toks[2] as f32, toks[3] as f32]
+            );
+        }
+
+        // Sequences which are already cached, or end on a node, add no nodes.
+        add(&mut manager, &[1, 2, 3, 4]);
+        add(&mut manager, &[1, 2]);
+        assert_eq!(manager.nodes.len(), 4);
+
+        // A sequence ending inside an edge splits it.
+        add(&mut manager, &[1]);
+        assert_eq!(manager.nodes.len(), 5);
+        assert_eq!(manager.node(node_id(&manager, &[1]).unwrap()).toks, vec![1]);
+        assert_eq!(
+            manager.node(node_id(&manager, &[1, 2]).unwrap()).toks,
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_prefix_cache_split() {
+        use super::PrefixCacheBudgets;
+
+        let mut manager = manager(PrefixCacheBudgets::default());
+        add(&mut manager, &[1, 2, 3, 4, 5]);
+        add(&mut manager, &[1, 2, 3, 4, 5, 6, 7]);
+        let prefix = node_id(&manager, &[1, 2, 3, 4, 5]).unwrap();
+        let grandchild = node_id(&manager, &[1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        manager.split(prefix, 2).unwrap();
+        let suffix = node_id(&manager, &[1, 2, 3, 4, 5]).unwrap();
+        assert_ne!(prefix, suffix);
+
+        // The node keeps the first half of the cache and the new child takes the rest.
+        assert_eq!(manager.node(prefix).toks, vec![1, 2]);
+        assert_eq!(
+            node_contents(&manager, prefix),
+            (vec![1., 2.], vec![10., 20.])
+        );
+        assert_eq!(manager.node(prefix).bytes, 16);
+        assert_eq!(manager.node(suffix).toks, vec![3, 4, 5]);
+        assert_eq!(
+            node_contents(&manager, suffix),
+            (vec![3., 4., 5.], vec![30., 40., 50.])
+        );
+        assert_eq!(manager.node(suffix).bytes, 24);
+
+        // The original children move to the new child.
+        assert_eq!(manager.node(suffix).parent, prefix);
+        assert_eq!(
+            manager.node(prefix).children.values().collect::<Vec<_>>(),
+            vec![&suffix]
+        );
+        assert_eq!(manager.node(grandchild).parent, suffix);
+        assert_eq!(
+            node_contents(&manager, grandchild),
+            (vec![6., 7.], vec![60., 70.])
+        );
+    }
+
+    #[test]
+    fn test_prefix_cache_longest_match() {
+        use super::PrefixCacheBudgets;
+
+        let mut manager = manager(PrefixCacheBudgets::default());
+        add(&mut manager, &[1, 2, 3, 4]);
+        add(&mut manager, &[1, 2, 5, 6]);
+        let shared = node_id(&manager, &[1, 2]).unwrap();
+        let leaf = node_id(&manager, &[1, 2, 3, 4]).unwrap();
+
+        // The walk stops inside an edge on a mismatch.
+        assert_eq!(manager.walk(&[1, 2, 3, 9]), vec![(shared, 2), (leaf, 1)]);
+        assert_eq!(manager.walk(&[1, 9]), vec![(shared, 1)]);
+        assert!(manager.walk(&[7, 8]).is_empty());
+
+        let matched = manager
+            .search_for_matching_cache(&[1, 2, 3, 4, 9])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matched.toks, vec![9]);
+        assert_eq!(
+            contents(&matched.normal),
+            (vec![1., 2., 3., 4.], vec![10., 20., 30., 40.])
+        );
+
+        // The last token is always left to be prefilled, so an exact match is narrowed.
+        let matched = manager
+            .search_for_matching_cache(&[1, 2, 3, 4])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matched.toks, vec![4]);
+        assert_eq!(
+            contents(&matched.normal),
+            (vec![1., 2., 3.], vec![10., 20., 30.])
+        );
+        // Searching does not split the matched nodes.
+        assert_eq!(manager.node(leaf).toks, vec![3, 4]);
+
+        let matched = manager
+            .search_for_matching_cache(&[1, 2, 5])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matched.toks, vec![5]);
+        assert_eq!(contents(&matched.normal), (vec![1., 2.], vec![10., 20.]));
+
+        assert!(manager.search_for_matching_cache(&[1]).unwrap().is_none());
+        assert!(manager
+            .search_for_matching_cache(&[7, 8])
+            .unwrap()
+            .is_none());
+        assert!(manager.search_for_matching_cache(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_prefix_cache_demotion() {
+        use super::{PrefixCacheBudgets, Tier};
+
+        // Each tier holds a single two token cache.
+        let mut manager = manager(PrefixCacheBudgets {
+            device_bytes: 16,
+            host_bytes: 16,
+            disk_dir: None,
+            disk_bytes: 0,
+        });
+        add(&mut manager, &[1, 2]);
+        add(&mut manager, &[3, 4]);
+        let first = node_id(&manager, &[1, 2]).unwrap();
+        let second = node_id(&manager, &[3, 4]).unwrap();
+        assert_eq!(manager.node(first).tier, Tier::Host);
+        assert_eq!(manager.node(second).tier, Tier::Device);
+
+        // Without a directory, the least recently used cache is dropped once the host is full.
+        add(&mut manager, &[5, 6]);
+        let third = node_id(&manager, &[5, 6]).unwrap();
+        assert!(node_id(&manager, &[1, 2]).is_none());
+        assert_eq!(manager.node(second).tier, Tier::Host);
+        assert_eq!(manager.node(third).tier, Tier::Device);
+
+        // A hit brings the cache back to the device and demotes the least recently used one.
+        let matched = manager
+            .search_for_matching_cache(&[3, 4, 9])
+            .unwrap()
+            .unwrap();
+        assert_eq!(contents(&matched.normal).0, vec![3., 4.]);
+        assert_eq!(manager.node(second).tier, Tier::Device);
+        assert_eq!(manager.node(third).tier, Tier::Host);
+    }
+
+    #[test]
+    fn test_prefix_cache_lru_order() {
+        use super::{PrefixCacheBudgets, Tier};
+
+        // A shared prefix is used along with its children, so it is demoted after them even if it
+        // was added first.
+        let mut manager = manager(PrefixCacheBudgets {
+            device_bytes: 32,
+            host_bytes: 16,
+            disk_dir: None,
+            disk_bytes: 0,
+        });
+        add(&mut manager, &[1, 2, 3, 4]);
+        add(&mut manager, &[1, 2, 5, 6]);
+        let shared = node_id(&manager, &[1, 2]).unwrap();
+        assert_eq!(manager.node(shared).tier, Tier::Device);
+        assert_eq!(
+            manager.node(node_id(&manager, &[1, 2, 3, 4]).unwrap()).tier,
+            Tier::Host
+        );
+        assert_eq!(
+            manager.node(node_id(&manager, &[1, 2, 5, 6]).unwrap()).tier,
+            Tier::Device
+        );
+    }
+
+    #[test]
+    fn test_prefix_cache_disk() {
+        use super::{PrefixCacheBudgets, Storage, Tier};
+
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs-prefix-cache-{}", std::process::id()));
+        let mut manager = manager(PrefixCacheBudgets {
+            device_bytes: 16,
+            host_bytes: 16,
+            disk_dir: Some(dir.clone()),
+            disk_bytes: 16,
+        });
+        let disk_path =
+            |manager: &super::PrefixCacheManager, id: usize| match &manager.node(id).storage {
+                Storage::Disk(path) => path.clone(),
+                Storage::Memory(_) => panic!("Node {id} is not on disk."),
+            };
+
+        // Caches are demoted from the device to the host and then spilled to disk.
+        add(&mut manager, &[1, 2]);
+        add(&mut manager, &[3, 4]);
+        add(&mut manager, &[5, 6]);
+        let first = node_id(&manager, &[1, 2]).unwrap();
+        let second = node_id(&manager, &[3, 4]).unwrap();
+        let third = node_id(&manager, &[5, 6]).unwrap();
+        assert_eq!(manager.node(first).tier, Tier::Disk);
+        assert_eq!(manager.node(second).tier, Tier::Host);
+        assert_eq!(manager.node(third).tier, Tier::Device);
+        let first_path = disk_path(&manager, first);
+        assert!(first_path.exists());
+
+        // A hit reloads the cache from disk and removes its file.
+        let matched = manager
+            .search_for_matching_cache(&[1, 2, 9])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matched.toks, vec![9]);
+        assert_eq!(contents(&matched.normal), (vec![1., 2.], vec![10., 20.]));
+        assert_eq!(manager.node(first).tier, Tier::Device);
+        assert!(!first_path.exists());
+        assert_eq!(manager.node(third).tier, Tier::Host);
+        assert_eq!(manager.node(second).tier, Tier::Disk);
+        let second_path = disk_path(&manager, second);
+
+        // Once every tier is full, the least recently used cache is dropped along with its file.
+        add(&mut manager, &[7, 8]);
+        assert!(node_id(&manager, &[3, 4]).is_none());
+        assert!(!second_path.exists());
+        assert_eq!(
+            manager.node(node_id(&manager, &[7, 8]).unwrap()).tier,
+            Tier::Device
+        );
+        assert_eq!(manager.node(first).tier, Tier::Host);
+        assert_eq!(manager.node(third).tier, Tier::Disk);
+
+        drop(manager);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_cache_pins() {
+        use super::{PrefixCacheBudgets, Tier};
+
+        let mut manager = manager(PrefixCacheBudgets {
+            device_bytes: 16,
+            host_bytes: 16,
+            disk_dir: None,
+            disk_bytes: 0,
+        });
+        add(&mut manager, &[1, 2]);
+        let pin = manager.pin(vec![1, 2]).unwrap().unwrap();
+        let pinned = node_id(&manager, &[1, 2]).unwrap();
+
+        // The pinned cache stays on the device while newer caches are demoted and dropped.
+        add(&mut manager, &[3, 4]);
+        add(&mut manager, &[5, 6]);
+        assert_eq!(manager.node(pinned).tier, Tier::Device);
+        assert!(node_id(&manager, &[3, 4]).is_none());
+        assert_eq!(
+            manager.node(node_id(&manager, &[5, 6]).unwrap()).tier,
+            Tier::Host
+        );
+        assert_eq!(manager.evict_all_to_cpu().unwrap(), 0);
+        assert_eq!(manager.node(pinned).tier, Tier::Device);
+        assert_eq!(manager.pinned_prefix(pin).unwrap().cached_tokens, 2);
+
+        // A prefix which is not cached yet is pinned once it is added.
+        let later = manager.pin(vec![7, 8, 9]).unwrap().unwrap();
+        assert_eq!(manager.pinned_prefix(later).unwrap().cached_tokens, 0);
+        add(&mut manager, &[7, 8, 9, 10]);
+        assert_eq!(manager.pinned_prefix(later).unwrap().cached_tokens, 3);
+        assert_eq!(
+            manager
+                .node(node_id(&manager, &[7, 8, 9, 10]).unwrap())
+                .tier,
+            Tier::Device
+        );
+
+        // Once unpinned, the caches are subject to the budgets again. The device is over its budget,
+        // so they are demoted and then dropped as the least recently used caches on the host.
+        assert!(manager.unpin(pin));
+        assert!(!manager.unpin(pin));
+        assert!(manager.pinned_prefix(pin).is_none());
+        assert!(node_id(&manager, &[1, 2]).is_none());
+        assert!(manager.unpin(later));
+        assert!(node_id(&manager, &[7, 8, 9, 10]).is_none());
+        assert_eq!(manager.nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_prefix_cache_usage() {
+        use std::sync::Arc;
+
+        use tokenizers::{models::bpe::BPE, Tokenizer};
+        use tokio::sync::Mutex;
+
+        use super::PrefixCacheBudgets;
+        use crate::{
+            response::CompletionChoice,
+            sampler::Sampler,
+            sequence::{Sequence, SequenceGroup, SequenceRecognizer},
+        };
+
+        let mut manager = manager(PrefixCacheBudgets::default());
+        add(&mut manager, &[1, 2, 3, 4]);
+        let prompt = vec![1, 2, 3, 4, 5, 6];
+        let matched = manager.search_for_matching_cache(&prompt).unwrap().unwrap();
+
+        let (responder, _rx) = tokio::sync::mpsc::channel(1);
+        let tokenizer = Arc::new(Tokenizer::new(BPE::default()));
+        let sampler = Sampler::new(Some(1.0), 0, tokenizer, None, None, None, 0, 1.0);
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, false, false, 1)));
+        let seq = Sequence::new_waiting(
+            prompt,
+            0,
+            0,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group.clone(),
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+        )
+        .prefill(matched.normal, matched.xlora, matched.toks);
+        assert_eq!(seq.prefix_cache_len(), 4);
+
+        seq.add_completion_choice_to_group(CompletionChoice {
+            finish_reason: "stop".to_string(),
+            index: 0,
+            text: String::new(),
+            logprobs: None,
+        });
+        let usage = group.try_lock().unwrap().get_usage();
+        assert_eq!(usage.prompt_tokens, 6);
+        assert_eq!(usage.cached_prompt_tokens, 4);
     }
 }
@@ -1,7 +1,13 @@
+use either::Either;
 use indexmap::IndexMap;
 
-use crate::{response::Response, sampler::SamplingParams};
-use std::fmt::Debug;
+use crate::{
+    logits_processor::LogitsProcessor,
+    prefix_cacher::PinnedPrefix,
+    response::{EmbeddingOutput, RerankOutput, Response},
+    sampler::SamplingParams,
+};
+use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
@@ -12,14 +18,60 @@ pub enum Constraint {
     None,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+/// A part of the content of a chat message.
+pub enum ContentPart {
+    Text(String),
+    ImageUrl(String),
+}
+
+/// The content of a chat message: a string, or an array of [`ContentPart`]s.
+pub type MessageContent = Either<String, Vec<ContentPart>>;
+
+/// Flatten chat messages to text for the chat template. The text parts of a message are joined by
+/// newlines, and other parts are rejected as text models cannot process them.
+pub(crate) fn text_messages(
+    messages: Vec<IndexMap<String, MessageContent>>,
+) -> Result<Vec<IndexMap<String, String>>, String> {
+    messages
+        .into_iter()
+        .map(|message| {
+            message
+                .into_iter()
+                .map(|(key, value)| {
+                    let text = match value {
+                        Either::Left(text) => text,
+                        Either::Right(parts) => parts
+                            .into_iter()
+                            .map(|part| match part {
+                                ContentPart::Text(text) => Ok(text),
+                                ContentPart::ImageUrl(_) => Err(
+                                    "Received an image content part, but this model only supports text content parts.".to_string(),
+                                ),
+                            })
+                            .collect::<Result<Vec<_>, _>>()?
+                            .join("\n"),
+                    };
+                    Ok((key, text))
+                })
+                .collect()
+        })
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 /// Message or messages for a [`Request`].
 pub enum RequestMessage {
-    Chat(Vec<IndexMap<String, String>>),
+    Chat(Vec<IndexMap<String, MessageContent>>),
+    /// A chat whose final message, from the assistant, is continued instead of starting a new
+    /// assistant turn. If `echo_prefill`, the content of that message is prepended to the output.
+    ChatContinuation {
+        messages: Vec<IndexMap<String, MessageContent>>,
+        echo_prefill: bool,
+    },
     Completion {
         text: String,
         echo_prompt: bool,
-        best_of: usize,
     },
     CompletionTokens(Vec<u32>),
 }
@@ -36,6 +88,8 @@ pub struct Request {
     pub id: usize,
     pub constraint: Constraint,
     pub suffix: Option<String>,
+    /// Run after the processors of the [`crate::MistralRsBuilder`], before the constraint.
+    pub logits_processors: Option<Vec<Arc<dyn LogitsProcessor>>>,
 }
 
 impl Debug for Request {
@@ -47,3 +101,43 @@ impl Debug for Request {
         )
     }
 }
+
+#[derive(Clone, Debug)]
+/// A prefix to pin in the prefix cache. Chat messages are formatted without a generation prompt.
+pub enum PrefixMessage {
+    Chat(Vec<IndexMap<String, MessageContent>>),
+    Text(String),
+}
+
+/// A request to the Engine to manage pinned prefix cache entries.
+pub enum PrefixCacheRequest {
+    /// Compute the cache for a prefix if needed, and pin it. The response is sent once the cache is ready.
+    Pin {
+        message: PrefixMessage,
+        response: Sender<Result<PinnedPrefix, String>>,
+    },
+    List {
+        response: Sender<Vec<PinnedPrefix>>,
+    },
+    /// Unpin a prefix, responding with whether it was pinned.
+    Unpin {
+        id: usize,
+        response: Sender<bool>,
+    },
+}
+
+/// A request to the encoder engine. The inputs of requests which are waiting together are run in
+/// the same batches.
+pub enum EncoderRequest {
+    /// Embed each input with an embedding model.
+    Embed {
+        inputs: Vec<String>,
+        response: Sender<Result<EmbeddingOutput, String>>,
+    },
+    /// Score the relevance of each document to the query with a cross-encoder.
+    Rerank {
+        query: String,
+        documents: Vec<String>,
+        response: Sender<Result<RerankOutput, String>>,
+    },
+}
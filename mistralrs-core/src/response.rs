@@ -90,6 +90,8 @@ generate_repr!(ChunkChoice);
 pub struct Usage {
     pub completion_tokens: usize,
     pub prompt_tokens: usize,
+    /// Prompt tokens which were served from the prefix cache.
+    pub cached_prompt_tokens: usize,
     pub total_tokens: usize,
     pub avg_tok_per_sec: f32,
     pub avg_prompt_tok_per_sec: f32,
@@ -159,6 +161,20 @@ pub struct CompletionResponse {
 
 generate_repr!(CompletionResponse);
 
+#[derive(Debug, Clone, Serialize)]
+/// The embeddings of the inputs of an [`crate::EncoderRequest::Embed`], in order.
+pub struct EmbeddingOutput {
+    pub embeddings: Vec<Vec<f32>>,
+    pub prompt_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// The relevance scores of the documents of an [`crate::EncoderRequest::Rerank`], in order.
+pub struct RerankOutput {
+    pub scores: Vec<f32>,
+    pub prompt_tokens: usize,
+}
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no suffix or prefix)
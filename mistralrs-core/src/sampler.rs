@@ -1,8 +1,9 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     iter::zip,
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
@@ -13,6 +14,8 @@ use rand_isaac::Isaac64Rng;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+use crate::logits_processor::LogitsProcessor;
+
 #[derive(Clone, Debug)]
 /// Stop sequences or ids.
 pub enum StopTokens {
@@ -29,10 +32,165 @@ pub struct SamplingParams {
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Divide the positive logits and multiply the negative logits of the tokens of the context by
+    /// this, as in HF `transformers`.
+    pub repetition_penalty: Option<f32>,
+    pub dry_params: Option<DrySamplingParams>,
+    /// The number of last tokens which the penalties consider. Defaults to the `repeat_last_n` of
+    /// the model.
+    pub repeat_last_n: Option<usize>,
     pub stop_toks: Option<StopTokens>,
+    /// Stop once the completion matches one of these regexes.
+    pub stop_regexes: Option<Vec<String>>,
+    /// Stop once the completion ends with one of these sequences of token ids.
+    pub stop_token_seqs: Option<Vec<Vec<u32>>>,
+    /// Keep the matched stop string, regex or token sequence in the output.
+    pub include_stop_str_in_output: bool,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub tfs_z: Option<f64>,
+    pub top_a: Option<f64>,
+    pub mirostat: Option<Mirostat>,
+    /// The order of the sampler chain. Defaults to [`SamplerStage::DEFAULT_ORDER`].
+    pub sampler_order: Option<Vec<SamplerStage>>,
     pub n_choices: usize,
+    /// Generate this many candidates, and return the `n_choices` with the highest mean logprob.
+    /// Defaults to `n_choices`.
+    pub best_of: Option<usize>,
+    /// Rank the `best_of` candidates by their mean logprob, or by their total logprob if false, which
+    /// favors shorter candidates. Defaults to true.
+    pub best_of_length_normalize: Option<bool>,
+    pub beam_search: Option<BeamSearchParams>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A stage of the sampler chain, which scales or truncates the logits before sampling.
+pub enum SamplerStage {
+    Temperature,
+    TopK,
+    TopP,
+    MinP,
+    Typical,
+    TailFree,
+    TopA,
+}
+
+impl SamplerStage {
+    pub const DEFAULT_ORDER: [SamplerStage; 7] = [
+        SamplerStage::Temperature,
+        SamplerStage::TopK,
+        SamplerStage::TopP,
+        SamplerStage::MinP,
+        SamplerStage::Typical,
+        SamplerStage::TailFree,
+        SamplerStage::TopA,
+    ];
+}
+
+impl FromStr for SamplerStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "temperature" => Ok(Self::Temperature),
+            "top_k" => Ok(Self::TopK),
+            "top_p" => Ok(Self::TopP),
+            "min_p" => Ok(Self::MinP),
+            "typical" => Ok(Self::Typical),
+            "tail_free" => Ok(Self::TailFree),
+            "top_a" => Ok(Self::TopA),
+            other => Err(format!(
+                "Unknown sampler `{other}`, expected one of `temperature`, `top_k`, `top_p`, `min_p`, `typical`, `tail_free` or `top_a`."
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Mirostat sampling targets a surprise of `tau` bits per token, adapting with learning rate `eta`.
+/// It replaces the final multinomial sampling, after the sampler chain.
+pub enum Mirostat {
+    V1 { tau: f32, eta: f32 },
+    V2 { tau: f32, eta: f32 },
+}
+
+#[derive(Clone, Debug)]
+/// The DRY ("Don't Repeat Yourself") sampler penalizes the tokens which would extend a sequence
+/// that already occurs in the context. A token extending a repeat of length `n >= allowed_length`
+/// is penalized by `multiplier * base ** (n - allowed_length)`. Repeats never extend across a
+/// sequence breaker.
+pub struct DrySamplingParams {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub sequence_breakers: Vec<String>,
+}
+
+impl DrySamplingParams {
+    /// DRY with the given multiplier, and the defaults for the unspecified parameters.
+    pub fn new_with_defaults(
+        multiplier: f32,
+        sequence_breakers: Option<Vec<String>>,
+        base: Option<f32>,
+        allowed_length: Option<usize>,
+    ) -> Self {
+        let default = Self::default();
+        Self {
+            multiplier,
+            base: base.unwrap_or(default.base),
+            allowed_length: allowed_length.unwrap_or(default.allowed_length),
+            sequence_breakers: sequence_breakers.unwrap_or(default.sequence_breakers),
+        }
+    }
+}
+
+impl Default for DrySamplingParams {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.8,
+            base: 1.75,
+            allowed_length: 2,
+            sequence_breakers: ["\n", ":", "\"", "*"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// DRY parameters with the sequence breakers tokenized.
+#[derive(Clone)]
+struct Dry {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    sequence_breakers: HashSet<u32>,
+}
+
+#[derive(Clone, Debug)]
+/// Beam search keeps the `num_beams` most likely sequences at each step, and returns the best
+/// `n_choices` of them, ranked by their cumulative logprob divided by `length ** length_penalty`.
+pub struct BeamSearchParams {
+    pub num_beams: usize,
+    pub length_penalty: f32,
+    /// Stop as soon as `num_beams` hypotheses are finished, instead of when no running beam can
+    /// improve on them.
+    pub early_stopping: bool,
+    /// Never generate an n-gram of this size twice. 0 disables this.
+    pub no_repeat_ngram_size: usize,
+}
+
+impl Default for BeamSearchParams {
+    fn default() -> Self {
+        Self {
+            num_beams: 1,
+            length_penalty: 1.0,
+            early_stopping: false,
+            no_repeat_ngram_size: 0,
+        }
+    }
 }
 
 impl Default for SamplingParams {
@@ -44,10 +202,25 @@ impl Default for SamplingParams {
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            dry_params: None,
+            repeat_last_n: None,
             stop_toks: None,
+            stop_regexes: None,
+            stop_token_seqs: None,
+            include_stop_str_in_output: false,
             max_len: None,
             logits_bias: None,
+            min_p: None,
+            typical_p: None,
+            tfs_z: None,
+            top_a: None,
+            mirostat: None,
+            sampler_order: None,
             n_choices: 1,
+            best_of: None,
+            best_of_length_normalize: None,
+            beam_search: None,
         }
     }
 }
@@ -60,9 +233,57 @@ pub struct Sampler {
     tokenizer: Arc<Tokenizer>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    dry: Option<Dry>,
+    repeat_last_n: Option<usize>,
     logits_bias: Option<Tensor>,
     topk: i64,
     topp: f64,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    top_a: Option<f64>,
+    order: Vec<SamplerStage>,
+    mirostat: Option<Mirostat>,
+    mirostat_mu: MirostatMu,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+}
+
+/// The Mirostat `mu` of a sequence. Cloning copies the state, so that each sequence adapts separately.
+#[derive(Default)]
+struct MirostatMu(Mutex<Option<f32>>);
+
+impl Clone for MirostatMu {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(
+            *self.0.lock().expect("could not lock mirostat mutex"),
+        ))
+    }
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Mask the logits of the tokens which are not kept, always keeping the most likely token.
+fn mask_logits(logits: &mut [f32], probs: &[f32], keep: impl Fn(usize, f32) -> bool) {
+    let best = (0..probs.len())
+        .reduce(|a, b| if probs[b] > probs[a] { b } else { a })
+        .unwrap_or(0);
+    for (tok, logit) in logits.iter_mut().enumerate() {
+        if tok != best && !keep(tok, probs[tok]) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+fn argsort_descending(values: &[f32]) -> Vec<usize> {
+    let mut indices = (0..values.len()).collect::<Vec<_>>();
+    indices.sort_unstable_by(|&i, &j| values[j].partial_cmp(&values[i]).expect("No ordering."));
+    indices
 }
 
 #[pyclass]
@@ -81,6 +302,45 @@ pub struct Logprobs {
     pub logprob: f32,
     pub bytes: String,
     pub top_logprobs: Option<Vec<TopLogprob>>,
+    /// The Mirostat surprise of the token, to adapt `mu` with if the token is kept.
+    #[serde(skip)]
+    pub(crate) mirostat_surprise: Option<f32>,
+}
+
+/// Penalize the tokens which would extend a repeat of the end of the context.
+fn apply_dry_penalty(dry: &Dry, logits: &mut [f32], context: &[u32]) {
+    let Some((&last, earlier)) = context.split_last() else {
+        return;
+    };
+    if dry.sequence_breakers.contains(&last) {
+        return;
+    }
+    // The longest repeat which each token would extend.
+    let mut match_lengths = HashMap::new();
+    for (i, _) in earlier.iter().enumerate().filter(|(_, tok)| **tok == last) {
+        let next = context[i + 1];
+        if dry.sequence_breakers.contains(&next) {
+            continue;
+        }
+        let mut match_length = 1;
+        while match_length <= i {
+            let tok = context[i - match_length];
+            if tok != context[context.len() - match_length - 1]
+                || dry.sequence_breakers.contains(&tok)
+            {
+                break;
+            }
+            match_length += 1;
+        }
+        let longest = match_lengths.entry(next).or_insert(0);
+        *longest = match_length.max(*longest);
+    }
+    for (tok, match_length) in match_lengths {
+        if match_length >= dry.allowed_length {
+            let exponent = (match_length - dry.allowed_length) as f32;
+            logits[tok as usize] -= dry.multiplier * dry.base.powf(exponent);
+        }
+    }
 }
 
 fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
@@ -110,10 +370,104 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repetition_penalty: None,
+            dry: None,
+            repeat_last_n: None,
             logits_bias,
             topk,
             topp,
+            min_p: None,
+            typical_p: None,
+            tfs_z: None,
+            top_a: None,
+            order: SamplerStage::DEFAULT_ORDER.to_vec(),
+            mirostat: None,
+            mirostat_mu: MirostatMu::default(),
+            logits_processors: Vec::new(),
+        }
+    }
+
+    /// Set the truncation samplers other than top-k and top-p.
+    pub fn with_truncation(
+        mut self,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        top_a: Option<f64>,
+    ) -> Self {
+        self.min_p = min_p;
+        self.typical_p = typical_p;
+        self.tfs_z = tfs_z;
+        self.top_a = top_a;
+        self
+    }
+
+    /// Set the order of the sampler chain. Stages which are not listed are not applied.
+    pub fn with_order(mut self, order: Option<Vec<SamplerStage>>) -> Self {
+        if let Some(order) = order {
+            self.order = order;
         }
+        self
+    }
+
+    pub fn with_repetition_penalty(
+        mut self,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+    ) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    /// Enable the DRY sampler. Each sequence breaker is tokenized, and all of its tokens break
+    /// sequences.
+    pub fn with_dry(mut self, params: Option<DrySamplingParams>) -> Result<Self> {
+        self.dry = match params {
+            Some(params) => {
+                let mut sequence_breakers = HashSet::new();
+                for breaker in &params.sequence_breakers {
+                    let encoding = self
+                        .tokenizer
+                        .encode(breaker.as_str(), false)
+                        .map_err(|x| Error::Msg(x.to_string()))?;
+                    sequence_breakers.extend(encoding.get_ids());
+                }
+                Some(Dry {
+                    multiplier: params.multiplier,
+                    base: params.base,
+                    allowed_length: params.allowed_length,
+                    sequence_breakers,
+                })
+            }
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// The number of last tokens which the penalties consider, if set for this request.
+    pub fn repeat_last_n(&self) -> Option<usize> {
+        self.repeat_last_n
+    }
+
+    pub fn with_logits_processors(
+        mut self,
+        logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    ) -> Self {
+        self.logits_processors = logits_processors;
+        self
+    }
+
+    /// Run the custom logits processors in order.
+    pub fn apply_logits_processors(&self, logits: Tensor, toks: &[u32]) -> Result<Tensor> {
+        self.logits_processors
+            .iter()
+            .try_fold(logits, |logits, processor| processor.apply(&logits, toks))
+    }
+
+    pub fn with_mirostat(mut self, mirostat: Option<Mirostat>) -> Self {
+        self.mirostat = mirostat;
+        self
     }
 
     fn get_top_logprobs(
@@ -177,6 +531,7 @@ impl Sampler {
                 .tokenizer
                 .decode(&[next_token], false)
                 .map_err(|x| Error::Msg(x.to_string()))?,
+            mirostat_surprise: None,
         })
     }
 
@@ -239,6 +594,7 @@ impl Sampler {
                 .tokenizer
                 .decode(&[next_token], false)
                 .map_err(|x| Error::Msg(x.to_string()))?,
+            mirostat_surprise: None,
         })
     }
 
@@ -269,57 +625,230 @@ impl Sampler {
                 .tokenizer
                 .decode(&[next_token.try_into().unwrap()], false)
                 .map_err(|x| Error::Msg(x.to_string()))?,
+            mirostat_surprise: None,
         })
     }
 
-    fn sample_topkp(
+    /// Sample after running the logits through the sampler chain, with Mirostat if enabled.
+    fn sample_chain(
         &self,
-        probs: &mut Vec<f32>,
-        top_k: i64,
-        top_p: f32,
+        logits: Tensor,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
-        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices
-            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
-
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val] = 0.0;
-                }
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        for stage in &self.order {
+            self.apply_stage(*stage, &mut logits);
+        }
+        let probs = softmax(&logits);
+        match self.mirostat {
+            Some(mirostat) => self.sample_mirostat(mirostat, probs, return_logprobs, rng),
+            None => {
+                let mut probs = probs;
+                let argsort_indices = argsort_descending(&probs);
+                self.sample_multinomial(&mut probs, argsort_indices, return_logprobs, rng)
             }
         }
+    }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+    /// Apply a stage of the sampler chain. Truncation stages mask tokens by setting their logits to
+    /// negative infinity, and always keep the most likely token.
+    fn apply_stage(&self, stage: SamplerStage, logits: &mut [f32]) {
+        match stage {
+            SamplerStage::Temperature => {
+                if let Some(temperature) = self.temperature {
+                    logits
+                        .iter_mut()
+                        .for_each(|logit| *logit /= temperature as f32);
+                }
+            }
+            SamplerStage::TopK => {
+                if self.topk > 0 && (self.topk as usize) < logits.len() {
+                    let sorted = argsort_descending(logits);
+                    for tok in &sorted[self.topk as usize..] {
+                        logits[*tok] = f32::NEG_INFINITY;
+                    }
+                }
+            }
+            SamplerStage::TopP => {
+                // top-p sampling (or "nucleus sampling") samples from the smallest set of
+                // tokens that exceed probability top_p.
+                let top_p = self.topp as f32;
+                if top_p > 0.0 && top_p < 1.0 {
+                    let probs = softmax(logits);
+                    let mut cumsum = 0.;
+                    for tok in argsort_descending(&probs) {
+                        if cumsum >= top_p {
+                            logits[tok] = f32::NEG_INFINITY;
+                        } else {
+                            cumsum += probs[tok];
+                        }
+                    }
+                }
+            }
+            SamplerStage::MinP => {
+                // Drop tokens less likely than `min_p` times the most likely token.
+                if let Some(min_p) = self.min_p.filter(|p| *p > 0.0) {
+                    let probs = softmax(logits);
+                    let max = probs.iter().copied().fold(0., f32::max);
+                    mask_logits(logits, &probs, |_, p| p >= min_p as f32 * max);
+                }
+            }
+            SamplerStage::TopA => {
+                // Drop tokens less likely than `top_a` times the square of the largest probability.
+                if let Some(top_a) = self.top_a.filter(|a| *a > 0.0) {
+                    let probs = softmax(logits);
+                    let max = probs.iter().copied().fold(0., f32::max);
+                    mask_logits(logits, &probs, |_, p| p >= top_a as f32 * max * max);
+                }
+            }
+            SamplerStage::Typical => {
+                // Locally typical sampling keeps the tokens whose surprise is closest to the
+                // entropy, up to a cumulative probability of `typical_p`.
+                if let Some(typical_p) = self.typical_p.filter(|p| *p > 0.0 && *p < 1.0) {
+                    let probs = softmax(logits);
+                    let entropy = -probs
+                        .iter()
+                        .filter(|p| **p > 0.0)
+                        .map(|p| p * p.ln())
+                        .sum::<f32>();
+                    let mut toks = (0..probs.len())
+                        .filter(|tok| probs[*tok] > 0.0)
+                        .collect::<Vec<_>>();
+                    toks.sort_by(|a, b| {
+                        let a = (-probs[*a].ln() - entropy).abs();
+                        let b = (-probs[*b].ln() - entropy).abs();
+                        a.partial_cmp(&b).expect("No ordering.")
+                    });
+                    let mut keep = vec![false; probs.len()];
+                    let mut cumsum = 0.;
+                    for tok in toks {
+                        keep[tok] = true;
+                        cumsum += probs[tok];
+                        if cumsum >= typical_p as f32 {
+                            break;
+                        }
+                    }
+                    mask_logits(logits, &probs, |tok, _| keep[tok]);
+                }
+            }
+            SamplerStage::TailFree => {
+                // Tail-free sampling cuts the tail where the second derivative of the sorted
+                // probabilities flattens out.
+                if let Some(z) = self.tfs_z.filter(|z| *z > 0.0 && *z < 1.0) {
+                    let probs = softmax(logits);
+                    let sorted = argsort_descending(&probs);
+                    let sorted_probs = sorted.iter().map(|tok| probs[*tok]).collect::<Vec<_>>();
+                    if sorted_probs.len() < 3 {
+                        return;
+                    }
+                    let first = sorted_probs
+                        .windows(2)
+                        .map(|w| w[0] - w[1])
+                        .collect::<Vec<_>>();
+                    let second = first
+                        .windows(2)
+                        .map(|w| (w[0] - w[1]).abs())
+                        .collect::<Vec<_>>();
+                    let total = second.iter().sum::<f32>();
+                    if total <= 0.0 {
+                        return;
+                    }
+                    let mut cumsum = 0.;
+                    let mut keep = sorted.len();
+                    for (i, d) in second.iter().enumerate() {
+                        cumsum += d / total;
+                        if cumsum > z as f32 && i >= 1 {
+                            keep = i;
+                            break;
+                        }
+                    }
+                    for tok in &sorted[keep..] {
+                        logits[*tok] = f32::NEG_INFINITY;
+                    }
+                }
+            }
         }
-        // TOP P
+    }
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+    /// Mirostat adapts the truncation so that the surprise of the sampled tokens approaches `tau`.
+    fn sample_mirostat(
+        &self,
+        mirostat: Mirostat,
+        mut probs: Vec<f32>,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let (Mirostat::V1 { tau, .. } | Mirostat::V2 { tau, .. }) = mirostat;
+        let mu_value = self
+            .mirostat_mu
+            .0
+            .lock()
+            .expect("could not lock mirostat mutex")
+            .unwrap_or(2. * tau);
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+        let sorted = argsort_descending(&probs);
+        let keep = match mirostat {
+            Mirostat::V1 { .. } => {
+                // Estimate the Zipf exponent from the top tokens, and derive the top-k from it.
+                let n_nonzero = sorted.iter().take_while(|tok| probs[**tok] > 0.0).count();
+                let m = 100.min(n_nonzero.saturating_sub(1));
+                let (mut num, mut den) = (0f32, 0f32);
+                for i in 0..m {
+                    let t = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    let b = (probs[sorted[i]] / probs[sorted[i + 1]]).ln();
+                    num += t * b;
+                    den += t * t;
+                }
+                let s_hat = num / den;
+                let epsilon_hat = s_hat - 1.;
+                let n = probs.len() as f32;
+                let k = ((epsilon_hat * 2f32.powf(mu_value)) / (1. - n.powf(-epsilon_hat)))
+                    .powf(1. / s_hat);
+                if k.is_finite() {
+                    (k.round() as usize).clamp(1, probs.len())
+                } else {
+                    probs.len()
+                }
             }
+            Mirostat::V2 { .. } => sorted
+                .iter()
+                .take_while(|tok| -probs[**tok].log2() <= mu_value)
+                .count()
+                .max(1),
+        };
+        for tok in &sorted[keep..] {
+            probs[*tok] = 0.0;
         }
+        let total = probs.iter().sum::<f32>();
+        probs.iter_mut().for_each(|p| *p /= total);
 
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        let mut sampled = self.sample_multinomial(&mut probs, sorted, return_logprobs, rng)?;
+        sampled.mirostat_surprise = Some(-probs[sampled.token as usize].log2());
+        Ok(sampled)
+    }
+
+    /// Adapt the Mirostat `mu` to the surprise of the token which was kept. A token which is
+    /// sampled and then rejected, by a grammar for example, does not move `mu`.
+    pub(crate) fn update_mirostat(&self, surprise: f32) {
+        let Some(Mirostat::V1 { tau, eta } | Mirostat::V2 { tau, eta }) = self.mirostat else {
+            return;
+        };
+        let mut mu = self
+            .mirostat_mu
+            .0
+            .lock()
+            .expect("could not lock mirostat mutex");
+        let mu_value = mu.unwrap_or(2. * tau);
+        *mu = Some(mu_value - eta * (surprise - tau));
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: Option<&[u32]>) -> Result<Tensor> {
-        if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
+        if self.frequency_penalty.is_some()
+            || self.presence_penalty.is_some()
+            || self.repetition_penalty.is_some()
+            || self.dry.is_some()
+        {
             if context.is_none() {
                 bail!("Must specify penalty context.");
             }
@@ -339,16 +868,75 @@ impl Sampler {
                 *logit = *logit
                     - count * frequency_penalty
                     - if count > 0.0 { 1. } else { 0. } * presence_penalty;
+                if let Some(penalty) = self.repetition_penalty.filter(|_| count > 0.0) {
+                    if *logit > 0.0 {
+                        *logit /= penalty;
+                    } else {
+                        *logit *= penalty;
+                    }
+                }
+            }
+
+            if let Some(ref dry) = self.dry {
+                apply_dry_penalty(dry, &mut logits, context);
             }
         }
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    /// The `n` most likely next tokens, most likely first, for beam search. Penalties and the logits
+    /// bias are applied, but not the temperature, top-k or top-p. `banned` tokens are never returned.
+    pub fn top_candidates(
+        &self,
+        logits: Tensor,
+        penalty_ctxt: Option<&[u32]>,
+        n: usize,
+        return_logprobs: bool,
+        banned: &[u32],
+    ) -> Result<Vec<Logprobs>> {
+        let mut logits = self.apply_penalties(logits.to_vec1()?, penalty_ctxt)?;
+        if let Some(ref bias) = self.logits_bias {
+            logits = (logits + bias)?;
+        }
+        let mut probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        for tok in banned {
+            probs[*tok as usize] = 0.0;
+        }
+
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+        } else {
+            None
+        };
+        argsort_indices
+            .into_iter()
+            .take(n)
+            .filter(|tok| probs[*tok] > 0.0)
+            .map(|tok| {
+                Ok(Logprobs {
+                    token: tok as u32,
+                    logprob: probs[tok].log(10.0),
+                    top_logprobs: top_logprobs.clone(),
+                    bytes: self
+                        .tokenizer
+                        .decode(&[tok as u32], false)
+                        .map_err(|x| Error::Msg(x.to_string()))?,
+                    mirostat_surprise: None,
+                })
+            })
+            .collect()
+    }
+
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
-    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// If the temperature is `None`, argmax sampling is used. Otherwise, the logits go through the
+    /// stages of the sampler chain in order, and the token is sampled with Mirostat or multinomial sampling.
+    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, it is skipped.
     /// If `frequency_penalty.is_some()` or `presence_penalty.is_some()`, then `penalty_ctxt` must be provided.
     pub fn sample(
         &self,
@@ -386,19 +974,7 @@ impl Sampler {
         } else {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                    let mut probs: Vec<f32> = probs.to_vec1()?;
-
-                    self.sample_topkp(
-                        &mut probs,
-                        self.topk,
-                        self.topp as f32,
-                        return_logprobs,
-                        rng,
-                    )?
-                }
+                Some(_) => self.sample_chain(logits, return_logprobs, rng)?,
             }
         };
         Ok(next_token)
@@ -461,4 +1037,139 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_dry_penalty() {
+        use super::{apply_dry_penalty, Dry};
+        use std::collections::HashSet;
+
+        let dry = Dry {
+            multiplier: 1.0,
+            base: 2.0,
+            allowed_length: 2,
+            sequence_breakers: HashSet::from([9]),
+        };
+        let mut logits = vec![0f32; 10];
+        // `4` would extend the repeat of `1 2 3`.
+        apply_dry_penalty(&dry, &mut logits, &[1, 2, 3, 4, 1, 2, 3]);
+        assert_eq!(logits[4], -2.0);
+        assert_eq!(logits.iter().filter(|l| **l != 0.0).count(), 1);
+
+        // Repeats do not extend across a sequence breaker.
+        let mut logits = vec![0f32; 10];
+        apply_dry_penalty(&dry, &mut logits, &[9, 3, 4, 9, 3]);
+        assert!(logits.iter().all(|l| *l == 0.0));
+    }
+
+    /// A sampler with a tokenizer which needs no download, for tests which only look at tokens.
+    #[allow(dead_code)]
+    fn local_sampler() -> super::Sampler {
+        use std::sync::Arc;
+        use tokenizers::{models::bpe::BPE, Tokenizer};
+
+        let tokenizer = Arc::new(Tokenizer::new(BPE::default()));
+        super::Sampler::new(Some(1.0), 0, tokenizer, None, None, None, 0, 1.0)
+    }
+
+    /// The tokens left after running the logits of `probs` through the sampler chain.
+    #[allow(dead_code)]
+    fn kept(sampler: &super::Sampler, probs: &[f32]) -> Vec<usize> {
+        let mut logits = probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+        for stage in &sampler.order {
+            sampler.apply_stage(*stage, &mut logits);
+        }
+        (0..logits.len())
+            .filter(|tok| logits[*tok].is_finite())
+            .collect()
+    }
+
+    #[test]
+    fn test_truncation_samplers() {
+        let probs = [0.5, 0.3, 0.15, 0.05];
+        // Min-p keeps tokens at least 0.2 times as likely as the best one.
+        let sampler = local_sampler().with_truncation(Some(0.2), None, None, None);
+        assert_eq!(kept(&sampler, &probs), [0, 1, 2]);
+        // Top-a keeps tokens at least 0.5 times the square of the best probability.
+        let sampler = local_sampler().with_truncation(None, None, None, Some(0.5));
+        assert_eq!(kept(&sampler, &probs), [0, 1, 2]);
+        // Typical sampling keeps the tokens whose surprise is closest to the entropy first.
+        let sampler = local_sampler().with_truncation(None, Some(0.7), None, None);
+        assert_eq!(kept(&sampler, &probs), [0, 1]);
+        // Tail-free sampling cuts where the second derivative of the sorted probabilities has
+        // accumulated past `z`.
+        let sampler = local_sampler().with_truncation(None, None, Some(0.7), None);
+        assert_eq!(kept(&sampler, &[0.4, 0.3, 0.15, 0.1, 0.03, 0.02]), [0, 1]);
+    }
+
+    #[test]
+    fn test_sampler_order() {
+        use super::SamplerStage;
+
+        let probs = [0.5, 0.3, 0.15, 0.05];
+        let sampler = || {
+            let mut sampler = local_sampler().with_truncation(Some(0.2), None, None, None);
+            sampler.temperature = Some(10.0);
+            sampler
+        };
+        // A high temperature flattens the distribution, so min-p keeps every token after it...
+        assert_eq!(kept(&sampler(), &probs), [0, 1, 2, 3]);
+        // ...but not before it.
+        let sampler =
+            sampler().with_order(Some(vec![SamplerStage::MinP, SamplerStage::Temperature]));
+        assert_eq!(kept(&sampler, &probs), [0, 1, 2]);
+        // Stages which are not listed are not applied.
+        let sampler = sampler.with_order(Some(vec![]));
+        assert_eq!(kept(&sampler, &probs), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_mirostat() {
+        use super::Mirostat;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let logits = Tensor::new(&[0.5f32, 0.3, 0.15, 0.05], &Device::Cpu)
+            .unwrap()
+            .log()
+            .unwrap();
+        let sample = |sampler: &super::Sampler| {
+            sampler
+                .sample(logits.clone(), None, false, rng.clone(), false)
+                .unwrap()
+        };
+
+        // Mirostat v2 starts at `mu = 2 * tau` bits, keeping the two tokens of at most 2 bits.
+        let sampler = local_sampler().with_mirostat(Some(Mirostat::V2 { tau: 1.0, eta: 0.5 }));
+        for _ in 0..20 {
+            let sampled = sample(&sampler);
+            assert!(sampled.token < 2);
+            let p = [0.625f32, 0.375][sampled.token as usize];
+            let surprise = sampled.mirostat_surprise.unwrap();
+            assert!((surprise + p.log2()).abs() < 1e-4);
+        }
+        // Sampling alone does not move `mu`, only the surprise of a kept token does.
+        assert_eq!(*sampler.mirostat_mu.0.lock().unwrap(), None);
+        sampler.update_mirostat(3.0);
+        assert_eq!(*sampler.mirostat_mu.0.lock().unwrap(), Some(1.0));
+        for _ in 0..20 {
+            assert_eq!(sample(&sampler).token, 0);
+        }
+
+        // Mirostat v1 derives a top-k from `mu`, and a very low `mu` keeps only the best token.
+        let sampler = local_sampler().with_mirostat(Some(Mirostat::V1 { tau: 1.0, eta: 1.0 }));
+        sampler.update_mirostat(100.0);
+        for _ in 0..20 {
+            let sampled = sample(&sampler);
+            assert_eq!(sampled.token, 0);
+            assert_eq!(sampled.mirostat_surprise, Some(0.0));
+        }
+        // The state is copied when a sampler is cloned, so each sequence adapts separately.
+        let cloned = sampler.clone();
+        cloned.update_mirostat(2.0);
+        assert_eq!(*sampler.mirostat_mu.0.lock().unwrap(), Some(-97.0));
+        assert_eq!(*cloned.mirostat_mu.0.lock().unwrap(), Some(-98.0));
+    }
 }
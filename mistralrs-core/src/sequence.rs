@@ -14,10 +14,13 @@ use crate::{
     CompletionResponse,
 };
 use crate::{
+    beam_search::BeamSearch,
     get_mut_group,
-    models::LayerCaches,
+    layers::RopeShift,
+    models::{CacheKind, LayerCaches},
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    sampler::{BeamSearchParams, Logprobs, Sampler},
+    stop_regex::StopRegexes,
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
@@ -33,6 +36,16 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    StopRegex {
+        stop_regex_idx: usize,
+        completion_bytes_pos: usize,
+        completion_bytes_end: usize,
+    },
+    /// A sequence of stop token ids. Unlike a single stop token, its tokens are in the completion.
+    StopTokSeq {
+        stop_seq_idx: usize,
+        completion_bytes_pos: usize,
+    },
     Canceled,
 }
 
@@ -41,7 +54,10 @@ impl Display for StopReason {
         match self {
             StopReason::Eos => write!(f, "stop"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::StopRegex { .. }
+            | StopReason::StopTokSeq { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
         }
     }
@@ -57,6 +73,15 @@ pub enum SequenceState {
     RunningPrefillPrompt,
 }
 
+/// Attention-sink (StreamingLLM) configuration. Once the KV cache of a sequence holds
+/// `sink_tokens + window` tokens, everything except the first `sink_tokens` and the most
+/// recent tokens is evicted, so generation is not limited by the model's maximum length.
+#[derive(Debug, Clone, Copy)]
+pub struct AttentionSinks {
+    pub sink_tokens: usize,
+    pub window: usize,
+}
+
 pub enum SequenceRecognizer {
     Regex(Box<StackRecognizer<StateID, RecRx>>),
     Cfg(Box<CfgParser>),
@@ -72,17 +97,25 @@ pub struct Sequence {
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
+    stop_regexes: Option<StopRegexes>,
+    stop_token_seqs: Vec<Vec<u32>>,
+    include_stop_str_in_output: bool,
     return_logprobs: bool,
     responder: Sender<Response>,
     response_index: usize,
     creation_time: u64,
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefix_cache_len: usize,
     suffix: Option<String>,
     prefix: Option<String>,
+    continues_message: bool, // The output continues a message, so its leading whitespace is kept
     is_tmp: bool,
+    attention_sinks: Option<AttentionSinks>,
+    forks: Vec<Sequence>, // Sibling choices waiting to be forked from this sequence's prompt cache
 
     // Cache
     scaling_cache: Option<Tensor>,
+    cache_kind: CacheKind,
     cache: LayerCaches,
     draft_cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
@@ -95,9 +128,13 @@ pub struct Sequence {
     last_completion_bytes_len: usize,
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
+    completion_tok_offsets: Vec<usize>, // Where each completion token starts in the completion bytes
+    stop_regex_state: Option<StateID>,
+    stop_match: Option<StopReason>,
     stream_idx: usize,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
+    evicted_toks: usize,       // The number of tokens evicted from the KV cache by attention sinks
 
     // GPU things
     pub prompt_tok_per_sec: f32,
@@ -135,6 +172,7 @@ impl Sequence {
             id,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
+            cache_kind: CacheKind::Kv,
             cache: vec![None; layers],
             draft_cache: vec![None; layers],
             xlora_cache: if is_xlora {
@@ -146,6 +184,9 @@ impl Sequence {
             sampler: sampler.into(),
             stop_tokens,
             stop_strings,
+            stop_regexes: None,
+            stop_token_seqs: Vec::new(),
+            include_stop_str_in_output: false,
             max_len,
             return_logprobs,
             prompt_tok_per_sec: 0.,
@@ -156,17 +197,153 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefix_cache_len: 0,
             suffix,
             prefix,
+            continues_message: false,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
+            completion_tok_offsets: Vec::new(),
+            stop_regex_state: None,
+            stop_match: None,
             stream_idx: 0,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
             scheduling_urgency: 0,
+            attention_sinks: None,
+            evicted_toks: 0,
+            forks: Vec::new(),
+        }
+    }
+
+    pub fn with_attention_sinks(mut self, attention_sinks: Option<AttentionSinks>) -> Self {
+        self.attention_sinks = attention_sinks;
+        self
+    }
+
+    /// The kind of cache the model keeps. A recurrent state does not grow with the sequence and cannot be
+    /// narrowed to a prefix.
+    pub fn with_cache_kind(mut self, cache_kind: CacheKind) -> Self {
+        self.cache_kind = cache_kind;
+        self
+    }
+
+    /// The output continues the final assistant message of a chat. Its leading whitespace is then
+    /// part of the message and is not trimmed, and the prefix (the echoed message) is prepended to
+    /// chat choices as well.
+    pub fn with_continued_message(mut self, continues_message: bool) -> Self {
+        self.continues_message = continues_message;
+        self
+    }
+
+    /// Stop when the completion matches one of `stop_regexes`, or ends with one of `stop_token_seqs`.
+    /// If `include_stop_str_in_output`, the matched stop string, regex or token sequence is kept
+    /// in the output.
+    pub fn with_stop_conditions(
+        mut self,
+        stop_regexes: Option<StopRegexes>,
+        stop_token_seqs: Vec<Vec<u32>>,
+        include_stop_str_in_output: bool,
+    ) -> Self {
+        self.stop_regex_state = stop_regexes.as_ref().map(StopRegexes::start_state);
+        self.stop_regexes = stop_regexes;
+        self.stop_token_seqs = stop_token_seqs;
+        self.include_stop_str_in_output = include_stop_str_in_output;
+        self
+    }
+
+    /// Sibling sequences with the same prompt. Instead of prefilling the prompt themselves, they are
+    /// forked from the KV cache of this sequence once its prompt has been processed.
+    pub fn with_forks(mut self, forks: Vec<Sequence>) -> Self {
+        self.forks = forks;
+        self
+    }
+
+    pub fn take_forks(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.forks)
+    }
+
+    pub fn forks_mut(&mut self) -> &mut [Sequence] {
+        &mut self.forks
+    }
+
+    /// The KV cache of all but the last prompt token, which sibling sequences can be forked from.
+    /// Returns `None` if the cache does not hold exactly the prompt, for example if it was rolled by
+    /// a sliding window, or if it is a recurrent state.
+    pub fn fork_cache(&self) -> Option<(LayerCaches, Option<LayerCaches>)> {
+        if self.cache_kind == CacheKind::Recurrent {
+            return None;
         }
+        let keep = self.prompt_len.checked_sub(1).filter(|keep| *keep > 0)?;
+        let narrow = |cache: &LayerCaches| -> Option<LayerCaches> {
+            cache
+                .iter()
+                .map(|layer| {
+                    let (k, v) = layer.as_ref()?;
+                    if k.dim(2).ok()? != self.prompt_len {
+                        return None;
+                    }
+                    Some(Some((
+                        k.narrow(2, 0, keep).ok()?,
+                        v.narrow(2, 0, keep).ok()?,
+                    )))
+                })
+                .collect()
+        };
+        let xlora_cache = match &self.xlora_cache {
+            Some(cache) => Some(narrow(cache)?),
+            None => None,
+        };
+        Some((narrow(&self.cache)?, xlora_cache))
+    }
+
+    /// Start from a cache made by [`Sequence::fork_cache`], so only the last prompt token is
+    /// processed to sample the first token.
+    pub fn fork_from(self, cache: LayerCaches, xlora_cache: Option<LayerCaches>) -> Self {
+        let last = self.tokens[self.prompt_len - 1];
+        self.prefill(cache, xlora_cache, vec![last])
+    }
+
+    /// The generation state of this beam, to fork other beams from.
+    pub fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            completion_bytes: self.completion_bytes.clone(),
+            completion_tok_offsets: self.completion_tok_offsets.clone(),
+            stop_regex_state: self.stop_regex_state,
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            prefix_cache_len: self.prefix_cache_len,
+            evicted_toks: self.evicted_toks,
+        }
+    }
+
+    /// Continue from the state of another beam of the same group, and add `tok` to it.
+    pub fn set_beam_state(&mut self, state: BeamState, tok: Logprobs, completion_bytes: Vec<u8>) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.completion_bytes = state.completion_bytes;
+        self.completion_tok_offsets = state.completion_tok_offsets;
+        self.stop_regex_state = state.stop_regex_state;
+        self.cache = state.cache;
+        self.xlora_cache = state.xlora_cache;
+        self.prefix_cache_len = state.prefix_cache_len;
+        self.evicted_toks = state.evicted_toks;
+        self.add_token(tok, completion_bytes, &None);
+        self.set_state(SequenceState::RunningCompletion);
+    }
+
+    pub fn is_beam_search(&self) -> bool {
+        get_mut_group!(self).beam_search.is_some()
+    }
+
+    pub fn same_group(&self, other: &Sequence) -> bool {
+        Arc::ptr_eq(&self.group, &other.group)
     }
 
     pub fn add_urgency(mut self) -> Self {
@@ -193,6 +370,7 @@ impl Sequence {
         xlora_cache: Option<LayerCaches>,
         toks: Vec<u32>,
     ) -> Self {
+        self.prefix_cache_len = self.tokens.len() - toks.len();
         self.cache = cache;
         self.xlora_cache = xlora_cache;
         self.prefill_prompt_toks = Some(toks);
@@ -200,12 +378,17 @@ impl Sequence {
         self
     }
 
+    /// The number of prompt tokens which were loaded from the prefix cache instead of being prefilled.
+    pub fn prefix_cache_len(&self) -> usize {
+        self.prefix_cache_len
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
             return toks.len();
         }
-        if self.is_tmp {
+        if self.is_tmp || self.cache_kind == CacheKind::Recurrent {
             return self.tokens.len();
         }
         // Use xlora cache first because of non granular
@@ -246,6 +429,10 @@ impl Sequence {
         *self.state.read().unwrap() == SequenceState::Waiting
     }
 
+    pub fn is_error(&self) -> bool {
+        *self.state.read().unwrap() == SequenceState::Error
+    }
+
     pub fn get_toks(&self) -> &[u32] {
         if let Some(toks) = &self.prefill_prompt_toks {
             return toks;
@@ -277,6 +464,62 @@ impl Sequence {
         self.xlora_cache.is_some()
     }
 
+    /// The number of tokens which were evicted from the KV cache. RoPE positions are relative
+    /// to the KV cache, so this must be subtracted from the token position.
+    pub fn evicted_toks(&self) -> usize {
+        self.evicted_toks
+    }
+
+    /// If attention sinks are enabled and the KV cache is full, evict the tokens between the sink
+    /// tokens and the recent window. The kept recent keys are rotated back so that the positions
+    /// in the KV cache stay contiguous. Returns whether the cache was modified.
+    pub fn evict_to_attention_sinks(
+        &mut self,
+        rope_shift: &RopeShift,
+    ) -> candle_core::Result<bool> {
+        let Some(AttentionSinks {
+            sink_tokens,
+            window,
+        }) = self.attention_sinks
+        else {
+            return Ok(false);
+        };
+        if self.cache_kind == CacheKind::Recurrent {
+            return Ok(false);
+        }
+        let Some((k, _)) = &self.cache[0] else {
+            return Ok(false);
+        };
+        let cache_len = k.dim(2)?;
+        if cache_len < sink_tokens + window {
+            return Ok(false);
+        }
+        // Keep one slot free for the token which is about to be added.
+        let recent = window - 1;
+        let shift = cache_len - sink_tokens - recent;
+
+        let evict = |cache: &mut LayerCaches| -> candle_core::Result<()> {
+            for layer in cache.iter_mut() {
+                if let Some((k, v)) = layer {
+                    let recent_k = k.narrow(2, cache_len - recent, recent)?;
+                    let recent_k = rope_shift.shift_keys(&recent_k, shift)?;
+                    let recent_v = v.narrow(2, cache_len - recent, recent)?;
+                    *layer = Some((
+                        Tensor::cat(&[&k.narrow(2, 0, sink_tokens)?, &recent_k], 2)?,
+                        Tensor::cat(&[&v.narrow(2, 0, sink_tokens)?, &recent_v], 2)?,
+                    ));
+                }
+            }
+            Ok(())
+        };
+        evict(&mut self.cache)?;
+        if let Some(xlora_cache) = &mut self.xlora_cache {
+            evict(xlora_cache)?;
+        }
+        self.evicted_toks += shift;
+        Ok(true)
+    }
+
     pub fn sampler(&mut self) -> Arc<Sampler> {
         self.sampler.clone()
     }
@@ -291,16 +534,20 @@ impl Sequence {
         self.prefill_prompt_toks = None
     }
 
+    /// Add a sampled token, given whether [`Sequence::is_done`] stops the sequence with it. Returns
+    /// the reason to stop the sequence, which is a stop string, regex or token sequence if the
+    /// token completes one.
     pub fn add_token(
         &mut self,
         tok: Logprobs,
         completion_bytes: Vec<u8>,
         is_done: &Option<StopReason>,
-    ) {
+    ) -> Option<StopReason> {
         let stopped_by_token = matches!(
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
+        let prev_len = self.completion_bytes.len();
         if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
@@ -309,12 +556,120 @@ impl Sequence {
             self.last_completion_bytes_len = completion_bytes.len();
         }
         self.last_logprob = tok.logprob;
-        self.last_is_done = *is_done;
 
         self.cumulative_logprob += tok.logprob;
+        self.completion_tok_offsets.push(prev_len);
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+
+        if !stopped_by_token {
+            self.stop_match = self.match_stop_conditions(prev_len);
+        }
+        if self.stop_match.is_none() && is_done.is_some() {
+            self.stop_match = self.match_stop_regex_at_end();
+        }
+        let is_done = self.stop_match.or(*is_done);
+        self.last_is_done = is_done;
+        is_done
+    }
+
+    /// Match the stop strings, regexes and token sequences, given that the completion bytes before
+    /// `prev_len` did not match. Only the new bytes and the bytes which a match may span are searched.
+    fn match_stop_conditions(&mut self, prev_len: usize) -> Option<StopReason> {
+        let completion_toks = &self.tokens[self.prompt_len..];
+        for (idx, seq) in self.stop_token_seqs.iter().enumerate() {
+            if completion_toks.ends_with(seq) {
+                return Some(StopReason::StopTokSeq {
+                    stop_seq_idx: idx,
+                    completion_bytes_pos: self.completion_tok_offsets
+                        [self.completion_tok_offsets.len() - seq.len()],
+                });
+            }
+        }
+
+        let mut stop_string = None;
+        for (idx, s) in self.stop_strings.iter().enumerate() {
+            let start = prev_len.saturating_sub(s.len().saturating_sub(1));
+            if let Some(pos) =
+                galil_seiferas::gs_find(&self.completion_bytes[start..], s.as_bytes())
+            {
+                let pos = start + pos;
+                if stop_string.map_or(true, |(_, best)| pos < best) {
+                    stop_string = Some((idx, pos));
+                }
+            }
+        }
+        if let Some((idx, pos)) = stop_string {
+            return Some(StopReason::StopString {
+                stop_string_idx: idx,
+                completion_bytes_pos: pos,
+            });
+        }
+
+        if let (Some(regexes), Some(state)) = (&self.stop_regexes, &mut self.stop_regex_state) {
+            let end = regexes.advance(state, &self.completion_bytes, prev_len)?;
+            let (idx, pos, end) = regexes.find(&self.completion_bytes, end)?;
+            return Some(StopReason::StopRegex {
+                stop_regex_idx: idx,
+                completion_bytes_pos: pos,
+                completion_bytes_end: end,
+            });
+        }
+        None
+    }
+
+    /// Match the stop regexes against the end of the completion, once generation ends. This is
+    /// where a match which ends the completion, such as one ending in `$` or `\b`, is found.
+    fn match_stop_regex_at_end(&self) -> Option<StopReason> {
+        let (regexes, state) = (self.stop_regexes.as_ref()?, self.stop_regex_state?);
+        let end = regexes.finish(state, &self.completion_bytes)?;
+        let (idx, pos, end) = regexes.find(&self.completion_bytes, end)?;
+        Some(StopReason::StopRegex {
+            stop_regex_idx: idx,
+            completion_bytes_pos: pos,
+            completion_bytes_end: end,
+        })
+    }
+
+    /// The length of the output in the completion bytes when the sequence stops for `reason`. The
+    /// output ends before the stop string, regex or token sequence, unless it is to be included.
+    pub fn output_len(&self, reason: &StopReason, completion_bytes: &[u8]) -> usize {
+        let (pos, end) = match *reason {
+            StopReason::StopString {
+                stop_string_idx,
+                completion_bytes_pos,
+            } => (
+                completion_bytes_pos,
+                completion_bytes_pos + self.stop_strings[stop_string_idx].len(),
+            ),
+            StopReason::StopRegex {
+                completion_bytes_pos,
+                completion_bytes_end,
+                ..
+            } => (completion_bytes_pos, completion_bytes_end),
+            StopReason::StopTokSeq {
+                completion_bytes_pos,
+                ..
+            } => (completion_bytes_pos, completion_bytes.len()),
+            _ => return completion_bytes.len(),
+        };
+        if self.include_stop_str_in_output {
+            end
+        } else {
+            pos
+        }
+    }
+
+    /// The output text when the sequence stops for `reason`.
+    pub fn output_text(&self, reason: &StopReason, completion_bytes: &[u8]) -> String {
+        let len = self.output_len(reason, completion_bytes);
+        let text = String::from_utf8_lossy(&completion_bytes[..len]);
+        if self.continues_message {
+            text.to_string()
+        } else {
+            text.trim_start().to_string()
+        }
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -327,7 +682,9 @@ impl Sequence {
 
     pub fn set_state(&self, state: SequenceState) {
         if matches!(state, SequenceState::Error) {
-            get_mut_group!(self).n_choices -= 1;
+            let mut group = get_mut_group!(self);
+            group.best_of = group.best_of.saturating_sub(1);
+            group.n_choices = group.n_choices.min(group.best_of);
         }
         *self.state.write().unwrap() = state;
     }
@@ -351,21 +708,13 @@ impl Sequence {
         {
             // add_token was already called
             Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
+        } else if self.attention_sinks.is_none()
+            && self.tokens.len().saturating_sub(self.prompt_len) == max_model_len
+        {
             Some(StopReason::ModelLength(max_model_len))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
-            }
-            None
+            // Stop strings, regexes and token sequences are matched as tokens are added.
+            self.stop_match
         }
     }
 
@@ -373,6 +722,10 @@ impl Sequence {
         &self.logprobs
     }
 
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
     pub fn return_logprobs(&self) -> bool {
         self.return_logprobs
     }
@@ -390,7 +743,14 @@ impl Sequence {
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        // Once the sequence is done, the output may end before the completion bytes.
+        let end = match self.last_is_done {
+            Some(ref reason) => self
+                .output_len(reason, &self.completion_bytes)
+                .max(self.stream_idx),
+            None => self.completion_bytes.len(),
+        };
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
         if new_decoded.ends_with('�') {
             return Ok(None);
@@ -400,6 +760,13 @@ impl Sequence {
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
         // Had we used HF's Tokenizer, it would have taken care of that for us.
+        // When continuing a message, the echoed prefill is streamed first instead.
+        if is_first && self.continues_message {
+            return Ok(Some(format!(
+                "{}{new_decoded}",
+                self.prefix.as_deref().unwrap_or("")
+            )));
+        }
         if is_first {
             return Ok(Some(new_decoded.trim_start().to_string()));
         }
@@ -414,7 +781,7 @@ impl Sequence {
         self.prompt_timestamp
     }
 
-    fn update_time_info(&self) {
+    fn update_time_info(&self, completion_toks: usize) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!")
@@ -428,15 +795,64 @@ impl Sequence {
         get_mut_group!(self).total_time += now - self.timestamp;
 
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
-        get_mut_group!(self).total_toks += self.len();
+        get_mut_group!(self).total_cached_prompt_toks += self.prefix_cache_len;
+        get_mut_group!(self).total_toks += self.prompt_len + completion_toks;
+    }
+
+    fn completion_toks(&self) -> usize {
+        self.tokens.len().saturating_sub(self.prompt_len)
     }
 
-    pub fn add_choice_to_group(&self, choice: Choice) {
-        get_mut_group!(self).choices.push(choice);
-        self.update_time_info();
+    /// The score which `best_of` candidates are ranked by: the mean logprob of the generated tokens,
+    /// or their total logprob without length normalization.
+    fn best_of_score(&self) -> f32 {
+        #![allow(clippy::cast_precision_loss)]
+        if get_mut_group!(self).length_normalize {
+            self.cumulative_logprob / self.completion_toks().max(1) as f32
+        } else {
+            self.cumulative_logprob
+        }
+    }
+
+    pub fn add_choice_to_group(&self, mut choice: Choice) {
+        if let Some(ref prefix) = self.prefix {
+            choice.message.content.insert_str(0, prefix);
+        }
+        let score = self.best_of_score();
+        get_mut_group!(self).choices.push((score, choice));
+        self.update_time_info(self.completion_toks());
+    }
+
+    /// Add a finished beam search hypothesis with `completion_toks` generated tokens, ranked by `score`.
+    pub fn add_beam_choice_to_group(&self, mut choice: Choice, score: f32, completion_toks: usize) {
+        if let Some(ref prefix) = self.prefix {
+            choice.message.content.insert_str(0, prefix);
+        }
+        get_mut_group!(self).choices.push((score, choice));
+        self.update_time_info(completion_toks);
     }
 
     pub fn add_completion_choice_to_group(&self, mut choice: CompletionChoice) {
+        choice.text = format!(
+            "{}{}{}",
+            self.prefix.as_deref().unwrap_or(""),
+            choice.text,
+            self.suffix.as_deref().unwrap_or("")
+        );
+        let score = self.best_of_score();
+        get_mut_group!(self)
+            .completion_choices
+            .push((score, choice));
+        self.update_time_info(self.completion_toks());
+    }
+
+    /// Add a finished beam search hypothesis with `completion_toks` generated tokens, ranked by `score`.
+    pub fn add_beam_completion_choice_to_group(
+        &self,
+        mut choice: CompletionChoice,
+        score: f32,
+        completion_toks: usize,
+    ) {
         choice.text = format!(
             "{}{}{}",
             self.prefix.as_deref().unwrap_or(""),
@@ -445,8 +861,8 @@ impl Sequence {
         );
         get_mut_group!(self)
             .completion_choices
-            .push((self.cumulative_logprob, choice));
-        self.update_time_info();
+            .push((score, choice));
+        self.update_time_info(completion_toks);
     }
 
     pub fn get_response_index(&self) -> usize {
@@ -462,19 +878,55 @@ impl Sequence {
     }
 }
 
+/// The generation state which is copied between beams.
+#[derive(Clone)]
+pub struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    completion_bytes: Vec<u8>,
+    completion_tok_offsets: Vec<usize>,
+    stop_regex_state: Option<StateID>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    prefix_cache_len: usize,
+    evicted_toks: usize,
+}
+
+impl BeamState {
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    pub fn logprobs(&self) -> &[Logprobs] {
+        &self.logprobs
+    }
+
+    pub fn completion_bytes(&self) -> &[u8] {
+        &self.completion_bytes
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
-    best_of: usize,   // Top n seqs based on cumulative logprobs.
+    best_of: usize, // The number of candidates to return the best `n_choices` of. Can be decreased if an error is thrown.
+    length_normalize: bool, // Whether the `best_of` candidates are ranked by mean rather than total logprob.
     pub total_prompt_toks: usize,
+    pub total_cached_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
-    choices: Vec<Choice>,
+    choices: Vec<(f32, Choice)>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub streaming_chunks: Vec<ChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub beam_search: Option<BeamSearch>,
 }
 
 impl SequenceGroup {
@@ -484,6 +936,7 @@ impl SequenceGroup {
             completion_choices: Vec::new(),
             n_choices,
             total_prompt_toks: 0,
+            total_cached_prompt_toks: 0,
             total_toks: 0,
             total_prompt_time: 0,
             total_time: 0,
@@ -492,24 +945,59 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            length_normalize: true,
+            beam_search: None,
         }
     }
 
-    /// This does not apply best_of.
-    pub fn get_choices(&self) -> &[Choice] {
-        &self.choices
+    pub fn with_beam_search(mut self, params: Option<BeamSearchParams>) -> Self {
+        self.beam_search = params.map(BeamSearch::new);
+        self
     }
 
-    /// This applies the best_of.
-    pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
-        let mut choices = self.completion_choices.clone();
-        // Sort by descending logprobs
+    pub fn with_length_normalize(mut self, length_normalize: bool) -> Self {
+        self.length_normalize = length_normalize;
+        self
+    }
+
+    pub fn n_choices(&self) -> usize {
+        self.n_choices
+    }
+
+    /// Keep the best `n_choices` of the `best_of` candidates, reindexed by rank. Without `best_of`,
+    /// all choices are kept in order.
+    fn best_choices<T: Clone>(
+        &self,
+        choices: &[(f32, T)],
+        set_index: impl Fn(&mut T, usize),
+    ) -> Vec<T> {
+        if self.best_of <= self.n_choices {
+            return choices.iter().map(|(_, x)| x.clone()).collect();
+        }
+        let mut choices = choices.to_vec();
+        // Sort by descending score
         choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
         choices
             .into_iter()
-            .take(self.best_of)
-            .map(|(_, x)| x)
-            .collect::<Vec<_>>()
+            .take(self.n_choices)
+            .enumerate()
+            .map(|(index, (_, mut x))| {
+                set_index(&mut x, index);
+                x
+            })
+            .collect()
+    }
+
+    /// This applies the best_of.
+    pub fn get_choices(&self) -> Vec<Choice> {
+        self.best_choices(&self.choices, |choice, index| choice.index = index)
+    }
+
+    /// This applies the best_of.
+    pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
+        self.best_choices(&self.completion_choices, |choice, index| {
+            choice.index = index
+        })
     }
 
     pub fn get_usage(&self) -> Usage {
@@ -517,6 +1005,7 @@ impl SequenceGroup {
         Usage {
             completion_tokens: self.total_toks - self.total_prompt_toks,
             prompt_tokens: self.total_prompt_toks,
+            cached_prompt_tokens: self.total_cached_prompt_toks,
             total_tokens: self.total_toks,
             avg_tok_per_sec: (self.total_toks as f32 / self.total_time as f32) * 1000.,
             avg_prompt_tok_per_sec: (self.total_prompt_toks as f32 / self.total_prompt_time as f32)
@@ -535,7 +1024,7 @@ impl SequenceGroup {
         response: ChatCompletionResponse,
         sender: Sender<Response>,
     ) -> Result<(), SendError<Response>> {
-        if self.choices.len() == self.n_choices {
+        if self.choices.len() == self.best_of {
             sender.send(Response::Done(response)).await?;
         }
 
@@ -571,7 +1060,7 @@ impl SequenceGroup {
         response: CompletionResponse,
         sender: Sender<Response>,
     ) -> Result<(), Box<SendError<Response>>> {
-        if self.completion_choices.len() == self.n_choices {
+        if self.completion_choices.len() == self.best_of {
             sender.send(Response::CompletionDone(response)).await?;
         }
         Ok(())
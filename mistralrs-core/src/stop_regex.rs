@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use regex_automata::{
+    dfa::{dense, Automaton},
+    meta,
+    util::primitives::StateID,
+    Input,
+};
+
+/// The most memory the stop regex DFA, and its determinization, may use. Stop regexes come from
+/// requests, so a pattern whose DFA blows up is rejected instead of stalling the engine.
+const STOP_REGEX_DFA_SIZE_LIMIT: usize = 10 * (1 << 20);
+
+/// Stop regexes, matched incrementally over the completion bytes of a sequence. A DFA over all
+/// regexes is advanced by the new bytes of each token, and once a match ends, its start is found
+/// by a single search of the completion.
+///
+/// A match is only reported once the byte after it is known, so that `$`, `\b` and `\B` are not
+/// checked against the end of an unfinished completion. A match at the very end of the completion
+/// is found by [`StopRegexes::finish`] when generation ends.
+#[derive(Clone)]
+pub struct StopRegexes {
+    dfa: Arc<dense::DFA<Vec<u32>>>,
+    regex: Arc<meta::Regex>,
+}
+
+impl StopRegexes {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let regex = meta::Regex::new_many(patterns).map_err(|e| {
+            anyhow::Error::msg(format!("Could not compile stop regexes {patterns:?} - {e}"))
+        })?;
+        if regex.is_match("") {
+            anyhow::bail!("Stop regexes must not match the empty string.");
+        }
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    // Unicode word boundaries are supported on ASCII, the DFA quits on other bytes.
+                    .unicode_word_boundary(true)
+                    .dfa_size_limit(Some(STOP_REGEX_DFA_SIZE_LIMIT))
+                    .determinize_size_limit(Some(STOP_REGEX_DFA_SIZE_LIMIT)),
+            )
+            .build_many(patterns)
+            .map_err(|e| {
+                anyhow::Error::msg(format!("Could not compile stop regexes {patterns:?} - {e}"))
+            })?;
+        Ok(Self {
+            dfa: Arc::new(dfa),
+            regex: Arc::new(regex),
+        })
+    }
+
+    /// The state before any completion bytes.
+    pub fn start_state(&self) -> StateID {
+        self.dfa
+            .start_state_forward(&Input::new(""))
+            .expect("Stop regex DFA has no unanchored start state.")
+    }
+
+    /// Advance `state` over the bytes of `completion` from `start`, stopping at the first match
+    /// which the byte after it confirms. Returns the end of that match in `completion`.
+    pub fn advance(&self, state: &mut StateID, completion: &[u8], start: usize) -> Option<usize> {
+        for (pos, byte) in completion.iter().enumerate().skip(start) {
+            if self.dfa.is_quit_state(*state) {
+                break;
+            }
+            *state = self.dfa.next_state(*state, *byte);
+            // Match states are delayed by one byte: this one ends a match before `byte`.
+            if self.dfa.is_match_state(*state) {
+                return Some(pos);
+            }
+        }
+        if self.dfa.is_quit_state(*state) {
+            // The DFA cannot decide a Unicode word boundary, search the completion instead. A
+            // match which ends the completion is not confirmed yet.
+            return self
+                .find(completion, completion.len())
+                .map(|(_, _, end)| end)
+                .filter(|end| *end < completion.len());
+        }
+        None
+    }
+
+    /// The end of a match which ends the completion, once generation has ended.
+    pub fn finish(&self, state: StateID, completion: &[u8]) -> Option<usize> {
+        if self.dfa.is_quit_state(state) {
+            self.find(completion, completion.len())
+                .map(|(_, _, end)| end)
+        } else {
+            self.dfa
+                .is_match_state(self.dfa.next_eoi_state(state))
+                .then_some(completion.len())
+        }
+    }
+
+    /// The leftmost match in `haystack` which ends by `end`, as the index of its regex and its
+    /// span. The bytes after `end` are still seen by look-around assertions.
+    pub fn find(&self, haystack: &[u8], end: usize) -> Option<(usize, usize, usize)> {
+        self.regex
+            .find(Input::new(haystack).range(..end))
+            .map(|m| (m.pattern().as_usize(), m.start(), m.end()))
+    }
+}
+
+mod tests {
+    #[allow(dead_code)]
+    fn stop_regexes(patterns: &[&str]) -> anyhow::Result<super::StopRegexes> {
+        super::StopRegexes::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    /// Feed `tokens` one at a time as a sequence does. Returns the match found by `advance` or at
+    /// the end by `finish`, and the number of tokens fed.
+    #[allow(dead_code)]
+    fn run(patterns: &[&str], tokens: &[&str]) -> Option<((usize, usize, usize), usize)> {
+        let regexes = stop_regexes(patterns).unwrap();
+        let mut state = regexes.start_state();
+        let mut completion = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let start = completion.len();
+            completion.extend_from_slice(token.as_bytes());
+            if let Some(end) = regexes.advance(&mut state, &completion, start) {
+                return Some((regexes.find(&completion, end).unwrap(), i + 1));
+            }
+        }
+        let end = regexes.finish(state, &completion)?;
+        Some((regexes.find(&completion, end).unwrap(), tokens.len()))
+    }
+
+    #[test]
+    fn test_stop_regex_across_tokens() {
+        // The match is confirmed by the token after it.
+        assert_eq!(
+            run(&["st[aeiou]p"], &["Please ", "st", "o", "p now"]),
+            Some(((0, 7, 11), 4))
+        );
+        assert_eq!(
+            run(&["st[aeiou]p"], &["Please ", "stap"]),
+            Some(((0, 7, 11), 2))
+        );
+        assert_eq!(run(&["st[aeiou]p"], &["Please ", "stxp"]), None);
+    }
+
+    #[test]
+    fn test_stop_regex_assertions() {
+        // `cat\b` does not stop at the start of "catalog", within one token or across tokens.
+        assert_eq!(run(&[r"cat\b"], &["a catalog"]), None);
+        assert_eq!(run(&[r"cat\b"], &["a cat", "alog"]), None);
+        assert_eq!(
+            run(&[r"cat\b"], &["a cat", "alog", " cat", "."]),
+            Some(((0, 10, 13), 4))
+        );
+        // A boundary at the end of the completion is only known once generation ends.
+        assert_eq!(run(&[r"cat\b"], &["a cat"]), Some(((0, 2, 5), 1)));
+        assert_eq!(run(&[r"\Bog"], &["og"]), None);
+        assert_eq!(run(&[r"\Bog"], &["og ", "dog"]), Some(((0, 4, 6), 2)));
+        // `$` is the end of the completion, not of the tokens generated so far.
+        assert_eq!(run(&["done$"], &["done", " and more"]), None);
+        assert_eq!(run(&["done$"], &["all ", "done"]), Some(((0, 4, 8), 2)));
+    }
+
+    #[test]
+    fn test_stop_regex_several_patterns() {
+        assert_eq!(
+            run(&["foo[0-9]+", "bar"], &["a bar", " foo12", " "]),
+            Some(((1, 2, 5), 2))
+        );
+        assert_eq!(
+            run(&["foo[0-9]+", "bar"], &["a foo1", " bar"]),
+            Some(((0, 2, 6), 2))
+        );
+    }
+
+    #[test]
+    fn test_stop_regex_rejected() {
+        assert!(stop_regexes(&["a*"]).is_err());
+        assert!(stop_regexes(&["x", "(y)?"]).is_err());
+        assert!(stop_regexes(&["(a|b)*a(a|b){25}"]).is_err());
+        assert!(stop_regexes(&["("]).is_err());
+    }
+}
@@ -1,10 +1,11 @@
 use std::fs::File;
 
+use either::Either;
 use serde::Deserialize;
 
 use crate::{
-    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    layers::RopeScaling, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
+    GGUFSpecificConfig, Loader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
 };
 
 fn default_repeat_last_n() -> usize {
@@ -71,8 +72,10 @@ enum TomlModelSelected {
         /// This may be a HF hub repo or a local path.
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as a list of all of its shards.
+        #[serde(with = "either::serde_untagged")]
+        quantized_filename: Either<String, Vec<String>>,
     },
 
     /// Select a GGUF model with X-LoRA.
@@ -84,8 +87,10 @@ enum TomlModelSelected {
         /// This may be a HF hub repo or a local path.
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as a list of all of its shards.
+        #[serde(with = "either::serde_untagged")]
+        quantized_filename: Either<String, Vec<String>>,
 
         /// Model ID to load X-LoRA from. This may be a HF hub repo or a local path.
         xlora_model_id: String,
@@ -107,8 +112,10 @@ enum TomlModelSelected {
         /// This may be a HF hub repo or a local path.
         quantized_model_id: String,
 
-        /// Quantized filename, only applicable if `quantized` is set.
-        quantized_filename: String,
+        /// Quantized filename, only applicable if `quantized` is set. A model split into several GGUF
+        /// files may be given as its first shard or as a list of all of its shards.
+        #[serde(with = "either::serde_untagged")]
+        quantized_filename: Either<String, Vec<String>>,
 
         /// Model ID to load X-LoRA from. This may be a HF hub repo or a local path.
         adapters_model_id: String,
@@ -195,6 +202,9 @@ pub struct TomlSelector {
     #[serde(default = "default_repeat_last_n")]
     repeat_last_n: usize,
 
+    /// Override the model's RoPE scaling. Only applies to plain, X-LoRA and LoRA models.
+    rope_scaling: Option<RopeScaling>,
+
     /// Selected model
     model: TomlModelSelected,
 }
@@ -206,6 +216,7 @@ struct TomlLoaderInnerParams {
     no_kv_cache: bool,
     tokenizer_json: Option<String>,
     repeat_last_n: usize,
+    rope_scaling: Option<RopeScaling>,
 }
 
 pub struct TomlLoaderArgs {
@@ -224,6 +235,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n: args.repeat_last_n,
+                rope_scaling: args.rope_scaling,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -240,6 +252,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n: args.repeat_last_n,
+                rope_scaling: args.rope_scaling,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -264,6 +277,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 repeat_last_n: args.repeat_last_n,
+                rope_scaling: args.rope_scaling,
             },
             args.chat_template,
             args.tokenizer_json,
@@ -289,7 +303,7 @@ fn loader_from_selected(
             args.tokenizer_json,
             Some(tok_model_id),
             quantized_model_id,
-            quantized_filename,
+            quantized_filename.either(|f| vec![f], |f| f),
         )
         .build(),
         TomlModelSelected::XLoraGGUF {
@@ -307,7 +321,7 @@ fn loader_from_selected(
             args.tokenizer_json,
             tok_model_id,
             quantized_model_id,
-            quantized_filename,
+            quantized_filename.either(|f| vec![f], |f| f),
         )
         .with_xlora(
             xlora_model_id,
@@ -333,7 +347,7 @@ fn loader_from_selected(
             args.tokenizer_json,
             tok_model_id,
             quantized_model_id,
-            quantized_filename,
+            quantized_filename.either(|f| vec![f], |f| f),
         )
         .with_lora(
             adapters_model_id,
@@ -429,6 +443,7 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
             no_kv_cache: args.no_kv_cache,
             tokenizer_json: selector.tokenizer_json,
             repeat_last_n: selector.repeat_last_n,
+            rope_scaling: selector.rope_scaling,
         };
         loader_from_selected(args.clone(), selector.model)
     }
@@ -85,7 +85,7 @@ macro_rules! handle_pipeline_forward_error {
                 for seq in $seq_slice.iter_mut() {
                     // Step 1: Add all choices to groups
                     let res = match tokenizer
-                        .decode(&seq.get_toks()[seq.prompt_tokens()..], false)
+                        .decode(seq.get_toks().get(seq.prompt_tokens()..).unwrap_or_default(), false)
                     {
                         Ok(v) => v,
                         Err(_) => "".to_string(),
@@ -119,7 +119,7 @@ macro_rules! handle_pipeline_forward_error {
                     if group.is_chat {
                         let partial_completion_response = ChatCompletionResponse {
                             id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
+                            choices: group.get_choices(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
@@ -137,7 +137,7 @@ macro_rules! handle_pipeline_forward_error {
                     } else {
                         let partial_completion_response = CompletionResponse {
                             id: seq.id().to_string(),
-                            choices: group.get_completion_choices().to_vec(),
+                            choices: group.get_completion_choices(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
@@ -1,15 +1,42 @@
 //! Utilities for creating a VarBuilder from a VarMap loaded from tensor storage formats.
 
-use std::{collections::HashMap, path::PathBuf, thread};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Shape, Tensor};
 use candle_nn::{
     var_builder::{SimpleBackend, VarBuilderArgs},
-    VarBuilder,
+    Init, VarBuilder,
 };
 
 use tqdm::Iter;
 
+/// The name of a tensor of the base model, as the models expect it.
+fn base_tensor_name(name: &str) -> String {
+    if name.contains("base_model.model.model") {
+        name.replace("base_model.model.model", "model")
+    } else {
+        name.to_string()
+    }
+}
+
+/// The name of a tensor of the `i`th adapter, as the models expect it. The X-LoRA classifier in
+/// the adapter files is skipped.
+fn adapter_tensor_name(name: &str, i: usize) -> Option<String> {
+    if name.contains("internal_xlora_classifier") {
+        return None;
+    }
+    let mut new_name = base_tensor_name(name);
+    let pos = new_name.find(".lora").unwrap();
+    new_name.insert_str(pos + 7, &format!(".{}", i + 1));
+    Some(new_name)
+}
+
 /// Load tensors into a VarBuilder backed by a VarMap using MmapedSafetensors.
 /// Set `silent` to not show a progress bar.
 pub(crate) fn from_mmaped_safetensors<'a>(
@@ -32,11 +59,7 @@ pub(crate) fn from_mmaped_safetensors<'a>(
 
             if silent {
                 for (name, _) in tensors.tensors() {
-                    let new_name = if name.contains("base_model.model.model") {
-                        name.replace("base_model.model.model", "model")
-                    } else {
-                        name.clone()
-                    };
+                    let new_name = base_tensor_name(&name);
                     let tensor = tensors
                         .load(&name, &device)?
                         .to_device(&device)?
@@ -45,11 +68,7 @@ pub(crate) fn from_mmaped_safetensors<'a>(
                 }
             } else {
                 for (name, _) in tensors.tensors().into_iter().tqdm() {
-                    let new_name = if name.contains("base_model.model.model") {
-                        name.replace("base_model.model.model", "model")
-                    } else {
-                        name.clone()
-                    };
+                    let new_name = base_tensor_name(&name);
                     let tensor = tensors
                         .load(&name, &device)?
                         .to_device(&device)?
@@ -75,16 +94,9 @@ pub(crate) fn from_mmaped_safetensors<'a>(
 
             if silent {
                 for (name, _) in tensors.tensors() {
-                    if name.contains("internal_xlora_classifier") {
+                    let Some(new_name) = adapter_tensor_name(&name, i) else {
                         continue;
-                    }
-                    let mut new_name = if name.contains("base_model.model.model") {
-                        name.replace("base_model.model.model", "model")
-                    } else {
-                        name.clone()
                     };
-                    let pos = new_name.find(".lora").unwrap();
-                    new_name.insert_str(pos + 7, &format!(".{}", i + 1));
                     let tensor = tensors
                         .load(&name, &device)?
                         .to_device(&device)?
@@ -93,16 +105,9 @@ pub(crate) fn from_mmaped_safetensors<'a>(
                 }
             } else {
                 for (name, _) in tensors.tensors().into_iter().tqdm() {
-                    if name.contains("internal_xlora_classifier") {
+                    let Some(new_name) = adapter_tensor_name(&name, i) else {
                         continue;
-                    }
-                    let mut new_name = if name.contains("base_model.model.model") {
-                        name.replace("base_model.model.model", "model")
-                    } else {
-                        name.clone()
                     };
-                    let pos = new_name.find(".lora").unwrap();
-                    new_name.insert_str(pos + 7, &format!(".{}", i + 1));
                     let tensor = tensors
                         .load(&name, &device)?
                         .to_device(&device)?
@@ -123,3 +128,87 @@ pub(crate) fn from_mmaped_safetensors<'a>(
     }
     Ok(VarBuilder::from_tensors(ws, dtype, device))
 }
+
+/// A backend which only checks the requested tensors against the safetensors headers, and returns
+/// placeholders which do not allocate the tensors.
+struct TensorCheckBackend {
+    tensors: HashMap<String, (Vec<usize>, Option<DType>)>,
+    problems: Arc<Mutex<Vec<String>>>,
+}
+
+impl SimpleBackend for TensorCheckBackend {
+    fn get(&self, s: Shape, name: &str, _: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let problem = match self.tensors.get(name) {
+            None => Some(format!("missing tensor `{name}` of shape {:?}", s.dims())),
+            Some((shape, _)) if shape != s.dims() => Some(format!(
+                "tensor `{name}` has shape {shape:?}, expected {:?}",
+                s.dims()
+            )),
+            Some((_, None)) => Some(format!("tensor `{name}` has an unsupported dtype")),
+            Some((_, Some(stored))) if stored.is_float() != dtype.is_float() => Some(format!(
+                "tensor `{name}` has dtype {stored:?}, which cannot be loaded as {dtype:?}"
+            )),
+            Some(_) => None,
+        };
+        if let Some(problem) = problem {
+            self.problems.lock().expect("Poisoned lock.").push(problem);
+        }
+        Tensor::zeros((), dtype, dev)?.broadcast_as(s)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+}
+
+/// Check, before any tensor is loaded, that the safetensors files contain every tensor the model
+/// needs, with the right shape and a dtype which can be converted to `dtype`. Only the headers
+/// are read: `load` builds the model on the CPU from placeholder tensors, and every problem is
+/// reported at once.
+pub(crate) fn check_mmaped_safetensors<F>(
+    paths: &[PathBuf],
+    xlora_paths: &[PathBuf],
+    dtype: DType,
+    load: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(VarBuilder) -> anyhow::Result<()>,
+{
+    let mut tensors = HashMap::new();
+    let mut add = |path: &PathBuf, rename: &dyn Fn(&str) -> Option<String>| -> Result<()> {
+        let safetensors = unsafe { candle_core::safetensors::MmapedSafetensors::new(path)? };
+        for (name, view) in safetensors.tensors() {
+            if let Some(new_name) = rename(&name) {
+                let stored = DType::try_from(view.dtype()).ok();
+                tensors.insert(new_name, (view.shape().to_vec(), stored));
+            }
+        }
+        Ok(())
+    };
+    for path in paths {
+        add(path, &|name| Some(base_tensor_name(name)))?;
+    }
+    for (i, path) in xlora_paths.iter().enumerate() {
+        add(path, &|name| adapter_tensor_name(name, i))?;
+    }
+
+    let problems = Arc::new(Mutex::new(Vec::new()));
+    let backend: Box<dyn SimpleBackend> = Box::new(TensorCheckBackend {
+        tensors,
+        problems: problems.clone(),
+    });
+    let loaded = load(VarBuilder::from_backend(backend, dtype, Device::Cpu));
+
+    let problems = problems.lock().expect("Poisoned lock.");
+    if !problems.is_empty() {
+        let mut msg = format!(
+            "The weights do not match the model, {} problem(s) were found:",
+            problems.len()
+        );
+        for problem in problems.iter() {
+            write!(msg, "\n  - {problem}")?;
+        }
+        anyhow::bail!(msg);
+    }
+    loaded
+}
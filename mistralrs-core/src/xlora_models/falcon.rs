@@ -0,0 +1,706 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Falcon model.
+/// https://huggingface.co/tiiuae/falcon-7b
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
+use candle_nn::{embedding, layer_norm, Embedding, LayerNorm, VarBuilder};
+use mistralrs_lora::{layer::QLinear, linear_b, LinearLayerLike, LoraConfig, Ordering};
+use std::sync::Arc;
+use tqdm::Iter;
+use tracing::info;
+
+use crate::{
+    device_map::DeviceMapper,
+    layers::{CausalMasker, RotaryEmbedding},
+    models::{
+        falcon::{split_qkv, Config},
+        flash_attn, repeat_kv,
+        starcoder2::qlinear_b,
+        Cache,
+    },
+    pipeline::{extract_logits, NormalModel},
+    DeviceMapMetadata,
+};
+
+use super::{classifier::XLoraClassifier, config::XLoraConfig, NonGranularState, ScalingsMaker};
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    dense_h_to_4h: Arc<dyn LinearLayerLike + Send + Sync>,
+    dense_4h_to_h: Arc<dyn LinearLayerLike + Send + Sync>,
+}
+
+impl MLP {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let dense_h_to_4h = linear_b(
+            cfg.hidden_size,
+            cfg.ffn_hidden_size,
+            cfg.bias,
+            mapper.set_device(layer_idx, vb.pp("dense_h_to_4h"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("dense_h_to_4h"), false),
+            lora_config,
+            count,
+            ord,
+        )?;
+        let dense_4h_to_h = linear_b(
+            cfg.ffn_hidden_size,
+            cfg.hidden_size,
+            cfg.bias,
+            mapper.set_device(layer_idx, vb.pp("dense_4h_to_h"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("dense_4h_to_h"), false),
+            lora_config,
+            count,
+            ord,
+        )?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if self.dense_h_to_4h.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        let mut res = self.dense_4h_to_h.lora_forward(
+            &self
+                .dense_h_to_4h
+                .lora_forward(
+                    &xs,
+                    scalings.clone(),
+                    global_scaling_weight,
+                    is_scaling_pass,
+                )?
+                .gelu_erf()?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.dense_h_to_4h.is_quant() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    query_key_value: Arc<dyn LinearLayerLike + Send + Sync>,
+    dense: Arc<dyn LinearLayerLike + Send + Sync>,
+    cfg: Config,
+    rotary_emb: Arc<RotaryEmbedding>,
+    neg_inf: Tensor,
+}
+
+impl Attention {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let query_key_value = linear_b(
+            cfg.hidden_size,
+            cfg.qkv_size(),
+            cfg.bias,
+            mapper.set_device(layer_idx, vb.pp("query_key_value"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("query_key_value"), false),
+            lora_config,
+            count,
+            ord,
+        )?;
+        let dense = linear_b(
+            cfg.hidden_size,
+            cfg.hidden_size,
+            cfg.bias,
+            mapper.set_device(layer_idx, vb.pp("dense"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("dense"), false),
+            lora_config,
+            count,
+            ord,
+        )?;
+        Ok(Self {
+            query_key_value,
+            dense,
+            cfg: cfg.clone(),
+            rotary_emb,
+            neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let num_heads = self.cfg.num_attention_heads;
+        let num_kv_heads = self.cfg.num_kv_heads;
+        let head_dim = self.cfg.head_dim();
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if self.query_key_value.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        let mut fused_qkv = self.query_key_value.lora_forward(
+            &xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.query_key_value.is_quant() {
+            fused_qkv = fused_qkv.to_dtype(original_dtype)?;
+        }
+        let (q, k, v) = split_qkv(&self.cfg, &fused_qkv)?;
+
+        let mut q = q.reshape((b_sz * q_len, num_heads, head_dim))?;
+        let mut k = k.reshape((b_sz * q_len, num_kv_heads, head_dim))?;
+        let v = v.transpose(1, 2)?.contiguous()?;
+
+        self.rotary_emb
+            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 {
+            q = q
+                .reshape((b_sz, q_len, num_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, q_len, num_kv_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        }
+
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, None)?;
+
+        let k = repeat_kv(k, num_heads / num_kv_heads)?.contiguous()?;
+        let v = repeat_kv(v, num_heads / num_kv_heads)?.contiguous()?;
+
+        let mut attn_output = if self.cfg.use_flash_attn {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (head_dim as f32).sqrt();
+            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else {
+            let scale = 1f64 / f64::sqrt(head_dim as f64);
+            let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+
+            let attn_weights =
+                CausalMasker.apply_mask(&attention_mask.cloned(), attn_weights, &self.neg_inf)?;
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&v)?
+        };
+        if self.query_key_value.is_quant() {
+            attn_output = attn_output.to_dtype(DType::F32)?;
+        }
+        let mut res = self.dense.lora_forward(
+            &attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.query_key_value.is_quant() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attention: Attention,
+    mlp: MLP,
+    input_layernorm: LayerNorm,
+    mlp_layernorm: Option<LayerNorm>,
+    is_parallel: bool,
+}
+
+impl DecoderLayer {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let self_attention = Attention::new(
+            rotary_emb,
+            cfg,
+            vb.pp("self_attention"),
+            lora_config,
+            count,
+            ord,
+            mapper,
+            layer_idx,
+            loading_isq,
+        )?;
+        let mlp = MLP::new(
+            cfg,
+            vb.pp("mlp"),
+            lora_config,
+            count,
+            ord,
+            mapper,
+            layer_idx,
+            loading_isq,
+        )?;
+        let norm = |name: &str| {
+            layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                mapper.set_device(layer_idx, vb.pp(name), false),
+            )
+        };
+        let (input_layernorm, mlp_layernorm) = if cfg.has_two_layer_norms() {
+            (norm("ln_attn")?, Some(norm("ln_mlp")?))
+        } else if !cfg.is_parallel() {
+            (
+                norm("input_layernorm")?,
+                Some(norm("post_attention_layernorm")?),
+            )
+        } else {
+            (norm("input_layernorm")?, None)
+        };
+        Ok(Self {
+            self_attention,
+            mlp,
+            input_layernorm,
+            mlp_layernorm,
+            is_parallel: cfg.is_parallel(),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let attn_input = xs.apply(&self.input_layernorm)?;
+        let attn_output = self.self_attention.forward(
+            &attn_input,
+            attention_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.is_parallel {
+            let mlp_input = match &self.mlp_layernorm {
+                Some(ln) => xs.apply(ln)?,
+                None => attn_input,
+            };
+            let mlp_output =
+                self.mlp
+                    .forward(&mlp_input, scalings, global_scaling_weight, is_scaling_pass)?;
+            (mlp_output + attn_output)? + residual
+        } else {
+            let xs = (attn_output + residual)?;
+            let mlp_output = self.mlp.forward(
+                &xs.apply(self.mlp_layernorm.as_ref().unwrap())?,
+                scalings,
+                global_scaling_weight,
+                is_scaling_pass,
+            )?;
+            mlp_output + xs
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct XLoraModel {
+    word_embeddings: Embedding,
+    h: Vec<DecoderLayer>,
+    ln_f: LayerNorm,
+    lm_head: QLinear,
+    dtype: DType,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    xlora_classifier: Option<XLoraClassifier>,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+}
+
+impl XLoraModel {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[(String, LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        is_gptx: bool,
+        mapper: DeviceMapMetadata,
+        loading_isq: bool,
+        real_device: Device,
+    ) -> Result<Self> {
+        let mapper = mapper.into_mapper(cfg.num_hidden_layers, &real_device)?;
+        let vb_t = vb.pp("transformer");
+        let word_embeddings = embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_t.pp("word_embeddings"), false),
+        )?;
+        let mut h = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_h = vb_t.pp("h");
+        let mut count = 0;
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let rotary_emb = Arc::new(RotaryEmbedding::new(
+                cfg.rope_theta as f32,
+                cfg.head_dim(),
+                cfg.max_position_embeddings,
+                mapper.device_for(layer_idx, false).unwrap_or(&real_device),
+                is_gptx,
+                vb.dtype(),
+                None,
+            )?);
+            let layer = DecoderLayer::new(
+                rotary_emb,
+                cfg,
+                vb_h.pp(layer_idx),
+                lora_config,
+                &mut count,
+                &xlora_ordering,
+                &*mapper,
+                layer_idx,
+                loading_isq,
+            )?;
+            h.push(layer)
+        }
+        if xlora_config.is_none() {
+            // We are now a LoRA model so we must merge the weights
+            info!("Merging LoRA adapters.");
+            for layer in h.iter_mut().tqdm() {
+                Arc::get_mut(&mut layer.self_attention.query_key_value)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.self_attention.dense)
+                    .unwrap()
+                    .merge_weights()?;
+
+                Arc::get_mut(&mut layer.mlp.dense_h_to_4h)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.mlp.dense_4h_to_h)
+                    .unwrap()
+                    .merge_weights()?;
+            }
+        }
+        let ln_f = layer_norm(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_t.pp("ln_f"), false),
+        )?;
+        let lm_head = if cfg.tie_word_embeddings {
+            QLinear::from_parts(word_embeddings.embeddings().clone(), None)
+        } else {
+            qlinear_b(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                false,
+                mapper.set_nm_device(vb.pp("lm_head"), loading_isq),
+            )?
+        };
+        Ok(Self {
+            word_embeddings,
+            h,
+            ln_f,
+            lm_head,
+            device: real_device,
+            dtype: vb.dtype(),
+            cache: Cache::new(cfg.num_hidden_layers, true),
+            max_seq_len: cfg.max_position_embeddings,
+            xlora_classifier: xlora_config.map(|xlora_config| {
+                XLoraClassifier::new(xlora_config, count, lora_config.len(), vb, false).unwrap()
+            }),
+            mapper,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn inner_forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        scalings: Option<Tensor>,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let mut cache = if is_full_pass {
+            if no_kv_cache {
+                let mut new_cache = Vec::new();
+                for _ in 0..self.cache.xlora_lock().len() {
+                    new_cache.push(None);
+                }
+
+                self.cache.xlora_lock().clone_from(&new_cache);
+            }
+            self.cache.xlora_lock()
+        } else {
+            self.cache.lock()
+        };
+        let attention_mask = CausalMasker.make_causal_mask(input_ids, &self.cache)?;
+        let mut xs = self.word_embeddings.forward(input_ids)?;
+        for (i, layer) in self.h.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+                scalings.clone(),
+                self.xlora_classifier
+                    .as_ref()
+                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .unwrap_or(1.0),
+                is_scaling_pass,
+            )?
+        }
+        let xs = xs.to_device(&self.device)?;
+        xs.apply(&self.ln_f)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: Tensor,
+        start_offsets_kernel_full: Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        if self.xlora_classifier.is_some() {
+            let scalings = self.get_scalings(
+                input_ids,
+                input_ids_full,
+                seqlen_offsets,
+                seqlen_offsets_full,
+                &start_offsets_kernel,
+                &start_offsets_kernel_full,
+                no_kv_cache,
+                non_granular_state,
+                &vec![usize::MAX; context_lens.len()],
+            )?;
+
+            if no_kv_cache {
+                let mut res = self
+                    .inner_forward(
+                        input_ids_full,
+                        seqlen_offsets_full,
+                        start_offsets_kernel_full,
+                        Some(scalings),
+                        true,
+                        no_kv_cache,
+                        None,
+                    )?
+                    .contiguous()?;
+                if self.lm_head.is_quant() {
+                    res = res.to_dtype(DType::F32)?;
+                }
+                extract_logits(&res.apply(&self.lm_head)?, context_lens)
+            } else {
+                // is_full_pass=true is ok because no_kv_cache=false
+                let mut res = self
+                    .inner_forward(
+                        input_ids,
+                        seqlen_offsets,
+                        start_offsets_kernel,
+                        Some(scalings),
+                        true,
+                        no_kv_cache,
+                        None,
+                    )?
+                    .contiguous()?;
+                if self.lm_head.is_quant() {
+                    res = res.to_dtype(DType::F32)?;
+                }
+                extract_logits(&res.apply(&self.lm_head)?, context_lens)
+            }
+        } else {
+            let mut res = self
+                .inner_forward(
+                    input_ids,
+                    seqlen_offsets,
+                    start_offsets_kernel,
+                    None,
+                    false,
+                    no_kv_cache,
+                    None,
+                )?
+                .contiguous()?;
+            if self.lm_head.is_quant() {
+                res = res.to_dtype(DType::F32)?;
+            }
+            extract_logits(&res.apply(&self.lm_head)?, context_lens)
+        }
+    }
+}
+
+impl NormalModel for XLoraModel {
+    fn forward(
+        &mut self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        unreachable!()
+    }
+    fn xlora_forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: Tensor,
+        start_offsets_kernel_full: Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            input_ids_full,
+            seqlen_offsets,
+            seqlen_offsets_full,
+            start_offsets_kernel,
+            start_offsets_kernel_full,
+            no_kv_cache,
+            non_granular_state,
+            context_lens,
+        )
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        true
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+        let mut tensors = Vec::new();
+        tensors.push((self.lm_head.inner(), None));
+        for (i, layer) in self.h.iter_mut().enumerate() {
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attention.query_key_value)
+                    .unwrap()
+                    .inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attention.dense)
+                    .unwrap()
+                    .inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.dense_h_to_4h).unwrap().inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.dense_4h_to_h).unwrap().inner(),
+                Some(i),
+            ));
+        }
+        (tensors, &*self.mapper)
+    }
+}
+
+impl ScalingsMaker for XLoraModel {
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+    fn get_cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn get_classifier(&self) -> &XLoraClassifier {
+        self.xlora_classifier.as_ref().unwrap()
+    }
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        scalings: Tensor,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        _context_lens: &[usize],
+    ) -> Result<Tensor> {
+        self.inner_forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            Some(scalings),
+            is_full_pass,
+            no_kv_cache,
+            is_scaling_pass,
+        )
+    }
+}
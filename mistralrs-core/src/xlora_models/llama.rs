@@ -1,7 +1,7 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor, D};
-use candle_nn::{embedding, Embedding, Module, RotaryEmbedding, VarBuilder};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use mistralrs_lora::{
     layer::QLinear, linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering,
 };
@@ -11,7 +11,7 @@ use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{CausalMasker, RmsNorm},
+    layers::{CausalMasker, RmsNorm, RotaryEmbedding},
     models::{self, flash_attn, llama::Config, repeat_kv, LayerCaches},
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
@@ -215,7 +215,12 @@ impl CausalSelfAttention {
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             use_flash_attn: cfg.use_flash_attn,
             rotary_emb: rope,
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |s| {
+                    s.max_position_embeddings(cfg.max_position_embeddings)
+                }),
             neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
         })
     }
@@ -589,6 +594,7 @@ impl XLoraLlama {
                         mapper.device_for(i, false).unwrap_or(&real_device),
                         is_gptx,
                         vb.dtype(),
+                        cfg.rope_scaling.as_ref(),
                     )
                     .expect("Failed to create RoPE"),
                 );
@@ -1,17 +1,21 @@
 mod classifier;
 mod config;
+mod falcon;
 mod gemma;
 mod llama;
 mod mistral;
 mod mixtral;
 mod phi2;
 mod phi3;
+mod quantized_gemma;
 mod quantized_llama;
+mod starcoder2;
 
 use std::sync::Arc;
 
 use candle_core::{DType, Device, Result, Tensor};
 pub use config::XLoraConfig;
+pub use falcon::XLoraModel as XLoraFalcon;
 pub use gemma::XLoraModel as XLoraGemma;
 pub use llama::XLoraLlama;
 pub use mistral::XLoraModel as XLoraMistral;
@@ -19,7 +23,9 @@ use mistralrs_lora::Ordering;
 pub use mixtral::XLoraModel as XLoraMixtral;
 pub use phi2::Model as XLoraPhi2;
 pub use phi3::Model as XLoraPhi3;
+pub use quantized_gemma::ModelWeights as XLoraQGemma;
 pub use quantized_llama::ModelWeights as XLoraModelWeights;
+pub use starcoder2::XLoraModel as XLoraStarcoder2;
 use tokio::sync::Mutex;
 
 use crate::{get_mut_arcmutex, models::Cache};
@@ -0,0 +1,553 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use candle_core::quantized::gguf_file;
+use candle_core::quantized::QMatMul;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Module, RotaryEmbedding, VarBuilder};
+use mistralrs_lora::{get_lora_cfg, LinearLayerLike, LoraConfig, Merge, Ordering, QLoraLinear};
+use tqdm::Iter;
+use tracing::info;
+
+use crate::device_map::DeviceMapper;
+use crate::layers::{CausalMasker, QRmsNorm};
+use crate::models::{repeat_kv, verify_sanity_gguf, Cache};
+use crate::pipeline::extract_logits;
+use crate::DeviceMapMetadata;
+
+use super::classifier::XLoraClassifier;
+use super::{verify_sanity_adapters, NonGranularState, ScalingsMaker, XLoraConfig};
+
+const MAX_SEQ_LEN: u32 = 8192;
+const SUPPORTED_LAYERS: [&str; 7] = [
+    "self_attn.q_proj",
+    "self_attn.k_proj",
+    "self_attn.v_proj",
+    "self_attn.o_proj",
+    "mlp.up_proj",
+    "mlp.down_proj",
+    "mlp.gate_proj",
+];
+
+#[derive(Debug)]
+struct Mlp {
+    feed_forward_w1: QLoraLinear,
+    feed_forward_w2: QLoraLinear,
+    feed_forward_w3: QLoraLinear,
+}
+
+impl Mlp {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.lora_forward(
+            xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let w3 = self.feed_forward_w3.lora_forward(
+            xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        self.feed_forward_w2.lora_forward(
+            &(w1.gelu()? * w3)?,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )
+    }
+}
+
+#[derive(Debug)]
+struct LayerWeights {
+    attention_wq: QLoraLinear,
+    attention_wk: QLoraLinear,
+    attention_wv: QLoraLinear,
+    attention_wo: QLoraLinear,
+    attention_norm: QRmsNorm,
+    mlp: Mlp,
+    ffn_norm: QRmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rotary: RotaryEmbedding,
+    neg_inf: Tensor,
+}
+
+impl LayerWeights {
+    #[allow(clippy::too_many_arguments)]
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: &Option<Tensor>,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let q = self.attention_wq.lora_forward(
+            x,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let k = self.attention_wk.lora_forward(
+            x,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let v = self.attention_wv.lora_forward(
+            x,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+
+        let mut q = q.reshape((b_sz * seq_len, self.n_head, self.head_dim))?;
+        let mut k = k.reshape((b_sz * seq_len, self.n_kv_head, self.head_dim))?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        self.rotary
+            .forward(start_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 {
+            q = q
+                .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+                .transpose(1, 2)?;
+        }
+
+        let (k, v) = match &*kv_cache {
+            None => (k, v),
+            Some((k_cache, v_cache)) => {
+                let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
+                let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
+                (k, v)
+            }
+        };
+        *kv_cache = Some((k.clone(), v.clone()));
+
+        let k = repeat_kv(k, self.n_head / self.n_kv_head)?.contiguous()?;
+        let v = repeat_kv(v, self.n_head / self.n_kv_head)?.contiguous()?;
+
+        let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
+        let att = CausalMasker.apply_mask(mask, att, &self.neg_inf)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        // The attention width of Gemma is not necessarily the hidden size.
+        let y = y
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.n_head * self.head_dim))?;
+        let y = self.attention_wo.lora_forward(
+            &y,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        Ok(y)
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    embedding_length: usize,
+    layers: Vec<LayerWeights>,
+    norm: QRmsNorm,
+    output: QMatMul,
+    pub device: Device,
+    pub cache: Cache,
+    xlora_classifier: Option<XLoraClassifier>,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+}
+
+impl ModelWeights {
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        lora_config: &[(String, LoraConfig)],
+        vb: &VarBuilder,
+        ordering: &Ordering,
+        xlora_config: Option<XLoraConfig>,
+        mapper: DeviceMapMetadata,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        verify_sanity_gguf(
+            md_get("general.architecture")?.to_string().unwrap(),
+            "gemma",
+        )?;
+        verify_sanity_adapters(ordering, &SUPPORTED_LAYERS)?;
+
+        // Parameter extraction from metadata.
+        let head_count = md_get("gemma.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("gemma.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("gemma.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("gemma.embedding_length")?.to_u32()? as usize;
+        let head_dim = md_get("gemma.attention.key_length")
+            .and_then(|m| m.to_u32())
+            .map(|m| m as usize)
+            .unwrap_or(embedding_length / head_count);
+        let rms_norm_eps = md_get("gemma.attention.layer_norm_rms_epsilon")?.to_f32()?;
+        let max_seq_len = md_get("gemma.context_length")
+            .and_then(|m| m.to_u64())
+            .unwrap_or(MAX_SEQ_LEN as u64) as usize;
+        let rope_freq_base = md_get("gemma.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let norm = QRmsNorm::new(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        // Gemma ties the output projection to the token embeddings.
+        let output = if ct.tensor_infos.contains_key("output.weight") {
+            ct.tensor(reader, "output.weight", device)?
+        } else {
+            ct.tensor(reader, "token_embd.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        let mut count = 0;
+        let mapper = mapper.into_mapper(block_count, device)?;
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+            let rotary = RotaryEmbedding::new_partial(
+                rope_freq_base,
+                head_dim,
+                head_dim,
+                max_seq_len,
+                device,
+                true,
+                DType::F32,
+            )?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo =
+                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let feed_forward_w1 =
+                ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
+            let feed_forward_w2 =
+                ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
+            let feed_forward_w3 = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+            let cfg_w1 = get_lora_cfg(&feed_forward_w1);
+            let cfg_w2 = get_lora_cfg(&feed_forward_w2);
+            let cfg_w3 = get_lora_cfg(&feed_forward_w3);
+            let mlp = Mlp {
+                feed_forward_w1: QLoraLinear::new(
+                    QMatMul::from_qtensor(feed_forward_w1)?,
+                    &cfg_w1,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.mlp.gate_proj"),
+                    &mut count,
+                )?,
+                feed_forward_w2: QLoraLinear::new(
+                    QMatMul::from_qtensor(feed_forward_w2)?,
+                    &cfg_w2,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.mlp.down_proj"),
+                    &mut count,
+                )?,
+                feed_forward_w3: QLoraLinear::new(
+                    QMatMul::from_qtensor(feed_forward_w3)?,
+                    &cfg_w3,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.mlp.up_proj"),
+                    &mut count,
+                )?,
+            };
+            let attention_norm =
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
+            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+            let cfgq = get_lora_cfg(&attention_wq);
+            let cfgk = get_lora_cfg(&attention_wk);
+            let cfgv = get_lora_cfg(&attention_wv);
+            let cfgo = get_lora_cfg(&attention_wo);
+            layers.push(LayerWeights {
+                attention_wq: QLoraLinear::new(
+                    QMatMul::from_qtensor(attention_wq)?,
+                    &cfgq,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.self_attn.q_proj"),
+                    &mut count,
+                )?,
+                attention_wk: QLoraLinear::new(
+                    QMatMul::from_qtensor(attention_wk)?,
+                    &cfgk,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.self_attn.k_proj"),
+                    &mut count,
+                )?,
+                attention_wv: QLoraLinear::new(
+                    QMatMul::from_qtensor(attention_wv)?,
+                    &cfgv,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.self_attn.v_proj"),
+                    &mut count,
+                )?,
+                attention_wo: QLoraLinear::new(
+                    QMatMul::from_qtensor(attention_wo)?,
+                    &cfgo,
+                    lora_config,
+                    vb,
+                    ordering,
+                    format!("model.layers.{layer_idx}.self_attn.o_proj"),
+                    &mut count,
+                )?,
+                attention_norm: QRmsNorm::new(attention_norm, rms_norm_eps)?,
+                mlp,
+                ffn_norm: QRmsNorm::new(ffn_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                rotary,
+                neg_inf,
+            })
+        }
+        if xlora_config.is_none() {
+            // We are now a LoRA model so we must merge the weights
+            info!("Merging LoRA adapters.");
+            for layer in layers.iter_mut().tqdm() {
+                layer.attention_wk.merge_weights()?;
+                layer.attention_wo.merge_weights()?;
+                layer.attention_wq.merge_weights()?;
+                layer.attention_wv.merge_weights()?;
+                layer.mlp.feed_forward_w1.merge_weights()?;
+                layer.mlp.feed_forward_w2.merge_weights()?;
+                layer.mlp.feed_forward_w3.merge_weights()?;
+            }
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            embedding_length,
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            device: device.clone(),
+            cache: Cache::new(block_count, true),
+            xlora_classifier: xlora_config.map(|xlora_config| {
+                XLoraClassifier::new(xlora_config, count, lora_config.len(), vb.clone(), true)
+                    .unwrap()
+            }),
+            max_seq_len,
+            mapper: Some(mapper),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn inner_forward(
+        &mut self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        scalings: Option<Tensor>,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let mask = CausalMasker.make_causal_mask(x, &self.cache)?;
+        let mut layer_in =
+            (self.tok_embeddings.forward(x)? * (self.embedding_length as f64).sqrt())?;
+        let mut cache = if is_full_pass {
+            if no_kv_cache {
+                let mut new_cache = Vec::new();
+                for _ in 0..self.cache.xlora_lock().len() {
+                    new_cache.push(None);
+                }
+
+                self.cache.xlora_lock().clone_from(&new_cache);
+            }
+            self.cache.xlora_lock()
+        } else {
+            self.cache.lock()
+        };
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(
+                &x,
+                &mask.as_ref().map(|m| m.to_device(x.device()).unwrap()),
+                start_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+                scalings.clone(),
+                self.xlora_classifier
+                    .as_ref()
+                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .unwrap_or(1.0),
+                is_scaling_pass,
+            )?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(
+                &x,
+                scalings.clone(),
+                self.xlora_classifier
+                    .as_ref()
+                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .unwrap_or(1.0),
+                is_scaling_pass,
+            )?;
+            let x = (x + residual)?;
+            layer_in = x;
+        }
+        let layer_in = layer_in.to_device(&self.device)?;
+        self.norm.forward(&layer_in)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: Tensor,
+        start_offsets_kernel_full: Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        if self.xlora_classifier.is_some() {
+            let scalings = self.get_scalings(
+                input_ids,
+                input_ids_full,
+                seqlen_offsets,
+                seqlen_offsets_full,
+                &start_offsets_kernel,
+                &start_offsets_kernel_full,
+                no_kv_cache,
+                non_granular_state,
+                &vec![usize::MAX; context_lens.len()],
+            )?;
+
+            if no_kv_cache {
+                extract_logits(
+                    &self
+                        .inner_forward(
+                            input_ids_full,
+                            seqlen_offsets_full,
+                            start_offsets_kernel_full,
+                            Some(scalings),
+                            true,
+                            no_kv_cache,
+                            None,
+                        )?
+                        .contiguous()?
+                        .apply(&self.output)?,
+                    context_lens,
+                )
+            } else {
+                // is_full_pass=true is ok because no_kv_cache=false
+                extract_logits(
+                    &self
+                        .inner_forward(
+                            input_ids,
+                            seqlen_offsets,
+                            start_offsets_kernel,
+                            Some(scalings),
+                            true,
+                            no_kv_cache,
+                            None,
+                        )?
+                        .contiguous()?
+                        .apply(&self.output)?,
+                    context_lens,
+                )
+            }
+        } else {
+            extract_logits(
+                &self
+                    .inner_forward(
+                        input_ids,
+                        seqlen_offsets,
+                        start_offsets_kernel,
+                        None,
+                        false,
+                        no_kv_cache,
+                        None,
+                    )?
+                    .contiguous()?
+                    .apply(&self.output)?,
+                context_lens,
+            )
+        }
+    }
+}
+
+impl ScalingsMaker for ModelWeights {
+    fn dtype(&self) -> DType {
+        DType::F32 // for dummy scalings
+    }
+    fn get_cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn get_classifier(&self) -> &XLoraClassifier {
+        self.xlora_classifier.as_ref().unwrap()
+    }
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        scalings: Tensor,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        _context_lens: &[usize],
+    ) -> Result<Tensor> {
+        self.inner_forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            Some(scalings),
+            is_full_pass,
+            no_kv_cache,
+            is_scaling_pass,
+        )
+    }
+}
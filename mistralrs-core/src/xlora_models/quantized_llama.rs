@@ -10,7 +10,7 @@ use tracing::info;
 
 use crate::device_map::DeviceMapper;
 use crate::layers::{CausalMasker, QRmsNorm};
-use crate::models::{repeat_kv, verify_sanity_gguf, Cache};
+use crate::models::{gguf_expert_weights, repeat_kv, verify_sanity_gguf, Cache};
 use crate::pipeline::extract_logits;
 use crate::DeviceMapMetadata;
 
@@ -520,14 +520,13 @@ impl ModelWeights {
             } else {
                 let feed_forward_gate_inp =
                     ct.tensor(reader, &format!("{prefix}.ffn_gate_inp.weight"), device)?;
+                let gate = gguf_expert_weights(&ct, reader, &prefix, "ffn_gate", n_expert, device)?;
+                let down = gguf_expert_weights(&ct, reader, &prefix, "ffn_down", n_expert, device)?;
+                let up = gguf_expert_weights(&ct, reader, &prefix, "ffn_up", n_expert, device)?;
                 let mut experts = Vec::with_capacity(n_expert);
-                for i in 0..n_expert {
-                    let feed_forward_w1 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_gate.{i}.weight"), device)?;
-                    let feed_forward_w2 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_down.{i}.weight"), device)?;
-                    let feed_forward_w3 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_up.{i}.weight"), device)?;
+                for (i, ((feed_forward_w1, feed_forward_w2), feed_forward_w3)) in
+                    gate.into_iter().zip(down).zip(up).enumerate()
+                {
                     let cfg_w1 = get_lora_cfg(&feed_forward_w1);
                     let cfg_w2 = get_lora_cfg(&feed_forward_w2);
                     let cfg_w3 = get_lora_cfg(&feed_forward_w3);
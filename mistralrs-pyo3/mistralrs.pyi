@@ -1,6 +1,9 @@
 from dataclasses import dataclass
 from enum import Enum
-from typing import Iterator
+from typing import Callable, Iterator
+
+# Takes the tokens of the sequence so far (prompt included) and the logits, and returns the new logits.
+LogitsProcessor = Callable[[list[int], list[float]], list[float]]
 
 @dataclass
 class ChatCompletionRequest:
@@ -16,6 +19,8 @@ class ChatCompletionRequest:
     top_logprobs: int | None = None
     max_tokens: int | None = None
     n_choices: int = 1
+    best_of: int | None = None
+    best_of_length_normalize: bool | None = None
     presence_penalty: float | None = None
     frequency_penalty: float | None = None
     stop_seqs: list[str] | None = None
@@ -25,6 +30,30 @@ class ChatCompletionRequest:
     top_k: int | None = None
     grammar: str | None = None
     grammar_type: str | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    no_repeat_ngram_size: int | None = None
+    min_p: float | None = None
+    typical_p: float | None = None
+    tfs_z: float | None = None
+    top_a: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[str] | None = None
+    repetition_penalty: float | None = None
+    repeat_last_n: int | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
+    logits_processors: list[LogitsProcessor] | None = None
+    stop_regexes: list[str] | None = None
+    stop_token_seqs: list[list[int]] | None = None
+    include_stop_str_in_output: bool = False
+    continue_final_message: bool = False
+    echo_prefill: bool = False
 
 @dataclass
 class CompletionRequest:
@@ -39,7 +68,8 @@ class CompletionRequest:
     logit_bias: dict[int, float] | None = None
     max_tokens: int | None = None
     n_choices: int = 1
-    best_of: int = 1
+    best_of: int | None = None
+    best_of_length_normalize: bool | None = None
     presence_penalty: float | None = None
     frequency_penalty: float | None = None
     stop_seqs: list[str] | None = None
@@ -49,6 +79,28 @@ class CompletionRequest:
     suffix: str | None = None
     grammar: str | None = None
     grammar_type: str | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    early_stopping: bool | None = None
+    no_repeat_ngram_size: int | None = None
+    min_p: float | None = None
+    typical_p: float | None = None
+    tfs_z: float | None = None
+    top_a: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[str] | None = None
+    repetition_penalty: float | None = None
+    repeat_last_n: int | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
+    logits_processors: list[LogitsProcessor] | None = None
+    stop_regexes: list[str] | None = None
+    stop_token_seqs: list[list[int]] | None = None
+    include_stop_str_in_output: bool = False
 
 @dataclass
 class Architecture(Enum):
@@ -57,6 +109,13 @@ class Architecture(Enum):
     Mixtral = "mixtral"
     Llama = "llama"
     Phi2 = "phi2"
+    Phi3 = "phi3"
+    Qwen2 = "qwen2"
+    Falcon = "falcon"
+    Starcoder2 = "starcoder2"
+    Qwen2Moe = "qwen2moe"
+    DeepSeek = "deepseek"
+    Mamba = "mamba"
 
 class Which(Enum):
     """
@@ -91,7 +150,7 @@ class Which(Enum):
         repeat_last_n: int = 64
     @dataclass
     class GGUF:
-        tok_model_id: str
+        tok_model_id: str | None
         quantized_model_id: str
         quantized_filename: str
         tokenizer_json: str | None = None
@@ -148,11 +207,17 @@ class Runner:
         which: Which,
         max_seqs: int = 16,
         no_kv_cache: bool = False,
-        prefix_cache_n: int = 16,
+        prefix_cache_device_mb: int = 1024,
+        prefix_cache_host_mb: int = 4096,
+        prefix_cache_dir: str | None = None,
+        prefix_cache_disk_mb: int = 16384,
         token_source: str = "cache",
         chat_template: str | None = None,
         num_device_layers: int | None = None,
         in_situ_quant: str | None = None,
+        logits_processors: list[LogitsProcessor] | None = None,
+        offline: bool = False,
+        verify_checksums: bool = False,
     ) -> None:
         """
         Load a model.
@@ -160,7 +225,10 @@ class Runner:
         - `which` specified which model to load.
         - `max_seqs` specifies how many sequences may be running at any time.
         - `no_kv_cache` disables the KV cache.
-        - `prefix_cache_n` sets the number of sequences to hold in the device prefix cache, others will be evicted to CPU.
+        - `prefix_cache_device_mb` sets the megabytes of prefix caches to hold on the device, least recently used caches will be evicted to CPU.
+        - `prefix_cache_host_mb` sets the megabytes of prefix caches to hold on the CPU, least recently used caches will be spilled to disk or dropped.
+        - `prefix_cache_dir` sets an optional directory to spill prefix caches to. Spilled caches are reloaded on a hit.
+        - `prefix_cache_disk_mb` sets the megabytes of prefix caches to hold on disk.
         - `token_source` specifies where to load the HF token from.
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `chat_template` specifies an optional JINJA chat template.
@@ -168,6 +236,10 @@ class Runner:
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
         - `num_device_layers` sets the number of layers to load and run on the device.
         - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
+        - `logits_processors` are run for every request before its own logits processors, before sampling and grammar masking.
+        - `offline` never uses the network: the model is only loaded from local directories or the Hugging Face cache.
+            All needed files are checked before loading and any missing ones are reported.
+        - `verify_checksums` verifies the SHA256 of safetensors weights downloaded from the HF hub against the hash recorded in the Hugging Face cache.
         """
         ...
 
@@ -193,6 +265,7 @@ class Runner:
 class Usage:
     completion_tokens: int
     prompt_tokens: int
+    cached_prompt_tokens: int
     total_tokens: int
     avg_tok_per_sec: float
     avg_prompt_tok_per_sec: float
@@ -1,6 +1,6 @@
 #![allow(clippy::too_many_arguments)]
 
-use candle_core::{quantized::GgmlDType, Result};
+use candle_core::{quantized::GgmlDType, Result, Tensor};
 use either::Either;
 use indexmap::IndexMap;
 use std::{
@@ -16,10 +16,12 @@ use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
 use candle_core::Device;
 use mistralrs_core::{
-    ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder,
-    NormalLoaderBuilder, NormalSpecificConfig, Request as _Request, RequestMessage, Response,
-    SamplingParams, SchedulerMethod, StopTokens, TokenSource,
+    set_offline, set_verify_checksums, BeamSearchParams, ChatCompletionResponse,
+    CompletionResponse, Constraint, DeviceMapMetadata, DrySamplingParams, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, LogitsProcessor, Mirostat,
+    MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig, PrefixCacheBudgets,
+    Request as _Request, RequestMessage, Response, SamplerStage, SamplingParams, SchedulerMethod,
+    StopTokens, TokenSource,
 };
 use pyo3::{
     exceptions::{PyTypeError, PyValueError},
@@ -90,21 +92,33 @@ impl Runner {
         which,
         max_seqs = 16,
         no_kv_cache = false,
-        prefix_cache_n = 16,
+        prefix_cache_device_mb = 1024,
+        prefix_cache_host_mb = 4096,
+        prefix_cache_dir = None,
+        prefix_cache_disk_mb = 16384,
         token_source = "cache",
         chat_template = None,
         num_device_layers = None,
-        in_situ_quant = None
+        in_situ_quant = None,
+        logits_processors = None,
+        offline = false,
+        verify_checksums = false
     ))]
     fn new(
         which: Which,
         max_seqs: usize,
         no_kv_cache: bool,
-        prefix_cache_n: usize,
+        prefix_cache_device_mb: usize,
+        prefix_cache_host_mb: usize,
+        prefix_cache_dir: Option<String>,
+        prefix_cache_disk_mb: usize,
         token_source: &str,
         chat_template: Option<String>,
         num_device_layers: Option<usize>,
         in_situ_quant: Option<String>,
+        logits_processors: Option<Vec<PyObject>>,
+        offline: bool,
+        verify_checksums: bool,
     ) -> PyResult<Self> {
         const REPEAT_LAST_N_DEFAULT: usize = 64;
         const GQA_DEFAULT: usize = 1;
@@ -150,6 +164,7 @@ impl Runner {
                 NormalSpecificConfig {
                     use_flash_attn,
                     repeat_last_n: repeat_last_n.unwrap_or(REPEAT_LAST_N_DEFAULT),
+                    rope_scaling: None,
                 },
                 chat_template,
                 tokenizer_json,
@@ -168,6 +183,7 @@ impl Runner {
                 NormalSpecificConfig {
                     use_flash_attn,
                     repeat_last_n: repeat_last_n.unwrap_or(REPEAT_LAST_N_DEFAULT),
+                    rope_scaling: None,
                 },
                 chat_template,
                 tokenizer_json,
@@ -195,6 +211,7 @@ impl Runner {
                 NormalSpecificConfig {
                     use_flash_attn,
                     repeat_last_n: repeat_last_n.unwrap_or(REPEAT_LAST_N_DEFAULT),
+                    rope_scaling: None,
                 },
                 chat_template,
                 tokenizer_json,
@@ -221,9 +238,9 @@ impl Runner {
                 },
                 chat_template,
                 tokenizer_json,
-                Some(tok_model_id),
+                tok_model_id,
                 quantized_model_id,
-                quantized_filename,
+                vec![quantized_filename],
             )
             .build(),
             Which::XLoraGGUF {
@@ -243,7 +260,7 @@ impl Runner {
                 tokenizer_json,
                 tok_model_id,
                 quantized_model_id,
-                quantized_filename,
+                vec![quantized_filename],
             )
             .with_xlora(
                 xlora_model_id,
@@ -272,7 +289,7 @@ impl Runner {
                 tokenizer_json,
                 tok_model_id,
                 quantized_model_id,
-                quantized_filename,
+                vec![quantized_filename],
             )
             .with_lora(
                 adapters_model_id,
@@ -365,6 +382,12 @@ impl Runner {
             .build(),
         };
 
+        if offline {
+            set_offline(true);
+        }
+        if verify_checksums {
+            set_verify_checksums(true);
+        }
         let device = get_device().map_err(|e| PyValueError::new_err(e.to_string()))?;
         let isq = if let Some(isq) = in_situ_quant {
             Some(parse_isq(&isq).map_err(|e| PyValueError::new_err(e.to_string()))?)
@@ -386,7 +409,7 @@ impl Runner {
             )
             .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
-        let mistralrs = MistralRsBuilder::new(
+        let mut builder = MistralRsBuilder::new(
             pipeline,
             SchedulerMethod::Fixed(
                 max_seqs
@@ -395,8 +418,16 @@ impl Runner {
             ),
         )
         .with_no_kv_cache(no_kv_cache)
-        .with_prefix_cache_n(prefix_cache_n)
-        .build();
+        .with_prefix_cache_budgets(PrefixCacheBudgets {
+            device_bytes: prefix_cache_device_mb * 1024 * 1024,
+            host_bytes: prefix_cache_host_mb * 1024 * 1024,
+            disk_dir: prefix_cache_dir.map(Into::into),
+            disk_bytes: prefix_cache_disk_mb * 1024 * 1024,
+        });
+        for processor in logits_processors.unwrap_or_default() {
+            builder = builder.with_logits_processor(Arc::new(PyLogitsProcessor(processor)));
+        }
+        let mistralrs = builder.build();
 
         Ok(Self { runner: mistralrs })
     }
@@ -454,17 +485,25 @@ impl Runner {
                                     "Only `user`, `assistant`, `system` roles supported.",
                                 ));
                             }
-                            message_map.insert("role".to_string(), role.to_string());
-                            message_map.insert("content".to_string(), content.clone());
+                            message_map.insert("role".to_string(), Either::Left(role.to_string()));
+                            message_map
+                                .insert("content".to_string(), Either::Left(content.clone()));
                             messages_vec.push(message_map);
                         }
-                        RequestMessage::Chat(messages_vec)
+                        if request.continue_final_message {
+                            RequestMessage::ChatContinuation {
+                                messages: messages_vec,
+                                echo_prefill: request.echo_prefill,
+                            }
+                        } else {
+                            RequestMessage::Chat(messages_vec)
+                        }
                     }
                     Either::Right(ref prompt) => {
                         let mut messages = Vec::new();
                         let mut message_map = IndexMap::new();
-                        message_map.insert("role".to_string(), "user".to_string());
-                        message_map.insert("content".to_string(), prompt.to_string());
+                        message_map.insert("role".to_string(), Either::Left("user".to_string()));
+                        message_map.insert("content".to_string(), Either::Left(prompt.to_string()));
                         messages.push(message_map);
                         RequestMessage::Chat(messages)
                     }
@@ -476,16 +515,37 @@ impl Runner {
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    dry_params: request.dry_params.clone(),
+                    repeat_last_n: request.repeat_last_n,
                     max_len: request.max_tokens,
                     stop_toks,
+                    stop_regexes: request.stop_regexes.clone(),
+                    stop_token_seqs: request.stop_token_seqs.clone(),
+                    include_stop_str_in_output: request.include_stop_str_in_output,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    best_of: request.best_of,
+                    best_of_length_normalize: request.best_of_length_normalize,
+                    beam_search: beam_search_params(
+                        request.num_beams,
+                        request.length_penalty,
+                        request.early_stopping,
+                        request.no_repeat_ngram_size,
+                    ),
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    top_a: request.top_a,
+                    mirostat: request.mirostat,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
                 is_streaming: request.stream,
                 constraint,
                 suffix: None,
+                logits_processors: py_logits_processors(py, &request.logits_processors),
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -495,7 +555,8 @@ impl Runner {
             if request.stream {
                 Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
             } else {
-                let response = rx.blocking_recv().unwrap();
+                // Release the GIL so that Python logits processors can run.
+                let response = py.allow_threads(|| rx.blocking_recv()).unwrap();
 
                 match response {
                     Response::ValidationError(e) | Response::InternalError(e) => {
@@ -555,7 +616,6 @@ impl Runner {
                 messages: RequestMessage::Completion {
                     text: request.prompt.clone(),
                     echo_prompt: request.echo_prompt,
-                    best_of: request.best_of,
                 },
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
@@ -564,22 +624,44 @@ impl Runner {
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    dry_params: request.dry_params.clone(),
+                    repeat_last_n: request.repeat_last_n,
                     max_len: request.max_tokens,
                     stop_toks,
+                    stop_regexes: request.stop_regexes.clone(),
+                    stop_token_seqs: request.stop_token_seqs.clone(),
+                    include_stop_str_in_output: request.include_stop_str_in_output,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    best_of: request.best_of,
+                    best_of_length_normalize: request.best_of_length_normalize,
+                    beam_search: beam_search_params(
+                        request.num_beams,
+                        request.length_penalty,
+                        request.early_stopping,
+                        request.no_repeat_ngram_size,
+                    ),
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    top_a: request.top_a,
+                    mirostat: request.mirostat,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
                 return_logprobs: false,
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
+                logits_processors: py_logits_processors(py, &request.logits_processors),
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
             let sender = self.runner.get_sender();
             sender.blocking_send(model_request).unwrap();
-            let response = rx.blocking_recv().unwrap();
+            // Release the GIL so that Python logits processors can run.
+            let response = py.allow_threads(|| rx.blocking_recv()).unwrap();
 
             match response {
                 Response::ValidationError(e) | Response::InternalError(e) => {
@@ -605,13 +687,88 @@ impl Runner {
     }
 }
 
+fn beam_search_params(
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    early_stopping: Option<bool>,
+    no_repeat_ngram_size: Option<usize>,
+) -> Option<BeamSearchParams> {
+    num_beams.map(|num_beams| BeamSearchParams {
+        num_beams,
+        length_penalty: length_penalty.unwrap_or(1.0),
+        early_stopping: early_stopping.unwrap_or(false),
+        no_repeat_ngram_size: no_repeat_ngram_size.unwrap_or(0),
+    })
+}
+
+/// A Python callable taking the tokens of the sequence and the logits, and returning the new logits.
+struct PyLogitsProcessor(PyObject);
+
+impl LogitsProcessor for PyLogitsProcessor {
+    fn apply(&self, logits: &Tensor, toks: &[u32]) -> Result<Tensor> {
+        let values: Vec<f32> = logits.to_vec1()?;
+        let processed = Python::with_gil(|py| {
+            self.0
+                .call1(py, (toks.to_vec(), values))?
+                .extract::<Vec<f32>>(py)
+        })
+        .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let len = processed.len();
+        Tensor::from_vec(processed, len, logits.device())
+    }
+}
+
+fn py_logits_processors(
+    py: Python<'_>,
+    processors: &Option<Vec<PyObject>>,
+) -> Option<Vec<Arc<dyn LogitsProcessor>>> {
+    processors.as_ref().map(|processors| {
+        processors
+            .iter()
+            .map(|processor| {
+                Arc::new(PyLogitsProcessor(processor.clone_ref(py))) as Arc<dyn LogitsProcessor>
+            })
+            .collect()
+    })
+}
+
+/// Mirostat mode 1 or 2, or 0 to disable it.
+fn mirostat_params(
+    mode: Option<usize>,
+    tau: Option<f32>,
+    eta: Option<f32>,
+) -> PyResult<Option<Mirostat>> {
+    let tau = tau.unwrap_or(5.0);
+    let eta = eta.unwrap_or(0.1);
+    match mode {
+        None | Some(0) => Ok(None),
+        Some(1) => Ok(Some(Mirostat::V1 { tau, eta })),
+        Some(2) => Ok(Some(Mirostat::V2 { tau, eta })),
+        Some(mode) => Err(PyValueError::new_err(format!(
+            "Mirostat mode must be 0, 1 or 2, got {mode}."
+        ))),
+    }
+}
+
+fn parse_sampler_order(order: Option<Vec<String>>) -> PyResult<Option<Vec<SamplerStage>>> {
+    order
+        .map(|order| {
+            order
+                .iter()
+                .map(|stage| SamplerStage::from_str(stage).map_err(PyValueError::new_err))
+                .collect()
+        })
+        .transpose()
+}
+
 #[pyclass]
 #[derive(Debug)]
 /// An OpenAI API compatible completion request.
 struct CompletionRequest {
     _model: String,
     prompt: String,
-    best_of: usize,
+    best_of: Option<usize>,
+    best_of_length_normalize: Option<bool>,
     echo_prompt: bool,
     presence_penalty: Option<f32>,
     frequency_penalty: Option<f32>,
@@ -625,6 +782,23 @@ struct CompletionRequest {
     top_k: Option<usize>,
     grammar: Option<String>,
     grammar_type: Option<String>,
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    early_stopping: Option<bool>,
+    no_repeat_ngram_size: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    top_a: Option<f64>,
+    mirostat: Option<Mirostat>,
+    sampler_order: Option<Vec<SamplerStage>>,
+    repetition_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
+    dry_params: Option<DrySamplingParams>,
+    logits_processors: Option<Vec<PyObject>>,
+    stop_regexes: Option<Vec<String>>,
+    stop_token_seqs: Option<Vec<Vec<u32>>>,
+    include_stop_str_in_output: bool,
 }
 
 #[pymethods]
@@ -633,7 +807,8 @@ impl CompletionRequest {
     #[pyo3(signature = (
         prompt,
         model,
-        best_of = 1,
+        best_of = None,
+        best_of_length_normalize = None,
         echo_prompt = false,
         presence_penalty=None,
         frequency_penalty=None,
@@ -646,12 +821,35 @@ impl CompletionRequest {
         suffix=None,
         top_k=None,
         grammar = None,
-        grammar_type = None
+        grammar_type = None,
+        num_beams = None,
+        length_penalty = None,
+        early_stopping = None,
+        no_repeat_ngram_size = None,
+        min_p = None,
+        typical_p = None,
+        tfs_z = None,
+        top_a = None,
+        mirostat = None,
+        mirostat_tau = None,
+        mirostat_eta = None,
+        sampler_order = None,
+        repetition_penalty = None,
+        repeat_last_n = None,
+        dry_multiplier = None,
+        dry_base = None,
+        dry_allowed_length = None,
+        dry_sequence_breakers = None,
+        logits_processors = None,
+        stop_regexes = None,
+        stop_token_seqs = None,
+        include_stop_str_in_output = false
     ))]
     fn new(
         prompt: String,
         model: String,
-        best_of: usize,
+        best_of: Option<usize>,
+        best_of_length_normalize: Option<bool>,
         echo_prompt: bool,
         presence_penalty: Option<f32>,
         frequency_penalty: Option<f32>,
@@ -665,10 +863,33 @@ impl CompletionRequest {
         top_k: Option<usize>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        no_repeat_ngram_size: Option<usize>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        top_a: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<String>>,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        logits_processors: Option<Vec<PyObject>>,
+        stop_regexes: Option<Vec<String>>,
+        stop_token_seqs: Option<Vec<Vec<u32>>>,
+        include_stop_str_in_output: bool,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
             best_of,
+            best_of_length_normalize,
             echo_prompt,
             suffix,
             _model: model,
@@ -683,6 +904,30 @@ impl CompletionRequest {
             top_k,
             grammar,
             grammar_type,
+            num_beams,
+            length_penalty,
+            early_stopping,
+            no_repeat_ngram_size,
+            min_p,
+            typical_p,
+            tfs_z,
+            top_a,
+            mirostat: mirostat_params(mirostat, mirostat_tau, mirostat_eta)?,
+            sampler_order: parse_sampler_order(sampler_order)?,
+            repetition_penalty,
+            repeat_last_n,
+            dry_params: dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    dry_sequence_breakers,
+                    dry_base,
+                    dry_allowed_length,
+                )
+            }),
+            logits_processors,
+            stop_regexes,
+            stop_token_seqs,
+            include_stop_str_in_output,
         })
     }
 }
@@ -698,6 +943,8 @@ struct ChatCompletionRequest {
     top_logprobs: Option<usize>,
     max_tokens: Option<usize>,
     n_choices: usize,
+    best_of: Option<usize>,
+    best_of_length_normalize: Option<bool>,
     presence_penalty: Option<f32>,
     frequency_penalty: Option<f32>,
     stop_seqs: Option<Vec<String>>,
@@ -707,6 +954,25 @@ struct ChatCompletionRequest {
     top_k: Option<usize>,
     grammar: Option<String>,
     grammar_type: Option<String>,
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    early_stopping: Option<bool>,
+    no_repeat_ngram_size: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    top_a: Option<f64>,
+    mirostat: Option<Mirostat>,
+    sampler_order: Option<Vec<SamplerStage>>,
+    repetition_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
+    dry_params: Option<DrySamplingParams>,
+    logits_processors: Option<Vec<PyObject>>,
+    stop_regexes: Option<Vec<String>>,
+    stop_token_seqs: Option<Vec<Vec<u32>>>,
+    include_stop_str_in_output: bool,
+    continue_final_message: bool,
+    echo_prefill: bool,
 }
 
 #[pymethods]
@@ -717,6 +983,8 @@ impl ChatCompletionRequest {
         model,
         logprobs = false,
         n_choices = 1,
+        best_of = None,
+        best_of_length_normalize = None,
         logit_bias = None,
         top_logprobs = None,
         max_tokens = None,
@@ -728,13 +996,39 @@ impl ChatCompletionRequest {
         top_k = None,
         stream=false,
         grammar = None,
-        grammar_type = None
+        grammar_type = None,
+        num_beams = None,
+        length_penalty = None,
+        early_stopping = None,
+        no_repeat_ngram_size = None,
+        min_p = None,
+        typical_p = None,
+        tfs_z = None,
+        top_a = None,
+        mirostat = None,
+        mirostat_tau = None,
+        mirostat_eta = None,
+        sampler_order = None,
+        repetition_penalty = None,
+        repeat_last_n = None,
+        dry_multiplier = None,
+        dry_base = None,
+        dry_allowed_length = None,
+        dry_sequence_breakers = None,
+        logits_processors = None,
+        stop_regexes = None,
+        stop_token_seqs = None,
+        include_stop_str_in_output = false,
+        continue_final_message = false,
+        echo_prefill = false
     ))]
     fn new(
         messages: Py<PyAny>,
         model: String,
         logprobs: bool,
         n_choices: usize,
+        best_of: Option<usize>,
+        best_of_length_normalize: Option<bool>,
         logit_bias: Option<HashMap<u32, f32>>,
         top_logprobs: Option<usize>,
         max_tokens: Option<usize>,
@@ -747,6 +1041,30 @@ impl ChatCompletionRequest {
         stream: Option<bool>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+        no_repeat_ngram_size: Option<usize>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        top_a: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<String>>,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        logits_processors: Option<Vec<PyObject>>,
+        stop_regexes: Option<Vec<String>>,
+        stop_token_seqs: Option<Vec<Vec<u32>>>,
+        include_stop_str_in_output: bool,
+        continue_final_message: bool,
+        echo_prefill: bool,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -772,6 +1090,8 @@ impl ChatCompletionRequest {
             top_logprobs,
             max_tokens,
             n_choices,
+            best_of,
+            best_of_length_normalize,
             presence_penalty,
             frequency_penalty,
             stop_seqs,
@@ -781,6 +1101,32 @@ impl ChatCompletionRequest {
             stream: stream.unwrap_or(false),
             grammar,
             grammar_type,
+            num_beams,
+            length_penalty,
+            early_stopping,
+            no_repeat_ngram_size,
+            min_p,
+            typical_p,
+            tfs_z,
+            top_a,
+            mirostat: mirostat_params(mirostat, mirostat_tau, mirostat_eta)?,
+            sampler_order: parse_sampler_order(sampler_order)?,
+            repetition_penalty,
+            repeat_last_n,
+            dry_params: dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    dry_sequence_breakers,
+                    dry_base,
+                    dry_allowed_length,
+                )
+            }),
+            logits_processors,
+            stop_regexes,
+            stop_token_seqs,
+            include_stop_str_in_output,
+            continue_final_message,
+            echo_prefill,
         })
     }
 }
@@ -24,7 +24,10 @@ impl ChatCompletionStreamer {
         if this.is_done {
             return None;
         }
-        match this.rx.blocking_recv() {
+        // Release the GIL so that Python logits processors can run.
+        let py = this.py();
+        let rx = &mut this.rx;
+        match py.allow_threads(|| rx.blocking_recv()) {
             Some(resp) => match resp {
                 Response::ModelError(msg, _) => Some(Err(PyValueError::new_err(msg.to_string()))),
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
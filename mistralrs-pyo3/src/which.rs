@@ -10,6 +10,12 @@ pub enum Architecture {
     Llama,
     Phi2,
     Phi3,
+    Qwen2,
+    Falcon,
+    Starcoder2,
+    Qwen2Moe,
+    DeepSeek,
+    Mamba,
 }
 
 impl From<Architecture> for NormalLoaderType {
@@ -21,6 +27,12 @@ impl From<Architecture> for NormalLoaderType {
             Architecture::Mixtral => Self::Mixtral,
             Architecture::Phi2 => Self::Phi2,
             Architecture::Phi3 => Self::Phi3,
+            Architecture::Qwen2 => Self::Qwen2,
+            Architecture::Falcon => Self::Falcon,
+            Architecture::Starcoder2 => Self::Starcoder2,
+            Architecture::Qwen2Moe => Self::Qwen2Moe,
+            Architecture::DeepSeek => Self::DeepSeek,
+            Architecture::Mamba => Self::Mamba,
         }
     }
 }
@@ -56,7 +68,7 @@ pub enum Which {
 
     #[allow(clippy::upper_case_acronyms)]
     GGUF {
-        tok_model_id: String,
+        tok_model_id: Option<String>,
         tokenizer_json: Option<String>,
         quantized_model_id: String,
         quantized_filename: String,
@@ -8,7 +8,7 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{ChatCompletionRequest, Grammar, StopTokens};
+use crate::openai::{mirostat, ChatCompletionRequest, Grammar, Message, StopTokens};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -21,8 +21,8 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, Request, RequestMessage, Response,
-    SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -157,21 +157,26 @@ fn parse_request(
         None => None,
     };
     let messages = match oairequest.messages {
-        Either::Left(req_messages) => {
-            let mut messages = Vec::new();
-            for message in req_messages {
-                let mut message_map = IndexMap::new();
-                message_map.insert("role".to_string(), message.role);
-                message_map.insert("content".to_string(), message.content);
-                messages.push(message_map);
+        Either::Left(req_messages) if oairequest.continue_final_message => {
+            RequestMessage::ChatContinuation {
+                messages: req_messages
+                    .into_iter()
+                    .map(Message::into_message_map)
+                    .collect(),
+                echo_prefill: oairequest.echo_prefill,
             }
-            RequestMessage::Chat(messages)
         }
+        Either::Left(req_messages) => RequestMessage::Chat(
+            req_messages
+                .into_iter()
+                .map(Message::into_message_map)
+                .collect(),
+        ),
         Either::Right(prompt) => {
             let mut messages = Vec::new();
             let mut message_map = IndexMap::new();
-            message_map.insert("role".to_string(), "user".to_string());
-            message_map.insert("content".to_string(), prompt);
+            message_map.insert("role".to_string(), Either::Left("user".to_string()));
+            message_map.insert("content".to_string(), Either::Left(prompt));
             messages.push(message_map);
             RequestMessage::Chat(messages)
         }
@@ -187,15 +192,49 @@ fn parse_request(
             top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    oairequest.dry_sequence_breakers,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                )
+            }),
+            repeat_last_n: oairequest.repeat_last_n,
             max_len: oairequest.max_tokens,
             stop_toks,
+            stop_regexes: oairequest.stop_regexes,
+            stop_token_seqs: oairequest.stop_token_seqs,
+            include_stop_str_in_output: oairequest.include_stop_str_in_output,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            best_of: oairequest.best_of,
+            best_of_length_normalize: oairequest.best_of_length_normalize,
+            beam_search: oairequest.num_beams.map(|num_beams| BeamSearchParams {
+                num_beams,
+                length_penalty: oairequest.length_penalty.unwrap_or(1.0),
+                early_stopping: oairequest.early_stopping.unwrap_or(false),
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size.unwrap_or(0),
+            }),
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            top_a: oairequest.top_a,
+            mirostat: mirostat(
+                oairequest.mirostat,
+                oairequest.mirostat_tau,
+                oairequest.mirostat_eta,
+            ),
+            sampler_order: oairequest
+                .sampler_order
+                .map(|order| order.into_iter().map(Into::into).collect()),
         },
         response: tx,
         return_logprobs: oairequest.logprobs,
         is_streaming: oairequest.stream.unwrap_or(false),
         suffix: None,
+        logits_processors: None,
         constraint: match oairequest.grammar {
             Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
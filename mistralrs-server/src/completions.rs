@@ -1,15 +1,15 @@
 use std::{error::Error, sync::Arc};
 use tokio::sync::mpsc::{channel, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::openai::{mirostat, CompletionRequest, Grammar, StopTokens};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
     response::IntoResponse,
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, Request, RequestMessage, Response, SamplingParams,
-    StopTokens as InternalStopTokens,
+    BeamSearchParams, CompletionResponse, Constraint, DrySamplingParams, MistralRs, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 use tracing::info;
@@ -109,7 +109,6 @@ fn parse_request(
         messages: RequestMessage::Completion {
             text: oairequest.prompt,
             echo_prompt: oairequest.echo_prompt,
-            best_of: oairequest.best_of,
         },
         sampling_params: SamplingParams {
             temperature: oairequest.temperature,
@@ -118,15 +117,49 @@ fn parse_request(
             top_n_logprobs: 1,
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    oairequest.dry_sequence_breakers,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                )
+            }),
+            repeat_last_n: oairequest.repeat_last_n,
             max_len: oairequest.max_tokens,
             stop_toks,
+            stop_regexes: oairequest.stop_regexes,
+            stop_token_seqs: oairequest.stop_token_seqs,
+            include_stop_str_in_output: oairequest.include_stop_str_in_output,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            best_of: oairequest.best_of,
+            best_of_length_normalize: oairequest.best_of_length_normalize,
+            beam_search: oairequest.num_beams.map(|num_beams| BeamSearchParams {
+                num_beams,
+                length_penalty: oairequest.length_penalty.unwrap_or(1.0),
+                early_stopping: oairequest.early_stopping.unwrap_or(false),
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size.unwrap_or(0),
+            }),
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            top_a: oairequest.top_a,
+            mirostat: mirostat(
+                oairequest.mirostat,
+                oairequest.mirostat_tau,
+                oairequest.mirostat_eta,
+            ),
+            sampler_order: oairequest
+                .sampler_order
+                .map(|order| order.into_iter().map(Into::into).collect()),
         },
         response: tx,
         return_logprobs: false,
         is_streaming: false,
         suffix: oairequest.suffix,
+        logits_processors: None,
         constraint: match oairequest.grammar {
             Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
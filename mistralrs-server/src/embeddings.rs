@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use mistralrs_core::{EncoderRequest, MistralRs};
+use serde_json::json;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::openai::{
+    Embedding, EmbeddingRequest, EmbeddingResponse, EmbeddingUsage, RerankRequest, RerankResponse,
+    RerankResult,
+};
+
+fn json_error(code: StatusCode, message: String) -> Response {
+    (code, Json(json!({ "message": message }))).into_response()
+}
+
+/// Send a request to an encoder engine and wait for its output. `missing` is the error when the
+/// encoder is not served.
+async fn run_request<T>(
+    sender: Option<Sender<EncoderRequest>>,
+    missing: &str,
+    request: EncoderRequest,
+    mut rx: Receiver<Result<T, String>>,
+) -> Result<T, Response> {
+    sender
+        .ok_or_else(|| json_error(StatusCode::NOT_FOUND, missing.to_string()))?
+        .send(request)
+        .await
+        .map_err(|e| json_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match rx.recv().await {
+        Some(Ok(output)) => Ok(output),
+        Some(Err(e)) => Err(json_error(StatusCode::UNPROCESSABLE_ENTITY, e)),
+        None => Err(json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "The encoder engine did not respond.".to_string(),
+        )),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings of the inputs", body = EmbeddingResponse))
+)]
+pub async fn embeddings(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Response {
+    MistralRs::maybe_log_request(state.clone(), format!("{request:?}"));
+    if let Some(format) = request.encoding_format.filter(|f| f != "float") {
+        return json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Unsupported encoding format `{format}`, only `float` is supported."),
+        );
+    }
+    let inputs = request.input.right_or_else(|input| vec![input]);
+    if inputs.is_empty() {
+        return json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Received no inputs to embed.".to_string(),
+        );
+    }
+    let (tx, rx) = channel(1);
+    let output = match run_request(
+        state.get_embedding_sender(),
+        "No embedding model is served, start the server with `--embedding-model-id`.",
+        EncoderRequest::Embed {
+            inputs,
+            response: tx,
+        },
+        rx,
+    )
+    .await
+    {
+        Ok(output) => output,
+        Err(e) => return e,
+    };
+    let response = EmbeddingResponse {
+        object: "list",
+        data: output
+            .embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| Embedding {
+                object: "embedding",
+                embedding,
+                index,
+            })
+            .collect(),
+        model: state.get_embedding_id().unwrap_or_default(),
+        usage: EmbeddingUsage {
+            prompt_tokens: output.prompt_tokens,
+            total_tokens: output.prompt_tokens,
+        },
+    };
+    MistralRs::maybe_log_response(state, &response);
+    Json(response).into_response()
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/rerank",
+    request_body = RerankRequest,
+    responses((status = 200, description = "The documents ordered by relevance to the query", body = RerankResponse))
+)]
+pub async fn rerank(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<RerankRequest>,
+) -> Response {
+    MistralRs::maybe_log_request(state.clone(), format!("{request:?}"));
+    if request.documents.is_empty() {
+        return json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Received no documents to rerank.".to_string(),
+        );
+    }
+    let (tx, rx) = channel(1);
+    let output = match run_request(
+        state.get_reranker_sender(),
+        "No reranker is served, start the server with `--reranker-model-id`.",
+        EncoderRequest::Rerank {
+            query: request.query,
+            documents: request.documents.clone(),
+            response: tx,
+        },
+        rx,
+    )
+    .await
+    {
+        Ok(output) => output,
+        Err(e) => return e,
+    };
+    let mut results = output
+        .scores
+        .into_iter()
+        .zip(request.documents)
+        .enumerate()
+        .map(|(index, (relevance_score, document))| RerankResult {
+            index,
+            relevance_score,
+            document: request.return_documents.then_some(document),
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    if let Some(top_n) = request.top_n {
+        results.truncate(top_n);
+    }
+    let response = RerankResponse {
+        model: state.get_reranker_id().unwrap_or_default(),
+        results,
+        usage: EmbeddingUsage {
+            prompt_tokens: output.prompt_tokens,
+            total_tokens: output.prompt_tokens,
+        },
+    };
+    MistralRs::maybe_log_response(state, &response);
+    Json(response).into_response()
+}
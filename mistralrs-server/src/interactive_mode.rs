@@ -1,3 +1,4 @@
+use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{Constraint, MistralRs, Request, RequestMessage, Response, SamplingParams};
 use std::{
@@ -18,10 +19,25 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        dry_params: None,
+        repeat_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
+        stop_regexes: None,
+        stop_token_seqs: None,
+        include_stop_str_in_output: false,
         logits_bias: None,
         n_choices: 1,
+        best_of: None,
+        best_of_length_normalize: None,
+        beam_search: None,
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        top_a: None,
+        mirostat: None,
+        sampler_order: None,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
     'outer: loop {
@@ -35,8 +51,8 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             return;
         }
         let mut user_message = IndexMap::new();
-        user_message.insert("role".to_string(), "user".to_string());
-        user_message.insert("content".to_string(), prompt);
+        user_message.insert("role".to_string(), Either::Left("user".to_string()));
+        user_message.insert("content".to_string(), Either::Left(prompt));
         messages.push(user_message);
 
         let (tx, mut rx) = channel(10_000);
@@ -49,6 +65,7 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             is_streaming: true,
             constraint: Constraint::None,
             suffix: None,
+            logits_processors: None,
         };
         sender.send(req).await.unwrap();
 
@@ -86,8 +103,8 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             }
         }
         let mut assistant_message = IndexMap::new();
-        assistant_message.insert("role".to_string(), "assistant".to_string());
-        assistant_message.insert("content".to_string(), assistant_output);
+        assistant_message.insert("role".to_string(), Either::Left("assistant".to_string()));
+        assistant_message.insert("content".to_string(), Either::Left(assistant_output));
         messages.push(assistant_message);
         println!();
     }
@@ -2,27 +2,39 @@ use anyhow::Result;
 use axum::{
     extract::{Json, State},
     http::{self, Method},
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use candle_core::{quantized::GgmlDType, Device};
 use clap::Parser;
 use mistralrs_core::{
-    get_tgt_non_granular_index, DeviceMapMetadata, Loader, LoaderBuilder, MistralRs,
-    MistralRsBuilder, ModelKind, ModelSelected, SchedulerMethod, TokenSource,
+    get_tgt_non_granular_index, set_offline, set_verify_checksums, DeviceMapMetadata,
+    EncoderLoaderBuilder, Loader, LoaderBuilder, MistralRs, MistralRsBuilder, ModelKind,
+    ModelSelected, Pooling, PrefixCacheBudgets, SchedulerMethod, TokenSource,
+};
+use openai::{
+    ChatCompletionRequest, ContentPart, Embedding, EmbeddingRequest, EmbeddingResponse,
+    EmbeddingUsage, ImageUrl, Message, ModelObjects, PinPrefixRequest, RerankRequest,
+    RerankResponse, RerankResult, StopTokens,
 };
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use std::sync::Arc;
 use tracing_subscriber::EnvFilter;
 mod chat_completion;
 mod completions;
+mod embeddings;
 use crate::{chat_completion::__path_chatcompletions, completions::completions};
+use embeddings::{__path_embeddings, __path_rerank, embeddings, rerank};
 
 use crate::{chat_completion::chatcompletions, openai::ModelObject};
 mod interactive_mode;
 mod openai;
+mod prefix_cache;
 
 use interactive_mode::interactive_mode;
+use prefix_cache::{
+    __path_list_pinned_prefixes, __path_pin_prefix, __path_unpin_prefix, list_pinned_prefixes,
+    pin_prefix, unpin_prefix,
+};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info, level_filters::LevelFilter};
 use utoipa::OpenApi;
@@ -32,6 +44,10 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_pooling(s: &str) -> Result<Pooling, String> {
+    s.parse()
+}
+
 fn parse_isq(s: &str) -> Result<GgmlDType, String> {
     match s {
         "Q4_0" => Ok(GgmlDType::Q4_0),
@@ -94,13 +110,36 @@ struct Args {
     #[arg(long, default_value_t = TokenSource::CacheToken, value_parser = parse_token_source)]
     token_source: TokenSource,
 
+    /// Never use the network: load the model only from local directories or the Hugging Face cache.
+    /// All needed files are checked before loading and any missing ones are reported.
+    /// Also enabled by the `MISTRALRS_OFFLINE` or `HF_HUB_OFFLINE` environment variables.
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+
+    /// Verify the SHA256 of safetensors weights downloaded from the HF hub against the hash
+    /// recorded in the Hugging Face cache.
+    #[arg(long, default_value_t = false)]
+    verify_checksums: bool,
+
     /// Enter interactive mode instead of serving a chat server.
     #[clap(long, short, action)]
     interactive_mode: bool,
 
-    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
-    #[arg(long, default_value_t = 16)]
-    prefix_cache_n: usize,
+    /// Megabytes of prefix caches to hold on the device. Least recently used caches are evicted to the CPU.
+    #[arg(long, default_value_t = 1024)]
+    prefix_cache_device_mb: usize,
+
+    /// Megabytes of prefix caches to hold on the CPU. Least recently used caches are spilled to disk or dropped.
+    #[arg(long, default_value_t = 4096)]
+    prefix_cache_host_mb: usize,
+
+    /// Directory to spill prefix caches to once the CPU budget is full. Spilled caches are reloaded on a hit.
+    #[arg(long)]
+    prefix_cache_dir: Option<String>,
+
+    /// Megabytes of prefix caches to hold on disk, if `prefix_cache_dir` is set.
+    #[arg(long, default_value_t = 16384)]
+    prefix_cache_disk_mb: usize,
 
     /// Number of device layers to load and run on the device. All others will be on the CPU.
     #[arg(short, long)]
@@ -109,6 +148,46 @@ struct Args {
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
     #[arg(long = "isq", value_parser = parse_isq)]
     in_situ_quant: Option<GgmlDType>,
+
+    /// Enable attention sinks (StreamingLLM): once the KV cache is full, keep the first `attention_sinks` tokens
+    /// and the most recent `attention_window` tokens so that generation can continue past the maximum model length.
+    #[arg(long)]
+    attention_window: Option<usize>,
+
+    /// Number of attention sink tokens to keep when `attention_window` is set.
+    #[arg(long, default_value_t = 4)]
+    attention_sinks: usize,
+
+    /// Model ID of an embedding model (BERT, RoBERTa or XLM-RoBERTa) to serve alongside the model
+    /// for `/v1/embeddings`.
+    #[arg(long)]
+    embedding_model_id: Option<String>,
+
+    /// Path to a local tokenizer.json file for the embedding model.
+    #[arg(long)]
+    embedding_tokenizer_json: Option<String>,
+
+    /// Pooling of the embeddings: `cls`, `mean` or `last`. Defaults to the pooling of the
+    /// sentence-transformers config of the model, or `cls`.
+    #[arg(long, value_parser = parse_pooling)]
+    embedding_pooling: Option<Pooling>,
+
+    /// Do not normalize the embeddings to unit length.
+    #[arg(long, default_value_t = false)]
+    embedding_no_normalize: bool,
+
+    /// Model ID of a cross-encoder (a BERT, RoBERTa or XLM-RoBERTa `...ForSequenceClassification`)
+    /// to serve alongside the model for `/v1/rerank`.
+    #[arg(long)]
+    reranker_model_id: Option<String>,
+
+    /// Path to a local tokenizer.json file for the reranker.
+    #[arg(long)]
+    reranker_tokenizer_json: Option<String>,
+
+    /// Maximum number of inputs the embedding model or reranker runs in one batch.
+    #[arg(long, default_value_t = 32)]
+    encoder_max_batch_size: usize,
 }
 
 #[utoipa::path(
@@ -118,14 +197,19 @@ struct Args {
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
 async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+    let model = |id| ModelObject {
+        id,
+        object: "model",
+        created: state.get_creation_time(),
+        owned_by: "local",
+    };
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: std::iter::once(state.get_id())
+            .chain(state.get_embedding_id())
+            .chain(state.get_reranker_id())
+            .map(model)
+            .collect(),
     })
 }
 
@@ -142,9 +226,9 @@ async fn health() -> &'static str {
 fn get_router(state: Arc<MistralRs>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, chatcompletions, pin_prefix, list_pinned_prefixes, unpin_prefix, embeddings, rerank),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message, ContentPart, ImageUrl, PinPrefixRequest, EmbeddingRequest, EmbeddingResponse, Embedding, EmbeddingUsage, RerankRequest, RerankResponse, RerankResult)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -161,7 +245,7 @@ fn get_router(state: Arc<MistralRs>) -> Router {
 
     let allow_origin = AllowOrigin::any();
     let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
         .allow_headers([http::header::CONTENT_TYPE])
         .allow_origin(allow_origin);
 
@@ -171,6 +255,13 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
         .route("/v1/models", get(models))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/rerank", post(rerank))
+        .route(
+            "/admin/prefix_cache",
+            post(pin_prefix).get(list_pinned_prefixes),
+        )
+        .route("/admin/prefix_cache/:id", delete(unpin_prefix))
         .route("/health", get(health))
         .route("/", get(health))
         .with_state(state)
@@ -190,6 +281,12 @@ async fn main() -> Result<()> {
     if tgt_non_granular_index.is_some() {
         args.max_seqs = 1;
     }
+    if args.offline {
+        set_offline(true);
+    }
+    if args.verify_checksums {
+        set_verify_checksums(true);
+    }
 
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_no_kv_cache(args.no_kv_cache)
@@ -233,7 +330,7 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
     let pipeline = loader.load_model(
         None,
-        args.token_source,
+        args.token_source.clone(),
         None,
         &device,
         false,
@@ -244,15 +341,74 @@ async fn main() -> Result<()> {
     )?;
     info!("Model loaded.");
 
-    let mistralrs = MistralRsBuilder::new(
+    let embedding_model = match args.embedding_model_id {
+        Some(model_id) => {
+            let mut loader = EncoderLoaderBuilder::new(model_id, args.embedding_tokenizer_json)
+                .with_normalize(!args.embedding_no_normalize)
+                .with_max_batch_size(args.encoder_max_batch_size);
+            if let Some(pooling) = args.embedding_pooling {
+                loader = loader.with_pooling(pooling);
+            }
+            let loader = loader.build();
+            info!(
+                "Loading embedding model `{}` on {device:?}...",
+                loader.get_id()
+            );
+            let encoder =
+                loader.load_model(None, args.token_source.clone(), None, &device, false)?;
+            if encoder.is_cross_encoder() {
+                anyhow::bail!(
+                    "`{}` is a cross-encoder, pass it as `--reranker-model-id`.",
+                    loader.get_id()
+                );
+            }
+            info!("Embedding model loaded.");
+            Some(encoder)
+        }
+        None => None,
+    };
+    let reranker = match args.reranker_model_id {
+        Some(model_id) => {
+            let loader = EncoderLoaderBuilder::new(model_id, args.reranker_tokenizer_json)
+                .with_max_batch_size(args.encoder_max_batch_size)
+                .build();
+            info!("Loading reranker `{}` on {device:?}...", loader.get_id());
+            let encoder = loader.load_model(None, args.token_source, None, &device, false)?;
+            if !encoder.is_cross_encoder() {
+                anyhow::bail!(
+                    "`{}` is not a cross-encoder (`...ForSequenceClassification`), pass it as `--embedding-model-id`.",
+                    loader.get_id()
+                );
+            }
+            info!("Reranker loaded.");
+            Some(encoder)
+        }
+        None => None,
+    };
+
+    let mut builder = MistralRsBuilder::new(
         pipeline,
         SchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
     )
     .with_opt_log(args.log)
     .with_truncate_sequence(args.truncate_sequence)
     .with_no_kv_cache(args.no_kv_cache)
-    .with_prefix_cache_n(args.prefix_cache_n)
-    .build();
+    .with_prefix_cache_budgets(PrefixCacheBudgets {
+        device_bytes: args.prefix_cache_device_mb * 1024 * 1024,
+        host_bytes: args.prefix_cache_host_mb * 1024 * 1024,
+        disk_dir: args.prefix_cache_dir.map(Into::into),
+        disk_bytes: args.prefix_cache_disk_mb * 1024 * 1024,
+    });
+    if let Some(attention_window) = args.attention_window {
+        builder = builder.with_attention_sinks(args.attention_sinks, attention_window);
+    }
+    if let Some(embedding_model) = embedding_model {
+        builder = builder.with_embedding_model(embedding_model);
+    }
+    if let Some(reranker) = reranker {
+        builder = builder.with_reranker(reranker);
+    }
+    let mistralrs = builder.build();
 
     if args.interactive_mode {
         interactive_mode(mistralrs).await;
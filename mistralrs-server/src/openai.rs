@@ -1,15 +1,56 @@
 use either::Either;
+use indexmap::IndexMap;
+use mistralrs_core::{MessageContent, Mirostat};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use utoipa::ToSchema;
 
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ImageUrl {
+    pub url: String,
+    pub detail: Option<String>,
+}
+
+/// A part of the content of a message.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+impl From<ContentPart> for mistralrs_core::ContentPart {
+    fn from(part: ContentPart) -> Self {
+        match part {
+            ContentPart::Text { text } => Self::Text(text),
+            ContentPart::ImageUrl { image_url } => Self::ImageUrl(image_url.url),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
-    pub content: String,
+    /// A string, or an array of content parts.
+    #[serde(with = "either::serde_untagged")]
+    pub content: Either<String, Vec<ContentPart>>,
     pub role: String,
     pub name: Option<String>,
 }
 
+impl Message {
+    /// The message as passed to the engine.
+    pub fn into_message_map(self) -> IndexMap<String, MessageContent> {
+        IndexMap::from([
+            ("role".to_string(), Either::Left(self.role)),
+            (
+                "content".to_string(),
+                self.content
+                    .map_right(|parts| parts.into_iter().map(Into::into).collect()),
+            ),
+        ])
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(untagged)]
 pub enum StopTokens {
@@ -34,9 +75,46 @@ pub enum Grammar {
     Yacc(String),
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerStage {
+    Temperature,
+    TopK,
+    TopP,
+    MinP,
+    Typical,
+    TailFree,
+    TopA,
+}
+
+impl From<SamplerStage> for mistralrs_core::SamplerStage {
+    fn from(stage: SamplerStage) -> Self {
+        match stage {
+            SamplerStage::Temperature => Self::Temperature,
+            SamplerStage::TopK => Self::TopK,
+            SamplerStage::TopP => Self::TopP,
+            SamplerStage::MinP => Self::MinP,
+            SamplerStage::Typical => Self::Typical,
+            SamplerStage::TailFree => Self::TailFree,
+            SamplerStage::TopA => Self::TopA,
+        }
+    }
+}
+
+/// Mirostat mode 1 or 2, as in llama.cpp. Any other mode disables Mirostat.
+pub fn mirostat(mode: Option<usize>, tau: Option<f32>, eta: Option<f32>) -> Option<Mirostat> {
+    let tau = tau.unwrap_or(5.0);
+    let eta = eta.unwrap_or(0.1);
+    match mode {
+        Some(1) => Some(Mirostat::V1 { tau, eta }),
+        Some(2) => Some(Mirostat::V2 { tau, eta }),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
-    #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
+    #[schema(example = json!(vec![Message{content:Either::Left("Why did the crab cross the road?".to_string()), role:"user".to_string(), name: None}]))]
     #[serde(with = "either::serde_untagged")]
     pub messages: Either<Vec<Message>, String>,
     #[schema(example = "mistral")]
@@ -54,6 +132,10 @@ pub struct ChatCompletionRequest {
     #[serde(default = "default_1usize")]
     #[schema(example = 1)]
     pub n_choices: usize,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub best_of: Option<usize>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub best_of_length_normalize: Option<bool>,
     #[schema(example = json!(Option::None::<f32>))]
     pub presence_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -74,6 +156,57 @@ pub struct ChatCompletionRequest {
 
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStage>>))]
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_regexes: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Vec<Vec<u32>>>))]
+    pub stop_token_seqs: Option<Vec<Vec<u32>>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub include_stop_str_in_output: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub continue_final_message: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub echo_prefill: bool,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -96,9 +229,10 @@ pub struct CompletionRequest {
     pub model: String,
     #[schema(example = "Say this is a test.")]
     pub prompt: String,
-    #[serde(default = "default_1usize")]
-    #[schema(example = 1)]
-    pub best_of: usize,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub best_of: Option<usize>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub best_of_length_normalize: Option<bool>,
     #[serde(rename = "echo")]
     #[serde(default = "default_false")]
     #[schema(example = false)]
@@ -137,4 +271,124 @@ pub struct CompletionRequest {
 
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStage>>))]
+    pub sampler_order: Option<Vec<SamplerStage>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_regexes: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<Vec<Vec<u32>>>))]
+    pub stop_token_seqs: Option<Vec<Vec<u32>>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub include_stop_str_in_output: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct PinPrefixRequest {
+    /// Chat messages, which are formatted without a generation prompt, or raw text.
+    #[schema(example = json!(vec![Message{content:Either::Left("You are a helpful assistant.".to_string()), role:"system".to_string(), name: None}]))]
+    #[serde(with = "either::serde_untagged")]
+    pub prefix: Either<Vec<Message>, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "bge")]
+    pub model: String,
+    /// A string, or an array of strings to embed.
+    #[schema(example = json!(vec!["The food was delicious."]))]
+    #[serde(with = "either::serde_untagged")]
+    pub input: Either<String, Vec<String>>,
+    /// Only `float` is supported.
+    #[schema(example = json!(Option::None::<String>))]
+    pub encoding_format: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Embedding {
+    pub object: &'static str,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmbeddingResponse {
+    pub object: &'static str,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct RerankRequest {
+    #[schema(example = "bge-reranker")]
+    pub model: String,
+    #[schema(example = "What is the capital of France?")]
+    pub query: String,
+    #[schema(example = json!(vec!["Paris is the capital of France.", "Berlin is in Germany."]))]
+    pub documents: Vec<String>,
+    /// Only return the `top_n` most relevant documents.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub top_n: Option<usize>,
+    /// Include the text of each document in the results.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_documents: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RerankResult {
+    /// The index of the document in the request.
+    pub index: usize,
+    pub relevance_score: f32,
+    pub document: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RerankResponse {
+    pub model: String,
+    /// The documents, from the most to the least relevant.
+    pub results: Vec<RerankResult>,
+    pub usage: EmbeddingUsage,
 }
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use either::Either;
+use mistralrs_core::{MistralRs, PrefixCacheRequest, PrefixMessage};
+use serde_json::json;
+use tokio::sync::mpsc::channel;
+
+use crate::openai::{Message, PinPrefixRequest};
+
+fn json_error(code: StatusCode, message: String) -> Response {
+    (code, Json(json!({ "message": message }))).into_response()
+}
+
+async fn send_request(state: &MistralRs, request: PrefixCacheRequest) -> Result<(), Response> {
+    state
+        .get_prefix_cache_sender()
+        .send(request)
+        .await
+        .map_err(|e| json_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+fn engine_gone() -> Response {
+    json_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "The engine did not respond.".to_string(),
+    )
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/admin/prefix_cache",
+    request_body = PinPrefixRequest,
+    responses((status = 200, description = "Compute and pin a prefix in the prefix cache"))
+)]
+pub async fn pin_prefix(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<PinPrefixRequest>,
+) -> Response {
+    let message = match request.prefix {
+        Either::Left(messages) => PrefixMessage::Chat(
+            messages
+                .into_iter()
+                .map(Message::into_message_map)
+                .collect(),
+        ),
+        Either::Right(text) => PrefixMessage::Text(text),
+    };
+    let (tx, mut rx) = channel(1);
+    if let Err(e) = send_request(
+        &state,
+        PrefixCacheRequest::Pin {
+            message,
+            response: tx,
+        },
+    )
+    .await
+    {
+        return e;
+    }
+    match rx.recv().await {
+        Some(Ok(pinned)) => Json(pinned).into_response(),
+        Some(Err(e)) => json_error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        None => engine_gone(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/admin/prefix_cache",
+    responses((status = 200, description = "List the pinned prefixes"))
+)]
+pub async fn list_pinned_prefixes(State(state): State<Arc<MistralRs>>) -> Response {
+    let (tx, mut rx) = channel(1);
+    if let Err(e) = send_request(&state, PrefixCacheRequest::List { response: tx }).await {
+        return e;
+    }
+    match rx.recv().await {
+        Some(pinned) => Json(pinned).into_response(),
+        None => engine_gone(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    tag = "Mistral.rs",
+    path = "/admin/prefix_cache/{id}",
+    params(("id" = usize, Path, description = "Id of the pinned prefix")),
+    responses(
+        (status = 200, description = "Unpinned the prefix"),
+        (status = 404, description = "No prefix is pinned with this id")
+    )
+)]
+pub async fn unpin_prefix(State(state): State<Arc<MistralRs>>, Path(id): Path<usize>) -> Response {
+    let (tx, mut rx) = channel(1);
+    if let Err(e) = send_request(&state, PrefixCacheRequest::Unpin { id, response: tx }).await {
+        return e;
+    }
+    match rx.recv().await {
+        Some(true) => StatusCode::OK.into_response(),
+        Some(false) => json_error(
+            StatusCode::NOT_FOUND,
+            format!("No prefix is pinned with id {id}."),
+        ),
+        None => engine_gone(),
+    }
+}
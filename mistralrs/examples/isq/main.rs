@@ -14,6 +14,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             repeat_last_n: 64,
+            rope_scaling: None,
         },
         None,
         None,
@@ -42,7 +43,6 @@ fn main() -> anyhow::Result<()> {
         messages: RequestMessage::Completion {
             text: "Hello! My name is ".to_string(),
             echo_prompt: false,
-            best_of: 1,
         },
         sampling_params: SamplingParams::default(),
         response: tx,
@@ -51,6 +51,7 @@ fn main() -> anyhow::Result<()> {
         id: 0,
         constraint: Constraint::None,
         suffix: None,
+        logits_processors: None,
     };
     mistralrs.get_sender().blocking_send(request)?;
 
@@ -16,7 +16,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         None,
         Some("mistralai/Mistral-7B-Instruct-v0.1".to_string()),
         "TheBloke/Mistral-7B-Instruct-v0.1-GGUF".to_string(),
-        "mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string(),
+        vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
     )
     .build();
     // Load, into a Pipeline
@@ -41,7 +41,6 @@ fn main() -> anyhow::Result<()> {
         messages: RequestMessage::Completion {
             text: "Hello! My name is ".to_string(),
             echo_prompt: false,
-            best_of: 1,
         },
         sampling_params: SamplingParams::default(),
         response: tx,
@@ -50,6 +49,7 @@ fn main() -> anyhow::Result<()> {
         id: 0,
         constraint: Constraint::None,
         suffix: None,
+        logits_processors: None,
     };
     mistralrs.get_sender().blocking_send(request)?;
 
@@ -1,6 +1,8 @@
 pub use mistralrs_core::{
-    ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder,
-    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerMethod, StopTokens, TokenSource, Usage,
+    BeamSearchParams, ChatCompletionResponse, CompletionResponse, Constraint, ContentPart,
+    DeviceMapMetadata, DrySamplingParams, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
+    GGUFSpecificConfig, Loader, LogitsProcessor, MessageContent, Mirostat, MistralRs,
+    MistralRsBuilder, NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    Request, RequestMessage, Response, SamplerStage, SamplingParams, SchedulerMethod, StopTokens,
+    TokenSource, Usage,
 };